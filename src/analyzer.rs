@@ -0,0 +1,545 @@
+use crate::chip8::{Platform, Quirks, MEMORY_SIZE};
+use crate::disassembler::disassemble_opcode;
+use crate::symbols::Symbols;
+use std::io::{self, Write};
+
+/// Where ROMs are loaded.
+const START: usize = 0x200;
+
+/// Something in a ROM that behaves differently between interpreters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// 8xy6 or 8xyE with x != y. `uses_vy` if y isn't 0: assemblers for
+    /// SUPER-CHIP write `SHR Vx` as 8x06, while VIP programs mean Vy.
+    Shift { uses_vy: bool },
+    /// An instruction that uses I after the Fx55 or Fx65 at `after`, before
+    /// I is set again. `increments` if it isn't ADD I, which suggests the
+    /// program expects I to have moved on.
+    MemoryThenI { after: u16, increments: bool },
+    /// DRW at a known position that crosses the right or bottom edge, with
+    /// the size of the sprite if I is known, or the most it could be.
+    Edge { x: u8, y: u8, width: u8, height: u8 },
+    /// Bnnn. `vx` if x, the top digit of nnn, isn't 0 and Vx was set just
+    /// before but V0 wasn't, as SUPER-CHIP programs do.
+    Jump { vx: bool },
+    /// A SUPER-CHIP instruction.
+    Schip,
+    /// An XO-CHIP instruction.
+    XoChip,
+    /// 0nnn, a call to COSMAC VIP machine code, which can't be emulated and
+    /// stops the emulator unless `--semihosting` makes it a host call.
+    Sys,
+}
+
+impl Kind {
+    /// Heading for findings of this kind in the report.
+    fn heading(&self) -> &'static str {
+        match self {
+            Kind::Shift { .. } => "Shifts with different x and y (shift quirk)",
+            Kind::MemoryThenI { .. } => "I used after LD [I], Vx or LD Vx, [I] (memory quirk)",
+            Kind::Edge { .. } => "Sprites drawn across the edge (wrap quirk)",
+            Kind::Jump { .. } => "Jumps with offsets (jump quirk)",
+            Kind::Schip => "SUPER-CHIP instructions",
+            Kind::XoChip => "XO-CHIP instructions",
+            Kind::Sys => "Machine code calls",
+        }
+    }
+
+    fn note(&self) -> String {
+        match *self {
+            Kind::Shift { uses_vy: true } => "shifts Vy on the VIP".to_string(),
+            Kind::Shift { uses_vy: false } => "y is 0, so probably shifts Vx".to_string(),
+            Kind::MemoryThenI { after, increments } => format!(
+                "after {:03x}, {}",
+                after,
+                if increments {
+                    "expects I to have moved on"
+                } else {
+                    "moves I itself"
+                }
+            ),
+            Kind::Edge {
+                x,
+                y,
+                width,
+                height,
+            } => format!("{}x{} sprite at ({}, {})", width, height, x, y),
+            Kind::Jump { vx: true } => "Vx was just set, not V0".to_string(),
+            Kind::Jump { vx: false } => "uses V0".to_string(),
+            Kind::Schip | Kind::XoChip => "needs that platform".to_string(),
+            Kind::Sys => "stops the emulator".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Finding {
+    pub addr: u16,
+    pub opcode: u16,
+    pub kind: Kind,
+}
+
+/// Findings from following a ROM's code without running it, to work out which
+/// platform and quirks it was written for.
+///
+/// Code is found by following jumps, calls and skips from 0x200, so data
+/// isn't mistaken for instructions. Register values are tracked along the
+/// way where they're set from constants, to find sprites drawn across the
+/// edges and which register a Bnnn jump uses. Code only reached through Bnnn
+/// is missed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Analysis {
+    pub rom_len: usize,
+    /// Instructions found by following the code.
+    pub instructions: usize,
+    pub findings: Vec<Finding>,
+}
+
+/// What's known while following one path through the code.
+#[derive(Clone, Copy, Default)]
+struct State {
+    /// Registers set from constants.
+    v: [Option<u8>; 16],
+    /// I, if it was set from constants.
+    i: Option<usize>,
+    /// The last LD [I], Vx or LD Vx, [I], if I hasn't been set since.
+    memory_op: Option<u16>,
+}
+
+impl Analysis {
+    /// Follow the code in `rom`, loaded at 0x200.
+    pub fn new(rom: &[u8]) -> Self {
+        let end = (START + rom.len()).min(MEMORY_SIZE);
+        let opcode_at = |pc: usize| (rom[pc - START] as u16) << 8 | rom[pc + 1 - START] as u16;
+        let mut seen = vec![false; MEMORY_SIZE];
+        let mut findings = Vec::new();
+        let mut paths = vec![(START, State::default())];
+
+        while let Some((mut pc, mut state)) = paths.pop() {
+            while pc >= START && pc + 1 < end && !seen[pc] {
+                seen[pc] = true;
+                let opcode = opcode_at(pc);
+                let mut find = |kind| {
+                    findings.push(Finding {
+                        addr: pc as u16,
+                        opcode,
+                        kind,
+                    })
+                };
+                let x = (opcode >> 8 & 0xF) as usize;
+                let y = (opcode >> 4 & 0xF) as usize;
+                let n = (opcode & 0xF) as u8;
+                let nn = opcode as u8;
+                let nnn = (opcode & 0xFFF) as usize;
+
+                if uses_i(opcode) {
+                    if let Some(after) = state.memory_op.take() {
+                        let increments = opcode & 0xF0FF != 0xF01E;
+                        find(Kind::MemoryThenI { after, increments });
+                    }
+                }
+                let mut next = pc + 2;
+                match opcode >> 12 {
+                    0x0 => match opcode {
+                        0x00E0 => (),
+                        0x00EE => break,
+                        0x00FD => {
+                            find(Kind::Schip);
+                            break;
+                        }
+                        0x00C0..=0x00CF | 0x00FB..=0x00FF => find(Kind::Schip),
+                        0x00D0..=0x00DF => find(Kind::XoChip),
+                        _ => find(Kind::Sys),
+                    },
+                    0x1 => next = nnn,
+                    0x2 => {
+                        paths.push((nnn, State::default()));
+                        // The subroutine may change anything
+                        state = State::default();
+                    }
+                    0x3 | 0x4 | 0x9 => paths.push((pc + 4, state)),
+                    0x5 => match n {
+                        0x0 => paths.push((pc + 4, state)),
+                        0x2 | 0x3 => find(Kind::XoChip),
+                        _ => break,
+                    },
+                    0x6 => state.v[x] = Some(nn),
+                    0x7 => state.v[x] = state.v[x].map(|v| v.wrapping_add(nn)),
+                    0x8 => {
+                        if (n == 0x6 || n == 0xE) && x != y {
+                            find(Kind::Shift { uses_vy: y != 0 });
+                        }
+                        if n == 0 {
+                            state.v[x] = state.v[y];
+                        } else {
+                            state.v[x] = None;
+                            state.v[0xF] = None;
+                        }
+                    }
+                    0xA => {
+                        state.i = Some(nnn);
+                        state.memory_op = None;
+                    }
+                    0xB => {
+                        let x = nnn >> 8;
+                        let vx = x != 0 && state.v[x].is_some() && state.v[0].is_none();
+                        find(Kind::Jump { vx });
+                        // Wherever it goes can't be followed
+                        break;
+                    }
+                    0xC => state.v[x] = None,
+                    0xD => {
+                        if n == 0 {
+                            find(Kind::Schip);
+                        }
+                        if let (Some(sx), Some(sy)) = (state.v[x], state.v[y]) {
+                            // Positions wrap on every platform, the sprite
+                            // itself doesn't on most
+                            let (sx, sy) = (sx % 64, sy % 32);
+                            let (width, height) = sprite_size(rom, state.i, n);
+                            if sx + width > 64 || sy + height > 32 {
+                                find(Kind::Edge {
+                                    x: sx,
+                                    y: sy,
+                                    width,
+                                    height,
+                                });
+                            }
+                        }
+                        state.v[0xF] = None;
+                    }
+                    0xE => match nn {
+                        0x9E | 0xA1 => paths.push((pc + 4, state)),
+                        _ => break,
+                    },
+                    _ => match nn {
+                        0x00 if opcode == 0xF000 => {
+                            find(Kind::XoChip);
+                            state.i = None;
+                            state.memory_op = None;
+                            next = pc + 4;
+                        }
+                        0x01 | 0x3A => find(Kind::XoChip),
+                        0x02 if opcode == 0xF002 => find(Kind::XoChip),
+                        0x07 | 0x0A => state.v[x] = None,
+                        0x15 | 0x18 | 0x33 => (),
+                        0x1E => {
+                            state.i = match (state.i, state.v[x]) {
+                                (Some(i), Some(v)) => Some(i + v as usize),
+                                _ => None,
+                            }
+                        }
+                        0x29 | 0x30 => {
+                            if nn == 0x30 {
+                                find(Kind::Schip);
+                            }
+                            state.i = None;
+                            state.memory_op = None;
+                        }
+                        0x55 | 0x65 => {
+                            if nn == 0x65 {
+                                for v in &mut state.v[..=x] {
+                                    *v = None;
+                                }
+                            }
+                            // Where I ends up depends on the memory quirk
+                            state.i = None;
+                            state.memory_op = Some(pc as u16);
+                        }
+                        0x75 => find(Kind::Schip),
+                        0x85 => {
+                            find(Kind::Schip);
+                            for v in &mut state.v[..=x] {
+                                *v = None;
+                            }
+                        }
+                        // Not an instruction, so probably data
+                        _ => break,
+                    },
+                }
+                pc = next;
+            }
+        }
+
+        findings.sort_by_key(|finding| finding.addr);
+        Analysis {
+            rom_len: rom.len(),
+            instructions: seen.iter().filter(|&&seen| seen).count(),
+            findings,
+        }
+    }
+
+    fn any(&self, matches: impl Fn(&Kind) -> bool) -> bool {
+        self.findings.iter().any(|finding| matches(&finding.kind))
+    }
+
+    /// The evidence for (`Some(true)`) or against a quirk, if it all agrees.
+    fn evidence(&self, vote: impl Fn(&Kind) -> Option<bool>) -> Option<bool> {
+        let votes: Vec<bool> = self.findings.iter().filter_map(|f| vote(&f.kind)).collect();
+        match votes.first() {
+            Some(&first) if votes.iter().all(|&vote| vote == first) => Some(first),
+            _ => None,
+        }
+    }
+
+    /// The platform the ROM seems to be written for, and the quirks to run
+    /// it with.
+    pub fn recommendation(&self) -> (Platform, Quirks) {
+        let shift_vx = self.evidence(|kind| match kind {
+            Kind::Shift { uses_vy } => Some(!uses_vy),
+            _ => None,
+        });
+        let memory = self.evidence(|kind| match kind {
+            Kind::MemoryThenI { increments, .. } => Some(*increments),
+            _ => None,
+        });
+        let jump_vx = self.evidence(|kind| match kind {
+            Kind::Jump { vx: true } => Some(true),
+            _ => None,
+        });
+
+        let platform = if self.any(|kind| *kind == Kind::XoChip) {
+            Platform::XoChip
+        } else if self.any(|kind| *kind == Kind::Schip) {
+            Platform::Schip
+        } else if self.any(|kind| *kind == Kind::Sys) {
+            Platform::Chip8
+        } else {
+            // Count which way each quirk points
+            let schip = [shift_vx, memory.map(|m| !m), jump_vx];
+            let for_schip = schip.iter().filter(|&&vote| vote == Some(true)).count();
+            let for_vip = schip.iter().filter(|&&vote| vote == Some(false)).count();
+            if for_schip > for_vip {
+                Platform::Schip
+            } else {
+                Platform::Chip8
+            }
+        };
+
+        let mut quirks = platform.quirks();
+        quirks.shift_vx = shift_vx.unwrap_or(quirks.shift_vx);
+        quirks.memory_increments_i = memory.unwrap_or(quirks.memory_increments_i);
+        quirks.jump_vx = jump_vx.unwrap_or(quirks.jump_vx);
+        (platform, quirks)
+    }
+
+    /// Write the findings grouped by kind, then the recommendation as
+    /// command line options.
+    pub fn write_report(&self, out: &mut impl Write, symbols: &Symbols) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} bytes, {} instructions found by following the code from 0x200",
+            self.rom_len, self.instructions
+        )?;
+
+        // Grouped by kind, in order of where each kind first appears
+        let mut headings: Vec<&str> = Vec::new();
+        for finding in &self.findings {
+            if !headings.contains(&finding.kind.heading()) {
+                headings.push(finding.kind.heading());
+            }
+        }
+        for heading in headings {
+            writeln!(out)?;
+            writeln!(out, "{}", heading)?;
+            for finding in &self.findings {
+                if finding.kind.heading() == heading {
+                    let line = disassemble_opcode(finding.addr as usize, finding.opcode, symbols);
+                    writeln!(out, "  {:<36} {}", line, finding.kind.note())?;
+                }
+            }
+        }
+        if self.findings.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "Nothing that depends on the platform")?;
+        }
+
+        let (platform, quirks) = self.recommendation();
+        writeln!(out)?;
+        if quirks == platform.quirks() {
+            writeln!(out, "Recommended: --platform {}", platform)?;
+        } else {
+            writeln!(
+                out,
+                "Recommended: --platform {} --quirks {}",
+                platform, quirks
+            )?;
+        }
+        if self.any(|kind| matches!(kind, Kind::Edge { .. })) {
+            writeln!(
+                out,
+                "Sprites cross the edges: try --quirks wrap if they should appear on the other side"
+            )?;
+        }
+        if self.any(|kind| *kind == Kind::Sys) {
+            writeln!(
+                out,
+                "Compatibility problem: the machine code calls stop the emulator when they run, \
+                 unless --semihosting makes them host calls"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The width and height of the sprite DRW draws with `rows` rows at `i`,
+/// leaving out blank columns on the right and rows at the bottom. Without
+/// knowing I, it's the most it could be.
+fn sprite_size(rom: &[u8], i: Option<usize>, rows: u8) -> (u8, u8) {
+    if rows == 0 {
+        // SUPER-CHIP's 16x16 sprites
+        return (16, 16);
+    }
+    let sprite = i
+        .filter(|&i| i >= START)
+        .and_then(|i| rom.get(i - START..i - START + rows as usize));
+    let sprite = match sprite {
+        Some(sprite) => sprite,
+        None => return (8, rows),
+    };
+    let width = sprite
+        .iter()
+        .map(|row| 8 - row.trailing_zeros().min(8))
+        .max();
+    let height = sprite
+        .iter()
+        .rposition(|&row| row != 0)
+        .map_or(0, |last| last + 1);
+    (width.unwrap_or(0) as u8, height as u8)
+}
+
+/// Whether an instruction uses the value of I.
+fn uses_i(opcode: u16) -> bool {
+    match opcode >> 12 {
+        0x5 => matches!(opcode & 0xF, 0x2 | 0x3),
+        0xD => true,
+        0xF => matches!(opcode & 0xFF, 0x1E | 0x33 | 0x55 | 0x65),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(rom: &[u8]) -> Vec<(u16, Kind)> {
+        let analysis = Analysis::new(rom);
+        analysis
+            .findings
+            .iter()
+            .map(|finding| (finding.addr, finding.kind))
+            .collect()
+    }
+
+    #[test]
+    fn vip_rom() {
+        let rom = [
+            0x81, 0x26, // 200: SHR V1, V2
+            0xA3, 0x00, // 202: LD I, 300
+            0xF1, 0x55, // 204: LD [I], V1
+            0xF1, 0x55, // 206: LD [I], V1
+            0x60, 0x3E, // 208: LD V0, 3e
+            0x61, 0x1E, // 20a: LD V1, 1e
+            0xD0, 0x14, // 20c: DRW V0, V1, 4
+            0x01, 0x23, // 20e: SYS 123
+            0x12, 0x00, // 210: JP 200
+            0xFF, 0xFF, // 212: data
+        ];
+        let analysis = Analysis::new(&rom);
+        assert_eq!(analysis.instructions, 9);
+        assert_eq!(
+            kinds(&rom),
+            vec![
+                (0x200, Kind::Shift { uses_vy: true }),
+                (
+                    0x206,
+                    Kind::MemoryThenI {
+                        after: 0x204,
+                        increments: true
+                    }
+                ),
+                (
+                    0x20c,
+                    Kind::MemoryThenI {
+                        after: 0x206,
+                        increments: true
+                    }
+                ),
+                (
+                    0x20c,
+                    Kind::Edge {
+                        x: 0x3E,
+                        y: 0x1E,
+                        width: 8,
+                        height: 4
+                    }
+                ),
+                (0x20e, Kind::Sys),
+            ]
+        );
+        assert_eq!(
+            analysis.recommendation(),
+            (Platform::Chip8, Platform::Chip8.quirks())
+        );
+        let mut report = Vec::new();
+        analysis.write_report(&mut report, &Symbols::new()).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("$123"));
+        assert!(report.contains(" stops the emulator\n"));
+        assert!(report.ends_with(
+            "Compatibility problem: the machine code calls stop the emulator when they run, \
+             unless --semihosting makes them host calls\n"
+        ));
+    }
+
+    #[test]
+    fn schip_rom() {
+        let rom = [
+            0x00, 0xFF, // 200: HIGH
+            0x81, 0x06, // 202: SHR V1
+            0x22, 0x0A, // 204: CALL 20a
+            0x62, 0x04, // 206: LD V2, 4
+            0xB2, 0x00, // 208: JP V0, 200 (V2 on SUPER-CHIP)
+            0xF1, 0x65, // 20a: LD V1, [I]
+            0xF1, 0x1E, // 20c: ADD I, V1
+            0x00, 0xEE, // 20e: RET
+        ];
+        assert_eq!(
+            kinds(&rom),
+            vec![
+                (0x200, Kind::Schip),
+                (0x202, Kind::Shift { uses_vy: false }),
+                (0x208, Kind::Jump { vx: true }),
+                (
+                    0x20c,
+                    Kind::MemoryThenI {
+                        after: 0x20a,
+                        increments: false
+                    }
+                ),
+            ]
+        );
+        let analysis = Analysis::new(&rom);
+        assert_eq!(analysis.instructions, 8);
+        assert_eq!(
+            analysis.recommendation(),
+            (Platform::Schip, Platform::Schip.quirks())
+        );
+
+        let mut report = Vec::new();
+        analysis.write_report(&mut report, &Symbols::new()).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("SUPER-CHIP instructions\n  0200 00 ff HIGH"));
+        assert!(report.ends_with("Recommended: --platform schip\n"));
+    }
+
+    #[test]
+    fn unknown_platform() {
+        // 200: LD V0, 1; 202: JP 202
+        let analysis = Analysis::new(&[0x60, 0x01, 0x12, 0x02]);
+        assert!(analysis.findings.is_empty());
+        assert_eq!(analysis.recommendation().0, Platform::Chip8);
+        // Odd length, so the last byte isn't an instruction
+        assert_eq!(Analysis::new(&[0x00, 0xE0, 0x12]).instructions, 1);
+    }
+}
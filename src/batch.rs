@@ -0,0 +1,310 @@
+//! Runs ROMs headless to see which ones work, for `chip8-compat`.
+
+use crate::chip8::{panic_message, Chip8, MEMORY_SIZE};
+use crate::compat;
+use crate::disassembler::mnemonic;
+use crate::recorder::{Format, Recorder, RecorderConfig};
+use crate::runner::DEFAULT_SPEED;
+use sha1_smol::Sha1;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Pixels per display pixel in thumbnails.
+const THUMBNAIL_SCALE: usize = 2;
+
+/// Where a ROM stopped the emulator.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Crash {
+    pub pc: u16,
+    pub opcode: u16,
+    /// The panic message, like `Unknown opcode: 0xFF`.
+    pub message: String,
+}
+
+impl Crash {
+    /// Like `0x2a4: 00ff (HIGH): Unknown opcode: 0xFF`.
+    pub fn describe(&self) -> String {
+        let mnemonic = mnemonic(self.opcode);
+        let mnemonic: Vec<&str> = mnemonic.split_whitespace().collect();
+        format!(
+            "{:#05x}: {:04x} ({}): {}",
+            self.pc,
+            self.opcode,
+            mnemonic.join(" "),
+            self.message
+        )
+    }
+}
+
+/// How a ROM got on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomReport {
+    /// File name.
+    pub name: String,
+    /// Title from the compatibility database, if it's a known ROM.
+    pub title: Option<String>,
+    /// Frames run before the end or a crash.
+    pub frames: usize,
+    pub instructions: u64,
+    /// Whether it ever drew a sprite. Clearing the screen doesn't count.
+    pub drew: bool,
+    pub crash: Option<Crash>,
+    /// The screen at the end.
+    pub display: Vec<u32>,
+}
+
+impl RomReport {
+    /// `OK`, `Crashed at …` or `Never drew`.
+    pub fn result(&self) -> String {
+        match &self.crash {
+            Some(crash) => format!("Crashed at {}", crash.describe()),
+            None if !self.drew => "Never drew".to_string(),
+            None => "OK".to_string(),
+        }
+    }
+}
+
+/// Run `rom` for `frames` frames, with the speed and quirks from the
+/// compatibility database if it's known, unless `speed` is given.
+///
+/// Crashes are caught, but the panic hook still prints them, so callers
+/// running many ROMs may want to replace it.
+pub fn run_rom(name: &str, rom: Vec<u8>, frames: usize, speed: Option<usize>) -> RomReport {
+    let info = compat::lookup(&Sha1::from(&rom).digest().to_string());
+    let speed = speed
+        .or_else(|| info.as_ref().and_then(|info| info.speed))
+        .unwrap_or(DEFAULT_SPEED);
+    let mut chip = Chip8::with_seed(0);
+    if let Some(info) = &info {
+        chip.quirks = info.quirks;
+    }
+    chip.load_binary(rom);
+
+    let mut run = 0;
+    let mut drew = false;
+    let mut pc = chip.pc();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        while run < frames {
+            for _ in 0..speed {
+                pc = chip.pc();
+                let draws = chip.opcode() & 0xF000 == 0xD000;
+                chip.step();
+                drew |= draws;
+            }
+            chip.decrement_timers();
+            run += 1;
+        }
+    }));
+    let crash = result.err().map(|payload| Crash {
+        pc,
+        opcode: u16::from_be_bytes([
+            chip.memory()[pc as usize % MEMORY_SIZE],
+            chip.memory()[(pc as usize + 1) % MEMORY_SIZE],
+        ]),
+        message: panic_message(payload),
+    });
+    RomReport {
+        name: name.to_string(),
+        title: info.map(|info| info.title),
+        frames: run,
+        instructions: chip.cycles(),
+        drew,
+        crash,
+        display: chip.display.to_vec(),
+    }
+}
+
+/// Save the final screen as a small GIF.
+pub fn save_thumbnail(path: &Path, display: &[u32]) -> io::Result<()> {
+    let config = RecorderConfig {
+        scale: THUMBNAIL_SCALE,
+        ..RecorderConfig::default()
+    };
+    let out = BufWriter::new(File::create(path)?);
+    let mut recorder = Recorder::new(out, Format::Gif, config)?;
+    recorder.capture(display)?;
+    recorder.finish()?;
+    Ok(())
+}
+
+/// File name for the thumbnail of the report at `index`, in the directory
+/// `write_markdown` and `write_html` link to. The index keeps names apart
+/// that only differ in punctuation, like `a.ch8` and `a_ch8`.
+pub fn thumbnail_name(index: usize, report: &RomReport) -> String {
+    let name: String = report
+        .name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{:03}_{}.gif", index, name)
+}
+
+fn counts(reports: &[RomReport]) -> String {
+    let crashed = reports.iter().filter(|r| r.crash.is_some()).count();
+    let blank = reports
+        .iter()
+        .filter(|r| r.crash.is_none() && !r.drew)
+        .count();
+    format!(
+        "{} ROMs: {} OK, {} crashed, {} never drew",
+        reports.len(),
+        reports.len() - crashed - blank,
+        crashed,
+        blank
+    )
+}
+
+/// Write a Markdown table of the reports, with thumbnails from
+/// `thumbnails/` if `thumbnails` is set.
+pub fn write_markdown(
+    out: &mut impl Write,
+    reports: &[RomReport],
+    thumbnails: Option<&str>,
+) -> io::Result<()> {
+    writeln!(out, "# Compatibility report\n")?;
+    writeln!(out, "{}\n", counts(reports))?;
+    write!(out, "| ROM | Title | Result | Frames | Instructions |")?;
+    if thumbnails.is_some() {
+        write!(out, " Screen |")?;
+    }
+    writeln!(out)?;
+    write!(out, "|---|---|---|---:|---:|")?;
+    if thumbnails.is_some() {
+        write!(out, "---|")?;
+    }
+    writeln!(out)?;
+    let cell = |text: &str| text.replace('|', "\\|");
+    for (index, report) in reports.iter().enumerate() {
+        write!(
+            out,
+            "| {} | {} | {} | {} | {} |",
+            cell(&report.name),
+            cell(report.title.as_deref().unwrap_or("")),
+            cell(&report.result()),
+            report.frames,
+            report.instructions
+        )?;
+        if let Some(dir) = thumbnails {
+            write!(out, " ![]({}/{}) |", dir, thumbnail_name(index, report))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Write the same table as `write_markdown` as an HTML page.
+pub fn write_html(
+    out: &mut impl Write,
+    reports: &[RomReport],
+    thumbnails: Option<&str>,
+) -> io::Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Compatibility report</title>")?;
+    writeln!(out, "<h1>Compatibility report</h1>")?;
+    writeln!(out, "<p>{}</p>", counts(reports))?;
+    writeln!(out, "<table>")?;
+    write!(
+        out,
+        "<tr><th>ROM<th>Title<th>Result<th>Frames<th>Instructions"
+    )?;
+    if thumbnails.is_some() {
+        write!(out, "<th>Screen")?;
+    }
+    writeln!(out)?;
+    for (index, report) in reports.iter().enumerate() {
+        write!(
+            out,
+            "<tr><td>{}<td>{}<td>{}<td>{}<td>{}",
+            escape(&report.name),
+            escape(report.title.as_deref().unwrap_or("")),
+            escape(&report.result()),
+            report.frames,
+            report.instructions
+        )?;
+        if let Some(dir) = thumbnails {
+            write!(
+                out,
+                "<td><img src=\"{}/{}\" alt=\"\">",
+                escape(dir),
+                escape(&thumbnail_name(index, report))
+            )?;
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "</table>")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_roms() {
+        // 200: LD F, V0
+        // 202: DRW V0, V0, 5
+        // 204: JP 204
+        let ok = run_rom(
+            "ok.ch8",
+            vec![0xF0, 0x29, 0xD0, 0x05, 0x12, 0x04],
+            3,
+            Some(10),
+        );
+        assert_eq!(ok.result(), "OK");
+        assert_eq!((ok.frames, ok.instructions), (3, 30));
+
+        // 200: JP 200
+        let blank = run_rom("blank.ch8", vec![0x12, 0x00], 3, Some(10));
+        assert_eq!(blank.result(), "Never drew");
+
+        // 200: CLS
+        // 202: HIGH, which is SUPER-CHIP
+        let crashed = run_rom("crash.ch8", vec![0x00, 0xE0, 0x00, 0xFF], 3, Some(10));
+        assert_eq!(
+            crashed.crash,
+            Some(Crash {
+                pc: 0x202,
+                opcode: 0x00FF,
+                message: "Unknown opcode: 0xFF".to_string(),
+            })
+        );
+        assert_eq!(
+            crashed.result(),
+            "Crashed at 0x202: 00ff (HIGH): Unknown opcode: 0xFF"
+        );
+        assert_eq!((crashed.frames, crashed.instructions), (0, 2));
+        assert!(!crashed.drew);
+
+        let reports = [ok, blank, crashed];
+        assert_eq!(thumbnail_name(0, &reports[0]), "000_ok_ch8.gif");
+        let a = RomReport {
+            name: "a.ch8".to_string(),
+            ..reports[1].clone()
+        };
+        let a_ch8 = RomReport {
+            name: "a_ch8".to_string(),
+            ..reports[1].clone()
+        };
+        assert_ne!(thumbnail_name(3, &a), thumbnail_name(4, &a_ch8));
+        let mut markdown = Vec::new();
+        write_markdown(&mut markdown, &reports, Some("thumbnails")).unwrap();
+        let markdown = String::from_utf8(markdown).unwrap();
+        assert!(markdown.contains("3 ROMs: 1 OK, 1 crashed, 1 never drew"));
+        assert!(markdown.contains("| ok.ch8 |  | OK | 3 | 30 | ![](thumbnails/000_ok_ch8.gif) |"));
+
+        let mut html = Vec::new();
+        write_html(&mut html, &reports, None).unwrap();
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("<tr><td>blank.ch8<td><td>Never drew<td>3<td>30\n"));
+        assert!(!html.contains("<img"));
+    }
+}
@@ -0,0 +1,53 @@
+//! Work out which platform and quirks a ROM needs without running it.
+
+use chip_8_emulator::analyzer::Analysis;
+use chip_8_emulator::symbols::Symbols;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: chip8-analyze [--symbols FILE] <rom>
+
+Follows the ROM's code from 0x200 and lists what behaves differently between
+the COSMAC VIP, SUPER-CHIP and XO-CHIP: shifts with different x and y, I used
+after LD [I], sprites drawn across the edges, Bnnn jumps, SUPER-CHIP and
+XO-CHIP instructions and machine code calls. Ends with the --platform and
+--quirks options to run it with.";
+
+fn main() {
+    let mut symbols = Symbols::new();
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| fail("--symbols takes a file"));
+                symbols = Symbols::load(Path::new(&path))
+                    .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 1 {
+        fail(USAGE);
+    }
+
+    let rom = fs::read(&paths[0]).unwrap_or_else(|e| fail(&format!("{}: {}", paths[0], e)));
+    println!("{}", paths[0]);
+    Analysis::new(&rom)
+        .write_report(&mut io::stdout(), &symbols)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
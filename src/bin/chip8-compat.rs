@@ -0,0 +1,129 @@
+//! Run every ROM in a directory and report which ones work.
+
+use chip_8_emulator::batch::{self, RomReport};
+use chip_8_emulator::chip8::MAX_ROM_SIZE;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+
+const USAGE: &str = "Usage: chip8-compat [options] <directory>
+
+Runs each ROM in the directory headless and writes a report of which crashed
+and where, which never drew a sprite, how many instructions each ran and a
+thumbnail of the screen each ended on. Known ROMs use the speed and quirks
+from the compatibility database.
+
+Options:
+  --frames N      Frames to run each ROM for (600 by default)
+  --speed N       Instructions per frame for every ROM
+  --output FILE   Report to write, as HTML if it ends in .html and Markdown
+                  otherwise (compat-report.md by default). Thumbnails go in
+                  a thumbnails directory next to it
+  --no-thumbnails Don't save thumbnails";
+
+fn main() {
+    let mut frames = 600;
+    let mut speed = None;
+    let mut output = PathBuf::from("compat-report.md");
+    let mut thumbnails = true;
+    let mut dirs = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| {
+            args.next()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or_else(|| fail(&format!("{} takes a number", name)))
+        };
+        match arg.as_str() {
+            "--frames" => frames = number("--frames"),
+            "--speed" => speed = Some(number("--speed")),
+            "--output" => {
+                output = args
+                    .next()
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| fail("--output takes a file"))
+            }
+            "--no-thumbnails" => thumbnails = false,
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => dirs.push(arg),
+        }
+    }
+    if dirs.len() != 1 {
+        fail(USAGE);
+    }
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(&dirs[0])
+        .and_then(|entries| entries.map(|entry| Ok(entry?.path())).collect())
+        .unwrap_or_else(|e| fail(&format!("{}: {}", dirs[0], e)));
+    paths.retain(|path| path.is_file());
+    paths.sort();
+
+    // Crashes go in the report instead
+    panic::set_hook(Box::new(|_| {}));
+    let mut reports = Vec::new();
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy();
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > MAX_ROM_SIZE as u64 => {
+                eprintln!("Skipping {}: too large for a ROM", name);
+                continue;
+            }
+            _ => {}
+        }
+        let rom = match fs::read(path) {
+            Ok(rom) => rom,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", name, e);
+                continue;
+            }
+        };
+        let report = batch::run_rom(&name, rom, frames, speed);
+        eprintln!("{}: {}", name, report.result());
+        reports.push(report);
+    }
+
+    let thumbnail_dir = "thumbnails";
+    if thumbnails {
+        let dir = output
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(thumbnail_dir);
+        fs::create_dir_all(&dir).unwrap_or_else(|e| fail(&format!("{}: {}", dir.display(), e)));
+        for (index, report) in reports.iter().enumerate() {
+            save_thumbnail(&dir, index, report);
+        }
+    }
+    let html = output
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"));
+    let thumbnails = Some(thumbnail_dir).filter(|_| thumbnails);
+    let result = File::create(&output).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        if html {
+            batch::write_html(&mut out, &reports, thumbnails)?;
+        } else {
+            batch::write_markdown(&mut out, &reports, thumbnails)?;
+        }
+        out.flush()
+    });
+    result.unwrap_or_else(|e| fail(&format!("{}: {}", output.display(), e)));
+    eprintln!("Wrote {}", output.display());
+}
+
+fn save_thumbnail(dir: &Path, index: usize, report: &RomReport) {
+    let path = dir.join(batch::thumbnail_name(index, report));
+    if let Err(e) = batch::save_thumbnail(&path, &report.display) {
+        eprintln!("Couldn't save {}: {}", path.display(), e);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
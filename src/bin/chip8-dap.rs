@@ -0,0 +1,13 @@
+//! Debug Adapter Protocol server over stdin and stdout, for debugging ROMs
+//! from an editor.
+
+use chip_8_emulator::debug::dap;
+use std::io;
+use std::process;
+
+fn main() {
+    if let Err(e) = dap::serve(io::stdin(), io::stdout()) {
+        eprintln!("chip8-dap: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,55 @@
+//! Make an IPS or BPS patch from an original ROM and a modified one.
+
+use chip_8_emulator::patch::{Format, Patch};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: chip8-patch [--ips | --bps] <original> <modified> <patch>
+
+Writes a patch that turns the original ROM into the modified one, for
+`chip_8_emulator --patch`. The format comes from the patch's extension
+(.ips or .bps) unless given. BPS patches carry checksums, so applying one to
+the wrong ROM fails instead of producing a broken game.";
+
+fn main() {
+    let mut format = None;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--ips" => format = Some(Format::Ips),
+            "--bps" => format = Some(Format::Bps),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 3 {
+        fail(USAGE);
+    }
+
+    let output = Path::new(&paths[2]);
+    let format = format
+        .or_else(|| Format::from_path(output))
+        .unwrap_or_else(|| fail("Name the patch .ips or .bps, or give --ips or --bps"));
+    let read = |path: &str| fs::read(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+    let (original, modified) = (read(&paths[0]), read(&paths[1]));
+
+    let patch = Patch::create(format, &original, &modified);
+    fs::write(output, patch.data())
+        .unwrap_or_else(|e| fail(&format!("{}: {}", output.display(), e)));
+    println!(
+        "Wrote {} byte {} patch to {}",
+        patch.data().len(),
+        format,
+        output.display()
+    );
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -0,0 +1,112 @@
+//! Find where two `--trace` logs first differ.
+
+use chip_8_emulator::disassembler::disassemble_opcode;
+use chip_8_emulator::symbols::Symbols;
+use chip_8_emulator::trace::{first_divergence, parse_trace, Difference, TraceLine};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "Usage: chip8-tracediff [--context N] [--symbols FILE] <trace-a> <trace-b>
+
+Lines the traces up by cycle and shows the first instruction or register that
+differs, with N lines of context (default 5), naming addresses with labels
+from the symbol file if given. Exits with 0 if the traces match, 1 if they
+differ and 2 on errors.";
+
+fn main() {
+    let mut context = 5;
+    let mut symbols = Symbols::new();
+    let mut paths = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--context" | "-C" => {
+                let n = args.next().and_then(|n| n.parse().ok());
+                context = n.unwrap_or_else(|| fail("--context takes a number of lines"));
+            }
+            "--symbols" => {
+                let path = args
+                    .next()
+                    .unwrap_or_else(|| fail("--symbols takes a file"));
+                symbols = Symbols::load(Path::new(&path))
+                    .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 {
+        fail(USAGE);
+    }
+
+    let load = |path: &str| {
+        let text = fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        parse_trace(&text).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)))
+    };
+    let (a, b) = (load(&paths[0]), load(&paths[1]));
+
+    let divergence = match first_divergence(&a, &b) {
+        Some(divergence) => divergence,
+        None => {
+            println!("Traces match ({} lines)", a.len());
+            return;
+        }
+    };
+
+    let (name_a, name_b) = (&paths[0], &paths[1]);
+    println!("First difference at cycle {}:", divergence.cycle);
+    match &divergence.difference {
+        Difference::OnlyInA => println!("  only {} has this cycle", name_a),
+        Difference::OnlyInB => println!("  only {} has this cycle", name_b),
+        Difference::Instruction => {
+            let (x, y) = (&a[divergence.a], &b[divergence.b]);
+            println!(
+                "  {} ran {} but {} ran {}",
+                name_a,
+                disassemble_opcode(x.pc as usize, x.opcode, &symbols),
+                name_b,
+                disassemble_opcode(y.pc as usize, y.opcode, &symbols)
+            );
+        }
+        Difference::Registers(registers) => {
+            for (name, value_a, value_b) in registers {
+                println!(
+                    "  {} is {:02x} in {} but {:02x} in {}",
+                    name, value_a, name_a, value_b, name_b
+                );
+            }
+        }
+    }
+
+    println!();
+    println!("--- {}", name_a);
+    println!("+++ {}", name_b);
+    // Lines before the difference are the same in both
+    let start = divergence.a.saturating_sub(context);
+    print_lines("  ", &a[start..divergence.a], &symbols);
+    print_lines("- ", after(&a, divergence.a, context), &symbols);
+    print_lines("+ ", after(&b, divergence.b, context), &symbols);
+    process::exit(1);
+}
+
+/// The differing line and up to `context` lines after it.
+fn after(lines: &[TraceLine], start: usize, context: usize) -> &[TraceLine] {
+    let end = (start + context + 1).min(lines.len());
+    &lines[start.min(end)..end]
+}
+
+fn print_lines(prefix: &str, lines: &[TraceLine], symbols: &Symbols) {
+    for line in lines {
+        println!("{}{}", prefix, line.format(symbols));
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2);
+}
@@ -0,0 +1,482 @@
+use crate::chip8::{Chip8, MEMORY_SIZE};
+use crate::runner::Hook;
+use crate::settings::{self, Section, Settings};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Commands understood by `Cheats::command`.
+pub const COMMANDS: &str = "search [VALUE|changed|unchanged|increased|decreased], \
+                            freeze TARGET=VALUE, unfreeze [TARGET], save NAME, load NAME, cheats";
+
+/// Candidates listed after a search.
+const SHOWN_CANDIDATES: usize = 8;
+
+/// A byte of memory or a V register.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Memory(u16),
+    Register(usize),
+}
+
+impl Target {
+    /// Parse a hex address like `2f5` or a register like `v3`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let lower = text.trim().to_ascii_lowercase();
+        if let Some(n) = lower.strip_prefix('v') {
+            if n.len() == 1 {
+                if let Ok(n) = usize::from_str_radix(n, 16) {
+                    return Ok(Target::Register(n));
+                }
+            }
+        }
+        match u16::from_str_radix(lower.trim_start_matches("0x"), 16) {
+            Ok(addr) if (addr as usize) < MEMORY_SIZE => Ok(Target::Memory(addr)),
+            _ => Err(format!("Invalid address or register {}", text.trim())),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Memory(addr) => write!(f, "{:03x}", addr),
+            Target::Register(n) => write!(f, "v{:x}", n),
+        }
+    }
+}
+
+/// A value written to a target at the start of every frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Freeze {
+    pub target: Target,
+    pub value: u8,
+}
+
+impl Freeze {
+    /// Parse `2f5=3` or `v3=0x10`: a target, then a value in decimal or hex
+    /// with `0x`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (target, value) = text
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid cheat {}, expected e.g. 2f5=3 or v3=0x10", text))?;
+        Ok(Freeze {
+            target: Target::parse(target)?,
+            value: parse_value(value)?,
+        })
+    }
+
+    fn apply(&self, chip: &mut Chip8) {
+        match self.target {
+            Target::Memory(addr) => chip.memory_mut()[addr as usize] = self.value,
+            Target::Register(n) => {
+                let mut registers = chip.registers();
+                registers.v[n] = self.value;
+                chip.set_registers(&registers);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Freeze {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.target, self.value)
+    }
+}
+
+/// A byte of memory set once when the ROM is loaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Poke {
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl Poke {
+    /// Parse `1ff=1`: a hex address, then a value as for `Freeze`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid poke {}, expected e.g. 1ff=1", text);
+        let (addr, value) = text.split_once('=').ok_or_else(invalid)?;
+        match Target::parse(addr)? {
+            Target::Memory(addr) => Ok(Poke {
+                addr,
+                value: parse_value(value)?,
+            }),
+            Target::Register(_) => Err(invalid()),
+        }
+    }
+
+    pub fn apply(&self, chip: &mut Chip8) {
+        chip.poke(self.addr, self.value);
+    }
+}
+
+/// How the values at candidate addresses must compare with the last search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Filter {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        Ok(match text.to_ascii_lowercase().as_str() {
+            "changed" => Filter::Changed,
+            "unchanged" => Filter::Unchanged,
+            "increased" => Filter::Increased,
+            "decreased" => Filter::Decreased,
+            _ => Filter::Equal(parse_value(text.strip_prefix('=').unwrap_or(text))?),
+        })
+    }
+
+    fn matches(self, old: u8, new: u8) -> bool {
+        match self {
+            Filter::Equal(value) => new == value,
+            Filter::Changed => new != old,
+            Filter::Unchanged => new == old,
+            Filter::Increased => new > old,
+            Filter::Decreased => new < old,
+        }
+    }
+}
+
+/// Narrows down where a game keeps something like the number of lives, by
+/// comparing snapshots of memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Search {
+    /// Memory when the last filter was applied.
+    snapshot: Vec<u8>,
+    /// Addresses that matched every filter so far.
+    candidates: Vec<u16>,
+}
+
+impl Search {
+    /// Start with every address as a candidate.
+    pub fn new(memory: &[u8]) -> Self {
+        Search {
+            snapshot: memory.to_vec(),
+            candidates: (0..memory.len() as u16).collect(),
+        }
+    }
+
+    /// Keep the candidates that match `filter`, comparing with the memory
+    /// when the last filter was applied.
+    pub fn filter(&mut self, memory: &[u8], filter: Filter) {
+        let snapshot = &self.snapshot;
+        self.candidates
+            .retain(|&addr| filter.matches(snapshot[addr as usize], memory[addr as usize]));
+        self.snapshot.copy_from_slice(memory);
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+/// Frozen values, the search in progress and the cheats saved for a ROM.
+///
+/// Cheats are saved by name in `cheats.ini` in the same directory and format
+/// as the display settings, with one `[sha1]` section per ROM and lines like
+/// `infinite lives = 2f5=3 v3=0x10`.
+#[derive(Clone, Debug, Default)]
+pub struct Cheats {
+    rom_hash: String,
+    /// The cheat file, or `None` if there's nowhere to save.
+    path: Option<PathBuf>,
+    frozen: Vec<Freeze>,
+    search: Option<Search>,
+}
+
+impl Cheats {
+    pub fn new(rom_hash: &str) -> Self {
+        Cheats::with_file(rom_hash, settings::config_path("cheats.ini"))
+    }
+
+    pub fn with_file(rom_hash: &str, path: Option<PathBuf>) -> Self {
+        Cheats {
+            rom_hash: rom_hash.to_string(),
+            path,
+            ..Default::default()
+        }
+    }
+
+    pub fn frozen(&self) -> &[Freeze] {
+        &self.frozen
+    }
+
+    /// Freeze a target, replacing any value it was frozen at.
+    pub fn freeze(&mut self, freeze: Freeze) {
+        self.unfreeze(freeze.target);
+        self.frozen.push(freeze);
+    }
+
+    /// Returns false if the target wasn't frozen.
+    pub fn unfreeze(&mut self, target: Target) -> bool {
+        let len = self.frozen.len();
+        self.frozen.retain(|freeze| freeze.target != target);
+        self.frozen.len() != len
+    }
+
+    /// Write the frozen values, which the runner does at the start of every
+    /// frame.
+    pub fn apply(&self, chip: &mut Chip8) {
+        for freeze in &self.frozen {
+            freeze.apply(chip);
+        }
+    }
+
+    /// Cheats saved for this ROM, by name.
+    pub fn saved(&self) -> Section {
+        let file = Settings::load_file(self.path.clone());
+        file.rom(&self.rom_hash).cloned().unwrap_or_default()
+    }
+
+    /// Freeze the values of a saved cheat.
+    pub fn load(&mut self, name: &str) -> Result<(), String> {
+        let saved = self.saved();
+        let text = saved
+            .get(name.trim())
+            .ok_or_else(|| format!("No cheat called {} for this ROM", name.trim()))?;
+        let freezes = text
+            .split_whitespace()
+            .map(Freeze::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        for freeze in freezes {
+            self.freeze(freeze);
+        }
+        Ok(())
+    }
+
+    /// Save the frozen values as a cheat for this ROM.
+    pub fn save(&self, name: &str) -> Result<(), String> {
+        let name = name.trim();
+        if name.is_empty() || name.contains(['=', '[', ']', '#', ';']) {
+            return Err(format!("Invalid cheat name {}", name));
+        }
+        if self.frozen.is_empty() {
+            return Err("Nothing is frozen".to_string());
+        }
+        if self.path.is_none() || self.rom_hash.is_empty() {
+            return Err("Cheats can't be saved without a config directory".to_string());
+        }
+        let mut file = Settings::load_file(self.path.clone());
+        let mut section = file.rom(&self.rom_hash).cloned().unwrap_or_default();
+        let text: Vec<String> = self.frozen.iter().map(Freeze::to_string).collect();
+        section.insert(name.to_string(), text.join(" "));
+        file.set_rom(&self.rom_hash, section);
+        file.save()
+            .map_err(|e| format!("Couldn't save cheats: {}", e))
+    }
+
+    /// Run one of `COMMANDS`, returning a line to show the user.
+    pub fn command(&mut self, chip: &mut Chip8, text: &str) -> Result<String, String> {
+        let text = text.trim();
+        let (command, args) = text.split_once(' ').unwrap_or((text, ""));
+        let args = args.trim();
+        match command {
+            "search" if args.is_empty() => {
+                self.search = Some(Search::new(chip.memory()));
+                Ok(format!("Searching {} addresses", MEMORY_SIZE))
+            }
+            "search" => {
+                let filter = Filter::parse(args)?;
+                if self.search.is_none() {
+                    if !matches!(filter, Filter::Equal(_)) {
+                        return Err("Start a search with a value first".to_string());
+                    }
+                    self.search = Some(Search::new(chip.memory()));
+                }
+                let search = self.search.as_mut().unwrap();
+                search.filter(chip.memory(), filter);
+                let candidates = search.candidates();
+                let shown: Vec<String> = candidates
+                    .iter()
+                    .take(SHOWN_CANDIDATES)
+                    .map(|addr| format!("{:03x}", addr))
+                    .collect();
+                let more = if candidates.len() > SHOWN_CANDIDATES {
+                    " ..."
+                } else {
+                    ""
+                };
+                Ok(format!(
+                    "{} addresses left: {}{}",
+                    candidates.len(),
+                    shown.join(" "),
+                    more
+                ))
+            }
+            "freeze" => {
+                // `freeze 2f5 3` or `freeze 2f5=3`
+                let freeze = Freeze::parse(&args.replacen(' ', "=", 1))?;
+                freeze.apply(chip);
+                self.freeze(freeze);
+                Ok(format!("Froze {}", freeze))
+            }
+            "unfreeze" if args.is_empty() => {
+                self.frozen.clear();
+                Ok("Unfroze everything".to_string())
+            }
+            "unfreeze" => {
+                let target = Target::parse(args)?;
+                if self.unfreeze(target) {
+                    Ok(format!("Unfroze {}", target))
+                } else {
+                    Err(format!("{} isn't frozen", target))
+                }
+            }
+            "save" => {
+                self.save(args)?;
+                Ok(format!("Saved {}", args))
+            }
+            "load" => {
+                self.load(args)?;
+                self.apply(chip);
+                Ok(format!("Loaded {}", args))
+            }
+            "cheats" => {
+                let frozen: Vec<String> = self.frozen.iter().map(Freeze::to_string).collect();
+                let saved: Vec<String> = self.saved().into_keys().collect();
+                Ok(format!(
+                    "Frozen: {}  Saved: {}",
+                    if frozen.is_empty() {
+                        "none".to_string()
+                    } else {
+                        frozen.join(" ")
+                    },
+                    if saved.is_empty() {
+                        "none".to_string()
+                    } else {
+                        saved.join(", ")
+                    }
+                ))
+            }
+            _ => Err(format!("Commands: {}", COMMANDS)),
+        }
+    }
+}
+
+/// Decimal, or hex with `0x`.
+fn parse_value(text: &str) -> Result<u8, String> {
+    let text = text.trim();
+    let value = match text.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    value.map_err(|_| format!("Invalid value {}, expected 0-255", text))
+}
+
+impl Hook for Cheats {
+    fn begin_frame(&mut self, chip: &mut Chip8) {
+        self.apply(chip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            Freeze::parse("2F5=3"),
+            Ok(Freeze {
+                target: Target::Memory(0x2f5),
+                value: 3
+            })
+        );
+        let freeze = Freeze::parse("vA=0x10").unwrap();
+        assert_eq!(freeze.target, Target::Register(10));
+        assert_eq!(freeze.to_string(), "va=16");
+        assert!(Freeze::parse("1000=1").is_err());
+        assert!(Freeze::parse("2f5=256").is_err());
+        assert!(Freeze::parse("2f5").is_err());
+        assert_eq!(
+            Poke::parse("1ff=0x2"),
+            Ok(Poke {
+                addr: 0x1ff,
+                value: 2
+            })
+        );
+        assert!(Poke::parse("v1=2").is_err());
+        assert_eq!(Filter::parse("= 3"), Ok(Filter::Equal(3)));
+        assert_eq!(Filter::parse("Decreased"), Ok(Filter::Decreased));
+        assert!(Filter::parse("fewer").is_err());
+    }
+
+    #[test]
+    fn search() {
+        let mut memory = vec![0u8; 8];
+        memory[2] = 3;
+        memory[5] = 3;
+        memory[6] = 3;
+        let mut search = Search::new(&memory);
+        search.filter(&memory, Filter::Equal(3));
+        assert_eq!(search.candidates(), [2, 5, 6]);
+
+        memory[5] = 2;
+        memory[6] = 4;
+        search.filter(&memory, Filter::Decreased);
+        assert_eq!(search.candidates(), [5]);
+        search.filter(&memory, Filter::Unchanged);
+        assert_eq!(search.candidates(), [5]);
+        memory[5] = 1;
+        search.filter(&memory, Filter::Changed);
+        assert_eq!(search.candidates(), [5]);
+    }
+
+    #[test]
+    fn commands() {
+        let path = env::temp_dir().join(format!("chip8-cheats-{}.ini", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut cheats = Cheats::with_file("abc123", Some(path.clone()));
+        let mut chip = Chip8::new();
+        chip.memory_mut()[0x300] = 3;
+
+        assert!(cheats.command(&mut chip, "search changed").is_err());
+        let reply = cheats.command(&mut chip, "search 3").unwrap();
+        assert!(reply.contains("300"));
+        chip.memory_mut()[0x300] = 2;
+        let reply = cheats.command(&mut chip, "search decreased").unwrap();
+        assert_eq!(reply, "1 addresses left: 300");
+
+        assert_eq!(
+            cheats.command(&mut chip, "freeze 300 9").unwrap(),
+            "Froze 300=9"
+        );
+        assert_eq!(chip.memory()[0x300], 9);
+        cheats.command(&mut chip, "freeze v3=0x10").unwrap();
+        assert_eq!(chip.registers().v[3], 0x10);
+        cheats.command(&mut chip, "save lives").unwrap();
+        assert!(cheats.command(&mut chip, "save bad=name").is_err());
+
+        // The frozen values are written every frame
+        chip.memory_mut()[0x300] = 0;
+        cheats.apply(&mut chip);
+        assert_eq!(chip.memory()[0x300], 9);
+
+        assert_eq!(
+            cheats.command(&mut chip, "unfreeze").unwrap(),
+            "Unfroze everything"
+        );
+        assert!(cheats.command(&mut chip, "unfreeze v3").is_err());
+        chip.memory_mut()[0x300] = 0;
+        cheats.command(&mut chip, "load lives").unwrap();
+        assert_eq!(chip.memory()[0x300], 9);
+        assert_eq!(
+            cheats.command(&mut chip, "cheats").unwrap(),
+            "Frozen: 300=9 v3=16  Saved: lives"
+        );
+        assert!(cheats.command(&mut chip, "load speed").is_err());
+        assert!(cheats.command(&mut chip, "teleport").is_err());
+
+        // Other ROMs have their own cheats
+        let other = Cheats::with_file("def456", Some(path.clone()));
+        assert!(other.saved().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,38 @@
+use super::MEMORY_SIZE;
+
+/// How often each address was executed, read and written.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    /// Instructions starting at each address.
+    pub executed: Vec<u64>,
+    /// Reads by instructions, not counting fetching the instruction itself.
+    pub reads: Vec<u64>,
+    pub writes: Vec<u64>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage {
+            executed: vec![0; MEMORY_SIZE],
+            reads: vec![0; MEMORY_SIZE],
+            writes: vec![0; MEMORY_SIZE],
+        }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// Whether an instruction covering `addr` was executed, including the
+    /// second byte of one.
+    pub fn is_code(&self, addr: usize) -> bool {
+        self.executed[addr] > 0 || (addr > 0 && self.executed[addr - 1] > 0)
+    }
+
+    /// All accesses to `addr` of any kind.
+    pub fn total(&self, addr: usize) -> u64 {
+        self.executed[addr] + self.reads[addr] + self.writes[addr]
+    }
+}
@@ -0,0 +1,250 @@
+use super::{Chip8, Registers, HEIGHT, WIDTH};
+use rand::rngs::StdRng;
+use std::collections::VecDeque;
+
+/// What's needed to undo one instruction.
+#[derive(Clone, Debug)]
+pub(super) struct Undo {
+    /// Value of `cycles` after the instruction.
+    cycle: u64,
+    /// Registers and timers before the instruction.
+    registers: Registers,
+    /// Memory written, as (address, old value), in order.
+    writes: Vec<(u16, u8)>,
+    /// Pixels changed, as (index, old value).
+    pixels: Vec<(u16, u32)>,
+    /// The random number generator before RND.
+    rng: Option<Box<StdRng>>,
+}
+
+/// The last write to a memory address found in the history.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LastWrite {
+    /// Cycle of the instruction that wrote it, as in traces.
+    pub cycle: u64,
+    /// Address of the instruction.
+    pub pc: u16,
+    /// The value before the write.
+    pub old: u8,
+}
+
+/// Undo information for the most recent instructions, for stepping backwards.
+#[derive(Clone, Debug)]
+pub struct History {
+    undo: VecDeque<Undo>,
+    /// Most instructions kept, dropping the oldest.
+    limit: usize,
+    /// The display before a drawing instruction.
+    display: Vec<u32>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            limit,
+            display: vec![0; WIDTH * HEIGHT],
+        }
+    }
+
+    /// Instructions that can be undone.
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    /// Save what `opcode` might change before `chip` runs it.
+    pub(super) fn before(&mut self, chip: &Chip8, opcode: u16) -> Undo {
+        if opcode == 0x00E0 || opcode & 0xF000 == 0xD000 {
+            self.display.copy_from_slice(&chip.display);
+        }
+        let rng = if opcode & 0xF000 == 0xC000 {
+            Some(Box::new(chip.rng.clone()))
+        } else {
+            None
+        };
+        // The cycle count is already up to date
+        Undo {
+            cycle: chip.cycles,
+            registers: chip.registers(),
+            writes: Vec::new(),
+            pixels: Vec::new(),
+            rng,
+        }
+    }
+
+    /// Record the instruction `chip` just ran, with what `before` saved.
+    pub(super) fn after(&mut self, chip: &Chip8, mut undo: Undo) {
+        undo.writes = chip
+            .accesses
+            .iter()
+            .filter(|access| access.write)
+            .map(|access| (access.addr, access.old))
+            .collect();
+        if chip.draw_flag {
+            let pixels = self.display.iter().zip(chip.display.iter()).enumerate();
+            for (index, (&old, &new)) in pixels {
+                if old != new {
+                    undo.pixels.push((index as u16, old));
+                }
+            }
+        }
+        if self.limit == 0 {
+            return;
+        }
+        if self.undo.len() == self.limit {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(undo);
+    }
+
+    /// Undo the last instruction. Returns false if there's nothing to undo.
+    pub(super) fn undo(&mut self, chip: &mut Chip8) -> bool {
+        let undo = match self.undo.pop_back() {
+            Some(undo) => undo,
+            None => return false,
+        };
+        for &(addr, old) in undo.writes.iter().rev() {
+            chip.memory[addr as usize] = old;
+        }
+        for &(index, old) in &undo.pixels {
+            chip.display[index as usize] = old;
+        }
+        chip.draw_flag = !undo.pixels.is_empty();
+        chip.set_registers(&undo.registers);
+        if let Some(rng) = undo.rng {
+            chip.rng = *rng;
+        }
+        chip.cycles = undo.cycle - 1;
+        chip.accesses.clear();
+        true
+    }
+
+    /// Put `chip` back as `before` found it, for an instruction that stopped
+    /// the emulator part way through.
+    pub(super) fn cancel(&self, chip: &mut Chip8, undo: Undo) {
+        let writes: Vec<_> = chip.accesses.iter().filter(|a| a.write).copied().collect();
+        for access in writes.iter().rev() {
+            chip.memory[access.addr as usize] = access.old;
+        }
+        chip.set_registers(&undo.registers);
+        chip.cycles = undo.cycle - 1;
+    }
+
+    /// The most recent write to `addr` that's still in the history.
+    pub fn last_write(&self, addr: u16) -> Option<LastWrite> {
+        self.undo.iter().rev().find_map(|undo| {
+            let &(_, old) = undo.writes.iter().find(|&&(written, _)| written == addr)?;
+            Some(LastWrite {
+                cycle: undo.cycle,
+                pc: undo.registers.pc,
+                old,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Registers, cycle count, memory and display.
+    type State = (Registers, u64, Vec<u8>, Vec<u32>);
+
+    fn state(chip: &Chip8) -> State {
+        let memory = chip.memory().to_vec();
+        (
+            chip.registers(),
+            chip.cycles(),
+            memory,
+            chip.display.to_vec(),
+        )
+    }
+
+    fn chip(limit: usize) -> Chip8 {
+        let mut chip = Chip8::with_seed(1);
+        chip.enable_history(limit);
+        // 200: RND V0, FF
+        // 202: LD I, 300
+        // 204: LD [I], V0
+        // 206: DRW V1, V1, 1
+        // 208: CALL 20c
+        // 20a: JP 200
+        // 20c: CLS
+        // 20e: RET
+        chip.load_binary(vec![
+            0xC0, 0xFF, 0xA3, 0x00, 0xF0, 0x55, 0xD1, 0x11, 0x22, 0x0C, 0x12, 0x00, 0x00, 0xE0,
+            0x00, 0xEE,
+        ]);
+        chip
+    }
+
+    #[test]
+    fn step_back_restores_everything() {
+        let mut chip = chip(100);
+        let mut registers = chip.registers();
+        registers.delay = 10;
+        chip.set_registers(&registers);
+        let mut states = Vec::new();
+        for n in 0..16 {
+            states.push(state(&chip));
+            chip.step();
+            if n == 5 {
+                chip.decrement_timers();
+            }
+        }
+        let end = state(&chip);
+        assert_eq!(chip.history().unwrap().len(), 16);
+
+        while let Some(expected) = states.pop() {
+            assert!(chip.step_back());
+            assert_eq!(state(&chip), expected);
+        }
+        assert!(!chip.step_back());
+
+        // Random numbers come out the same the second time round
+        for n in 0..16 {
+            chip.step();
+            if n == 5 {
+                chip.decrement_timers();
+            }
+        }
+        assert_eq!(state(&chip), end);
+    }
+
+    #[test]
+    fn last_write() {
+        let mut chip = chip(100);
+        assert_eq!(chip.history().unwrap().last_write(0x300), None);
+        // Written on the 3rd and 11th cycles
+        for _ in 0..3 {
+            chip.step();
+        }
+        let first = chip.memory()[0x300];
+        for _ in 0..8 {
+            chip.step();
+        }
+        let history = chip.history().unwrap();
+        let write = history.last_write(0x300).unwrap();
+        assert_eq!((write.cycle, write.pc, write.old), (11, 0x204, first));
+        assert_eq!(history.last_write(0x301), None);
+    }
+
+    #[test]
+    fn limit() {
+        let mut chip = chip(3);
+        for _ in 0..5 {
+            chip.step();
+        }
+        assert_eq!(chip.history().unwrap().len(), 3);
+        assert!(chip.step_back() && chip.step_back() && chip.step_back());
+        assert!(!chip.step_back());
+        assert_eq!(chip.pc(), 0x204);
+        assert_eq!(chip.cycles(), 2);
+        assert!(Chip8::new().history().is_none());
+        assert!(!Chip8::new().step_back());
+    }
+}
@@ -1,21 +1,99 @@
+mod coverage;
+mod history;
 mod opcodes;
+mod quirks;
+pub mod semihosting;
+mod strict;
+
+pub use coverage::Coverage;
+pub use history::{History, LastWrite};
+pub use quirks::{Platform, Quirks};
+pub use semihosting::{HostCall, HostOutput, Semihosting};
+pub use strict::{Lint, Strict, Warning, DEFAULT_STACK_LIMIT};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
 
 pub const WIDTH: usize = 64;
 pub const HEIGHT: usize = 32;
+pub const MEMORY_SIZE: usize = 4096;
+/// Largest ROM that fits in memory after the interpreter's 0x200 bytes.
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - 0x200;
+/// Calls that can be nested before the stack is full.
+pub const STACK_SIZE: usize = 16;
+
+/// A memory read or write by the last instruction executed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub write: bool,
+    /// The value before a write.
+    pub old: u8,
+}
+
+/// Snapshot of the CPU registers, for debuggers and tools.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl Registers {
+    /// Check a debugger's changes leave the PC in memory and the stack
+    /// pointer in the stack.
+    pub fn check(&self) -> Result<(), String> {
+        if self.pc as usize >= MEMORY_SIZE {
+            return Err(format!("PC {:#x} is past the end of memory", self.pc));
+        }
+        if self.sp as usize > STACK_SIZE {
+            return Err(format!("SP {} is past the end of the stack", self.sp));
+        }
+        Ok(())
+    }
+}
+
+/// The message from the panic that stopped the emulator, like `Unknown
+/// opcode: 0xFF`.
+pub fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
 
 pub struct Chip8 {
-    memory: [u8; 4096],
+    memory: [u8; MEMORY_SIZE],
     // pub display: [[bool; 64]; 32],
     pub display: [u32; WIDTH * HEIGHT],
     pc: usize,
     i: usize,
     sp: usize,
-    stack: [usize; 16],
+    stack: [usize; STACK_SIZE],
     delay: u8,
     sound: u8,
     v: [u8; 16],
     pub keypad: [bool; 16],
     pub draw_flag: bool,
+    /// Interpreter behaviours to copy, chosen for the ROM.
+    pub quirks: Quirks,
+    seed: u64,
+    rng: StdRng,
+    cycles: u64,
+    coverage: Option<Box<Coverage>>,
+    history: Option<Box<History>>,
+    strict: Option<Box<Strict>>,
+    semihosting: Option<Box<Semihosting>>,
+    accesses: Vec<MemoryAccess>,
 }
 
 const SPRITES: [u8; 80] = [
@@ -37,21 +115,41 @@ const SPRITES: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+impl Default for Chip8 {
+    fn default() -> Self {
+        Chip8::new()
+    }
+}
+
 impl Chip8 {
     pub fn new() -> Self {
+        Chip8::with_seed(rand::random())
+    }
+
+    /// Create a `Chip8` whose random numbers (RND) are the same on every run.
+    pub fn with_seed(seed: u64) -> Self {
         let mut chip = Chip8 {
-            memory: [0; 4096],
+            memory: [0; MEMORY_SIZE],
             //display: [[false; 64]; 32],
             display: [0; WIDTH * HEIGHT],
             pc: 512,
             i: 0,
             sp: 0,
-            stack: [0; 16],
+            stack: [0; STACK_SIZE],
             delay: 0,
             sound: 0,
             v: [0; 16],
             keypad: [false; 16],
             draw_flag: false,
+            quirks: Quirks::default(),
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            cycles: 0,
+            coverage: None,
+            history: None,
+            strict: None,
+            semihosting: None,
+            accesses: Vec::new(),
         };
 
         // Load sprites starting at mem location 80 (0x50)
@@ -60,10 +158,14 @@ impl Chip8 {
         chip
     }
 
+    /// Panics if `binary` is longer than `MAX_ROM_SIZE`.
     pub fn load_binary(&mut self, binary: Vec<u8>) {
         for (i, byte) in binary.iter().enumerate() {
             self.memory[512 + i] = *byte;
         }
+        if let Some(strict) = &mut self.strict {
+            strict.loaded(512, binary.len());
+        }
     }
 
     fn handle_opcode(&mut self, opcode: u16) {
@@ -77,8 +179,9 @@ impl Chip8 {
 
         match (first_byte & 0xF0) >> 4 {
             0x00 => match second_byte {
-                0xE0 => self.op_00e0(), // CLS
-                0xEE => self.op_00ee(), // RET
+                0xE0 => self.op_00e0(),                             // CLS
+                0xEE => self.op_00ee(),                             // RET
+                _ if self.is_host_call(addr) => self.op_0nnn(addr), // SYS
                 _ => panic!("Unknown opcode: {:#04X}", opcode),
             },
             0x01 => self.op_1nnn(addr),           // JP
@@ -95,9 +198,9 @@ impl Chip8 {
                 0x03 => self.op_8xy3(x, y), // XOR
                 0x04 => self.op_8xy4(x, y), // ADD
                 0x05 => self.op_8xy5(x, y), // SUB
-                0x06 => self.op_8xy6(x),    // SHR
+                0x06 => self.op_8xy6(x, y), // SHR
                 0x07 => self.op_8xy7(x, y), // SUBN
-                0x0E => self.op_8xye(x),    // SHL
+                0x0E => self.op_8xye(x, y), // SHL
                 _ => panic!("Unknown opcode: {:#04X}", opcode),
             },
             0x09 => self.op_9xy0(x, y),                     // SNE
@@ -127,16 +230,284 @@ impl Chip8 {
     }
 
     pub fn step(&mut self) {
+        // The PC is 12 bits, so a jump to the last byte or a skip past it
+        // wraps round to the start
+        self.pc %= MEMORY_SIZE;
         self.draw_flag = false;
-        let opcode =
-            (self.memory[self.pc as usize] as u16) << 8 | self.memory[self.pc as usize + 1] as u16;
-        self.handle_opcode(opcode);
+        self.cycles += 1;
+        self.accesses.clear();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.executed[self.pc] += 1;
+        }
+        let opcode = self.opcode();
+        if let Some(strict) = &mut self.strict {
+            strict.execute(self.cycles, self.pc, opcode);
+        }
+        match self.history.take() {
+            Some(mut history) => {
+                let undo = history.before(self, opcode);
+                // An instruction the emulator can't run is left to run again,
+                // so debuggers can stop on it
+                let result = panic::catch_unwind(AssertUnwindSafe(|| self.handle_opcode(opcode)));
+                match result {
+                    Ok(()) => history.after(self, undo),
+                    Err(payload) => {
+                        history.cancel(self, undo);
+                        self.history = Some(history);
+                        panic::resume_unwind(payload);
+                    }
+                }
+                self.history = Some(history);
+            }
+            None => self.handle_opcode(opcode),
+        }
+    }
+
+    /// Start keeping undo information for the last `limit` instructions, so
+    /// `step_back` can reverse them.
+    pub fn enable_history(&mut self, limit: usize) {
+        if self.history.is_none() {
+            self.history = Some(Box::new(History::new(limit)));
+        }
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_deref()
+    }
+
+    /// Undo the last instruction, including any timer tick after it. Returns
+    /// false if there's no history of it.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.take() {
+            Some(mut history) => {
+                let undone = history.undo(self);
+                self.history = Some(history);
+                undone
+            }
+            None => false,
+        }
+    }
+
+    /// Start warning about undefined behaviour, with calls allowed to nest
+    /// `stack_limit` deep. Enable it before `load_binary` so the ROM counts as
+    /// written.
+    pub fn enable_strict(&mut self, stack_limit: usize) {
+        if self.strict.is_none() {
+            let mut strict = Strict::new(stack_limit);
+            strict.loaded(0x50, SPRITES.len());
+            self.strict = Some(Box::new(strict));
+        }
+    }
+
+    /// Warnings from strict mode since the last call.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        match &mut self.strict {
+            Some(strict) => strict.take_warnings(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Make `0nnn` at `addr` run `call` instead of panicking.
+    pub fn register_host_call(&mut self, addr: u16, call: HostCall) {
+        self.semihosting
+            .get_or_insert_with(Default::default)
+            .register(addr, call);
+    }
+
+    /// Register the host calls in `semihosting::standard`.
+    pub fn enable_semihosting(&mut self) {
+        for (addr, call) in semihosting::standard() {
+            self.register_host_call(addr, call);
+        }
+    }
+
+    fn is_host_call(&self, addr: usize) -> bool {
+        match &self.semihosting {
+            Some(semihosting) => semihosting.is_registered(addr as u16),
+            None => false,
+        }
+    }
+
+    /// What host calls printed since the last call.
+    pub fn take_host_output(&mut self) -> Vec<HostOutput> {
+        match &mut self.semihosting {
+            Some(semihosting) => semihosting.take_output(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Start counting how often each address is executed, read and written.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Box::new(Coverage::new()));
+        }
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_deref()
+    }
+
+    /// Memory read and written by the last instruction, in order.
+    pub fn accesses(&self) -> &[MemoryAccess] {
+        &self.accesses
+    }
+
+    /// Check an access to `len` bytes from I in strict mode.
+    fn check_access(&mut self, len: usize) {
+        if let Some(strict) = &mut self.strict {
+            strict.access(self.i, len);
+        }
+    }
+
+    /// Memory read by an instruction. Addresses past the end wrap round.
+    fn read(&mut self, addr: usize) -> u8 {
+        let addr = addr % MEMORY_SIZE;
+        if let Some(strict) = &mut self.strict {
+            strict.read(addr);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.reads[addr] += 1;
+        }
+        let value = self.memory[addr];
+        self.accesses.push(MemoryAccess {
+            addr: addr as u16,
+            write: false,
+            old: value,
+        });
+        value
+    }
+
+    /// Memory written by an instruction. Addresses past the end wrap round.
+    fn write(&mut self, addr: usize, value: u8) {
+        let addr = addr % MEMORY_SIZE;
+        if let Some(strict) = &mut self.strict {
+            strict.write(addr);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.writes[addr] += 1;
+        }
+        self.accesses.push(MemoryAccess {
+            addr: addr as u16,
+            write: true,
+            old: self.memory[addr],
+        });
+        self.memory[addr] = value;
+    }
+
+    /// The instruction at `pc`, which runs on the next `step`. An instruction
+    /// at the last byte takes its second byte from the start of memory.
+    pub fn opcode(&self) -> u16 {
+        let pc = self.pc % MEMORY_SIZE;
+        (self.memory[pc] as u16) << 8 | self.memory[(pc + 1) % MEMORY_SIZE] as u16
+    }
+
+    /// Instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
     }
 
     pub fn decrement_timers(&mut self) {
         if self.delay > 0 {
             self.delay -= 1;
         }
+        if self.sound > 0 {
+            self.sound -= 1;
+        }
+    }
+
+    /// Whether the buzzer should be sounding.
+    pub fn sound_active(&self) -> bool {
+        self.sound > 0
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc as u16
+    }
+
+    pub fn registers(&self) -> Registers {
+        let mut stack = [0; 16];
+        for (to, from) in stack.iter_mut().zip(self.stack.iter()) {
+            *to = *from as u16;
+        }
+        Registers {
+            v: self.v,
+            i: self.i as u16,
+            pc: self.pc as u16,
+            sp: self.sp as u8,
+            stack,
+            delay: self.delay,
+            sound: self.sound,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.v = registers.v;
+        self.i = registers.i as usize;
+        self.pc = registers.pc as usize;
+        self.sp = registers.sp as usize;
+        for (to, from) in self.stack.iter_mut().zip(registers.stack.iter()) {
+            *to = *from as usize;
+        }
+        self.delay = registers.delay;
+        self.sound = registers.sound;
+    }
+
+    pub fn memory(&self) -> &[u8; MEMORY_SIZE] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8; MEMORY_SIZE] {
+        &mut self.memory
+    }
+
+    /// Preset a byte of memory before running, like the options some test
+    /// ROMs read from 0x1FF. Strict mode counts it as loaded.
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        let addr = addr as usize % MEMORY_SIZE;
+        self.memory[addr] = value;
+        if let Some(strict) = &mut self.strict {
+            strict.loaded(addr, 1);
+        }
+    }
+
+    /// The display as lines of `#` for pixels that are on and `.` for ones
+    /// that are off.
+    pub fn display_text(&self) -> String {
+        let rows: Vec<String> = self
+            .display
+            .chunks(WIDTH)
+            .map(|row| {
+                row.iter()
+                    .map(|&pixel| if pixel != 0 { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        rows.join("\n")
+    }
+
+    /// Seed of the random number generator.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// FNV-1a hash of the display and registers, for checking that two runs stay in sync.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        };
+
+        let pixels: Vec<u8> = self.display.iter().map(|&p| (p != 0) as u8).collect();
+        feed(&pixels);
+        feed(&self.v);
+        for reg in [self.pc, self.i, self.sp].iter().chain(self.stack.iter()) {
+            feed(&(*reg as u16).to_le_bytes());
+        }
+        feed(&[self.delay, self.sound]);
+        hash
     }
 
     pub fn dump_info(&self) {
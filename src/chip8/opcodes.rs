@@ -1,7 +1,18 @@
-use super::{Chip8, HEIGHT, WIDTH};
+use super::{Chip8, Lint, HEIGHT, STACK_SIZE, WIDTH};
 use rand::Rng;
 
 impl Chip8 {
+    /// 0nnn - SYS addr
+    ///
+    /// Run the host call registered at nnn.
+    pub(super) fn op_0nnn(&mut self, addr: usize) {
+        // Taken out so the call can borrow the whole chip
+        if let Some(mut semihosting) = self.semihosting.take() {
+            semihosting.call(self, addr as u16);
+            self.semihosting = Some(semihosting);
+        }
+    }
+
     /// 00E0 - CLS
     ///
     /// Clear the display.
@@ -12,8 +23,15 @@ impl Chip8 {
 
     /// 00EE - RET
     ///
-    /// Return from a subroutine.
+    /// Return from a subroutine. In strict mode a return without a call is a
+    /// warning and does nothing.
     pub(super) fn op_00ee(&mut self) {
+        if self.sp == 0 {
+            match &mut self.strict {
+                Some(strict) => return strict.warn(Lint::StackUnderflow),
+                None => panic!("Stack underflow: RET with no call to return from"),
+            }
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
     }
@@ -27,11 +45,23 @@ impl Chip8 {
 
     /// 2nnn - CALL addr
     ///
-    /// Call subroutine at nnn.
+    /// Call subroutine at nnn. In strict mode a call with the stack full is a
+    /// warning and drops the oldest return address.
     pub(super) fn op_2nnn(&mut self, addr: usize) {
+        if self.sp == STACK_SIZE {
+            match &mut self.strict {
+                Some(strict) => strict.warn(Lint::StackOverflow),
+                None => panic!("Stack overflow: calls nested more than {} deep", STACK_SIZE),
+            }
+            self.stack.copy_within(1.., 0);
+            self.sp -= 1;
+        }
         self.stack[self.sp] = self.pc;
         self.sp += 1;
         self.pc = addr;
+        if let Some(strict) = &mut self.strict {
+            strict.call(self.sp);
+        }
     }
 
     /// 3xkk - SE Vx, byte
@@ -81,6 +111,9 @@ impl Chip8 {
     /// Set Vx = Vx OR Vy.
     pub(super) fn op_8xy1(&mut self, x: usize, y: usize) {
         self.v[x] |= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0x0F] = 0;
+        }
     }
 
     /// 8xy2 - AND Vx, Vy
@@ -88,6 +121,9 @@ impl Chip8 {
     /// Set Vx = Vx AND Vy.
     pub(super) fn op_8xy2(&mut self, x: usize, y: usize) {
         self.v[x] &= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0x0F] = 0;
+        }
     }
 
     /// 8xy3 - XOR Vx, Vy
@@ -95,6 +131,9 @@ impl Chip8 {
     /// Set Vx = Vx XOR Vy.
     pub(super) fn op_8xy3(&mut self, x: usize, y: usize) {
         self.v[x] ^= self.v[y];
+        if self.quirks.vf_reset {
+            self.v[0x0F] = 0;
+        }
     }
 
     /// 8xy4 - ADD Vx, Vy
@@ -117,10 +156,15 @@ impl Chip8 {
 
     /// 8xy6 - SHR Vx {, Vy}
     ///
-    /// Set Vx = Vx SHR 1.
-    pub(super) fn op_8xy6(&mut self, x: usize) {
-        self.v[0x0F] = self.v[x] & 1;
-        self.v[x] >>= 1;
+    /// Set Vx = Vx SHR 1, or Vy SHR 1 without the shift quirk.
+    pub(super) fn op_8xy6(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shift_vx {
+            self.v[x]
+        } else {
+            self.v[y]
+        };
+        self.v[0x0F] = value & 1;
+        self.v[x] = value >> 1;
     }
 
     /// 8xy7 - SUBN Vx, Vy
@@ -134,10 +178,15 @@ impl Chip8 {
 
     /// 8xyE - SHL Vx {, Vy}
     ///
-    /// Set Vx = Vx SHL 1.
-    pub(super) fn op_8xye(&mut self, x: usize) {
-        self.v[0x0F] = (self.v[x] & 0x80) >> 7;
-        self.v[x] <<= 1;
+    /// Set Vx = Vx SHL 1, or Vy SHL 1 without the shift quirk.
+    pub(super) fn op_8xye(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shift_vx {
+            self.v[x]
+        } else {
+            self.v[y]
+        };
+        self.v[0x0F] = (value & 0x80) >> 7;
+        self.v[x] = value << 1;
     }
 
     /// 9xy0 - SNE Vx, Vy
@@ -156,35 +205,43 @@ impl Chip8 {
 
     /// Bnnn - JP V0, addr
     ///
-    /// Jump to location nnn + V0.
+    /// Jump to location nnn + V0, or nnn + Vx with the jump quirk.
     pub(super) fn op_bnnn(&mut self, addr: usize) {
-        self.pc = addr + self.v[0] as usize;
+        let x = if self.quirks.jump_vx { addr >> 8 } else { 0 };
+        self.pc = addr + self.v[x] as usize;
     }
 
     /// Cxkk - RND Vx, byte
     ///
     /// Set Vx = random byte AND kk.
     pub(super) fn op_cxkk(&mut self, x: usize, byte: u8) {
-        let mut rng = rand::thread_rng();
-        let random: u8 = rng.gen();
+        let random: u8 = self.rng.gen();
         self.v[x] = random & byte;
     }
 
     /// Dxyn - DRW Vx, Vy, nibble
     ///
     /// Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
+    /// Sprites are clipped at the edges, or wrap around with the wrap quirk.
     pub(super) fn op_dxyn(&mut self, x: usize, y: usize, nibble: u8) {
-        for byte in 0..nibble {
-            let sprite_y = (self.v[y] + byte) as usize;
-            if sprite_y >= HEIGHT {
+        self.check_access(nibble as usize);
+        let wrap = self.quirks.wrap;
+        for byte in 0..nibble as usize {
+            let mut sprite_y = self.v[y] as usize + byte;
+            if wrap {
+                sprite_y %= HEIGHT;
+            } else if sprite_y >= HEIGHT {
                 break;
             }
+            let row = self.read(self.i + byte);
             for bit in 0..8 {
-                let sprite_x = (self.v[x] + bit) as usize;
-                if sprite_x >= WIDTH {
+                let mut sprite_x = self.v[x] as usize + bit;
+                if wrap {
+                    sprite_x %= WIDTH;
+                } else if sprite_x >= WIDTH {
                     break;
                 }
-                let pixel = (self.memory[self.i + byte as usize] >> (7 - bit)) & 1;
+                let pixel = (row >> (7 - bit)) & 1;
                 self.v[0x0F] |=
                     pixel & (self.display[sprite_y * WIDTH + sprite_x] == u32::MAX) as u8;
                 self.display[sprite_y * WIDTH + sprite_x] ^= (pixel as u32) * u32::MAX;
@@ -269,9 +326,10 @@ impl Chip8 {
     ///
     /// Store BCD representation of Vx in memory locations I, I+1, and I+2.
     pub(super) fn op_fx33(&mut self, x: usize) {
-        self.memory[self.i] = self.v[x] / 100;
-        self.memory[self.i + 1] = (self.v[x] % 100) / 10;
-        self.memory[self.i + 2] = self.v[x] % 10;
+        self.check_access(3);
+        self.write(self.i, self.v[x] / 100);
+        self.write(self.i + 1, (self.v[x] % 100) / 10);
+        self.write(self.i + 2, self.v[x] % 10);
     }
 
     /// Fx55 - LD [I], Vx
@@ -279,8 +337,12 @@ impl Chip8 {
     /// Store registers V0 through Vx in memory starting at location I.
     pub(super) fn op_fx55(&mut self, x: usize) {
         // self.memory[(self.i)..(self.i + x + 1)].copy_from_slice(&self.v[0..(x + 1)]);
+        self.check_access(x + 1);
         for i in 0..x + 1 {
-            self.memory[self.i + i] = self.v[i];
+            self.write(self.i + i, self.v[i]);
+        }
+        if self.quirks.memory_increments_i {
+            self.i += x + 1;
         }
     }
 
@@ -289,8 +351,12 @@ impl Chip8 {
     /// Read registers V0 through Vx from memory starting at location I.
     pub(super) fn op_fx65(&mut self, x: usize) {
         // self.v[0..(x + 1)].copy_from_slice(&self.memory[(self.i)..(self.i + x + 1)]);
+        self.check_access(x + 1);
         for i in 0..x + 1 {
-            self.v[i] = self.memory[self.i + i];
+            self.v[i] = self.read(self.i + i);
+        }
+        if self.quirks.memory_increments_i {
+            self.i += x + 1;
         }
     }
 }
@@ -298,6 +364,7 @@ impl Chip8 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chip8::Quirks;
 
     #[test]
     fn op_00e0() {
@@ -527,6 +594,18 @@ mod tests {
         assert_eq!(chip8.pc, 0x23 + 0x123);
     }
 
+    #[test]
+    fn op_cxkk() {
+        let mut chip8 = Chip8::with_seed(1);
+        let mut other = Chip8::with_seed(1);
+        for _ in 0..8 {
+            chip8.handle_opcode(0xC00F);
+            other.handle_opcode(0xC00F);
+            assert_eq!(chip8.v[0], other.v[0]);
+            assert_eq!(chip8.v[0] & 0xF0, 0);
+        }
+    }
+
     #[test]
     fn op_fx07() {
         let mut chip8 = Chip8::new();
@@ -607,7 +686,61 @@ mod tests {
         assert_eq!(chip8.v[2], 2);
     }
 
-    // TODO: write tests for the following:
-    // cxkk
-    // dxyn
+    #[test]
+    fn quirks() {
+        let mut chip8 = Chip8::new();
+        chip8.quirks = Quirks::NONE;
+        chip8.v[0] = 0x01;
+        chip8.v[1] = 0x81;
+        chip8.v[0x0F] = 1;
+        chip8.handle_opcode(0x8011);
+        assert_eq!(chip8.v[0x0F], 1);
+        chip8.handle_opcode(0x8016);
+        assert_eq!((chip8.v[0], chip8.v[0x0F]), (0x40, 1));
+        chip8.handle_opcode(0x801E);
+        assert_eq!((chip8.v[0], chip8.v[0x0F]), (0x02, 1));
+        chip8.v[2] = 0x10;
+        chip8.handle_opcode(0xB220);
+        assert_eq!(chip8.pc, 0x220 + 0x02);
+        chip8.i = 0x300;
+        chip8.handle_opcode(0xF255);
+        assert_eq!(chip8.i, 0x300);
+
+        chip8.quirks.change("vf-reset,shift,jump,memory").unwrap();
+        chip8.handle_opcode(0x8011);
+        assert_eq!(chip8.v[0x0F], 0);
+        chip8.v[0] = 0x03;
+        chip8.handle_opcode(0x8016);
+        assert_eq!((chip8.v[0], chip8.v[0x0F]), (0x01, 1));
+        chip8.handle_opcode(0xB220);
+        assert_eq!(chip8.pc, 0x220 + 0x10);
+        chip8.handle_opcode(0xF255);
+        assert_eq!(chip8.i, 0x303);
+        chip8.handle_opcode(0xF165);
+        assert_eq!(chip8.i, 0x305);
+    }
+
+    #[test]
+    fn op_dxyn() {
+        let mut chip8 = Chip8::new();
+        chip8.memory[0x300] = 0xFF;
+        chip8.memory[0x301] = 0xFF;
+        chip8.i = 0x300;
+        chip8.v[0] = 60;
+        chip8.v[1] = 31;
+        chip8.handle_opcode(0xD012);
+        let lit = |chip8: &Chip8| chip8.display.iter().filter(|&&p| p != 0).count();
+        assert_eq!(lit(&chip8), 4);
+        assert_eq!(chip8.v[0x0F], 0);
+        chip8.handle_opcode(0xD012);
+        assert_eq!(lit(&chip8), 0);
+        assert_eq!(chip8.v[0x0F], 1);
+
+        // Wrapped round to the left edge, and the second row to the top
+        chip8.quirks.wrap = true;
+        chip8.handle_opcode(0xD012);
+        assert_eq!(lit(&chip8), 16);
+        assert_ne!(chip8.display[31 * WIDTH], 0);
+        assert_ne!(chip8.display[3], 0);
+    }
 }
@@ -0,0 +1,196 @@
+use std::fmt;
+
+/// Names for `Quirks`' fields, as `Quirks::parse` and `Display` use them.
+const NAMES: [&str; 5] = ["vf-reset", "memory", "shift", "jump", "wrap"];
+
+/// Behaviours that differ between CHIP-8 interpreters. ROMs written for one
+/// often misbehave on another, so they're chosen per ROM.
+///
+/// The default is this emulator's original behaviour, which only shifts Vx
+/// in place.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// `vf-reset`: 8xy1, 8xy2 and 8xy3 (OR, AND, XOR) set VF to 0.
+    pub vf_reset: bool,
+    /// `memory`: Fx55 and Fx65 leave I after the last register they stored or
+    /// loaded instead of leaving it unchanged.
+    pub memory_increments_i: bool,
+    /// `shift`: 8xy6 and 8xyE shift Vx in place instead of setting Vx to Vy
+    /// shifted.
+    pub shift_vx: bool,
+    /// `jump`: Bnnn jumps to nnn + Vx, where x is the top digit of nnn,
+    /// instead of nnn + V0.
+    pub jump_vx: bool,
+    /// `wrap`: sprites wrap around to the other side of the display instead
+    /// of being clipped at the edges.
+    pub wrap: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks {
+            shift_vx: true,
+            ..Quirks::NONE
+        }
+    }
+}
+
+impl Quirks {
+    /// Every quirk off.
+    pub const NONE: Quirks = Quirks {
+        vf_reset: false,
+        memory_increments_i: false,
+        shift_vx: false,
+        jump_vx: false,
+        wrap: false,
+    };
+
+    /// Parse a comma-separated list of the quirks that are on, like
+    /// `shift,jump`, or `none`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut quirks = Quirks::NONE;
+        quirks.change(text)?;
+        Ok(quirks)
+    }
+
+    /// Turn quirks on or off from a comma-separated list like `wrap,no-shift`.
+    pub fn change(&mut self, text: &str) -> Result<(), String> {
+        for name in text.split(',').map(str::trim) {
+            if name == "none" {
+                *self = Quirks::NONE;
+                continue;
+            }
+            let (name, on) = match name.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (name, true),
+            };
+            let quirk = match name {
+                "vf-reset" => &mut self.vf_reset,
+                "memory" => &mut self.memory_increments_i,
+                "shift" => &mut self.shift_vx,
+                "jump" => &mut self.jump_vx,
+                "wrap" => &mut self.wrap,
+                _ => {
+                    return Err(format!(
+                        "Unknown quirk {}: expected {} or none",
+                        name,
+                        NAMES.join(", ")
+                    ))
+                }
+            };
+            *quirk = on;
+        }
+        Ok(())
+    }
+
+    fn flags(&self) -> [bool; 5] {
+        [
+            self.vf_reset,
+            self.memory_increments_i,
+            self.shift_vx,
+            self.jump_vx,
+            self.wrap,
+        ]
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let on: Vec<&str> = NAMES
+            .iter()
+            .zip(self.flags().iter())
+            .filter(|(_, &on)| on)
+            .map(|(&name, _)| name)
+            .collect();
+        if on.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&on.join(","))
+        }
+    }
+}
+
+/// Interpreters that ROMs were written for, each with its own quirks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    /// The original interpreter on the COSMAC VIP.
+    Chip8,
+    /// SUPER-CHIP on HP48 calculators, and CHIP-48 before it.
+    Schip,
+    /// Octo's XO-CHIP.
+    XoChip,
+}
+
+impl Platform {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "chip8" | "chip-8" | "vip" => Ok(Platform::Chip8),
+            "schip" | "superchip" | "chip48" => Ok(Platform::Schip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!(
+                "Unknown platform {}: expected chip8, schip or xo-chip",
+                name
+            )),
+        }
+    }
+
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks {
+                vf_reset: true,
+                memory_increments_i: true,
+                ..Quirks::NONE
+            },
+            Platform::Schip => Quirks {
+                shift_vx: true,
+                jump_vx: true,
+                ..Quirks::NONE
+            },
+            Platform::XoChip => Quirks {
+                memory_increments_i: true,
+                wrap: true,
+                ..Quirks::NONE
+            },
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Platform::Chip8 => "chip8",
+            Platform::Schip => "schip",
+            Platform::XoChip => "xo-chip",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let quirks = Quirks::parse("shift, jump").unwrap();
+        assert_eq!(quirks, Platform::Schip.quirks());
+        assert_eq!(quirks.to_string(), "shift,jump");
+        assert_eq!(Quirks::parse("none").unwrap(), Quirks::NONE);
+        assert_eq!(Quirks::NONE.to_string(), "none");
+        assert_eq!(
+            Quirks::parse(&Quirks::default().to_string()),
+            Ok(Quirks::default())
+        );
+        assert!(Quirks::parse("shift,warp").is_err());
+
+        let mut quirks = Platform::XoChip.quirks();
+        quirks.change("no-wrap,vf-reset").unwrap();
+        assert_eq!(quirks.to_string(), "vf-reset,memory");
+
+        assert_eq!(Platform::parse("xo-chip"), Ok(Platform::XoChip));
+        assert_eq!(
+            Platform::parse(&Platform::Schip.to_string()),
+            Ok(Platform::Schip)
+        );
+        assert!(Platform::parse("megachip").is_err());
+    }
+}
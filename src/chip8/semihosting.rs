@@ -0,0 +1,171 @@
+//! Host calls for homebrew ROMs. Once registered, `0nnn` at a call's address
+//! runs Rust code instead of machine code, so test ROMs can print results and
+//! check conditions. `standard` has the calls `--semihosting` turns on:
+//!
+//! | Opcode | Call                                               |
+//! |--------|----------------------------------------------------|
+//! | `001x` | Print Vx                                           |
+//! | `0020` | Print the string at I, up to a zero byte           |
+//! | `003x` | Assert Vx isn't zero, failing with the string at I |
+//! | `0040` | Print the display                                  |
+
+use super::{Chip8, MEMORY_SIZE};
+use std::collections::HashMap;
+
+pub const PRINT_V: u16 = 0x010;
+pub const PRINT_STRING: u16 = 0x020;
+pub const ASSERT_V: u16 = 0x030;
+pub const DUMP_DISPLAY: u16 = 0x040;
+
+/// Longest string `PRINT_STRING` and `ASSERT_V` read, in case the zero byte
+/// is missing.
+const MAX_STRING: usize = 256;
+
+/// What a host call prints.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostOutput {
+    /// Text for the console, without a trailing newline.
+    Text(String),
+    /// A failed assertion, which makes the run fail.
+    Failure(String),
+}
+
+/// Code run by `0nnn`. It can read and change the `Chip8` it runs in.
+pub type HostCall = Box<dyn FnMut(&mut Chip8) -> Option<HostOutput> + Send>;
+
+/// Host calls by address, and what they've printed.
+#[derive(Default)]
+pub struct Semihosting {
+    calls: HashMap<u16, HostCall>,
+    output: Vec<HostOutput>,
+}
+
+impl Semihosting {
+    pub fn new() -> Self {
+        Semihosting::default()
+    }
+
+    pub fn register(&mut self, addr: u16, call: HostCall) {
+        self.calls.insert(addr, call);
+    }
+
+    pub fn is_registered(&self, addr: u16) -> bool {
+        self.calls.contains_key(&addr)
+    }
+
+    /// Run the call at `addr`, if there is one.
+    pub(super) fn call(&mut self, chip: &mut Chip8, addr: u16) {
+        if let Some(call) = self.calls.get_mut(&addr) {
+            self.output.extend(call(chip));
+        }
+    }
+
+    /// Output since the last call.
+    pub fn take_output(&mut self) -> Vec<HostOutput> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+/// The calls in the table above, by address.
+pub fn standard() -> Vec<(u16, HostCall)> {
+    let mut calls: Vec<(u16, HostCall)> = Vec::new();
+    for x in 0..16 {
+        calls.push((
+            PRINT_V + x,
+            Box::new(move |chip: &mut Chip8| {
+                let value = chip.v[x as usize];
+                Some(HostOutput::Text(format!(
+                    "v{:x} = {} ({:#04x})",
+                    x, value, value
+                )))
+            }),
+        ));
+        calls.push((
+            ASSERT_V + x,
+            Box::new(move |chip: &mut Chip8| {
+                if chip.v[x as usize] != 0 {
+                    return None;
+                }
+                Some(HostOutput::Failure(format!(
+                    "Assertion failed at {:#05x}: {}",
+                    chip.pc - 2,
+                    string_at_i(chip)
+                )))
+            }),
+        ));
+    }
+    calls.push((
+        PRINT_STRING,
+        Box::new(|chip: &mut Chip8| Some(HostOutput::Text(string_at_i(chip)))),
+    ));
+    calls.push((
+        DUMP_DISPLAY,
+        Box::new(|chip: &mut Chip8| Some(HostOutput::Text(chip.display_text()))),
+    ));
+    calls
+}
+
+/// The ASCII string at I, up to a zero byte.
+fn string_at_i(chip: &Chip8) -> String {
+    (0..MAX_STRING)
+        .map(|n| chip.memory[(chip.i + n) % MEMORY_SIZE])
+        .take_while(|&byte| byte != 0)
+        .map(char::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{HEIGHT, WIDTH};
+    use super::*;
+
+    #[test]
+    fn standard_calls() {
+        let mut chip = Chip8::with_seed(1);
+        chip.enable_semihosting();
+        // 200: LD V3, 2a
+        // 202: SYS 013
+        // 204: LD I, 210
+        // 206: SYS 020
+        // 208: SYS 033
+        // 20a: SYS 034
+        // 20c: CLS
+        // 20e: SYS 040
+        // 210: "ok"
+        chip.load_binary(vec![
+            0x63, 0x2A, 0x00, 0x13, 0xA2, 0x10, 0x00, 0x20, 0x00, 0x33, 0x00, 0x34, 0x00, 0xE0,
+            0x00, 0x40, b'o', b'k', 0,
+        ]);
+        for _ in 0..8 {
+            chip.step();
+        }
+        assert_eq!(
+            chip.take_host_output(),
+            vec![
+                HostOutput::Text("v3 = 42 (0x2a)".to_string()),
+                HostOutput::Text("ok".to_string()),
+                HostOutput::Failure("Assertion failed at 0x20a: ok".to_string()),
+                HostOutput::Text(vec![".".repeat(WIDTH); HEIGHT].join("\n")),
+            ]
+        );
+        assert_eq!(chip.take_host_output(), vec![]);
+    }
+
+    #[test]
+    fn custom_calls() {
+        let mut chip = Chip8::with_seed(1);
+        chip.register_host_call(
+            0x123,
+            Box::new(|chip: &mut Chip8| {
+                chip.v[0xF] = 7;
+                None
+            }),
+        );
+        // 200: SYS 123
+        chip.load_binary(vec![0x01, 0x23]);
+        chip.step();
+        assert_eq!(chip.registers().v[0xF], 7);
+        assert_eq!(chip.pc(), 0x202);
+        assert_eq!(chip.take_host_output(), vec![]);
+    }
+}
@@ -0,0 +1,312 @@
+use super::{MEMORY_SIZE, STACK_SIZE};
+use crate::disassembler::mnemonic_with_symbols;
+use crate::symbols::Symbols;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::{self, Discriminant};
+
+/// Calls the COSMAC VIP had room for.
+pub const DEFAULT_STACK_LIMIT: usize = 12;
+
+/// Something a ROM did that works by accident, if at all.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lint {
+    /// Executing the font or the interpreter's area below 0x200.
+    LowMemory,
+    /// Executing from an odd address.
+    OddPc,
+    /// Executing the last byte of memory, so the instruction's second byte
+    /// comes from the start.
+    PcPastEnd,
+    /// I plus the bytes accessed goes past the end of memory, so the access
+    /// wrapped round to the start.
+    PastEnd { i: u16, len: u16 },
+    /// Reading memory that hasn't been loaded or written.
+    Uninitialized { addr: u16 },
+    /// Calls nested deeper than the limit.
+    StackDepth { depth: usize },
+    /// A call with all 16 stack entries in use.
+    StackOverflow,
+    /// A return with no call to return from.
+    StackUnderflow,
+    /// Writing over an instruction that has already run.
+    WriteToCode { addr: u16 },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Lint::LowMemory => write!(f, "executing below 0x200"),
+            Lint::OddPc => write!(f, "executing from an odd address"),
+            Lint::PcPastEnd => write!(f, "instruction runs past the end of memory"),
+            Lint::PastEnd { i, len } => write!(
+                f,
+                "I is {:#05x}, so {} bytes from it go past the end of memory",
+                i, len
+            ),
+            Lint::Uninitialized { addr } => {
+                write!(f, "reading {:#05x}, which was never written", addr)
+            }
+            Lint::StackDepth { depth } => write!(f, "calls nested {} deep", depth),
+            Lint::StackOverflow => write!(
+                f,
+                "calls nested more than {} deep, dropping the oldest return address",
+                STACK_SIZE
+            ),
+            Lint::StackUnderflow => write!(f, "returning with no call to return from"),
+            Lint::WriteToCode { addr } => {
+                write!(f, "writing {:#05x}, which has already run as code", addr)
+            }
+        }
+    }
+}
+
+/// A `Lint` and the instruction that caused it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Warning {
+    /// Cycle of the instruction, as in traces.
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    pub lint: Lint,
+}
+
+impl Warning {
+    /// Like `Display`, naming the instruction's address with `symbols`.
+    pub fn format(&self, symbols: &Symbols) -> String {
+        let mnemonic = mnemonic_with_symbols(self.opcode, symbols);
+        let mnemonic: Vec<&str> = mnemonic.split_whitespace().collect();
+        format!(
+            "Strict: cycle {} at {}: {}: {}",
+            self.cycle,
+            symbols.describe(self.pc),
+            mnemonic.join(" "),
+            self.lint
+        )
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(&Symbols::new()))
+    }
+}
+
+/// Checks for undefined behaviour as instructions run, for `Chip8` in strict
+/// mode. Each kind of warning is only given once for each instruction.
+#[derive(Clone, Debug)]
+pub struct Strict {
+    /// Deepest calls can go without a warning, at most `STACK_SIZE`.
+    pub stack_limit: usize,
+    written: Vec<bool>,
+    executed: Vec<bool>,
+    /// Cycle, address and opcode of the instruction running.
+    current: (u64, u16, u16),
+    reported: HashSet<(u16, Discriminant<Lint>)>,
+    warnings: Vec<Warning>,
+}
+
+impl Strict {
+    /// A limit over `STACK_SIZE` is lowered to it, as calls can't go deeper.
+    pub fn new(stack_limit: usize) -> Self {
+        Strict {
+            stack_limit: stack_limit.min(STACK_SIZE),
+            written: vec![false; MEMORY_SIZE],
+            executed: vec![false; MEMORY_SIZE],
+            current: (0, 0, 0),
+            reported: HashSet::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Count memory as written, when it's loaded.
+    pub(super) fn loaded(&mut self, start: usize, len: usize) {
+        let end = (start + len).min(MEMORY_SIZE);
+        for written in &mut self.written[start..end] {
+            *written = true;
+        }
+    }
+
+    /// Check the instruction at `pc` before it runs.
+    pub(super) fn execute(&mut self, cycle: u64, pc: usize, opcode: u16) {
+        self.current = (cycle, pc as u16, opcode);
+        if pc < 0x200 {
+            self.warn(Lint::LowMemory);
+        }
+        if pc % 2 == 1 {
+            self.warn(Lint::OddPc);
+        }
+        if pc == MEMORY_SIZE - 1 {
+            self.warn(Lint::PcPastEnd);
+        }
+        self.executed[pc] = true;
+        self.executed[(pc + 1) % MEMORY_SIZE] = true;
+    }
+
+    /// Check an access of `len` bytes from I.
+    pub(super) fn access(&mut self, i: usize, len: usize) {
+        if i + len > MEMORY_SIZE {
+            self.warn(Lint::PastEnd {
+                i: i as u16,
+                len: len as u16,
+            });
+        }
+    }
+
+    pub(super) fn read(&mut self, addr: usize) {
+        if !self.written[addr] {
+            self.warn(Lint::Uninitialized { addr: addr as u16 });
+        }
+    }
+
+    pub(super) fn write(&mut self, addr: usize) {
+        if self.executed[addr] {
+            self.warn(Lint::WriteToCode { addr: addr as u16 });
+        }
+        self.written[addr] = true;
+    }
+
+    /// Check the depth after a call.
+    pub(super) fn call(&mut self, depth: usize) {
+        if depth > self.stack_limit {
+            self.warn(Lint::StackDepth { depth });
+        }
+    }
+
+    pub(super) fn warn(&mut self, lint: Lint) {
+        let (cycle, pc, opcode) = self.current;
+        if self.reported.insert((pc, mem::discriminant(&lint))) {
+            self.warnings.push(Warning {
+                cycle,
+                pc,
+                opcode,
+                lint,
+            });
+        }
+    }
+
+    /// Warnings since the last call.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        mem::take(&mut self.warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Chip8, Registers};
+    use super::*;
+
+    #[test]
+    fn well_behaved_rom() {
+        let mut chip = Chip8::with_seed(1);
+        chip.enable_strict(DEFAULT_STACK_LIMIT);
+        // 200: LD I, 300
+        // 202: LD [I], V0
+        // 204: DRW V1, V1, 1
+        // 206: LD F, V0
+        // 208: DRW V1, V1, 5
+        // 20a: CALL 20e
+        // 20c: JP 200
+        // 20e: RET
+        chip.load_binary(vec![
+            0xA3, 0x00, 0xF0, 0x55, 0xD1, 0x11, 0xF0, 0x29, 0xD1, 0x15, 0x22, 0x0E, 0x12, 0x00,
+            0x00, 0xEE,
+        ]);
+        for _ in 0..32 {
+            chip.step();
+        }
+        assert_eq!(chip.take_warnings(), vec![]);
+    }
+
+    #[test]
+    fn warnings() {
+        let mut chip = Chip8::with_seed(1);
+        chip.enable_strict(0);
+        // 200: LD I, 200
+        // 202: LD [I], V0
+        // 204: LD I, 300
+        // 206: LD V0, [I]
+        // 208: LD I, fff
+        // 20a: LD V1, [I]
+        // 20c: CALL 210
+        // 210: JP 051
+        chip.load_binary(vec![
+            0xA2, 0x00, 0xF0, 0x55, 0xA3, 0x00, 0xF0, 0x65, 0xAF, 0xFF, 0xF1, 0x65, 0x22, 0x10,
+            0x00, 0x00, 0x10, 0x51,
+        ]);
+        for _ in 0..9 {
+            chip.step();
+        }
+        let warnings = chip.take_warnings();
+        let lints: Vec<(u16, Lint)> = warnings.iter().map(|w| (w.pc, w.lint)).collect();
+        assert_eq!(
+            lints,
+            vec![
+                (0x202, Lint::WriteToCode { addr: 0x200 }),
+                (0x206, Lint::Uninitialized { addr: 0x300 }),
+                (0x20a, Lint::PastEnd { i: 0xfff, len: 2 }),
+                (0x20a, Lint::Uninitialized { addr: 0xfff }),
+                (0x20c, Lint::StackDepth { depth: 1 }),
+                (0x51, Lint::LowMemory),
+                (0x51, Lint::OddPc),
+            ]
+        );
+        assert_eq!(
+            warnings[0].to_string(),
+            "Strict: cycle 2 at 0x202: LD [I], V0: writing 0x200, which has already run as code"
+        );
+        assert_eq!(chip.take_warnings(), vec![]);
+
+        // Each warning is only given once for each instruction
+        chip.set_registers(&Registers {
+            pc: 0x206,
+            ..chip.registers()
+        });
+        chip.step();
+        assert_eq!(chip.take_warnings(), vec![]);
+    }
+
+    #[test]
+    fn keeps_running() {
+        let mut chip = Chip8::with_seed(1);
+        chip.enable_strict(20);
+        // 200: RET
+        // 202: CALL 202
+        chip.load_binary(vec![0x00, 0xEE, 0x22, 0x02]);
+        for _ in 0..18 {
+            chip.step();
+        }
+        let lints: Vec<(u16, Lint)> = chip
+            .take_warnings()
+            .iter()
+            .map(|w| (w.pc, w.lint))
+            .collect();
+        assert_eq!(
+            lints,
+            vec![(0x200, Lint::StackUnderflow), (0x202, Lint::StackOverflow)]
+        );
+        let registers = chip.registers();
+        assert_eq!((registers.pc, registers.sp), (0x202, 16));
+        assert_eq!(registers.stack[15], 0x204);
+
+        // The last byte of memory is followed by the first
+        chip.memory_mut()[0xFFF] = 0x12;
+        chip.memory_mut()[0] = 0x00;
+        chip.set_registers(&Registers {
+            pc: 0xFFF,
+            ..chip.registers()
+        });
+        chip.step();
+        assert_eq!(chip.pc(), 0x200);
+        let lints: Vec<Lint> = chip.take_warnings().iter().map(|w| w.lint).collect();
+        assert_eq!(lints, vec![Lint::OddPc, Lint::PcPastEnd]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stack underflow")]
+    fn underflow_without_strict() {
+        let mut chip = Chip8::with_seed(1);
+        chip.load_binary(vec![0x00, 0xEE]);
+        chip.step();
+    }
+}
@@ -0,0 +1,122 @@
+use crate::chip8::{Platform, Quirks};
+use crate::settings::{self, Section};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Settings for known ROMs, in the same format as the settings file. See the
+/// file for the keys each ROM can have.
+const DATABASE: &str = include_str!("compat.ini");
+
+/// What the built-in database knows about a ROM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    /// The interpreter it was written for.
+    pub platform: Option<Platform>,
+    /// The platform's quirks with the ROM's own changes, or the default
+    /// quirks if neither is known.
+    pub quirks: Quirks,
+    /// Recommended instructions per frame.
+    pub speed: Option<usize>,
+    /// What the keypad keys do.
+    pub keys: Option<String>,
+}
+
+impl RomInfo {
+    pub fn from_section(section: &Section) -> Result<Self, String> {
+        let platform = section
+            .get("platform")
+            .map(|name| Platform::parse(name))
+            .transpose()?;
+        let mut quirks = platform.map_or_else(Quirks::default, Platform::quirks);
+        if let Some(changes) = section.get("quirks") {
+            quirks.change(changes)?;
+        }
+        let speed = section
+            .get("speed")
+            .map(|speed| {
+                speed
+                    .parse()
+                    .map_err(|_| format!("Invalid speed {}", speed))
+            })
+            .transpose()?;
+        Ok(RomInfo {
+            title: section.get("title").cloned().unwrap_or_default(),
+            platform,
+            quirks,
+            speed,
+            keys: section.get("keys").cloned(),
+        })
+    }
+
+    /// A line to show when the ROM is loaded, like `Pong, a chip8 ROM. Keys:
+    /// 1 and 4 move the left paddle`.
+    pub fn summary(&self) -> String {
+        let mut summary = self.title.clone();
+        if let Some(platform) = self.platform {
+            summary += &format!(", a {} ROM", platform);
+        }
+        if let Some(keys) = &self.keys {
+            summary += &format!(". Keys: {}", keys);
+        }
+        summary
+    }
+}
+
+/// Look a ROM up in the built-in database by its SHA-1.
+pub fn lookup(rom_hash: &str) -> Option<RomInfo> {
+    database().get(rom_hash).cloned()
+}
+
+/// The database by SHA-1, parsed the first time it's needed.
+fn database() -> &'static BTreeMap<String, RomInfo> {
+    static ROMS: OnceLock<BTreeMap<String, RomInfo>> = OnceLock::new();
+    ROMS.get_or_init(|| {
+        let sections = settings::parse(DATABASE);
+        sections
+            .into_iter()
+            .map(|(hash, section)| {
+                // Entries are checked by the tests, so a bad one is a bug
+                let info =
+                    RomInfo::from_section(&section).unwrap_or_else(|e| panic!("{}: {}", hash, e));
+                (hash, info)
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_entries_are_valid() {
+        let sections = settings::parse(DATABASE);
+        assert!(!sections.is_empty());
+        for (hash, section) in &sections {
+            assert_eq!(hash.len(), 40, "{} isn't a SHA-1", hash);
+            let info = RomInfo::from_section(section).unwrap_or_else(|e| panic!("{}: {}", hash, e));
+            assert!(!info.title.is_empty(), "{} has no title", hash);
+        }
+    }
+
+    #[test]
+    fn lookups() {
+        let squash = lookup("a58ec7cc63707f9e7274026de27c15ec1d9945bd").unwrap();
+        assert_eq!(squash.platform, Some(Platform::Chip8));
+        assert_eq!(squash.quirks, Platform::Chip8.quirks());
+        assert!(squash
+            .summary()
+            .starts_with("Squash (David Winter, 1997), a chip8 ROM. Keys: 1 and 4"));
+        let pong = lookup("b232ef880bd6060fb45fa6effed7edf0ae95670e").unwrap();
+        assert_eq!((pong.platform, pong.quirks), (None, Quirks::default()));
+        assert_eq!(lookup("0000000000000000000000000000000000000000"), None);
+
+        let section = settings::parse("[x]\ntitle = T\nplatform = schip\nquirks = wrap\n");
+        let info = RomInfo::from_section(&section["x"]).unwrap();
+        assert_eq!(info.quirks.to_string(), "shift,jump,wrap");
+        assert_eq!((info.speed, info.keys), (None, None));
+        let section = settings::parse("[x]\nspeed = fast\n");
+        assert!(RomInfo::from_section(&section["x"]).is_err());
+    }
+}
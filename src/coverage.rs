@@ -0,0 +1,243 @@
+use crate::chip8::{Coverage, MEMORY_SIZE};
+use crate::disassembler::disassemble_opcode;
+use crate::symbols::Symbols;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Heatmap cells per row. 64x64 cells cover the 4 KiB address space.
+const HEATMAP_WIDTH: usize = 64;
+/// Pixels per heatmap cell.
+const HEATMAP_SCALE: usize = 8;
+
+/// Where the ROM is loaded.
+const ROM_START: usize = 0x200;
+
+/// Write the disassembly of the ROM with execution, read and write counts.
+pub fn save_listing(
+    path: &Path,
+    memory: &[u8],
+    coverage: &Coverage,
+    rom_len: usize,
+    symbols: &Symbols,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_listing(&mut out, memory, coverage, rom_len, symbols)?;
+    out.flush()
+}
+
+pub fn save_heatmap(path: &Path, coverage: &Coverage) -> io::Result<()> {
+    write_heatmap(BufWriter::new(File::create(path)?), coverage, HEATMAP_SCALE)
+}
+
+/// List the ROM with how many times each instruction ran and how often it
+/// was read and written as data. Counts of zero are shown as `.`:
+///
+/// ```text
+/// ; 6 of 8 ROM bytes executed (75.0%)
+/// ;    executed      reads     writes
+///             1          .          .  0200 6a 02 LD         Va, #$02
+/// ```
+pub fn write_listing(
+    out: &mut impl Write,
+    memory: &[u8],
+    coverage: &Coverage,
+    rom_len: usize,
+    symbols: &Symbols,
+) -> io::Result<()> {
+    let end = (ROM_START + rom_len).min(MEMORY_SIZE);
+    let executed = (ROM_START..end)
+        .filter(|&addr| coverage.is_code(addr))
+        .count();
+    let percent = if rom_len == 0 {
+        0.0
+    } else {
+        100.0 * executed as f64 / rom_len as f64
+    };
+    writeln!(
+        out,
+        "; {} of {} ROM bytes executed ({:.1}%)",
+        executed, rom_len, percent
+    )?;
+    writeln!(out, ";    executed      reads     writes")?;
+
+    let count = |n: u64| {
+        if n == 0 {
+            ".".to_string()
+        } else {
+            n.to_string()
+        }
+    };
+    let mut addr = ROM_START;
+    while addr < end {
+        // Code can start at odd addresses, so show a lone byte before it
+        let len =
+            if addr + 1 < end && coverage.executed[addr] == 0 && coverage.executed[addr + 1] > 0 {
+                1
+            } else {
+                2.min(end - addr)
+            };
+        let bytes = addr..addr + len;
+        let reads = bytes.clone().map(|a| coverage.reads[a]).sum();
+        let writes = bytes.map(|a| coverage.writes[a]).sum();
+        let text = if len == 2 {
+            let opcode = (memory[addr] as u16) << 8 | memory[addr + 1] as u16;
+            disassemble_opcode(addr, opcode, symbols)
+        } else {
+            format!("{:04x} {:02x}", addr, memory[addr])
+        };
+        if let Some(label) = symbols.label(addr as u16) {
+            writeln!(out, "{:36}{}:", "", label)?;
+        }
+        writeln!(
+            out,
+            "{:>12} {:>10} {:>10}  {}",
+            count(coverage.executed[addr]),
+            count(reads),
+            count(writes),
+            text
+        )?;
+        addr += len;
+    }
+    Ok(())
+}
+
+/// Draw the address space as 64x64 cells, one per byte, coloured by how
+/// often it was accessed on a log scale from black through red and yellow
+/// to white.
+pub fn write_heatmap<W: Write>(out: W, coverage: &Coverage, scale: usize) -> io::Result<()> {
+    let max = (0..MEMORY_SIZE)
+        .map(|addr| coverage.total(addr))
+        .max()
+        .unwrap_or(0);
+    let scale_count = |n: u64| {
+        if n == 0 {
+            0
+        } else {
+            // 1-255, so that anything touched at all is visible
+            let t = (n as f64).ln_1p() / (max as f64).ln_1p();
+            1 + (t * 254.0).round() as u8
+        }
+    };
+
+    let cells: Vec<u8> = (0..MEMORY_SIZE)
+        .map(|addr| scale_count(coverage.total(addr)))
+        .collect();
+    let size = HEATMAP_WIDTH * scale;
+    let mut pixels = Vec::with_capacity(size * size);
+    for row in cells.chunks(HEATMAP_WIDTH) {
+        let mut line = Vec::with_capacity(size);
+        for &cell in row {
+            line.extend(std::iter::repeat_n(cell, scale));
+        }
+        for _ in 0..scale {
+            pixels.extend_from_slice(&line);
+        }
+    }
+
+    let palette: Vec<u8> = (0..=255).flat_map(heat_color).collect();
+    let mut encoder =
+        gif::Encoder::new(out, size as u16, size as u16, &palette).map_err(gif_error)?;
+    let frame = gif::Frame {
+        width: size as u16,
+        height: size as u16,
+        buffer: pixels.into(),
+        ..Default::default()
+    };
+    encoder.write_frame(&frame).map_err(gif_error)
+}
+
+fn heat_color(level: u8) -> [u8; 3] {
+    let channel = |start: u32| (level as u32).saturating_sub(start).min(85) * 255 / 85;
+    [channel(0) as u8, channel(85) as u8, channel(170) as u8]
+}
+
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip8::Chip8;
+
+    fn covered() -> Chip8 {
+        let mut chip = Chip8::new();
+        chip.enable_coverage();
+        // 200: LD I, 206
+        // 202: LD V0, [I]
+        // 204: JP 200
+        // 206: (data)
+        chip.load_binary(vec![0xA2, 0x06, 0xF0, 0x65, 0x12, 0x00, 0xFF, 0xFF]);
+        for _ in 0..7 {
+            chip.step();
+        }
+        chip
+    }
+
+    #[test]
+    fn counts() {
+        let chip = covered();
+        let coverage = chip.coverage().unwrap();
+        assert_eq!(coverage.executed[0x200], 3);
+        assert_eq!(coverage.executed[0x201], 0);
+        assert!(coverage.is_code(0x201));
+        assert_eq!(coverage.reads[0x206], 2);
+        assert_eq!(coverage.reads[0x202], 0);
+        assert!(!coverage.is_code(0x206));
+        assert_eq!(Chip8::new().coverage(), None);
+    }
+
+    #[test]
+    fn listing() {
+        let chip = covered();
+        let mut out = Vec::new();
+        let symbols = Symbols::parse("206 sprite", Path::new("")).unwrap();
+        let coverage = chip.coverage().unwrap();
+        write_listing(&mut out, chip.memory(), coverage, 8, &symbols).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "; 6 of 8 ROM bytes executed (75.0%)");
+        assert_eq!(
+            lines[2],
+            format!(
+                "{:>12} {:>10} {:>10}  0200 a2 06 LD         I, sprite",
+                3, ".", "."
+            )
+        );
+        assert_eq!(lines[5], format!("{:36}sprite:", ""));
+        assert_eq!(
+            lines[6],
+            format!("{:>12} {:>10} {:>10}  0206 ff ff UNKNOWN", ".", 2, ".")
+        );
+        assert_eq!(lines.len(), 7);
+    }
+
+    #[test]
+    fn heatmap() {
+        let chip = covered();
+        let mut out = Vec::new();
+        write_heatmap(&mut out, chip.coverage().unwrap(), 2).unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(&out[..]).unwrap();
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!((frame.width, frame.height), (128, 128));
+        let cell = |addr: usize| frame.buffer[(addr / 64) * 2 * 128 + (addr % 64) * 2];
+        assert_eq!(cell(0x100), 0);
+        assert_eq!(cell(0x200), 255);
+        assert!(cell(0x206) > 0 && cell(0x206) < 255);
+    }
+
+    #[test]
+    fn heat_colors() {
+        assert_eq!(heat_color(0), [0, 0, 0]);
+        assert_eq!(heat_color(85), [255, 0, 0]);
+        assert_eq!(heat_color(170), [255, 255, 0]);
+        assert_eq!(heat_color(255), [255, 255, 255]);
+    }
+}
@@ -0,0 +1,529 @@
+//! Expressions for conditional breakpoints and watchpoints.
+//!
+//! ```text
+//! pc == 0x2A4 && v3 > 10     registers after an instruction ran
+//! write [0x300..0x310]       memory written by it, end exclusive
+//! read [0x2ea]               or read, or either with `access`
+//! vf changes                 any expression whose value it changed
+//! [i + 1] >= 0x80            memory contents
+//! ```
+//!
+//! Registers are `v0`-`vf`, `i`, `pc`, `sp`, `dt` (or `delay`), `st` (or
+//! `sound`) and `cycle`. Numbers are decimal, or hex with `0x` or `$`. Other
+//! names are labels from `Symbols`, standing for their address, as in
+//! `pc == draw_paddle` or `[lives] == 0`.
+//! Operators are `||`, `&&`, `!`, the comparisons, `&`, `|`, `^`, `+` and `-`,
+//! with the usual precedence. Comparisons and logic give 1 or 0, and any
+//! nonzero value is true.
+
+use crate::chip8::{Chip8, MemoryAccess, Registers};
+use crate::symbols::Symbols;
+use std::convert::TryFrom;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Delay,
+    Sound,
+    Cycle,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Any,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    /// The byte at an address.
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+    /// Whether the last instruction changed the value.
+    Changes(Box<Expr>),
+    /// Whether the last instruction accessed memory from start up to end.
+    Access(AccessKind, Box<Expr>, Box<Expr>),
+}
+
+/// Machine state around one instruction.
+pub struct Context<'a> {
+    pub before: Registers,
+    pub after: Registers,
+    pub cycle: u64,
+    pub memory: &'a [u8],
+    pub accesses: &'a [MemoryAccess],
+}
+
+impl<'a> Context<'a> {
+    /// State after `chip` executed an instruction, starting with `before`.
+    pub fn new(before: Registers, chip: &'a Chip8) -> Self {
+        Context {
+            before,
+            after: chip.registers(),
+            cycle: chip.cycles(),
+            memory: chip.memory(),
+            accesses: chip.accesses(),
+        }
+    }
+
+    /// State while stopped, where nothing has just changed.
+    pub fn current(chip: &'a Chip8) -> Self {
+        let mut context = Context::new(chip.registers(), chip);
+        context.accesses = &[];
+        context
+    }
+
+    fn register(&self, register: Register, after: bool) -> i64 {
+        let registers = if after { &self.after } else { &self.before };
+        match register {
+            Register::V(n) => registers.v[n] as i64,
+            Register::I => registers.i as i64,
+            Register::Pc => registers.pc as i64,
+            Register::Sp => registers.sp as i64,
+            Register::Delay => registers.delay as i64,
+            Register::Sound => registers.sound as i64,
+            // The cycle count goes up by one each instruction
+            Register::Cycle if after => self.cycle as i64,
+            Register::Cycle => self.cycle as i64 - 1,
+        }
+    }
+
+    fn memory(&self, addr: i64, after: bool) -> i64 {
+        let index = match usize::try_from(addr) {
+            Ok(index) if index < self.memory.len() => index,
+            _ => return 0,
+        };
+        if !after {
+            // The first write has the value from before the instruction
+            let write = self
+                .accesses
+                .iter()
+                .find(|access| access.write && access.addr as usize == index);
+            if let Some(write) = write {
+                return write.old as i64;
+            }
+        }
+        self.memory[index] as i64
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        Expr::parse_with_symbols(text, &Symbols::new())
+    }
+
+    /// Like `parse`, with labels from `symbols` standing for their addresses.
+    pub fn parse_with_symbols(text: &str, symbols: &Symbols) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            symbols,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("Unexpected {} in {}", token, text)),
+        }
+    }
+
+    pub fn is_true(&self, context: &Context) -> bool {
+        self.value(context) != 0
+    }
+
+    /// Value after the instruction ran.
+    pub fn value(&self, context: &Context) -> i64 {
+        self.evaluate(context, true)
+    }
+
+    fn evaluate(&self, context: &Context, after: bool) -> i64 {
+        let eval = |expr: &Expr| expr.evaluate(context, after);
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(register) => context.register(*register, after),
+            Expr::Memory(addr) => context.memory(eval(addr), after),
+            Expr::Not(expr) => (eval(expr) == 0) as i64,
+            Expr::Binary(Op::Or, a, b) => (eval(a) != 0 || eval(b) != 0) as i64,
+            Expr::Binary(Op::And, a, b) => (eval(a) != 0 && eval(b) != 0) as i64,
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (eval(a), eval(b));
+                match op {
+                    Op::Eq => (a == b) as i64,
+                    Op::Ne => (a != b) as i64,
+                    Op::Lt => (a < b) as i64,
+                    Op::Le => (a <= b) as i64,
+                    Op::Gt => (a > b) as i64,
+                    Op::Ge => (a >= b) as i64,
+                    Op::BitOr => a | b,
+                    Op::BitXor => a ^ b,
+                    Op::BitAnd => a & b,
+                    Op::Add => a.wrapping_add(b),
+                    Op::Sub => a.wrapping_sub(b),
+                    Op::Or | Op::And => unreachable!(),
+                }
+            }
+            Expr::Changes(expr) => {
+                (expr.evaluate(context, false) != expr.evaluate(context, true)) as i64
+            }
+            Expr::Access(kind, start, end) => {
+                let (start, end) = (eval(start), eval(end));
+                context.accesses.iter().any(|access| {
+                    let kind_matches = match kind {
+                        AccessKind::Read => !access.write,
+                        AccessKind::Write => access.write,
+                        AccessKind::Any => true,
+                    };
+                    kind_matches && (start..end).contains(&(access.addr as i64))
+                }) as i64
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    /// A register, keyword or label, as written.
+    Name(String),
+    Symbol(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => write!(f, "`{}`", name),
+            Token::Symbol(symbol) => write!(f, "`{}`", symbol),
+        }
+    }
+}
+
+/// Longest first, so `<=` isn't read as `<` then `=`.
+const SYMBOLS: [&str; 19] = [
+    "||", "&&", "==", "!=", "<=", ">=", "..", "<", ">", "!", "&", "|", "^", "+", "-", "(", ")",
+    "[", "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        } else if rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '$' || c == '_') {
+            let end = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .map_or(rest.len(), |end| end + 1);
+            let word = &rest[..end];
+            let lower = word.to_ascii_lowercase();
+            let number =
+                if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+                    Some(i64::from_str_radix(hex, 16))
+                } else if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    Some(word.parse())
+                } else {
+                    None
+                };
+            tokens.push(match number {
+                Some(n) => Token::Number(n.map_err(|_| format!("Invalid number {}", word))?),
+                None => Token::Name(word.to_string()),
+            });
+            rest = &rest[end..];
+        } else {
+            let c = rest.chars().next().unwrap();
+            return Err(format!("Unexpected `{}` in {}", c, text));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    /// Whether the next token is the register or keyword `name`, in any case.
+    fn at_name(&self, name: &str) -> bool {
+        matches!(self.peek(), Some(Token::Name(word)) if word.eq_ignore_ascii_case(name))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            return Ok(());
+        }
+        match self.peek() {
+            Some(token) => Err(format!("Expected `{}` but found {}", symbol, token)),
+            None => Err(format!("Expected `{}`", symbol)),
+        }
+    }
+
+    /// Parse operands separated by any of `ops`, left to right.
+    fn binary(
+        &mut self,
+        ops: &[(&str, Op)],
+        operand: fn(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut expr = operand(self)?;
+        'outer: loop {
+            for (symbol, op) in ops {
+                if self.eat(symbol) {
+                    expr = Expr::Binary(*op, Box::new(expr), Box::new(operand(self)?));
+                    continue 'outer;
+                }
+            }
+            return Ok(expr);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", Op::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", Op::And)], Parser::not)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.watch()
+    }
+
+    fn watch(&mut self) -> Result<Expr, String> {
+        let kind = if self.at_name("read") {
+            Some(AccessKind::Read)
+        } else if self.at_name("write") {
+            Some(AccessKind::Write)
+        } else if self.at_name("access") {
+            Some(AccessKind::Any)
+        } else {
+            None
+        };
+        if let Some(kind) = kind {
+            self.pos += 1;
+            self.expect("[")?;
+            let start = self.bits()?;
+            let end = if self.eat("..") {
+                self.bits()?
+            } else {
+                Expr::Binary(Op::Add, Box::new(start.clone()), Box::new(Expr::Number(1)))
+            };
+            self.expect("]")?;
+            return Ok(Expr::Access(kind, Box::new(start), Box::new(end)));
+        }
+
+        let expr = self.comparison()?;
+        if self.at_name("changes") {
+            self.pos += 1;
+            return Ok(Expr::Changes(Box::new(expr)));
+        }
+        Ok(expr)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let ops = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ];
+        let a = self.bits()?;
+        for (symbol, op) in ops.iter() {
+            if self.eat(symbol) {
+                return Ok(Expr::Binary(*op, Box::new(a), Box::new(self.bits()?)));
+            }
+        }
+        Ok(a)
+    }
+
+    fn bits(&mut self) -> Result<Expr, String> {
+        let ops = [("|", Op::BitOr), ("^", Op::BitXor), ("&", Op::BitAnd)];
+        self.binary(&ops, Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", Op::Add), ("-", Op::Sub)], Parser::atom)
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Name(name)) => {
+                if let Some(register) = register(&name.to_ascii_lowercase()) {
+                    return Ok(Expr::Register(register));
+                }
+                match self.symbols.address(&name) {
+                    Some(addr) => Ok(Expr::Number(addr as i64)),
+                    None => Err(format!("Unknown register or label `{}`", name)),
+                }
+            }
+            Some(Token::Symbol("[")) => {
+                let addr = self.bits()?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(addr)))
+            }
+            Some(Token::Symbol("(")) => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(token) => Err(format!("Unexpected {}", token)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    let register = match name {
+        "i" => Register::I,
+        "pc" => Register::Pc,
+        "sp" => Register::Sp,
+        "dt" | "delay" => Register::Delay,
+        "st" | "sound" => Register::Sound,
+        "cycle" => Register::Cycle,
+        _ => {
+            let n = name.strip_prefix('v')?;
+            if n.len() != 1 {
+                return None;
+            }
+            Register::V(usize::from_str_radix(n, 16).ok()?)
+        }
+    };
+    Some(register)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Run `program` for `steps` instructions and evaluate `text` after the last.
+    fn check(program: &[u8], steps: usize, text: &str) -> bool {
+        let mut chip = Chip8::new();
+        chip.load_binary(program.to_vec());
+        let mut before = chip.registers();
+        for _ in 0..steps {
+            before = chip.registers();
+            chip.step();
+        }
+        let expr = Expr::parse(text).unwrap_or_else(|e| panic!("{}", e));
+        expr.is_true(&Context::new(before, &chip))
+    }
+
+    // 200: LD V3, 11
+    // 202: LD I, 300
+    // 204: LD [I], V3
+    // 206: LD VF, 1
+    const PROGRAM: [u8; 8] = [0x63, 0x0B, 0xA3, 0x00, 0xF3, 0x55, 0x6F, 0x01];
+
+    #[test]
+    fn registers_and_logic() {
+        assert!(check(&PROGRAM, 2, "pc == 0x204 && v3 > 10"));
+        assert!(!check(&PROGRAM, 2, "pc == 0x204 && v3 > 11"));
+        assert!(check(&PROGRAM, 2, "i >= $300 || vf"));
+        assert!(check(&PROGRAM, 2, "!(v3 == 10) && (v3 & 0xF) - 1 == 10"));
+        assert!(check(
+            &PROGRAM,
+            1,
+            "cycle == 1 && sp == 0 && dt == 0 && sound == 0"
+        ));
+        assert!(check(&PROGRAM, 1, "V3 == 0X0b"));
+    }
+
+    #[test]
+    fn memory_watches() {
+        assert!(check(&PROGRAM, 3, "write [0x300..0x310]"));
+        assert!(check(&PROGRAM, 3, "write [0x303] && access [i..i+4]"));
+        assert!(!check(&PROGRAM, 3, "read [0x300..0x310]"));
+        assert!(!check(&PROGRAM, 3, "write [0x310..0x320]"));
+        assert!(!check(&PROGRAM, 2, "write [0x300..0x310]"));
+        assert!(check(&PROGRAM, 3, "[0x303] == 11 && [0x303] changes"));
+        assert!(!check(&PROGRAM, 3, "[0x304] changes"));
+    }
+
+    #[test]
+    fn changes() {
+        assert!(!check(&PROGRAM, 3, "vf changes"));
+        assert!(check(&PROGRAM, 4, "vf changes"));
+        assert!(check(&PROGRAM, 1, "v3 changes && !(i changes)"));
+        assert!(check(&PROGRAM, 4, "cycle changes"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Expr::parse("pc ==").is_err());
+        assert!(Expr::parse("vg == 1").is_err());
+        assert!(Expr::parse("write 0x300").is_err());
+        assert!(Expr::parse("(v1").is_err());
+        assert!(Expr::parse("v1 v2").is_err());
+        assert!(Expr::parse("v1 # 2").is_err());
+        assert!(Expr::parse("0xzz").is_err());
+    }
+
+    #[test]
+    fn labels() {
+        let symbols = Symbols::parse("204 Store\n300 table", Path::new("")).unwrap();
+        let parse = |text| Expr::parse_with_symbols(text, &symbols).unwrap();
+        assert_eq!(parse("pc == Store"), Expr::parse("pc == 0x204").unwrap());
+        assert_eq!(
+            parse("write [table..table + 0x10]"),
+            Expr::parse("write [0x300..0x300 + 0x10]").unwrap()
+        );
+        assert!(Expr::parse_with_symbols("pc == store", &symbols).is_err());
+        assert_eq!(
+            Expr::parse("pc == Store"),
+            Err("Unknown register or label `Store`".to_string())
+        );
+    }
+}
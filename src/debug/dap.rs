@@ -0,0 +1,1415 @@
+//! Debug Adapter Protocol server, for debugging ROMs from an editor.
+//!
+//! Speaks DAP over a pair of streams (stdin and stdout for the `chip8-dap`
+//! binary). The `launch` request takes:
+//!
+//! - `program`: path to the ROM
+//! - `patches`: optional IPS or BPS patches to apply to it, in order
+//! - `sourceMap`: optional source map from an assembler, see `SourceMap`
+//! - `symbols`: optional symbol file naming addresses, see `Symbols`. Its
+//!   source lines are used if there's no `sourceMap`
+//! - `stopOnEntry`: stop before the first instruction
+//! - `headless`: run without a window
+//! - `speed`: instructions per frame
+//!
+//! Known ROMs get their quirks and speed from the built-in database, as on
+//! the command line.
+//!
+//! There's a single thread. Breakpoints can be set on source lines when there
+//! is a source map, or on addresses with instruction breakpoints. They can
+//! have conditions and log messages in the watchpoint expression language,
+//! which `evaluate` also takes. Memory references are hex addresses like
+//! `0x2a4`, and instruction breakpoints can also be set on labels.
+//!
+//! Recent instructions can be undone with `stepBack` and `reverseContinue`,
+//! and evaluating `last-write ADDR` finds the instruction that last wrote a
+//! byte, where ADDR can be any expression.
+//!
+//! An instruction the emulator can't run stops with an exception, leaving
+//! the PC on it. Turning off the `illegal` exception breakpoint ends the
+//! session instead.
+
+use super::{Breakpoints, SourceMap, StopReason, Watchpoint, Watchpoints, HISTORY_LIMIT};
+use crate::chip8::{Chip8, MAX_ROM_SIZE, MEMORY_SIZE};
+use crate::compat;
+use crate::disassembler::mnemonic_with_symbols;
+use crate::frontend::{DisplaySettings, Frontend, Headless, Window};
+use crate::patch::Patch;
+use crate::recorder::VideoRecorder;
+use crate::runner::Runner;
+use crate::settings::Settings;
+use crate::symbols::Symbols;
+use serde_json::{json, Value};
+use sha1_smol::Sha1;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+const THREAD_ID: u64 = 1;
+
+/// Variable references for the scopes.
+const REGISTERS: u64 = 1;
+const TIMERS: u64 = 2;
+const STACK: u64 = 3;
+
+/// Exception breakpoint filter for instructions the emulator can't run.
+const ILLEGAL_FILTER: &str = "illegal";
+
+/// Serve a single debug session.
+pub fn serve(input: impl Read + Send + 'static, output: impl Write) -> io::Result<()> {
+    let (sender, messages) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut adapter = Adapter::new(output, messages);
+    while let Ok(request) = adapter.messages.recv() {
+        match command(&request) {
+            "initialize" => adapter.respond(&request, Ok(capabilities()))?,
+            "launch" => {
+                let loaded = LaunchConfig::parse(&request["arguments"])
+                    .and_then(|config| Ok((config.load()?, config)));
+                let (binary, config) = match loaded {
+                    Ok(loaded) => loaded,
+                    Err(e) => {
+                        adapter.respond(&request, Err(e))?;
+                        continue;
+                    }
+                };
+                adapter.respond(&request, Ok(Value::Null))?;
+                adapter.event("initialized", Value::Null)?;
+                return config.launch(&mut adapter, binary);
+            }
+            "disconnect" => return adapter.respond(&request, Ok(Value::Null)),
+            _ => adapter.respond(&request, Err("Not launched yet".to_string()))?,
+        }
+    }
+    Ok(())
+}
+
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsSetVariable": true,
+        "supportsReadMemoryRequest": true,
+        "supportsWriteMemoryRequest": true,
+        "supportsDisassembleRequest": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsConditionalBreakpoints": true,
+        "supportsHitConditionalBreakpoints": true,
+        "supportsLogPoints": true,
+        "supportsTerminateRequest": true,
+        "supportsStepBack": true,
+        "supportsExceptionInfoRequest": true,
+        "exceptionBreakpointFilters": [{
+            "filter": ILLEGAL_FILTER,
+            "label": "Instructions the emulator can't run",
+            "default": true,
+        }],
+    })
+}
+
+fn command(message: &Value) -> &str {
+    message["command"].as_str().unwrap_or("")
+}
+
+struct LaunchConfig {
+    program: PathBuf,
+    patches: Vec<Patch>,
+    source_map: SourceMap,
+    symbols: Symbols,
+    stop_on_entry: bool,
+    headless: bool,
+    speed: Option<usize>,
+}
+
+impl LaunchConfig {
+    fn parse(args: &Value) -> Result<Self, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("Missing \"program\" to debug")?;
+        let mut patches = Vec::new();
+        for path in args["patches"].as_array().into_iter().flatten() {
+            let path = path.as_str().ok_or("\"patches\" should be file names")?;
+            let patch = Patch::load(Path::new(path))
+                .map_err(|e| format!("Couldn't load patch {}: {}", path, e))?;
+            patches.push(patch);
+        }
+        let symbols = match args["symbols"].as_str() {
+            Some(path) => Symbols::load(Path::new(path))
+                .map_err(|e| format!("Couldn't load symbols {}: {}", path, e))?,
+            None => Symbols::new(),
+        };
+        let source_map = match args["sourceMap"].as_str() {
+            Some(path) => SourceMap::load(Path::new(path))
+                .map_err(|e| format!("Couldn't load source map {}: {}", path, e))?,
+            None => symbols.source_map.clone(),
+        };
+        Ok(LaunchConfig {
+            program: PathBuf::from(program),
+            patches,
+            source_map,
+            symbols,
+            stop_on_entry: args["stopOnEntry"].as_bool().unwrap_or(false),
+            headless: args["headless"].as_bool().unwrap_or(false),
+            speed: args["speed"].as_u64().map(|speed| speed as usize),
+        })
+    }
+
+    /// Read the ROM and apply the patches.
+    fn load(&self) -> Result<Vec<u8>, String> {
+        let mut binary = fs::read(&self.program)
+            .map_err(|e| format!("Couldn't load {}: {}", self.program.display(), e))?;
+        for patch in &self.patches {
+            binary = patch.apply(&binary)?;
+        }
+        if binary.len() > MAX_ROM_SIZE {
+            return Err(format!(
+                "{} is {} bytes, but at most {} fit in memory",
+                self.program.display(),
+                binary.len(),
+                MAX_ROM_SIZE
+            ));
+        }
+        Ok(binary)
+    }
+
+    fn launch<W: Write>(mut self, adapter: &mut Adapter<W>, binary: Vec<u8>) -> io::Result<()> {
+        let rom_hash = Sha1::from(&binary).digest().to_string();
+        let mut chip = Chip8::new();
+        chip.load_binary(binary);
+        if let Some(info) = compat::lookup(&rom_hash) {
+            chip.quirks = info.quirks;
+            self.speed = self.speed.or(info.speed);
+        }
+
+        if self.headless {
+            self.start(adapter, Runner::new(chip, Headless::new(None)))
+        } else {
+            let settings = Settings::load();
+            let display = settings
+                .rom(&rom_hash)
+                .map(DisplaySettings::from_section)
+                .unwrap_or_default();
+            self.start(adapter, Runner::new(chip, Window::new(&display)))
+        }
+    }
+
+    fn start<W: Write, F: Frontend>(
+        self,
+        adapter: &mut Adapter<W>,
+        mut runner: Runner<F>,
+    ) -> io::Result<()> {
+        if let Some(speed) = self.speed {
+            runner.speed = speed;
+        }
+        runner.symbols = self.symbols;
+        // For the recording hotkey
+        runner.hooks.add(VideoRecorder::default());
+        let mut session = Session::new(runner, self.source_map);
+        session.stop_on_entry = self.stop_on_entry;
+        session.run(adapter)
+    }
+}
+
+/// Message framing and sequence numbers.
+struct Adapter<W: Write> {
+    output: W,
+    seq: u64,
+    messages: Receiver<Value>,
+    /// Requests that arrived while the emulator was running.
+    pending: VecDeque<Value>,
+    disconnected: bool,
+}
+
+impl<W: Write> Adapter<W> {
+    fn new(output: W, messages: Receiver<Value>) -> Self {
+        Adapter {
+            output,
+            seq: 0,
+            messages,
+            pending: VecDeque::new(),
+            disconnected: false,
+        }
+    }
+
+    /// Queue any requests that have arrived. Returns true if there are some
+    /// to handle, or the client has gone.
+    fn poll(&mut self) -> bool {
+        loop {
+            match self.messages.try_recv() {
+                Ok(message) => self.pending.push_back(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    break;
+                }
+            }
+        }
+        !self.pending.is_empty() || self.disconnected
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(Value::Null) => (),
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(message)
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        let body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        self.event("stopped", body)
+    }
+
+    fn exception(&mut self, description: &str) -> io::Result<()> {
+        let body = json!({
+            "reason": "exception",
+            "description": description,
+            "text": description,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        self.event("stopped", body)
+    }
+}
+
+/// How the emulator should run until it next stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Run {
+    Continue,
+    /// Run backwards until a breakpoint or the start of the history.
+    ReverseContinue,
+    /// Run until the stack pointer is at most this, to step over or out of
+    /// a subroutine.
+    Return(u8),
+}
+
+struct Session<F: Frontend> {
+    runner: Runner<F>,
+    source_map: SourceMap,
+    source_breakpoints: BTreeMap<PathBuf, Vec<u16>>,
+    instruction_breakpoints: Vec<u16>,
+    /// All of the above.
+    breakpoints: Breakpoints,
+    /// Ids of the runner's watchpoints for breakpoints with conditions, hit
+    /// counts or log messages.
+    source_watchpoints: BTreeMap<PathBuf, Vec<usize>>,
+    instruction_watchpoints: Vec<usize>,
+    stop_on_entry: bool,
+    /// Stop when an instruction can't run, rather than ending the session.
+    break_on_crash: bool,
+    /// What stopped the emulator, if it was an instruction that can't run.
+    crash: Option<String>,
+    /// `None` while stopped.
+    running: Option<Run>,
+    finished: bool,
+}
+
+impl<F: Frontend> Session<F> {
+    fn new(mut runner: Runner<F>, source_map: SourceMap) -> Self {
+        runner.chip.enable_history(HISTORY_LIMIT);
+        Session {
+            runner,
+            source_map,
+            source_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: Vec::new(),
+            breakpoints: Breakpoints::new(),
+            source_watchpoints: BTreeMap::new(),
+            instruction_watchpoints: Vec::new(),
+            stop_on_entry: false,
+            break_on_crash: true,
+            crash: None,
+            running: None,
+            finished: false,
+        }
+    }
+
+    fn run<W: Write>(&mut self, adapter: &mut Adapter<W>) -> io::Result<()> {
+        while !self.finished {
+            if self.running.is_some() {
+                self.advance(adapter)?;
+                while let Some(request) = adapter.pending.pop_front() {
+                    self.handle(adapter, &request)?;
+                }
+                if adapter.disconnected {
+                    break;
+                }
+                continue;
+            }
+
+            // Keep the window alive while stopped
+            match adapter.messages.recv_timeout(Duration::from_millis(15)) {
+                Ok(request) => self.handle(adapter, &request)?,
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.runner.frontend.should_quit() {
+                        self.exit(adapter)?;
+                    } else {
+                        self.runner.idle();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.runner.finish();
+        Ok(())
+    }
+
+    /// Run until the emulator stops or a request arrives.
+    fn advance<W: Write>(&mut self, adapter: &mut Adapter<W>) -> io::Result<()> {
+        let run = match self.running {
+            Some(run) => run,
+            None => return Ok(()),
+        };
+        let interrupted = || adapter.poll();
+        let reason = match run {
+            Run::Continue => super::resume(&mut self.runner, &self.breakpoints, interrupted),
+            Run::ReverseContinue => {
+                super::reverse_resume(&mut self.runner, &self.breakpoints, interrupted)
+            }
+            Run::Return(sp) => {
+                super::run_until(&mut self.runner, &self.breakpoints, interrupted, |chip| {
+                    chip.registers().sp <= sp
+                })
+            }
+        };
+
+        self.send_log(adapter)?;
+        match reason {
+            StopReason::Interrupted => Ok(()),
+            StopReason::Quit => self.exit(adapter),
+            StopReason::Breakpoint(_) | StopReason::Watchpoint(_) => {
+                self.stop(adapter, "breakpoint")
+            }
+            StopReason::Step => self.stop(adapter, "step"),
+            StopReason::HistoryStart => self.history_start(adapter),
+            StopReason::Crashed { pc, message } => self.crashed(adapter, pc, message),
+        }
+    }
+
+    /// Stop on an instruction that can't run, or end the session if the
+    /// exception breakpoint is off.
+    fn crashed<W: Write>(
+        &mut self,
+        adapter: &mut Adapter<W>,
+        pc: u16,
+        message: String,
+    ) -> io::Result<()> {
+        let description = format!("{}: {}", self.runner.symbols.describe(pc), message);
+        if !self.break_on_crash {
+            let output = format!("Stopped at {}\n", description);
+            adapter.event("output", json!({ "category": "stderr", "output": output }))?;
+            self.running = None;
+            self.finished = true;
+            adapter.event("terminated", Value::Null)?;
+            return adapter.event("exited", json!({ "exitCode": 1 }));
+        }
+        self.running = None;
+        adapter.exception(&description)?;
+        self.crash = Some(description);
+        Ok(())
+    }
+
+    fn history_start<W: Write>(&mut self, adapter: &mut Adapter<W>) -> io::Result<()> {
+        let output = "Reached the start of the history\n";
+        adapter.event("output", json!({ "category": "console", "output": output }))?;
+        self.stop(adapter, "step")
+    }
+
+    /// Send lines logged by logpoints as output.
+    fn send_log<W: Write>(&mut self, adapter: &mut Adapter<W>) -> io::Result<()> {
+        for line in self.runner.hooks.get_or_default::<Watchpoints>().take_log() {
+            let output = format!("{}\n", line);
+            adapter.event("output", json!({ "category": "console", "output": output }))?;
+        }
+        Ok(())
+    }
+
+    fn stop<W: Write>(&mut self, adapter: &mut Adapter<W>, reason: &str) -> io::Result<()> {
+        self.running = None;
+        self.crash = None;
+        adapter.stopped(reason)
+    }
+
+    fn exit<W: Write>(&mut self, adapter: &mut Adapter<W>) -> io::Result<()> {
+        self.running = None;
+        self.finished = true;
+        adapter.event("terminated", Value::Null)?;
+        adapter.event("exited", json!({ "exitCode": 0 }))
+    }
+
+    fn handle<W: Write>(&mut self, adapter: &mut Adapter<W>, request: &Value) -> io::Result<()> {
+        let args = &request["arguments"];
+        let body = match command(request) {
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(self.set_exception_breakpoints(args)),
+            "exceptionInfo" => match &self.crash {
+                Some(description) => Ok(json!({
+                    "exceptionId": ILLEGAL_FILTER,
+                    "description": description,
+                    "breakMode": "always",
+                })),
+                None => Err("Not stopped on an exception".to_string()),
+            },
+            "configurationDone" => {
+                adapter.respond(request, Ok(Value::Null))?;
+                if self.stop_on_entry {
+                    return adapter.stopped("entry");
+                }
+                self.running = Some(Run::Continue);
+                return Ok(());
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+            ]})),
+            "variables" => Ok(self.variables(args["variablesReference"].as_u64())),
+            "setVariable" => self.set_variable(args),
+            "readMemory" => self.read_memory(args),
+            "writeMemory" => self.write_memory(args),
+            "disassemble" => self.disassemble(args),
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or("");
+                self.evaluate(expression)
+                    .map(|result| json!({ "result": result, "variablesReference": 0 }))
+            }
+            "continue" => {
+                self.running = Some(Run::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "reverseContinue" => {
+                self.running = Some(Run::ReverseContinue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "stepBack" => {
+                adapter.respond(request, Ok(Value::Null))?;
+                return match super::step_back(&mut self.runner) {
+                    StopReason::HistoryStart => self.history_start(adapter),
+                    _ => self.stop(adapter, "step"),
+                };
+            }
+            "next" | "stepIn" | "stepOut" => {
+                adapter.respond(request, Ok(Value::Null))?;
+                return self.step(adapter, command(request));
+            }
+            "pause" => {
+                adapter.respond(request, Ok(Value::Null))?;
+                if self.running.is_some() {
+                    return self.stop(adapter, "pause");
+                }
+                return Ok(());
+            }
+            "terminate" => {
+                adapter.respond(request, Ok(Value::Null))?;
+                return self.exit(adapter);
+            }
+            "disconnect" => {
+                self.finished = true;
+                Ok(Value::Null)
+            }
+            command => Err(format!("Unsupported request: {}", command)),
+        };
+        adapter.respond(request, body)
+    }
+
+    fn step<W: Write>(&mut self, adapter: &mut Adapter<W>, command: &str) -> io::Result<()> {
+        let registers = self.runner.chip.registers();
+        let opcode = read_opcode(&self.runner.chip, registers.pc).unwrap_or(0);
+        let is_call = opcode & 0xF000 == 0x2000;
+
+        match command {
+            "next" if is_call => self.running = Some(Run::Return(registers.sp)),
+            "stepOut" if registers.sp > 0 => self.running = Some(Run::Return(registers.sp - 1)),
+            _ => {
+                let reason = super::step(&mut self.runner);
+                self.send_log(adapter)?;
+                return match reason {
+                    StopReason::Quit => self.exit(adapter),
+                    StopReason::Watchpoint(_) => self.stop(adapter, "breakpoint"),
+                    StopReason::Crashed { pc, message } => self.crashed(adapter, pc, message),
+                    _ => self.stop(adapter, "step"),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate an expression, or `last-write` followed by one.
+    fn evaluate(&self, expression: &str) -> Result<String, String> {
+        let (chip, symbols) = (&self.runner.chip, &self.runner.symbols);
+        if let Some(addr) = expression.trim().strip_prefix("last-write") {
+            let addr = Watchpoint::evaluate(addr, chip, symbols)?;
+            return match u16::try_from(addr) {
+                Ok(addr) if (addr as usize) < MEMORY_SIZE => {
+                    Ok(super::describe_last_write(chip, addr))
+                }
+                _ => Err(format!("Address {:#x} is outside memory", addr)),
+            };
+        }
+        Watchpoint::evaluate(expression, chip, symbols)
+            .map(|value| format!("{} ({:#x})", value, value))
+    }
+
+    fn update_breakpoints(&mut self) {
+        self.breakpoints = Breakpoints::new();
+        let source = self.source_breakpoints.values().flatten();
+        for &addr in source.chain(&self.instruction_breakpoints) {
+            self.breakpoints.insert(addr);
+        }
+    }
+
+    /// Remove the runner's watchpoints with these ids.
+    fn remove_watchpoints(&mut self, ids: &[usize]) {
+        for &id in ids {
+            self.runner.hooks.get_or_default::<Watchpoints>().remove(id);
+        }
+    }
+
+    /// Add a breakpoint at `addr` to `addresses`, or to the runner's
+    /// watchpoints if it has a condition, hit count or log message.
+    fn add_breakpoint(
+        &mut self,
+        addr: u16,
+        breakpoint: &Value,
+        addresses: &mut Vec<u16>,
+        watchpoints: &mut Vec<usize>,
+    ) -> Result<(), String> {
+        let condition = breakpoint["condition"]
+            .as_str()
+            .filter(|c| !c.trim().is_empty());
+        let hit_condition = breakpoint["hitCondition"]
+            .as_str()
+            .filter(|c| !c.trim().is_empty());
+        let message = breakpoint["logMessage"].as_str();
+        if condition.is_none() && hit_condition.is_none() && message.is_none() {
+            addresses.push(addr);
+            return Ok(());
+        }
+
+        let condition = match condition {
+            Some(condition) => format!("pc == {:#x} && ({})", addr, condition),
+            None => format!("pc == {:#x}", addr),
+        };
+        let mut watchpoint = match message {
+            Some(message) => Watchpoint::log(&condition, Some(message), &self.runner.symbols)?,
+            None => Watchpoint::new(&condition, &self.runner.symbols)?,
+        };
+        if let Some(hit_condition) = hit_condition {
+            watchpoint = watchpoint.hit_condition(hit_condition)?;
+        }
+        watchpoints.push(
+            self.runner
+                .hooks
+                .get_or_default::<Watchpoints>()
+                .insert(watchpoint),
+        );
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let path = PathBuf::from(args["source"]["path"].as_str().unwrap_or(""));
+        let old = self.source_watchpoints.remove(&path).unwrap_or_default();
+        self.remove_watchpoints(&old);
+        let mut addresses = Vec::new();
+        let mut watchpoints = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            match self.source_map.address(&path, line) {
+                Some((addr, line)) => {
+                    let added =
+                        self.add_breakpoint(addr, breakpoint, &mut addresses, &mut watchpoints);
+                    if let Err(e) = added {
+                        results.push(json!({ "verified": false, "line": line, "message": e }));
+                        continue;
+                    }
+                    results.push(json!({
+                        "verified": true,
+                        "line": line,
+                        "instructionReference": memory_reference(addr),
+                    }));
+                }
+                None => results.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "No code at this line",
+                })),
+            }
+        }
+        self.source_breakpoints.insert(path.clone(), addresses);
+        self.source_watchpoints.insert(path, watchpoints);
+        self.update_breakpoints();
+        json!({ "breakpoints": results })
+    }
+
+    /// Only `illegal` is known. Other filters are reported as unverified.
+    fn set_exception_breakpoints(&mut self, args: &Value) -> Value {
+        let filters: Vec<&str> = args["filters"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect();
+        self.break_on_crash = filters.contains(&ILLEGAL_FILTER);
+        let breakpoints: Vec<Value> = filters
+            .iter()
+            .map(|&filter| json!({ "verified": filter == ILLEGAL_FILTER }))
+            .collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Value {
+        let old = std::mem::take(&mut self.instruction_watchpoints);
+        self.remove_watchpoints(&old);
+        let mut addresses = Vec::new();
+        let mut watchpoints = Vec::new();
+        let mut results = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().into_iter().flatten() {
+            let addr = breakpoint["instructionReference"]
+                .as_str()
+                .and_then(|reference| {
+                    let label = self.runner.symbols.address(reference);
+                    label
+                        .map(usize::from)
+                        .or_else(|| parse_memory_reference(reference))
+                })
+                .map(|addr| addr as i64 + breakpoint["offset"].as_i64().unwrap_or(0))
+                .filter(|&addr| (0..MEMORY_SIZE as i64).contains(&addr));
+            match addr {
+                Some(addr) => {
+                    let added = self.add_breakpoint(
+                        addr as u16,
+                        breakpoint,
+                        &mut addresses,
+                        &mut watchpoints,
+                    );
+                    match added {
+                        Ok(()) => results.push(json!({ "verified": true })),
+                        Err(e) => results.push(json!({ "verified": false, "message": e })),
+                    }
+                }
+                None => results.push(json!({
+                    "verified": false,
+                    "message": "Address is outside memory",
+                })),
+            }
+        }
+        self.instruction_breakpoints = addresses;
+        self.instruction_watchpoints = watchpoints;
+        self.update_breakpoints();
+        json!({ "breakpoints": results })
+    }
+
+    fn stack_trace(&self) -> Value {
+        let registers = self.runner.chip.registers();
+        // The stack holds return addresses, so the calls are just before them
+        let calls = registers.stack[..registers.sp as usize]
+            .iter()
+            .rev()
+            .map(|addr| addr.wrapping_sub(2));
+        let frames: Vec<Value> = Some(registers.pc)
+            .into_iter()
+            .chain(calls)
+            .enumerate()
+            .map(|(id, addr)| {
+                let name = self.runner.symbols.locate(addr);
+                let mut frame = json!({
+                    "id": id,
+                    "name": name.unwrap_or_else(|| memory_reference(addr)),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": memory_reference(addr),
+                });
+                if let Some(location) = self.source_map.location(addr) {
+                    frame["line"] = json!(location.line);
+                    frame["source"] = source(&location.file);
+                }
+                frame
+            })
+            .collect();
+        json!({ "stackFrames": frames, "totalFrames": frames.len() })
+    }
+
+    fn variables(&self, reference: Option<u64>) -> Value {
+        let registers = self.runner.chip.registers();
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let address = |name: &str, addr: u16| {
+            json!({
+                "name": name,
+                "value": memory_reference(addr),
+                "variablesReference": 0,
+                "memoryReference": memory_reference(addr),
+            })
+        };
+
+        let mut variables = Vec::new();
+        match reference {
+            Some(REGISTERS) => {
+                for (n, v) in registers.v.iter().enumerate() {
+                    variables.push(variable(format!("V{:X}", n), format!("0x{:02x}", v)));
+                }
+                variables.push(address("I", registers.i));
+                variables.push(address("PC", registers.pc));
+                variables.push(variable("SP".to_string(), registers.sp.to_string()));
+            }
+            Some(TIMERS) => {
+                variables.push(variable("delay".to_string(), registers.delay.to_string()));
+                variables.push(variable("sound".to_string(), registers.sound.to_string()));
+            }
+            Some(STACK) => {
+                for (n, &addr) in registers.stack[..registers.sp as usize].iter().enumerate() {
+                    variables.push(address(&n.to_string(), addr));
+                }
+            }
+            _ => (),
+        }
+        json!({ "variables": variables })
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().unwrap_or("");
+        let text = args["value"].as_str().unwrap_or("");
+        let value = parse_number(text).ok_or_else(|| format!("Not a number: {}", text))?;
+        let byte = || u8::try_from(value).map_err(|_| format!("{} doesn't fit in a byte", text));
+        let word = || u16::try_from(value).map_err(|_| format!("{} is too big", text));
+
+        let mut registers = self.runner.chip.registers();
+        match (args["variablesReference"].as_u64(), name) {
+            (Some(REGISTERS), "I") => registers.i = word()?,
+            (Some(REGISTERS), "PC") => registers.pc = word()?,
+            (Some(REGISTERS), "SP") => registers.sp = byte()?,
+            (Some(REGISTERS), _) => {
+                let n = name
+                    .strip_prefix('V')
+                    .and_then(|n| usize::from_str_radix(n, 16).ok())
+                    .filter(|&n| n < 16)
+                    .ok_or_else(|| format!("Can't set {}", name))?;
+                registers.v[n] = byte()?;
+            }
+            (Some(TIMERS), "delay") => registers.delay = byte()?,
+            (Some(TIMERS), "sound") => registers.sound = byte()?,
+            _ => return Err(format!("Can't set {}", name)),
+        }
+        registers.check()?;
+        self.runner.chip.set_registers(&registers);
+
+        let value = match name {
+            "I" | "PC" => memory_reference(value as u16),
+            "SP" | "delay" | "sound" => value.to_string(),
+            _ => format!("0x{:02x}", value),
+        };
+        Ok(json!({ "value": value }))
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let start = memory_address(args)?;
+        let count = args["count"].as_u64().unwrap_or(0) as usize;
+        let memory = self.runner.chip.memory();
+        let end = start.saturating_add(count).min(MEMORY_SIZE);
+        let data = memory.get(start..end).unwrap_or(&[]);
+        Ok(json!({
+            "address": memory_reference(start as u16),
+            "data": base64_encode(data),
+            "unreadableBytes": count - data.len(),
+        }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let start = memory_address(args)?;
+        let data = args["data"]
+            .as_str()
+            .and_then(base64_decode)
+            .ok_or("Invalid data")?;
+        let memory = self.runner.chip.memory_mut();
+        let target = start
+            .checked_add(data.len())
+            .and_then(|end| memory.get_mut(start..end))
+            .ok_or("Write goes past the end of memory")?;
+        target.copy_from_slice(&data);
+        Ok(json!({ "bytesWritten": data.len() }))
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let start = memory_address(args)? as i64;
+        let first = start + 2 * args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().unwrap_or(0) as i64;
+
+        let instructions: Vec<Value> = (0..count)
+            .map(|n| first + 2 * n)
+            .map(|addr| {
+                let opcode = u16::try_from(addr)
+                    .ok()
+                    .and_then(|addr| read_opcode(&self.runner.chip, addr).map(|op| (addr, op)));
+                let (addr, opcode) = match opcode {
+                    Some(found) => found,
+                    None => {
+                        return json!({
+                            "address": format!("{:#x}", addr),
+                            "instruction": "",
+                            "presentationHint": "invalid",
+                        })
+                    }
+                };
+                let mut instruction = json!({
+                    "address": memory_reference(addr),
+                    "instructionBytes": format!("{:02x} {:02x}", opcode >> 8, opcode & 0xFF),
+                    "instruction": mnemonic_with_symbols(opcode, &self.runner.symbols),
+                });
+                if let Some(label) = self.runner.symbols.label(addr) {
+                    instruction["symbol"] = json!(label);
+                }
+                if let Some(location) = self.source_map.location(addr) {
+                    instruction["location"] = source(&location.file);
+                    instruction["line"] = json!(location.line);
+                }
+                instruction
+            })
+            .collect();
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+fn read_opcode(chip: &Chip8, addr: u16) -> Option<u16> {
+    let addr = addr as usize;
+    let bytes = chip.memory().get(addr..addr + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn source(path: &Path) -> Value {
+    let name = path.file_name().map(|name| name.to_string_lossy());
+    json!({ "name": name, "path": path })
+}
+
+fn memory_reference(addr: u16) -> String {
+    format!("0x{:03x}", addr)
+}
+
+fn parse_memory_reference(text: &str) -> Option<usize> {
+    usize::from_str_radix(text.strip_prefix("0x").unwrap_or(text), 16).ok()
+}
+
+/// `memoryReference` plus `offset`, checked to be in memory.
+fn memory_address(args: &Value) -> Result<usize, String> {
+    let reference = args["memoryReference"].as_str().unwrap_or("");
+    let base = parse_memory_reference(reference)
+        .ok_or_else(|| format!("Invalid memory reference: {}", reference))?;
+    let addr = base as i64 + args["offset"].as_i64().unwrap_or(0);
+    if !(0..MEMORY_SIZE as i64).contains(&addr) {
+        return Err(format!("Address {:#x} is outside memory", addr));
+    }
+    Ok(addr as usize)
+}
+
+/// Decimal, or hex with `0x`.
+fn parse_number(text: &str) -> Option<u64> {
+    match text.trim().strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.trim().parse().ok(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
+
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::other("Missing Content-Length header"))?;
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    let message = serde_json::from_slice(&body).map_err(io::Error::other)?;
+    Ok(Some(message))
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Sender;
+
+    fn session(program: &[u8]) -> Session<Headless> {
+        let mut chip = Chip8::new();
+        chip.load_binary(program.to_vec());
+        let mut runner = Runner::new(chip, Headless::new(None));
+        runner.throttle = false;
+        Session::new(runner, SourceMap::default())
+    }
+
+    fn adapter() -> (Adapter<Vec<u8>>, Sender<Value>) {
+        let (sender, messages) = mpsc::channel();
+        (Adapter::new(Vec::new(), messages), sender)
+    }
+
+    /// Messages sent since the last call.
+    fn sent(adapter: &mut Adapter<Vec<u8>>) -> Vec<Value> {
+        let output = std::mem::take(&mut adapter.output);
+        let mut input = &output[..];
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut input).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn request(
+        session: &mut Session<Headless>,
+        adapter: &mut Adapter<Vec<u8>>,
+        command: &str,
+        arguments: Value,
+    ) -> Vec<Value> {
+        let request =
+            json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments });
+        session.handle(adapter, &request).unwrap();
+        sent(adapter)
+    }
+
+    #[test]
+    fn message_framing() {
+        let message = json!({ "seq": 1, "command": "threads" });
+        let mut output = Vec::new();
+        write_message(&mut output, &message).unwrap();
+        assert!(output.starts_with(b"Content-Length: 29\r\n\r\n{"));
+        assert_eq!(read_message(&mut &output[..]).unwrap(), Some(message));
+        assert_eq!(read_message(&mut &b""[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn base64() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x80"] {
+            assert_eq!(base64_decode(&base64_encode(data)), Some(data.to_vec()));
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+        assert_eq!(base64_decode("!!"), None);
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        // 200: CALL 206
+        // 202: JP 202
+        // 204: (padding)
+        // 206: LD V1, 7
+        // 208: RET
+        let mut session = session(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x61, 0x07, 0x00, 0xEE]);
+        let (mut adapter, _sender) = adapter();
+
+        let args = json!({ "breakpoints": [{ "instructionReference": "0x206" }, { "instructionReference": "0x1000" }] });
+        let replies = request(
+            &mut session,
+            &mut adapter,
+            "setInstructionBreakpoints",
+            args,
+        );
+        let results = &replies[0]["body"]["breakpoints"];
+        assert_eq!(results[0]["verified"], true);
+        assert_eq!(results[1]["verified"], false);
+
+        request(&mut session, &mut adapter, "configurationDone", json!({}));
+        session.advance(&mut adapter).unwrap();
+        let stopped = sent(&mut adapter);
+        assert_eq!(stopped[0]["event"], "stopped");
+        assert_eq!(stopped[0]["body"]["reason"], "breakpoint");
+        assert_eq!(session.runner.chip.pc(), 0x206);
+
+        let trace = &request(&mut session, &mut adapter, "stackTrace", json!({}))[0]["body"];
+        assert_eq!(
+            trace["stackFrames"][0]["instructionPointerReference"],
+            "0x206"
+        );
+        assert_eq!(
+            trace["stackFrames"][1]["instructionPointerReference"],
+            "0x200"
+        );
+
+        let replies = request(&mut session, &mut adapter, "stepOut", json!({}));
+        assert_eq!(replies.len(), 1);
+        session.advance(&mut adapter).unwrap();
+        assert_eq!(sent(&mut adapter)[0]["body"]["reason"], "step");
+        assert_eq!(session.runner.chip.pc(), 0x202);
+        assert_eq!(session.runner.chip.registers().v[1], 7);
+
+        // Stepping over the call stops after it returns
+        let mut registers = session.runner.chip.registers();
+        registers.pc = 0x200;
+        session.runner.chip.set_registers(&registers);
+        request(
+            &mut session,
+            &mut adapter,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [] }),
+        );
+        request(&mut session, &mut adapter, "next", json!({}));
+        session.advance(&mut adapter).unwrap();
+        assert_eq!(sent(&mut adapter)[0]["body"]["reason"], "step");
+        assert_eq!(session.runner.chip.pc(), 0x202);
+
+        // Stepping into it stops straight away
+        let replies = request(&mut session, &mut adapter, "stepIn", json!({}));
+        assert_eq!(replies[1]["body"]["reason"], "step");
+        assert_eq!(session.runner.chip.pc(), 0x202);
+    }
+
+    #[test]
+    fn conditions_and_logpoints() {
+        // 200: ADD V3, 1
+        // 202: JP 200
+        let mut session = session(&[0x73, 0x01, 0x12, 0x00]);
+        let (mut adapter, _sender) = adapter();
+        let args = json!({ "breakpoints": [
+            { "instructionReference": "0x202", "logMessage": "v3={v3}", "hitCondition": "% 2" },
+            { "instructionReference": "0x200", "condition": "v3 == 3" },
+            { "instructionReference": "0x200", "condition": "v3 ==" },
+        ]});
+        let replies = request(
+            &mut session,
+            &mut adapter,
+            "setInstructionBreakpoints",
+            args,
+        );
+        let results = &replies[0]["body"]["breakpoints"];
+        assert_eq!(results[0]["verified"], true);
+        assert_eq!(results[1]["verified"], true);
+        assert_eq!(results[2]["verified"], false);
+        assert!(!session.breakpoints.contains(0x200));
+
+        request(&mut session, &mut adapter, "configurationDone", json!({}));
+        session.advance(&mut adapter).unwrap();
+        let events = sent(&mut adapter);
+        assert_eq!(events[0]["event"], "output");
+        assert_eq!(events[0]["body"]["output"], "v3=0x2\n");
+        assert_eq!(events[1]["body"]["reason"], "breakpoint");
+        assert_eq!(session.runner.chip.pc(), 0x200);
+        assert_eq!(session.runner.chip.registers().v[3], 3);
+
+        let args = json!({ "expression": "v3 + 0x10" });
+        let reply = &request(&mut session, &mut adapter, "evaluate", args)[0];
+        assert_eq!(reply["body"]["result"], "19 (0x13)");
+        let args = json!({ "expression": "v3 +" });
+        assert_eq!(
+            request(&mut session, &mut adapter, "evaluate", args)[0]["success"],
+            false
+        );
+
+        // Replacing the breakpoints removes the watchpoints
+        request(
+            &mut session,
+            &mut adapter,
+            "setInstructionBreakpoints",
+            json!({ "breakpoints": [] }),
+        );
+        assert!(session
+            .runner
+            .hooks
+            .get_or_default::<Watchpoints>()
+            .is_empty());
+    }
+
+    #[test]
+    fn reverse_execution() {
+        // 200: ADD V3, 1
+        // 202: LD I, 300
+        // 204: LD [I], V3
+        // 206: JP 200
+        let mut session = session(&[0x73, 0x01, 0xA3, 0x00, 0xF3, 0x55, 0x12, 0x00]);
+        let (mut adapter, _sender) = adapter();
+        assert_eq!(capabilities()["supportsStepBack"], true);
+        let args = json!({ "breakpoints": [{ "instructionReference": "0x204" }] });
+        request(
+            &mut session,
+            &mut adapter,
+            "setInstructionBreakpoints",
+            args,
+        );
+        request(&mut session, &mut adapter, "configurationDone", json!({}));
+        session.advance(&mut adapter).unwrap();
+        sent(&mut adapter);
+        request(&mut session, &mut adapter, "continue", json!({}));
+        session.advance(&mut adapter).unwrap();
+        sent(&mut adapter);
+        assert_eq!(session.runner.chip.registers().v[3], 2);
+
+        let args = json!({ "expression": "last-write i" });
+        let reply = &request(&mut session, &mut adapter, "evaluate", args)[0];
+        assert_eq!(
+            reply["body"]["result"],
+            "0x300 was last written at cycle 3 by 0x204: LD [I], V3, was 0x00"
+        );
+
+        // Back to the breakpoint the first time round
+        request(&mut session, &mut adapter, "reverseContinue", json!({}));
+        session.advance(&mut adapter).unwrap();
+        assert_eq!(sent(&mut adapter)[0]["body"]["reason"], "breakpoint");
+        assert_eq!(session.runner.chip.pc(), 0x204);
+        assert_eq!(session.runner.chip.registers().v[3], 1);
+
+        let replies = request(&mut session, &mut adapter, "stepBack", json!({}));
+        assert_eq!(replies[1]["body"]["reason"], "step");
+        assert_eq!(session.runner.chip.pc(), 0x202);
+
+        request(&mut session, &mut adapter, "reverseContinue", json!({}));
+        session.advance(&mut adapter).unwrap();
+        let events = sent(&mut adapter);
+        assert_eq!(
+            events[0]["body"]["output"],
+            "Reached the start of the history\n"
+        );
+        assert_eq!(events[1]["body"]["reason"], "step");
+        assert_eq!(session.runner.chip.pc(), 0x200);
+        assert_eq!(session.runner.chip.registers().v[3], 0);
+    }
+
+    #[test]
+    fn labels() {
+        // 200: CALL 206
+        // 202: JP 202
+        // 204: (padding)
+        // 206: LD V1, 7
+        // 208: RET
+        let mut session = session(&[0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x61, 0x07, 0x00, 0xEE]);
+        session.runner.symbols = Symbols::parse("200 main\n206 setup", Path::new("")).unwrap();
+        let (mut adapter, _sender) = adapter();
+        let args = json!({ "breakpoints": [{ "instructionReference": "setup", "offset": 2 }] });
+        request(
+            &mut session,
+            &mut adapter,
+            "setInstructionBreakpoints",
+            args,
+        );
+        assert!(session.breakpoints.contains(0x208));
+
+        request(&mut session, &mut adapter, "configurationDone", json!({}));
+        session.advance(&mut adapter).unwrap();
+        sent(&mut adapter);
+        let trace = &request(&mut session, &mut adapter, "stackTrace", json!({}))[0]["body"];
+        assert_eq!(trace["stackFrames"][0]["name"], "setup+0x2");
+        assert_eq!(trace["stackFrames"][1]["name"], "main");
+
+        let args = json!({ "memoryReference": "0x200", "instructionCount": 4 });
+        let body = &request(&mut session, &mut adapter, "disassemble", args)[0]["body"];
+        assert_eq!(body["instructions"][0]["instruction"], "CALL       setup");
+        assert_eq!(body["instructions"][0]["symbol"], "main");
+        assert!(body["instructions"][1].get("symbol").is_none());
+    }
+
+    #[test]
+    fn source_breakpoints() {
+        let mut session = session(&[0x12, 0x00]);
+        session.source_map = SourceMap::parse("200 game.8o:4", Path::new("/src")).unwrap();
+        let (mut adapter, _sender) = adapter();
+        let args = json!({
+            "source": { "path": "/src/game.8o" },
+            "breakpoints": [{ "line": 2 }, { "line": 9 }],
+        });
+        let results =
+            &request(&mut session, &mut adapter, "setBreakpoints", args)[0]["body"]["breakpoints"];
+        assert_eq!(results[0]["verified"], true);
+        assert_eq!(results[0]["line"], 4);
+        assert_eq!(results[1]["verified"], false);
+        assert!(session.breakpoints.contains(0x200));
+
+        let trace = &request(&mut session, &mut adapter, "stackTrace", json!({}))[0]["body"];
+        assert_eq!(trace["stackFrames"][0]["line"], 4);
+        assert_eq!(trace["stackFrames"][0]["source"]["name"], "game.8o");
+    }
+
+    #[test]
+    fn variables_and_memory() {
+        let mut session = session(&[0x12, 0x00]);
+        let (mut adapter, _sender) = adapter();
+
+        let args = json!({ "variablesReference": REGISTERS, "name": "VA", "value": "0x2a" });
+        let reply = &request(&mut session, &mut adapter, "setVariable", args)[0];
+        assert_eq!(reply["body"]["value"], "0x2a");
+        let args = json!({ "variablesReference": REGISTERS, "name": "VA", "value": "300" });
+        assert_eq!(
+            request(&mut session, &mut adapter, "setVariable", args)[0]["success"],
+            false
+        );
+        for (name, value) in [("PC", "0x1000"), ("SP", "17")] {
+            let args = json!({ "variablesReference": REGISTERS, "name": name, "value": value });
+            let reply = &request(&mut session, &mut adapter, "setVariable", args)[0];
+            assert_eq!(reply["success"], false);
+        }
+        assert_eq!(session.runner.chip.pc(), 0x200);
+
+        let variables = &request(
+            &mut session,
+            &mut adapter,
+            "variables",
+            json!({ "variablesReference": REGISTERS }),
+        )[0];
+        let variables = variables["body"]["variables"].as_array().unwrap();
+        assert_eq!(variables[10]["name"], "VA");
+        assert_eq!(variables[10]["value"], "0x2a");
+        assert_eq!(variables[17]["memoryReference"], "0x200");
+
+        let args =
+            json!({ "memoryReference": "0x300", "offset": 1, "data": base64_encode(&[1, 2, 3]) });
+        assert_eq!(
+            request(&mut session, &mut adapter, "writeMemory", args)[0]["body"]["bytesWritten"],
+            3
+        );
+        let args = json!({ "memoryReference": "0x300", "count": 4 });
+        let body = &request(&mut session, &mut adapter, "readMemory", args)[0]["body"];
+        assert_eq!(body["data"], base64_encode(&[0, 1, 2, 3]));
+        let args = json!({ "memoryReference": "0xffe", "count": 4 });
+        let body = &request(&mut session, &mut adapter, "readMemory", args)[0]["body"];
+        assert_eq!(body["unreadableBytes"], 2);
+
+        let args =
+            json!({ "memoryReference": "0x200", "instructionOffset": -1, "instructionCount": 2 });
+        let body = &request(&mut session, &mut adapter, "disassemble", args)[0]["body"];
+        assert_eq!(body["instructions"][0]["address"], "0x1fe");
+        assert_eq!(body["instructions"][1]["instruction"], "JP         $200");
+    }
+
+    #[test]
+    fn launch_errors() {
+        let launch = json!({
+            "seq": 1,
+            "type": "request",
+            "command": "launch",
+            "arguments": { "program": "/nonexistent/rom.ch8" },
+        });
+        let mut input = Vec::new();
+        write_message(&mut input, &launch).unwrap();
+        let mut output = Vec::new();
+        serve(io::Cursor::new(input), &mut output).unwrap();
+        let mut output = &output[..];
+        let reply = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(reply["success"], false);
+        assert!(reply["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Couldn't load /nonexistent/rom.ch8"));
+        // Not initialized
+        assert_eq!(read_message(&mut output).unwrap(), None);
+    }
+
+    #[test]
+    fn exceptions() {
+        // 200: LD V0, 1
+        // 202: HIGH, which is SUPER-CHIP
+        let mut session = session(&[0x60, 0x01, 0x00, 0xFF]);
+        let (mut adapter, _sender) = adapter();
+        let args = json!({ "filters": [ILLEGAL_FILTER, "other"] });
+        let reply = &request(&mut session, &mut adapter, "setExceptionBreakpoints", args)[0];
+        assert_eq!(
+            reply["body"]["breakpoints"],
+            json!([{ "verified": true }, { "verified": false }])
+        );
+
+        request(&mut session, &mut adapter, "configurationDone", json!({}));
+        session.advance(&mut adapter).unwrap();
+        let stopped = &sent(&mut adapter)[0];
+        assert_eq!(stopped["body"]["reason"], "exception");
+        assert_eq!(
+            stopped["body"]["description"],
+            "0x202: Unknown opcode: 0xFF"
+        );
+        assert_eq!(session.runner.chip.pc(), 0x202);
+        let info = &request(&mut session, &mut adapter, "exceptionInfo", json!({}))[0];
+        assert_eq!(info["body"]["exceptionId"], ILLEGAL_FILTER);
+
+        // Without the exception breakpoint the session ends
+        let args = json!({ "filters": [] });
+        request(&mut session, &mut adapter, "setExceptionBreakpoints", args);
+        let events = request(&mut session, &mut adapter, "stepIn", json!({}));
+        let events: Vec<&Value> = events.iter().map(|event| &event["event"]).collect();
+        assert_eq!(
+            events[1..],
+            [&json!("output"), &json!("terminated"), &json!("exited")]
+        );
+        assert!(session.finished);
+    }
+}
@@ -0,0 +1,845 @@
+//! GDB remote serial protocol stub.
+//!
+//! Listens for a single GDB connection and lets it inspect and control the
+//! emulator. Registers are numbered v0-vf (0-15), i (16), pc (17), sp (18),
+//! delay (19) and sound (20), and memory is the 4 KiB address space. The
+//! register layout is sent to GDB as a target description.
+//!
+//! Watchpoints set by GDB are checked alongside any from the command line.
+//! An instruction the emulator can't run stops with SIGILL, leaving the PC
+//! on it.
+//!
+//! The last instructions can be undone with `reverse-stepi` and
+//! `reverse-continue`, and `monitor last-write ADDR` finds the instruction
+//! that last wrote a byte of memory.
+
+use super::{Breakpoints, StopReason, Watchpoint, Watchpoints, HISTORY_LIMIT};
+use crate::chip8::{Chip8, Registers, MEMORY_SIZE};
+use crate::frontend::Frontend;
+use crate::runner::Runner;
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Names and sizes in bytes of the registers, in GDB's numbering.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1),
+    ("v1", 1),
+    ("v2", 1),
+    ("v3", 1),
+    ("v4", 1),
+    ("v5", 1),
+    ("v6", 1),
+    ("v7", 1),
+    ("v8", 1),
+    ("v9", 1),
+    ("va", 1),
+    ("vb", 1),
+    ("vc", 1),
+    ("vd", 1),
+    ("ve", 1),
+    ("vf", 1),
+    ("i", 2),
+    ("pc", 2),
+    ("sp", 1),
+    ("delay", 1),
+    ("sound", 1),
+];
+
+/// Wait for GDB to connect on `port`, then debug until it detaches, kills the
+/// program or the frontend is closed. Execution starts halted.
+pub fn serve<F: Frontend>(runner: &mut Runner<F>, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    eprintln!("Waiting for GDB on port {}", port);
+    let (stream, addr) = listener.accept()?;
+    eprintln!("GDB connected from {}", addr);
+
+    stream.set_nodelay(true)?;
+    let mut conn = Connection::new(stream);
+    let mut stub = Stub::new();
+    stub.watchpoints = std::mem::take(runner.hooks.get_or_default::<Watchpoints>());
+    runner.chip.enable_history(HISTORY_LIMIT);
+
+    loop {
+        // Wait for a packet, keeping the window alive meanwhile
+        conn.stream.set_nonblocking(false)?;
+        conn.stream
+            .set_read_timeout(Some(Duration::from_millis(15)))?;
+        let packet = match conn.poll()? {
+            Some(Event::Packet(packet)) => packet,
+            // Already stopped
+            Some(Event::Interrupt) => {
+                conn.send("S02")?;
+                continue;
+            }
+            None => {
+                if runner.frontend.should_quit() {
+                    conn.send("W00")?;
+                    break;
+                }
+                runner.idle();
+                continue;
+            }
+        };
+
+        match stub.handle(&mut runner.chip, &packet) {
+            Action::Reply(reply) => {
+                conn.send(&reply)?;
+                conn.no_ack = stub.no_ack;
+            }
+            Action::Resume(resume) => {
+                conn.stream.set_nonblocking(true)?;
+                let watchpoints = runner.hooks.get_or_default::<Watchpoints>();
+                std::mem::swap(watchpoints, &mut stub.watchpoints);
+                // Stop on Ctrl-C, or if GDB went away
+                let mut interrupted =
+                    || !matches!(conn.poll(), Ok(None) | Ok(Some(Event::Packet(_))));
+                let reason = match resume {
+                    Resume::Step => super::step(runner),
+                    Resume::Continue => super::resume(runner, &stub.breakpoints, interrupted),
+                    Resume::StepBack => super::step_back(runner),
+                    Resume::ContinueBack => {
+                        let reason =
+                            super::reverse_resume(runner, &stub.breakpoints, &mut interrupted);
+                        runner.idle();
+                        reason
+                    }
+                };
+                let watchpoints = runner.hooks.get_or_default::<Watchpoints>();
+                std::mem::swap(watchpoints, &mut stub.watchpoints);
+                for line in stub.watchpoints.take_log() {
+                    eprintln!("{}", line);
+                }
+                conn.stream.set_nonblocking(false)?;
+                conn.send(&stub.stop_reply(&reason))?;
+                if reason == StopReason::Quit {
+                    break;
+                }
+            }
+            Action::Detach => {
+                conn.send("OK")?;
+                eprintln!("GDB detached");
+                runner.hooks.add(stub.watchpoints);
+                runner.run();
+                return Ok(());
+            }
+            Action::Kill => break,
+        }
+    }
+
+    runner.finish();
+    Ok(())
+}
+
+/// What to do after handling a packet.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Resume(Resume),
+    Detach,
+    Kill,
+}
+
+#[derive(Debug, PartialEq)]
+enum Resume {
+    Continue,
+    Step,
+    ContinueBack,
+    StepBack,
+}
+
+/// Protocol state that outlives a single packet.
+struct Stub {
+    breakpoints: Breakpoints,
+    /// Swapped into the runner while it runs.
+    watchpoints: Watchpoints,
+    /// Ids of the watchpoints GDB set, by Z packet type, address and length.
+    data_watchpoints: HashMap<(u8, usize, usize), usize>,
+    no_ack: bool,
+}
+
+impl Stub {
+    fn new() -> Self {
+        Stub {
+            breakpoints: Breakpoints::new(),
+            watchpoints: Watchpoints::new(),
+            data_watchpoints: HashMap::new(),
+            no_ack: false,
+        }
+    }
+
+    fn stop_reply(&self, reason: &StopReason) -> String {
+        match *reason {
+            StopReason::Step => "S05".to_string(),
+            StopReason::Breakpoint(_) => "T05swbreak:;".to_string(),
+            StopReason::Watchpoint(hit) => {
+                let watch = self
+                    .data_watchpoints
+                    .iter()
+                    .find(|(_, &id)| id == hit.id)
+                    .map(|(&(kind, addr, _), _)| (kind, addr));
+                match watch {
+                    Some((2, addr)) => format!("T05watch:{:x};", addr),
+                    Some((3, addr)) => format!("T05rwatch:{:x};", addr),
+                    Some((_, addr)) => format!("T05awatch:{:x};", addr),
+                    // Set from the command line
+                    None => "S05".to_string(),
+                }
+            }
+            StopReason::HistoryStart => "T05replaylog:begin;".to_string(),
+            StopReason::Interrupted => "S02".to_string(),
+            StopReason::Crashed { .. } => "S04".to_string(),
+            StopReason::Quit => "W00".to_string(),
+        }
+    }
+
+    fn handle(&mut self, chip: &mut Chip8, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        let error = || reply("E01");
+        if packet.is_empty() {
+            return reply("");
+        }
+        let (command, args) = packet.split_at(1);
+
+        match command {
+            "?" => reply("S05"),
+            "g" => Action::Reply(hex(&register_bytes(chip))),
+            "G" => match unhex(args).and_then(|bytes| registers_from_bytes(chip, &bytes)) {
+                Some(registers) => {
+                    chip.set_registers(&registers);
+                    reply("OK")
+                }
+                None => error(),
+            },
+            "p" => match parse_hex(args).and_then(|n| read_register(chip, n)) {
+                Some(bytes) => Action::Reply(hex(&bytes)),
+                None => error(),
+            },
+            "P" => {
+                let parsed = args
+                    .split_once('=')
+                    .and_then(|(n, value)| Some((parse_hex(n)?, unhex(value)?)));
+                let mut registers = chip.registers();
+                match parsed {
+                    Some((n, bytes))
+                        if write_register(&mut registers, n, &bytes)
+                            && registers.check().is_ok() =>
+                    {
+                        chip.set_registers(&registers);
+                        reply("OK")
+                    }
+                    _ => error(),
+                }
+            }
+            "m" => {
+                let range = parse_range(args)
+                    .and_then(|(addr, len)| Some((addr, addr.checked_add(len)?)))
+                    .filter(|&(addr, _)| addr < MEMORY_SIZE);
+                match range {
+                    Some((addr, end)) => {
+                        Action::Reply(hex(&chip.memory()[addr..end.min(MEMORY_SIZE)]))
+                    }
+                    None => error(),
+                }
+            }
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, unhex(data)?)));
+                match parsed {
+                    Some(((addr, len), bytes)) if bytes.len() == len => {
+                        let memory = addr
+                            .checked_add(len)
+                            .and_then(|end| chip.memory_mut().get_mut(addr..end));
+                        match memory {
+                            Some(memory) => {
+                                memory.copy_from_slice(&bytes);
+                                reply("OK")
+                            }
+                            None => error(),
+                        }
+                    }
+                    _ => error(),
+                }
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match parse_hex(args).filter(|&addr| addr < MEMORY_SIZE) {
+                        Some(addr) => {
+                            let mut registers = chip.registers();
+                            registers.pc = addr as u16;
+                            chip.set_registers(&registers);
+                        }
+                        None => return error(),
+                    }
+                }
+                if command == "c" {
+                    Action::Resume(Resume::Continue)
+                } else {
+                    Action::Resume(Resume::Step)
+                }
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex).unwrap_or(1);
+                match (kind, addr) {
+                    // Software and hardware breakpoints work the same here
+                    (Some("0") | Some("1"), Some(addr)) => {
+                        if command == "Z" {
+                            self.breakpoints.insert(addr as u16);
+                        } else {
+                            self.breakpoints.remove(addr as u16);
+                        }
+                        reply("OK")
+                    }
+                    (Some(kind @ ("2" | "3" | "4")), Some(addr)) => {
+                        let end = match addr.checked_add(len) {
+                            Some(end) if end <= MEMORY_SIZE => end,
+                            _ => return error(),
+                        };
+                        let kind: u8 = kind.parse().unwrap();
+                        let key = (kind, addr, len);
+                        if command == "z" {
+                            if let Some(id) = self.data_watchpoints.remove(&key) {
+                                self.watchpoints.remove(id);
+                            }
+                            return reply("OK");
+                        }
+                        let access = ["write", "read", "access"][kind as usize - 2];
+                        let condition = format!("{} [{:#x}..{:#x}]", access, addr, end);
+                        let watchpoint = Watchpoint::new(&condition, &Symbols::new()).unwrap();
+                        let id = self.watchpoints.insert(watchpoint);
+                        if let Some(old) = self.data_watchpoints.insert(key, id) {
+                            self.watchpoints.remove(old);
+                        }
+                        reply("OK")
+                    }
+                    _ => reply(""),
+                }
+            }
+            "b" => match args {
+                "s" => Action::Resume(Resume::StepBack),
+                "c" => Action::Resume(Resume::ContinueBack),
+                _ => reply(""),
+            },
+            "k" => Action::Kill,
+            "D" => Action::Detach,
+            // There's only one thread
+            "H" | "T" => reply("OK"),
+            "q" => match packet.strip_prefix("qRcmd,") {
+                Some(command) => match unhex(command) {
+                    Some(command) => {
+                        let output = monitor(chip, &String::from_utf8_lossy(&command));
+                        Action::Reply(hex(format!("{}\n", output).as_bytes()))
+                    }
+                    None => error(),
+                },
+                None => self.query(packet),
+            },
+            "Q" => {
+                if packet == "QStartNoAckMode" {
+                    self.no_ack = true;
+                    reply("OK")
+                } else {
+                    reply("")
+                }
+            }
+            "v" => {
+                if packet == "vCont?" {
+                    reply("vCont;c;s")
+                } else if packet.starts_with("vCont;c") {
+                    Action::Resume(Resume::Continue)
+                } else if packet.starts_with("vCont;s") {
+                    Action::Resume(Resume::Step)
+                } else if packet.starts_with("vKill") {
+                    Action::Kill
+                } else {
+                    reply("")
+                }
+            }
+            _ => reply(""),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> Action {
+        let reply = |s: &str| Action::Reply(s.to_string());
+        if packet.starts_with("qSupported") {
+            reply(
+                "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;\
+                 ReverseStep+;ReverseContinue+",
+            )
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(args) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = offset.min(xml.len());
+                    let end = offset.saturating_add(len).min(xml.len());
+                    let prefix = if end == xml.len() { "l" } else { "m" };
+                    Action::Reply(format!("{}{}", prefix, &xml[start..end]))
+                }
+                None => reply("E01"),
+            }
+        } else if packet == "qAttached" {
+            reply("1")
+        } else if packet == "qC" {
+            reply("QC1")
+        } else if packet == "qfThreadInfo" {
+            reply("m1")
+        } else if packet == "qsThreadInfo" {
+            reply("l")
+        } else {
+            reply("")
+        }
+    }
+}
+
+/// Run a `monitor` command and return its output.
+fn monitor(chip: &Chip8, command: &str) -> String {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("last-write"), Some(addr)) => {
+            let addr = addr.trim_start_matches("0x");
+            match u16::from_str_radix(addr, 16) {
+                Ok(addr) if (addr as usize) < MEMORY_SIZE => super::describe_last_write(chip, addr),
+                _ => format!("Invalid address {}", addr),
+            }
+        }
+        _ => "Commands: last-write ADDR".to_string(),
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <feature name=\"org.chip8.cpu\">\n",
+    );
+    for (n, (name, size)) in REGISTERS.iter().enumerate() {
+        let kind = match *name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ => "uint8",
+        };
+        xml += &format!(
+            "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>\n",
+            name,
+            size * 8,
+            kind,
+            n
+        );
+    }
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+/// Registers in GDB's order, little endian.
+fn register_bytes(chip: &Chip8) -> Vec<u8> {
+    (0..REGISTERS.len())
+        .flat_map(|n| read_register(chip, n).unwrap())
+        .collect()
+}
+
+/// The registers from a `G` packet, if it's the right size and they're valid.
+fn registers_from_bytes(chip: &Chip8, mut bytes: &[u8]) -> Option<Registers> {
+    if bytes.len() != register_bytes(chip).len() {
+        return None;
+    }
+    let mut registers = chip.registers();
+    for (n, (_, size)) in REGISTERS.iter().enumerate() {
+        write_register(&mut registers, n, &bytes[..*size]);
+        bytes = &bytes[*size..];
+    }
+    registers.check().ok()?;
+    Some(registers)
+}
+
+fn read_register(chip: &Chip8, n: usize) -> Option<Vec<u8>> {
+    let registers = chip.registers();
+    let bytes = match n {
+        0..=15 => vec![registers.v[n]],
+        16 => registers.i.to_le_bytes().to_vec(),
+        17 => registers.pc.to_le_bytes().to_vec(),
+        18 => vec![registers.sp],
+        19 => vec![registers.delay],
+        20 => vec![registers.sound],
+        _ => return None,
+    };
+    Some(bytes)
+}
+
+/// Returns false if there's no such register or the value is the wrong size.
+/// The caller checks the registers are valid.
+fn write_register(registers: &mut Registers, n: usize, bytes: &[u8]) -> bool {
+    match REGISTERS.get(n) {
+        Some((_, size)) if *size == bytes.len() => (),
+        _ => return false,
+    }
+
+    let word = || u16::from_le_bytes([bytes[0], bytes[1]]);
+    match n {
+        0..=15 => registers.v[n] = bytes[0],
+        16 => registers.i = word(),
+        17 => registers.pc = word(),
+        18 => registers.sp = bytes[0],
+        19 => registers.delay = bytes[0],
+        _ => registers.sound = bytes[0],
+    }
+    true
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+/// Parse `addr,length`.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Packet(String),
+    /// Ctrl-C from GDB.
+    Interrupt,
+}
+
+/// Packet framing over a TCP stream.
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    no_ack: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream,
+            buffer: Vec::new(),
+            no_ack: false,
+        }
+    }
+
+    /// Read the next event, or `None` if nothing arrives before the read
+    /// timeout (or straight away when non-blocking).
+    fn poll(&mut self) -> io::Result<Option<Event>> {
+        loop {
+            if let Some((event, ok)) = parse_event(&mut self.buffer) {
+                if !self.no_ack {
+                    self.stream.write_all(if ok { b"+" } else { b"-" })?;
+                }
+                if ok {
+                    return Ok(event);
+                }
+                continue;
+            }
+
+            let mut chunk = [0; 1024];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = frame_packet(data);
+        self.stream.write_all(&packet)
+    }
+}
+
+/// Take the next event off the front of `buffer`. The flag is false for a
+/// packet with a bad checksum. Acks from GDB are skipped.
+fn parse_event(buffer: &mut Vec<u8>) -> Option<(Option<Event>, bool)> {
+    loop {
+        match buffer.first()? {
+            b'+' | b'-' => {
+                buffer.remove(0);
+            }
+            0x03 => {
+                buffer.remove(0);
+                return Some((Some(Event::Interrupt), true));
+            }
+            b'$' => break,
+            // Junk between packets
+            _ => {
+                buffer.remove(0);
+            }
+        }
+    }
+
+    let end = buffer.iter().position(|&b| b == b'#')?;
+    if buffer.len() < end + 3 {
+        return None;
+    }
+    let packet: Vec<u8> = buffer.drain(..end + 3).collect();
+    let data = &packet[1..end];
+    let expected = std::str::from_utf8(&packet[end + 1..])
+        .ok()
+        .and_then(|sum| u8::from_str_radix(sum, 16).ok());
+    if expected != Some(checksum(data)) {
+        return Some((None, false));
+    }
+    let text = String::from_utf8_lossy(data).into_owned();
+    Some((Some(Event::Packet(text)), true))
+}
+
+/// Add the framing and checksum to a reply, escaping special characters.
+fn frame_packet(data: &str) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &b in data.as_bytes() {
+        if matches!(b, b'$' | b'#' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(b ^ 0x20);
+        } else {
+            escaped.push(b);
+        }
+    }
+
+    let mut packet = vec![b'$'];
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::Headless;
+
+    fn reply(stub: &mut Stub, chip: &mut Chip8, packet: &str) -> String {
+        match stub.handle(chip, packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("Expected a reply, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn packet_framing() {
+        assert_eq!(frame_packet("OK"), b"$OK#9a");
+        assert_eq!(frame_packet("a#"), b"$a}\x03#e1");
+
+        let mut buffer = b"+$g#67$m0,2#fb\x03$?#00".to_vec();
+        assert_eq!(
+            parse_event(&mut buffer),
+            Some((Some(Event::Packet("g".to_string())), true))
+        );
+        assert_eq!(
+            parse_event(&mut buffer),
+            Some((Some(Event::Packet("m0,2".to_string())), true))
+        );
+        assert_eq!(
+            parse_event(&mut buffer),
+            Some((Some(Event::Interrupt), true))
+        );
+        assert_eq!(parse_event(&mut buffer), Some((None, false)));
+        assert_eq!(parse_event(&mut buffer), None);
+
+        let mut partial = b"$g#6".to_vec();
+        assert_eq!(parse_event(&mut partial), None);
+    }
+
+    #[test]
+    fn registers() {
+        let mut stub = Stub::new();
+        let mut chip = Chip8::new();
+        let all = reply(&mut stub, &mut chip, "g");
+        // v0-vf, i, pc = 0x200, sp, delay, sound
+        assert_eq!(
+            all,
+            format!("{}{}{}{}", "00".repeat(16), "0000", "0002", "000000")
+        );
+
+        assert_eq!(reply(&mut stub, &mut chip, "P3=2a"), "OK");
+        assert_eq!(reply(&mut stub, &mut chip, "P10=3412"), "OK");
+        assert_eq!(reply(&mut stub, &mut chip, "P10=34"), "E01");
+        assert_eq!(reply(&mut stub, &mut chip, "p3"), "2a");
+        assert_eq!(chip.registers().i, 0x1234);
+
+        let mut changed = all.clone();
+        changed.replace_range(36..40, "0403");
+        assert_eq!(reply(&mut stub, &mut chip, &format!("G{}", changed)), "OK");
+        assert_eq!(chip.pc(), 0x304);
+        assert_eq!(reply(&mut stub, &mut chip, "p11"), "0403");
+        assert_eq!(reply(&mut stub, &mut chip, "p15"), "E01");
+
+        // The PC stays in memory and the stack pointer in the stack
+        assert_eq!(reply(&mut stub, &mut chip, "P11=0010"), "E01");
+        assert_eq!(reply(&mut stub, &mut chip, "P12=10"), "OK");
+        assert_eq!(reply(&mut stub, &mut chip, "P12=11"), "E01");
+        let mut bad = all.clone();
+        bad.replace_range(36..40, "ffff");
+        assert_eq!(reply(&mut stub, &mut chip, &format!("G{}", bad)), "E01");
+        assert_eq!(chip.pc(), 0x304);
+    }
+
+    #[test]
+    fn memory() {
+        let mut stub = Stub::new();
+        let mut chip = Chip8::new();
+        assert_eq!(reply(&mut stub, &mut chip, "M300,3:a1b2c3"), "OK");
+        assert_eq!(reply(&mut stub, &mut chip, "m2ff,5"), "00a1b2c300");
+        // Reads are cut off at the end of memory
+        assert_eq!(reply(&mut stub, &mut chip, "mffe,4"), "0000");
+        assert_eq!(reply(&mut stub, &mut chip, "m1000,1"), "E01");
+        assert_eq!(reply(&mut stub, &mut chip, "Mfff,2:0102"), "E01");
+        assert_eq!(reply(&mut stub, &mut chip, "M300,2:01"), "E01");
+        assert_eq!(reply(&mut stub, &mut chip, "m1,ffffffffffffffff"), "E01");
+        assert_eq!(reply(&mut stub, &mut chip, "M1,ffffffffffffffff:01"), "E01");
+        assert_eq!(
+            reply(&mut stub, &mut chip, "Z2,fff,ffffffffffffffff"),
+            "E01"
+        );
+    }
+
+    #[test]
+    fn breakpoints_and_resume() {
+        let mut stub = Stub::new();
+        let mut chip = Chip8::new();
+        assert_eq!(reply(&mut stub, &mut chip, "Z0,204,2"), "OK");
+        assert!(stub.breakpoints.contains(0x204));
+        assert_eq!(reply(&mut stub, &mut chip, "z0,204,2"), "OK");
+        assert!(!stub.breakpoints.contains(0x204));
+        assert_eq!(reply(&mut stub, &mut chip, "Z5,300,1"), "");
+
+        assert_eq!(
+            stub.handle(&mut chip, "c"),
+            Action::Resume(Resume::Continue)
+        );
+        assert_eq!(stub.handle(&mut chip, "s206"), Action::Resume(Resume::Step));
+        assert_eq!(chip.pc(), 0x206);
+        assert_eq!(reply(&mut stub, &mut chip, "s1000"), "E01");
+        assert_eq!(chip.pc(), 0x206);
+        assert_eq!(
+            stub.handle(&mut chip, "vCont;s:1"),
+            Action::Resume(Resume::Step)
+        );
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut stub = Stub::new();
+        let mut chip = Chip8::new();
+        // 200: LD I, 300
+        // 202: LD [I], V1
+        chip.load_binary(vec![0xA3, 0x00, 0xF1, 0x55]);
+        assert_eq!(reply(&mut stub, &mut chip, "Z2,301,1"), "OK");
+        assert_eq!(reply(&mut stub, &mut chip, "Z3,300,2"), "OK");
+        assert_eq!(stub.watchpoints.iter().count(), 2);
+        assert_eq!(reply(&mut stub, &mut chip, "z3,300,2"), "OK");
+        assert_eq!(stub.watchpoints.iter().count(), 1);
+
+        for _ in 0..2 {
+            let (before, opcode) = (chip.registers(), chip.opcode());
+            chip.step();
+            stub.watchpoints.check(&chip, before, opcode);
+        }
+        let hit = stub.watchpoints.take_triggered().unwrap();
+        assert_eq!(hit.pc, 0x202);
+        assert_eq!(
+            stub.stop_reply(&StopReason::Watchpoint(hit)),
+            "T05watch:301;"
+        );
+    }
+
+    #[test]
+    fn reverse_execution() {
+        let mut stub = Stub::new();
+        let mut chip = Chip8::new();
+        assert_eq!(
+            stub.handle(&mut chip, "bs"),
+            Action::Resume(Resume::StepBack)
+        );
+        assert_eq!(
+            stub.handle(&mut chip, "bc"),
+            Action::Resume(Resume::ContinueBack)
+        );
+        assert_eq!(
+            stub.stop_reply(&StopReason::HistoryStart),
+            "T05replaylog:begin;"
+        );
+
+        // 200: LD I, 300
+        // 202: LD [I], V0
+        chip.load_binary(vec![0xA3, 0x00, 0xF0, 0x55]);
+        chip.enable_history(10);
+        chip.step();
+        chip.step();
+        let command = |text: &str| format!("qRcmd,{}", hex(text.as_bytes()));
+        let output = reply(&mut stub, &mut chip, &command("last-write 0x300"));
+        assert_eq!(
+            unhex(&output).unwrap(),
+            b"0x300 was last written at cycle 2 by 0x202: LD [I], V0, was 0x00\n"
+        );
+        let output = reply(&mut stub, &mut chip, &command("last-write zz"));
+        assert_eq!(unhex(&output).unwrap(), b"Invalid address zz\n");
+    }
+
+    #[test]
+    fn illegal_instruction() {
+        let mut runner = Runner::new(Chip8::with_seed(1), Headless::new(None));
+        runner.chip.enable_history(10);
+        // 200: LD V0, 1
+        // 202: HIGH, which is SUPER-CHIP
+        runner.chip.load_binary(vec![0x60, 0x01, 0x00, 0xFF]);
+        let reason = super::super::resume(&mut runner, &Breakpoints::new(), || false);
+        assert_eq!(
+            reason,
+            StopReason::Crashed {
+                pc: 0x202,
+                message: "Unknown opcode: 0xFF".to_string()
+            }
+        );
+        assert_eq!(Stub::new().stop_reply(&reason), "S04");
+        // Stopped on the instruction, with the one before still undoable
+        assert_eq!((runner.chip.pc(), runner.chip.cycles()), (0x202, 1));
+        assert!(runner.chip.step_back());
+        assert_eq!(runner.chip.pc(), 0x200);
+    }
+
+    #[test]
+    fn target_description() {
+        let mut stub = Stub::new();
+        let mut chip = Chip8::new();
+        let xml = target_xml();
+        let first = reply(&mut stub, &mut chip, "qXfer:features:read:target.xml:0,10");
+        assert_eq!(first, format!("m{}", &xml[..0x10]));
+        let rest = reply(
+            &mut stub,
+            &mut chip,
+            &format!("qXfer:features:read:target.xml:10,{:x}", xml.len()),
+        );
+        assert_eq!(rest, format!("l{}", &xml[0x10..]));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\" regnum=\"17\"/>"));
+    }
+}
@@ -0,0 +1,218 @@
+mod condition;
+pub mod dap;
+pub mod gdb;
+mod source_map;
+pub mod tui;
+mod watch;
+
+pub use condition::{Context, Expr};
+pub use source_map::{SourceLine, SourceMap};
+pub use watch::{Hit, Watchpoint, Watchpoints};
+
+use crate::chip8::{panic_message, Chip8};
+use crate::disassembler::mnemonic;
+use crate::frontend::Frontend;
+use crate::runner::Runner;
+use std::collections::BTreeSet;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Instructions debuggers keep undo information for, to step backwards.
+pub const HISTORY_LIMIT: usize = 100_000;
+
+/// Undone instructions between checks for interruptions when running
+/// backwards, which has no frames to check at.
+const REVERSE_CHECK_INTERVAL: usize = 10_000;
+
+/// Why execution stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// A single step finished.
+    Step,
+    /// Reached a breakpoint at this address.
+    Breakpoint(u16),
+    /// A watchpoint's condition became true.
+    Watchpoint(Hit),
+    /// Running backwards reached the oldest instruction in the history.
+    HistoryStart,
+    /// The debugger asked to stop.
+    Interrupted,
+    /// The instruction at `pc` stopped the emulator, like one it doesn't
+    /// have. It's left to run again.
+    Crashed { pc: u16, message: String },
+    /// The frontend was closed.
+    Quit,
+}
+
+/// Program counter addresses to stop at.
+#[derive(Clone, Debug, Default)]
+pub struct Breakpoints {
+    addresses: BTreeSet<u16>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints::default()
+    }
+
+    /// Returns false if there was already a breakpoint at `addr`.
+    pub fn insert(&mut self, addr: u16) -> bool {
+        self.addresses.insert(addr)
+    }
+
+    /// Returns false if there was no breakpoint at `addr`.
+    pub fn remove(&mut self, addr: u16) -> bool {
+        self.addresses.remove(&addr)
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.addresses.contains(&addr)
+    }
+}
+
+/// Execute a single instruction.
+pub fn step<F: Frontend>(runner: &mut Runner<F>) -> StopReason {
+    if let Some(crash) = try_step(runner) {
+        return crash;
+    }
+    if runner.at_frame_start() {
+        runner.wait_for_frame();
+    }
+    match runner
+        .hooks
+        .get_mut::<Watchpoints>()
+        .and_then(Watchpoints::take_triggered)
+    {
+        Some(hit) => StopReason::Watchpoint(hit),
+        None => StopReason::Step,
+    }
+}
+
+/// Run `runner.step`, returning `StopReason::Crashed` if the instruction
+/// panics. Debuggers keep a history, which leaves the chip as it was before.
+fn try_step<F: Frontend>(runner: &mut Runner<F>) -> Option<StopReason> {
+    let pc = runner.chip.pc();
+    // The crash goes in the stop reason instead of over the debugger's output
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| runner.step()));
+    panic::set_hook(hook);
+    result.err().map(|payload| StopReason::Crashed {
+        pc,
+        message: panic_message(payload),
+    })
+}
+
+/// Undo the last instruction.
+pub fn step_back<F: Frontend>(runner: &mut Runner<F>) -> StopReason {
+    if runner.step_back() {
+        StopReason::Step
+    } else {
+        StopReason::HistoryStart
+    }
+}
+
+/// Run backwards until the PC reaches a breakpoint, the history runs out or
+/// `interrupted` returns true. Stops just before the breakpoint's
+/// instruction ran, as running forwards would have.
+///
+/// Watchpoints only apply running forwards.
+pub fn reverse_resume<F: Frontend>(
+    runner: &mut Runner<F>,
+    breakpoints: &Breakpoints,
+    mut interrupted: impl FnMut() -> bool,
+) -> StopReason {
+    for n in 1.. {
+        if !runner.step_back() {
+            return StopReason::HistoryStart;
+        }
+        let pc = runner.chip.pc();
+        if breakpoints.contains(pc) {
+            return StopReason::Breakpoint(pc);
+        }
+        if n % REVERSE_CHECK_INTERVAL == 0 && interrupted() {
+            return StopReason::Interrupted;
+        }
+    }
+    unreachable!()
+}
+
+/// Describe the last write to `addr` in the history, for debugger commands.
+pub fn describe_last_write(chip: &Chip8, addr: u16) -> String {
+    let history = match chip.history() {
+        Some(history) => history,
+        None => return "No history is being kept".to_string(),
+    };
+    match history.last_write(addr) {
+        Some(write) => format!(
+            "{:#05x} was last written at cycle {} by {:#05x}: {}, was {:#04x}",
+            addr,
+            write.cycle,
+            write.pc,
+            disassemble_at(chip, write.pc),
+            write.old
+        ),
+        None => format!(
+            "{:#05x} wasn't written in the last {} instructions",
+            addr,
+            history.len()
+        ),
+    }
+}
+
+/// The instruction at `addr` without the padding used for columns.
+fn disassemble_at(chip: &Chip8, addr: u16) -> String {
+    let memory = chip.memory();
+    let addr = addr as usize % (memory.len() - 1);
+    let text = mnemonic((memory[addr] as u16) << 8 | memory[addr + 1] as u16);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Run until a breakpoint or watchpoint is reached, `interrupted` returns
+/// true or the frontend quits. `interrupted` is checked once per frame.
+///
+/// The instruction at the current PC always runs, so continuing from a
+/// breakpoint doesn't stop on it again straight away.
+pub fn resume<F: Frontend>(
+    runner: &mut Runner<F>,
+    breakpoints: &Breakpoints,
+    interrupted: impl FnMut() -> bool,
+) -> StopReason {
+    run_until(runner, breakpoints, interrupted, |_| false)
+}
+
+/// Like `resume`, but also stops with `StopReason::Step` once `done` returns
+/// true after an instruction. Used for stepping over and out of subroutines.
+pub fn run_until<F: Frontend>(
+    runner: &mut Runner<F>,
+    breakpoints: &Breakpoints,
+    mut interrupted: impl FnMut() -> bool,
+    mut done: impl FnMut(&Chip8) -> bool,
+) -> StopReason {
+    loop {
+        if let Some(crash) = try_step(runner) {
+            return crash;
+        }
+
+        if runner.at_frame_start() {
+            runner.wait_for_frame();
+            if runner.frontend.should_quit() {
+                return StopReason::Quit;
+            }
+            if interrupted() {
+                return StopReason::Interrupted;
+            }
+        }
+
+        let watchpoints = runner.hooks.get_mut::<Watchpoints>();
+        if let Some(hit) = watchpoints.and_then(Watchpoints::take_triggered) {
+            return StopReason::Watchpoint(hit);
+        }
+        let pc = runner.chip.pc();
+        if breakpoints.contains(pc) {
+            return StopReason::Breakpoint(pc);
+        }
+        if done(&runner.chip) {
+            return StopReason::Step;
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Maps instruction addresses to the assembler source lines they came from.
+///
+/// Loaded from a text file with one `address file:line` entry per line, with
+/// the address in hex and the file relative to the map. Blank lines and lines
+/// starting with `#` are ignored:
+///
+/// ```text
+/// # pong.8o
+/// 200 pong.8o:12
+/// 202 pong.8o:13
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    /// Sorted by address.
+    entries: Vec<SourceLine>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLine {
+    pub addr: u16,
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+impl SourceMap {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        SourceMap::parse(&text, dir).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parse a source map, resolving file names relative to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        for (n, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid source map line {}: {}", n + 1, line);
+            let (addr, location) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let (file, line_number) = location.trim().rsplit_once(':').ok_or_else(error)?;
+            let addr = addr.trim_start_matches("0x");
+            entries.push(SourceLine {
+                addr: u16::from_str_radix(addr, 16).map_err(|_| error())?,
+                file: normalize(&dir.join(file)),
+                line: line_number.parse().map_err(|_| error())?,
+            });
+        }
+        entries.sort_by_key(|entry| entry.addr);
+        Ok(SourceMap { entries })
+    }
+
+    /// Source line of the instruction at `addr`.
+    pub fn location(&self, addr: u16) -> Option<&SourceLine> {
+        let i = self
+            .entries
+            .binary_search_by_key(&addr, |entry| entry.addr)
+            .ok()?;
+        Some(&self.entries[i])
+    }
+
+    /// First instruction on `line` of `file`, or on the next line with code
+    /// if that line has none. Returns the address and the line it's on.
+    pub fn address(&self, file: &Path, line: usize) -> Option<(u16, usize)> {
+        let file = normalize(file);
+        self.entries
+            .iter()
+            .filter(|entry| entry.file == file && entry.line >= line)
+            .min_by_key(|entry| (entry.line, entry.addr))
+            .map(|entry| (entry.addr, entry.line))
+    }
+}
+
+/// Editors may refer to the same file by different paths.
+fn normalize(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let map = SourceMap::parse(
+            "# comment\n\
+             204 game.8o:7\n\
+             0x200 game.8o:3\n\
+             202 game.8o:3\n\
+             \n\
+             300 lib.8o:1\n",
+            Path::new("/src"),
+        )
+        .unwrap();
+
+        let game = Path::new("/src/game.8o");
+        assert_eq!(map.location(0x204).map(|l| l.line), Some(7));
+        assert_eq!(map.location(0x202).map(|l| l.file.as_path()), Some(game));
+        assert_eq!(map.location(0x206), None);
+
+        assert_eq!(map.address(game, 3), Some((0x200, 3)));
+        assert_eq!(map.address(game, 4), Some((0x204, 7)));
+        assert_eq!(map.address(game, 8), None);
+        assert_eq!(map.address(Path::new("/src/lib.8o"), 1), Some((0x300, 1)));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(SourceMap::parse("200", Path::new("")).is_err());
+        assert!(SourceMap::parse("zz game.8o:1", Path::new("")).is_err());
+        assert!(SourceMap::parse("200 game.8o", Path::new("")).is_err());
+    }
+}
@@ -0,0 +1,922 @@
+//! Full-screen terminal debugger.
+//!
+//! Shows the disassembly around the cursor, the registers and call stack, a
+//! hex view of memory and the display, with any labels from the runner's
+//! symbols. Registers and memory that changed
+//! since the last stop are highlighted. While running, keypad keys go to the
+//! program as in `--terminal`.
+//!
+//! Recent instructions can be undone by stepping or continuing backwards, and
+//! `:` opens a prompt for the cheat commands.
+
+use super::{Breakpoints, StopReason, Watchpoints, HISTORY_LIMIT};
+use crate::cheats::{Cheats, COMMANDS as CHEAT_COMMANDS};
+use crate::chip8::{Chip8, Registers, HEIGHT, MEMORY_SIZE, WIDTH};
+use crate::disassembler::disassemble_opcode;
+use crate::frontend::{enter_raw_mode, leave_raw_mode, render_cells, Frontend, Glyphs, HeldKeys};
+use crate::runner::Runner;
+use crate::symbols::Symbols;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, queue, style, terminal};
+use std::collections::VecDeque;
+use std::io::{self, Stdout, Write};
+use std::time::Duration;
+
+/// Width of the disassembly and memory panes.
+const LEFT_WIDTH: usize = 40;
+/// Where the registers and display panes start.
+const RIGHT_X: usize = LEFT_WIDTH + 2;
+/// Where the stack pane starts, right of the registers.
+const STACK_X: usize = RIGHT_X + 26;
+/// Bytes per row of the memory pane.
+const MEMORY_COLUMNS: usize = 8;
+/// Rows of the memory and display panes. Half-blocks fit the display in 16.
+const BOTTOM_ROWS: usize = HEIGHT / 2;
+/// Lines of the disassembly pane on short terminals.
+const MIN_TOP_ROWS: usize = 8;
+/// Log lines kept for the message line.
+const MAX_MESSAGES: usize = 100;
+
+const HELP_STOPPED: &str = "s step  n next  o out  c continue  r/R back  b breakpoint  \
+                            \u{2191}\u{2193} move  PgUp/PgDn memory  i memory at I  \
+                            w last write to I  : cheats  p pc  q quit";
+const HISTORY_START: &str = "At the start of the history";
+const HELP_RUNNING: &str = "F5/Esc pause  Ctrl-C quit  keypad 1234 qwer asdf zxcv";
+
+/// What a key asks the debugger to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Step,
+    /// Step over calls.
+    Next,
+    /// Run until the current subroutine returns.
+    Out,
+    Continue,
+    /// Undo the last instruction.
+    StepBack,
+    /// Run backwards to the previous breakpoint.
+    ContinueBack,
+    Pause,
+    ToggleBreakpoint,
+    CursorUp,
+    CursorDown,
+    MemoryUp,
+    MemoryDown,
+    /// Show memory at I.
+    MemoryAtI,
+    /// Move the cursor back to the PC.
+    CursorAtPc,
+    /// Show which instruction last wrote the byte at I.
+    LastWrite,
+    /// Open the prompt for cheat commands.
+    Prompt,
+    /// Add a character to the prompt.
+    Type(char),
+    /// Delete the last character of the prompt.
+    Erase,
+    /// Run the command in the prompt.
+    Submit,
+    /// Close the prompt.
+    Cancel,
+    Quit,
+}
+
+/// The command for a key. While running, only pausing and quitting are
+/// commands and other keys are left for the keypad. While the prompt is open
+/// keys edit it.
+fn command(key: &KeyEvent, running: bool, prompting: bool) -> Option<Command> {
+    if key.kind == KeyEventKind::Release {
+        return None;
+    }
+    let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+    let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+    match key.code {
+        KeyCode::Char('c') if ctrl => Some(Command::Quit),
+        KeyCode::Enter if prompting => Some(Command::Submit),
+        KeyCode::Esc if prompting => Some(Command::Cancel),
+        KeyCode::Backspace if prompting => Some(Command::Erase),
+        KeyCode::Char(c) if prompting => Some(Command::Type(c)),
+        _ if prompting => None,
+        KeyCode::F(5) | KeyCode::Esc if running => Some(Command::Pause),
+        _ if running => None,
+        KeyCode::F(5) | KeyCode::Char('c') => Some(Command::Continue),
+        KeyCode::F(11) if shift => Some(Command::Out),
+        KeyCode::F(11) | KeyCode::Char('s') => Some(Command::Step),
+        KeyCode::F(10) | KeyCode::Char('n') => Some(Command::Next),
+        KeyCode::Char('o') => Some(Command::Out),
+        KeyCode::Char('r') => Some(Command::StepBack),
+        KeyCode::Char('R') => Some(Command::ContinueBack),
+        KeyCode::F(9) | KeyCode::Char('b') => Some(Command::ToggleBreakpoint),
+        KeyCode::Up | KeyCode::Char('k') => Some(Command::CursorUp),
+        KeyCode::Down | KeyCode::Char('j') => Some(Command::CursorDown),
+        KeyCode::PageUp => Some(Command::MemoryUp),
+        KeyCode::PageDown => Some(Command::MemoryDown),
+        KeyCode::Char('i') => Some(Command::MemoryAtI),
+        KeyCode::Char('p') => Some(Command::CursorAtPc),
+        KeyCode::Char('w') => Some(Command::LastWrite),
+        KeyCode::Char(':') => Some(Command::Prompt),
+        KeyCode::Char('q') => Some(Command::Quit),
+        _ => None,
+    }
+}
+
+/// The terminal the debugger draws on, which is also the emulator's frontend.
+///
+/// The terminal is restored when this is dropped.
+pub struct Tui {
+    out: Stdout,
+    keys: HeldKeys,
+    /// Whether keys go to the keypad.
+    running: bool,
+    /// Whether keys go to the prompt.
+    prompting: bool,
+    commands: VecDeque<Command>,
+    /// What's on the terminal, or `None` to redraw everything.
+    drawn: Option<Screen>,
+    quit: bool,
+}
+
+impl Tui {
+    pub fn new() -> io::Result<Self> {
+        let mut out = io::stdout();
+        let releases = enter_raw_mode(&mut out)?;
+        Ok(Tui {
+            out,
+            keys: HeldKeys::new(releases),
+            running: false,
+            prompting: false,
+            commands: VecDeque::new(),
+            drawn: None,
+            quit: false,
+        })
+    }
+
+    /// Read terminal events for up to `timeout`, queueing commands.
+    fn read_events(&mut self, timeout: Duration) -> io::Result<()> {
+        let mut timeout = timeout;
+        while event::poll(timeout)? {
+            timeout = Duration::ZERO;
+            match event::read()? {
+                Event::Key(key) => match command(&key, self.running, self.prompting) {
+                    Some(Command::Quit) => self.quit = true,
+                    Some(command) => self.commands.push_back(command),
+                    None if self.running => {
+                        self.keys.handle(&key);
+                    }
+                    None => (),
+                },
+                // Contents are lost on resize, so force a full redraw
+                Event::Resize(..) => self.drawn = None,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn size() -> (usize, usize) {
+        terminal::size().map_or((80, 24), |(w, h)| (w as usize, h as usize))
+    }
+
+    /// Draw `screen`, writing only the cells that changed since the last draw.
+    fn draw(&mut self, screen: Screen) -> io::Result<()> {
+        let full = match &self.drawn {
+            Some(drawn) => (drawn.width, drawn.height) != (screen.width, screen.height),
+            None => true,
+        };
+        if full {
+            queue!(self.out, terminal::Clear(terminal::ClearType::All))?;
+        }
+
+        let mut current = None;
+        for (i, &(c, style)) in screen.cells.iter().enumerate() {
+            let same = !full && self.drawn.as_ref().map(|drawn| drawn.cells[i]) == Some((c, style));
+            if same {
+                continue;
+            }
+            if current != Some(style) {
+                queue!(self.out, style::SetAttribute(style::Attribute::Reset))?;
+                queue!(self.out, style::ResetColor)?;
+                match style {
+                    Style::Normal => (),
+                    Style::Title | Style::Cursor => {
+                        queue!(self.out, style::SetAttribute(style::Attribute::Reverse))?
+                    }
+                    Style::Dim => {
+                        queue!(self.out, style::SetForegroundColor(style::Color::DarkGrey))?
+                    }
+                    Style::Changed => queue!(
+                        self.out,
+                        style::SetForegroundColor(style::Color::Yellow),
+                        style::SetAttribute(style::Attribute::Bold)
+                    )?,
+                    Style::Current => {
+                        queue!(self.out, style::SetForegroundColor(style::Color::Green))?
+                    }
+                    Style::Breakpoint => {
+                        queue!(self.out, style::SetForegroundColor(style::Color::Red))?
+                    }
+                }
+                current = Some(style);
+            }
+            let (x, y) = (i % screen.width, i / screen.width);
+            queue!(
+                self.out,
+                cursor::MoveTo(x as u16, y as u16),
+                style::Print(c)
+            )?;
+        }
+        queue!(self.out, style::SetAttribute(style::Attribute::Reset))?;
+        self.drawn = Some(screen);
+        self.out.flush()
+    }
+}
+
+impl Frontend for Tui {
+    fn poll_input(&mut self, keypad: &mut [bool; 16]) {
+        self.read_events(Duration::ZERO)
+            .expect("Error reading terminal input");
+        self.keys.update(keypad);
+    }
+
+    /// The debugger draws the display along with everything else.
+    fn present(&mut self, _display: &[u32]) {}
+
+    fn play_tone(&mut self) {
+        let _ = queue!(self.out, style::Print('\x07'));
+    }
+
+    /// Whether Ctrl-C or `q` was pressed.
+    fn should_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        leave_raw_mode(&mut self.out, self.keys.releases);
+    }
+}
+
+/// How the emulator runs until it next stops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Run {
+    Continue,
+    /// Run until the stack pointer is at most this.
+    Return(u8),
+}
+
+/// Debug in the terminal until the user quits. Execution starts stopped.
+pub fn run(runner: &mut Runner<Tui>) -> io::Result<()> {
+    let mut view = View::new(&runner.chip);
+    let mut breakpoints = Breakpoints::new();
+    let mut running = None;
+    runner.chip.enable_history(HISTORY_LIMIT);
+
+    while !runner.frontend.should_quit() {
+        let (width, height) = Tui::size();
+        let mut screen = Screen::new(width, height);
+        render(
+            &mut screen,
+            &runner.chip,
+            &view,
+            &breakpoints,
+            &runner.symbols,
+            running.is_some(),
+        );
+        runner.frontend.draw(screen)?;
+
+        let reason = match running {
+            None => {
+                runner.frontend.read_events(Duration::from_millis(50))?;
+                None
+            }
+            // One frame at a time, to keep the panes up to date
+            Some(Run::Continue) => Some(super::run_until(runner, &breakpoints, || true, |_| false)),
+            Some(Run::Return(sp)) => Some(super::run_until(
+                runner,
+                &breakpoints,
+                || true,
+                |chip| chip.registers().sp <= sp,
+            )),
+        };
+        for line in runner.hooks.get_or_default::<Watchpoints>().take_log() {
+            view.message(line);
+        }
+        if running.is_some() {
+            view.cursor = runner.chip.pc();
+        }
+
+        let stopped = match reason {
+            Some(StopReason::Step) => Some("Stepped".to_string()),
+            Some(StopReason::Breakpoint(addr)) => {
+                Some(format!("Breakpoint at {}", runner.symbols.describe(addr)))
+            }
+            Some(StopReason::Watchpoint(hit)) => Some(watchpoint_status(runner, hit.id)),
+            Some(StopReason::HistoryStart) => Some(HISTORY_START.to_string()),
+            Some(StopReason::Crashed { pc, message }) => Some(crash_status(runner, pc, &message)),
+            Some(StopReason::Quit) => break,
+            Some(StopReason::Interrupted) | None => None,
+        };
+        if let Some(status) = stopped {
+            running = None;
+            view.stop(&runner.chip, status);
+        }
+
+        while let Some(command) = runner.frontend.commands.pop_front() {
+            let registers = runner.chip.registers();
+            match command {
+                Command::Pause => {
+                    if running.take().is_some() {
+                        view.stop(&runner.chip, "Paused".to_string());
+                    }
+                }
+                Command::Continue => running = Some(Run::Continue),
+                Command::Step | Command::Next | Command::Out => {
+                    let opcode = runner.chip.opcode();
+                    let is_call = opcode & 0xF000 == 0x2000;
+                    match command {
+                        Command::Next if is_call => running = Some(Run::Return(registers.sp)),
+                        Command::Out if registers.sp > 0 => {
+                            running = Some(Run::Return(registers.sp - 1))
+                        }
+                        _ => {
+                            let status = match super::step(runner) {
+                                StopReason::Watchpoint(hit) => watchpoint_status(runner, hit.id),
+                                StopReason::Crashed { pc, message } => {
+                                    crash_status(runner, pc, &message)
+                                }
+                                _ => "Stepped".to_string(),
+                            };
+                            for line in runner.hooks.get_or_default::<Watchpoints>().take_log() {
+                                view.message(line);
+                            }
+                            view.stop(&runner.chip, status);
+                        }
+                    }
+                }
+                Command::StepBack | Command::ContinueBack => {
+                    let reason = if command == Command::StepBack {
+                        super::step_back(runner)
+                    } else {
+                        super::reverse_resume(runner, &breakpoints, || false)
+                    };
+                    let status = match reason {
+                        StopReason::Breakpoint(addr) => {
+                            format!("Breakpoint at {}", runner.symbols.describe(addr))
+                        }
+                        StopReason::HistoryStart => HISTORY_START.to_string(),
+                        _ => "Stepped back".to_string(),
+                    };
+                    view.cursor = runner.chip.pc();
+                    view.stop(&runner.chip, status);
+                }
+                Command::LastWrite => {
+                    let addr = registers.i % MEMORY_SIZE as u16;
+                    view.message(super::describe_last_write(&runner.chip, addr));
+                }
+                Command::Prompt => view.prompt = Some(String::new()),
+                Command::Type(c) => view.prompt.get_or_insert_with(String::new).push(c),
+                Command::Erase => {
+                    view.prompt.as_mut().map(String::pop);
+                }
+                Command::Submit => {
+                    let text = view.prompt.take().unwrap_or_default();
+                    match runner
+                        .hooks
+                        .get_or_default::<Cheats>()
+                        .command(&mut runner.chip, &text)
+                    {
+                        Ok(line) | Err(line) => view.message(line),
+                    }
+                }
+                Command::Cancel => view.prompt = None,
+                Command::ToggleBreakpoint => {
+                    if !breakpoints.remove(view.cursor) {
+                        breakpoints.insert(view.cursor);
+                    }
+                }
+                Command::CursorUp => view.cursor = view.cursor.saturating_sub(2),
+                Command::CursorDown => view.cursor = (view.cursor + 2).min(MEMORY_SIZE as u16 - 2),
+                Command::MemoryUp => view.scroll_memory(-(BOTTOM_ROWS as i32)),
+                Command::MemoryDown => view.scroll_memory(BOTTOM_ROWS as i32),
+                Command::MemoryAtI => view.memory = align(registers.i),
+                Command::CursorAtPc => view.cursor = registers.pc,
+                Command::Quit => runner.frontend.quit = true,
+            }
+        }
+        runner.frontend.running = running.is_some();
+        runner.frontend.prompting = view.prompt.is_some();
+    }
+
+    runner.finish();
+    Ok(())
+}
+
+fn watchpoint_status<F: Frontend>(runner: &Runner<F>, id: usize) -> String {
+    let watchpoints = runner.hooks.get::<Watchpoints>();
+    match watchpoints.and_then(|watchpoints| watchpoints.get(id)) {
+        Some(watchpoint) => format!(
+            "Watchpoint {} (hit {}): {}",
+            id, watchpoint.hits, watchpoint.text
+        ),
+        None => format!("Watchpoint {}", id),
+    }
+}
+
+/// Status for a crash, redrawing everything in case it printed over the
+/// screen.
+fn crash_status(runner: &mut Runner<Tui>, pc: u16, message: &str) -> String {
+    runner.frontend.drawn = None;
+    format!("Stopped at {}: {}", runner.symbols.describe(pc), message)
+}
+
+fn align(addr: u16) -> u16 {
+    addr - addr % MEMORY_COLUMNS as u16
+}
+
+/// Debugger state besides the emulator.
+struct View {
+    /// Address the disassembly is centred on. Follows the PC when it stops.
+    cursor: u16,
+    /// First address in the memory pane.
+    memory: u16,
+    /// Registers and memory when execution last stopped, to show changes.
+    old_registers: Registers,
+    old_memory: Vec<u8>,
+    status: String,
+    messages: VecDeque<String>,
+    /// Text typed at the prompt, while it's open.
+    prompt: Option<String>,
+}
+
+impl View {
+    fn new(chip: &Chip8) -> Self {
+        let registers = chip.registers();
+        View {
+            cursor: registers.pc,
+            memory: align(registers.pc),
+            old_registers: registers,
+            old_memory: chip.memory().to_vec(),
+            status: "Stopped".to_string(),
+            messages: VecDeque::new(),
+            prompt: None,
+        }
+    }
+
+    /// Show that execution stopped. Changes are highlighted until the next stop.
+    fn stop(&mut self, chip: &Chip8, status: String) {
+        self.cursor = chip.pc();
+        self.status = status;
+        self.old_registers = chip.registers();
+        self.old_memory = chip.memory().to_vec();
+    }
+
+    fn message(&mut self, line: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(line);
+    }
+
+    fn scroll_memory(&mut self, rows: i32) {
+        let last = (MEMORY_SIZE - BOTTOM_ROWS * MEMORY_COLUMNS) as i32;
+        let addr = self.memory as i32 + rows * MEMORY_COLUMNS as i32;
+        self.memory = addr.clamp(0, last) as u16;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Style {
+    Normal,
+    /// Status bar and pane headers.
+    Title,
+    Dim,
+    /// Changed since execution last stopped.
+    Changed,
+    /// The PC, and the byte I points at.
+    Current,
+    /// The disassembly line the cursor is on.
+    Cursor,
+    Breakpoint,
+}
+
+/// A grid of styled characters. Text outside it is clipped.
+#[derive(Clone, Debug, PartialEq)]
+struct Screen {
+    width: usize,
+    height: usize,
+    cells: Vec<(char, Style)>,
+}
+
+impl Screen {
+    fn new(width: usize, height: usize) -> Self {
+        Screen {
+            width,
+            height,
+            cells: vec![(' ', Style::Normal); width * height],
+        }
+    }
+
+    /// Write `text` at (x, y), returning the column after it.
+    fn print(&mut self, x: usize, y: usize, text: &str, style: Style) -> usize {
+        let mut x = x;
+        for c in text.chars() {
+            if x < self.width && y < self.height {
+                self.cells[y * self.width + x] = (c, style);
+            }
+            x += 1;
+        }
+        x
+    }
+
+    /// Write `text` padded with spaces to `width` columns.
+    fn print_padded(&mut self, x: usize, y: usize, width: usize, text: &str, style: Style) {
+        let padded = format!("{:<width$}", text, width = width);
+        self.print(x, y, &padded.chars().take(width).collect::<String>(), style);
+    }
+
+    /// Text from column `x` to the end of row `y`.
+    #[cfg(test)]
+    fn text(&self, x: usize, y: usize) -> String {
+        let row = &self.cells[y * self.width + x..(y + 1) * self.width];
+        row.iter().map(|&(c, _)| c).collect()
+    }
+
+    #[cfg(test)]
+    fn style(&self, x: usize, y: usize) -> Style {
+        self.cells[y * self.width + x].1
+    }
+}
+
+fn render(
+    screen: &mut Screen,
+    chip: &Chip8,
+    view: &View,
+    breakpoints: &Breakpoints,
+    symbols: &Symbols,
+    running: bool,
+) {
+    let width = screen.width;
+    let top_rows = screen
+        .height
+        .saturating_sub(BOTTOM_ROWS + 6)
+        .max(MIN_TOP_ROWS);
+    let bottom_y = top_rows + 3;
+    let registers = chip.registers();
+
+    let state = if running { "Running" } else { &view.status };
+    let status = format!(" {}  |  cycle {}", state, chip.cycles());
+    screen.print_padded(0, 0, width, &status, Style::Title);
+
+    // Disassembly, with the cursor in the middle and labels on lines of
+    // their own
+    screen.print_padded(0, 1, LEFT_WIDTH, " Disassembly", Style::Title);
+    let label_rows = |addr: u16| symbols.label(addr).is_some() as usize;
+    let mut start = view.cursor;
+    let mut above = label_rows(start);
+    while start >= 2 && above + 1 + label_rows(start - 2) <= top_rows / 2 {
+        start -= 2;
+        above += 1 + label_rows(start);
+    }
+    let end_y = top_rows + 2;
+    let mut y = 2;
+    for addr in (start..MEMORY_SIZE as u16 - 1).step_by(2) {
+        if let Some(label) = symbols.label(addr) {
+            screen.print(1, y, &format!("{}:", label), Style::Normal);
+            y += 1;
+        }
+        if y >= end_y {
+            break;
+        }
+        let memory = chip.memory();
+        let opcode = (memory[addr as usize] as u16) << 8 | memory[addr as usize + 1] as u16;
+        let line = disassemble_opcode(addr as usize, opcode, symbols);
+        let style = if addr == view.cursor && !running {
+            Style::Cursor
+        } else if addr == registers.pc {
+            Style::Current
+        } else {
+            Style::Normal
+        };
+        screen.print_padded(0, y, LEFT_WIDTH, "", style);
+        if breakpoints.contains(addr) {
+            screen.print(0, y, "\u{25cf}", Style::Breakpoint);
+        }
+        if addr == registers.pc {
+            screen.print(1, y, "\u{25b6}", style);
+        }
+        screen.print(3, y, &line, style);
+        y += 1;
+    }
+
+    // Registers, highlighted if they changed
+    let old = &view.old_registers;
+    screen.print_padded(
+        RIGHT_X,
+        1,
+        STACK_X - RIGHT_X - 1,
+        " Registers",
+        Style::Title,
+    );
+    let changed = |is_changed: bool| {
+        if is_changed {
+            Style::Changed
+        } else {
+            Style::Normal
+        }
+    };
+    let mut field = |x: usize, y: usize, name: &str, value: String, is_changed: bool| {
+        let x = screen.print(x, y, name, Style::Dim);
+        screen.print(x + 1, y, &value, changed(is_changed));
+    };
+    for n in 0..8 {
+        for &(column, n) in &[(0, n), (10, n + 8)] {
+            let name = format!("V{:X}", n);
+            let value = format!("{:02x}", registers.v[n]);
+            field(
+                RIGHT_X + 1 + column,
+                2 + n % 8,
+                &name,
+                value,
+                registers.v[n] != old.v[n],
+            );
+        }
+    }
+    let y = 11;
+    field(
+        RIGHT_X + 1,
+        y,
+        "I ",
+        format!("{:04x}", registers.i),
+        registers.i != old.i,
+    );
+    field(
+        RIGHT_X + 11,
+        y,
+        "PC",
+        format!("{:04x}", registers.pc),
+        registers.pc != old.pc,
+    );
+    field(
+        RIGHT_X + 1,
+        y + 1,
+        "DT",
+        format!("{:02x}", registers.delay),
+        registers.delay != old.delay,
+    );
+    field(
+        RIGHT_X + 11,
+        y + 1,
+        "ST",
+        format!("{:02x}", registers.sound),
+        registers.sound != old.sound,
+    );
+    field(
+        RIGHT_X + 1,
+        y + 2,
+        "SP",
+        format!("{:x}", registers.sp),
+        registers.sp != old.sp,
+    );
+
+    // Return addresses, innermost first
+    screen.print_padded(STACK_X, 1, 12, " Stack", Style::Title);
+    let stack = &registers.stack[..registers.sp as usize];
+    for (row, addr) in stack.iter().rev().enumerate().take(top_rows) {
+        let n = stack.len() - 1 - row;
+        screen.print(STACK_X + 1, 2 + row, &format!("{:x} ", n), Style::Dim);
+        let location = symbols.locate(*addr).unwrap_or_default();
+        screen.print(
+            STACK_X + 3,
+            2 + row,
+            &format!("{:04x} {}", addr, location),
+            Style::Normal,
+        );
+    }
+
+    // Memory, highlighting bytes that changed and the one I points at
+    screen.print_padded(0, bottom_y, LEFT_WIDTH, " Memory", Style::Title);
+    for row in 0..BOTTOM_ROWS {
+        let y = bottom_y + 1 + row;
+        let addr = view.memory as usize + row * MEMORY_COLUMNS;
+        if addr >= MEMORY_SIZE {
+            break;
+        }
+        screen.print(1, y, &format!("{:04x}", addr), Style::Dim);
+        for column in 0..MEMORY_COLUMNS {
+            let addr = addr + column;
+            let value = chip.memory()[addr];
+            let style = if value != view.old_memory[addr] {
+                Style::Changed
+            } else if addr == registers.i as usize {
+                Style::Current
+            } else {
+                Style::Normal
+            };
+            screen.print(7 + column * 3, y, &format!("{:02x}", value), style);
+        }
+    }
+
+    screen.print_padded(RIGHT_X, bottom_y, WIDTH, " Display", Style::Title);
+    let cells = render_cells(Glyphs::HalfBlock, &chip.display, WIDTH, HEIGHT);
+    for (row, line) in cells.chunks(WIDTH).enumerate() {
+        let line: String = line.iter().collect();
+        screen.print(RIGHT_X, bottom_y + 1 + row, &line, Style::Normal);
+    }
+
+    let y = bottom_y + BOTTOM_ROWS + 1;
+    match &view.prompt {
+        Some(prompt) => {
+            screen.print(1, y, &format!(":{}_", prompt), Style::Normal);
+            let help = format!("Enter run  Esc cancel  {}", CHEAT_COMMANDS);
+            screen.print(1, y + 1, &help, Style::Dim);
+        }
+        None => {
+            let message = view.messages.back().map_or("", String::as_str);
+            screen.print(1, y, message, Style::Dim);
+            let help = if running { HELP_RUNNING } else { HELP_STOPPED };
+            screen.print(1, y + 1, help, Style::Dim);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            command(&key(KeyCode::Char('s')), false, false),
+            Some(Command::Step)
+        );
+        assert_eq!(
+            command(&key(KeyCode::F(10)), false, false),
+            Some(Command::Next)
+        );
+        let shift_f11 = KeyEvent::new(KeyCode::F(11), KeyModifiers::SHIFT);
+        assert_eq!(command(&shift_f11, false, false), Some(Command::Out));
+        assert_eq!(
+            command(&key(KeyCode::F(9)), false, false),
+            Some(Command::ToggleBreakpoint)
+        );
+        assert_eq!(
+            command(&key(KeyCode::Char('r')), false, false),
+            Some(Command::StepBack)
+        );
+        let shift_r = KeyEvent::new(KeyCode::Char('R'), KeyModifiers::SHIFT);
+        assert_eq!(command(&shift_r, false, false), Some(Command::ContinueBack));
+
+        // Letters are keypad keys while running
+        assert_eq!(command(&key(KeyCode::Char('s')), true, false), None);
+        assert_eq!(
+            command(&key(KeyCode::Esc), true, false),
+            Some(Command::Pause)
+        );
+        assert_eq!(
+            command(&key(KeyCode::F(5)), false, false),
+            Some(Command::Continue)
+        );
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(command(&ctrl_c, true, false), Some(Command::Quit));
+
+        // Keys edit the prompt while it's open
+        assert_eq!(
+            command(&key(KeyCode::Char(':')), false, false),
+            Some(Command::Prompt)
+        );
+        assert_eq!(
+            command(&key(KeyCode::Char('q')), false, true),
+            Some(Command::Type('q'))
+        );
+        assert_eq!(
+            command(&key(KeyCode::Enter), false, true),
+            Some(Command::Submit)
+        );
+        assert_eq!(
+            command(&key(KeyCode::Esc), false, true),
+            Some(Command::Cancel)
+        );
+        assert_eq!(command(&ctrl_c, false, true), Some(Command::Quit));
+    }
+
+    #[test]
+    fn panes() {
+        let mut chip = Chip8::new();
+        // 200: LD I, 300
+        // 202: LD V1, 5
+        // 204: LD [I], V1
+        // 206: CALL 20a
+        // 208: JP 208
+        // 20a: DRW V0, V0, 1
+        chip.load_binary(vec![
+            0xA3, 0x00, 0x61, 0x05, 0xF1, 0x55, 0x22, 0x0A, 0x12, 0x08, 0xD0, 0x01,
+        ]);
+        let mut view = View::new(&chip);
+        view.memory = 0x300;
+        for _ in 0..4 {
+            chip.step();
+        }
+        let mut breakpoints = Breakpoints::new();
+        breakpoints.insert(0x20a);
+        view.cursor = chip.pc();
+        view.status = "Stepped".to_string();
+
+        let mut screen = Screen::new(110, 40);
+        render(
+            &mut screen,
+            &chip,
+            &view,
+            &breakpoints,
+            &Symbols::new(),
+            false,
+        );
+        assert!(screen.text(0, 0).starts_with(" Stepped  |  cycle 4 "));
+        assert_eq!(screen.style(0, 0), Style::Title);
+
+        // The cursor is centred, on the PC, and has a breakpoint
+        let top_rows = 40 - BOTTOM_ROWS - 6;
+        let y = (2..2 + top_rows)
+            .find(|&y| screen.text(0, y).contains("020a d0 01"))
+            .unwrap();
+        assert_eq!(y, 2 + top_rows / 2);
+        assert!(screen
+            .text(0, y)
+            .starts_with("\u{25cf}\u{25b6} 020a d0 01 DRW"));
+        assert_eq!(screen.style(0, y), Style::Breakpoint);
+        assert_eq!(screen.style(3, y), Style::Cursor);
+        assert!(screen.text(0, 2).starts_with("   01f8 00 00"));
+
+        // V1 and I changed, V0 didn't
+        assert!(screen.text(RIGHT_X, 3).starts_with(" V1 05     V9 00"));
+        assert_eq!(screen.style(RIGHT_X + 4, 3), Style::Changed);
+        assert_eq!(screen.style(RIGHT_X + 4, 2), Style::Normal);
+        assert!(screen.text(RIGHT_X, 11).starts_with(" I  0300   PC 020a"));
+        assert!(screen.text(STACK_X, 2).starts_with(" 0 0208"));
+
+        // The written byte that changed is highlighted, and I points at 300
+        let memory_y = top_rows + 4;
+        assert!(screen.text(0, memory_y).starts_with(" 0300  00 05 00"));
+        assert_eq!(screen.style(10, memory_y), Style::Changed);
+        assert_eq!(screen.style(7, memory_y), Style::Current);
+        assert_eq!(screen.style(13, memory_y), Style::Normal);
+
+        // Stopping again resets the highlighting
+        view.stop(&chip, "Paused".to_string());
+        render(
+            &mut screen,
+            &chip,
+            &view,
+            &breakpoints,
+            &Symbols::new(),
+            false,
+        );
+        assert_eq!(screen.style(10, memory_y), Style::Normal);
+        assert_eq!(screen.style(RIGHT_X + 4, 3), Style::Normal);
+    }
+
+    #[test]
+    fn labels() {
+        let mut chip = Chip8::new();
+        // 200: CALL 206
+        // 202: JP 202
+        // 204: (padding)
+        // 206: JP 206
+        chip.load_binary(vec![0x22, 0x06, 0x12, 0x02, 0x00, 0x00, 0x12, 0x06]);
+        chip.step();
+        let symbols = Symbols::parse("200 main\n206 spin", Path::new("")).unwrap();
+        let view = View::new(&chip);
+        let mut screen = Screen::new(110, 40);
+        render(
+            &mut screen,
+            &chip,
+            &view,
+            &Breakpoints::new(),
+            &symbols,
+            false,
+        );
+
+        // Labels get lines of their own, keeping the cursor centred
+        let top_rows = 40 - BOTTOM_ROWS - 6;
+        let y = 2 + top_rows / 2;
+        assert!(screen.text(0, y).contains("0206 12 06 JP         spin"));
+        assert!(screen.text(0, y - 1).starts_with(" spin:"));
+        assert!(screen.text(0, y - 2).contains("0204 00 00"));
+        assert!(screen.text(0, y - 4).contains("0200 22 06 CALL       spin"));
+        assert!(screen.text(0, y - 5).starts_with(" main:"));
+        assert!(screen.text(STACK_X, 2).starts_with(" 0 0202 main+0x2"));
+    }
+
+    #[test]
+    fn clipping() {
+        let mut screen = Screen::new(4, 1);
+        assert_eq!(screen.print(2, 0, "abc", Style::Normal), 5);
+        screen.print(0, 3, "ignored", Style::Normal);
+        assert_eq!(screen.text(0, 0), "  ab");
+    }
+}
@@ -0,0 +1,340 @@
+use super::condition::{Context, Expr};
+use crate::chip8::{Chip8, Registers};
+use crate::runner::Hook;
+use crate::symbols::Symbols;
+
+/// An expression checked after every instruction, which stops execution or
+/// logs a line each time it's true.
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    /// The expression as written.
+    pub text: String,
+    condition: Expr,
+    /// `None` to stop, or the message to log with `{expr}` parts filled in.
+    log: Option<Vec<Part>>,
+    hit_condition: Option<HitCondition>,
+    /// Times the condition has been true.
+    pub hits: u64,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Text(String),
+    Expr(Expr),
+}
+
+/// Which hits take effect, like `5` for only the fifth, `>= 5` for the fifth
+/// onwards or `% 5` for every fifth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HitCondition {
+    Equal(u64),
+    Greater(u64),
+    AtLeast(u64),
+    Multiple(u64),
+}
+
+impl HitCondition {
+    fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let error = || format!("Invalid hit count {}, expected e.g. 5, >= 5 or % 5", text);
+        let (make, n): (fn(u64) -> Self, &str) = if let Some(n) = text.strip_prefix(">=") {
+            (HitCondition::AtLeast, n)
+        } else if let Some(n) = text.strip_prefix('>') {
+            (HitCondition::Greater, n)
+        } else if let Some(n) = text.strip_prefix('%') {
+            (HitCondition::Multiple, n)
+        } else {
+            (HitCondition::Equal, text.strip_prefix("==").unwrap_or(text))
+        };
+        match n.trim().parse() {
+            Ok(0) if text.starts_with('%') => Err(error()),
+            Ok(n) => Ok(make(n)),
+            Err(_) => Err(error()),
+        }
+    }
+
+    fn matches(self, hits: u64) -> bool {
+        match self {
+            HitCondition::Equal(n) => hits == n,
+            HitCondition::Greater(n) => hits > n,
+            HitCondition::AtLeast(n) => hits >= n,
+            HitCondition::Multiple(n) => hits.is_multiple_of(n),
+        }
+    }
+}
+
+impl Watchpoint {
+    /// A watchpoint that stops when `condition` is true. Labels in it are
+    /// looked up in `symbols`.
+    pub fn new(condition: &str, symbols: &Symbols) -> Result<Self, String> {
+        Ok(Watchpoint {
+            text: condition.trim().to_string(),
+            condition: Expr::parse_with_symbols(condition, symbols)?,
+            log: None,
+            hit_condition: None,
+            hits: 0,
+        })
+    }
+
+    /// A watchpoint that logs `message` instead of stopping, or the cycle,
+    /// address and condition if there's no message. Expressions in braces
+    /// are replaced with their values, as in `sprite at {vc},{vd}`.
+    pub fn log(condition: &str, message: Option<&str>, symbols: &Symbols) -> Result<Self, String> {
+        let mut watchpoint = Watchpoint::new(condition, symbols)?;
+        watchpoint.log = Some(match message {
+            Some(message) => parse_message(message, symbols)?,
+            None => Vec::new(),
+        });
+        Ok(watchpoint)
+    }
+
+    /// Only act on some hits, like `5`, `> 5`, `>= 5` or `% 5`.
+    pub fn hit_condition(mut self, text: &str) -> Result<Self, String> {
+        self.hit_condition = Some(HitCondition::parse(text)?);
+        Ok(self)
+    }
+
+    pub fn is_log(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// Value of the condition while stopped, for showing to the user.
+    pub fn evaluate(text: &str, chip: &Chip8, symbols: &Symbols) -> Result<i64, String> {
+        Ok(Expr::parse_with_symbols(text, symbols)?.value(&Context::current(chip)))
+    }
+
+    fn message(&self, context: &Context, pc: u16) -> String {
+        let parts = self.log.as_deref().unwrap_or(&[]);
+        if parts.is_empty() {
+            return format!(
+                "{:08} {:04x} {} (hit {})",
+                context.cycle, pc, self.text, self.hits
+            );
+        }
+        parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Expr(expr) => format!("{:#x}", expr.value(context)),
+            })
+            .collect()
+    }
+}
+
+fn parse_message(message: &str, symbols: &Symbols) -> Result<Vec<Part>, String> {
+    let mut parts = Vec::new();
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed `{{` in {}", message))?;
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].to_string()));
+        }
+        let expr = &rest[start + 1..start + end];
+        parts.push(Part::Expr(Expr::parse_with_symbols(expr, symbols)?));
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(Part::Text(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+/// A watchpoint that stopped execution.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub id: usize,
+    /// Address of the instruction that made the condition true.
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+/// Watchpoints by id, with the lines they logged and the one that last
+/// stopped execution.
+#[derive(Clone, Debug, Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+    log: Vec<String>,
+    triggered: Option<Hit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints::default()
+    }
+
+    /// Add a watchpoint and return its id, counting from 1.
+    pub fn insert(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.watchpoints.push((self.next_id, watchpoint));
+        self.next_id
+    }
+
+    /// Returns false if there was no watchpoint with this id.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|(other, _)| *other != id);
+        self.watchpoints.len() != len
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Watchpoint> {
+        self.iter()
+            .find(|(other, _)| *other == id)
+            .map(|(_, watchpoint)| watchpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints
+            .iter()
+            .map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watchpoints.is_empty()
+    }
+
+    /// Check every watchpoint after `chip` executed `opcode`, which started
+    /// with the registers in `before`.
+    pub fn check(&mut self, chip: &Chip8, before: Registers, opcode: u16) {
+        let context = Context::new(before, chip);
+        for (id, watchpoint) in &mut self.watchpoints {
+            if !watchpoint.condition.is_true(&context) {
+                continue;
+            }
+            watchpoint.hits += 1;
+            if let Some(hit_condition) = watchpoint.hit_condition {
+                if !hit_condition.matches(watchpoint.hits) {
+                    continue;
+                }
+            }
+            if watchpoint.is_log() {
+                self.log.push(watchpoint.message(&context, before.pc));
+            } else if self.triggered.is_none() {
+                self.triggered = Some(Hit {
+                    id: *id,
+                    pc: before.pc,
+                    opcode,
+                });
+            }
+        }
+    }
+
+    /// Whether a watchpoint stopped execution since the last `take_triggered`.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.is_some()
+    }
+
+    pub fn take_triggered(&mut self) -> Option<Hit> {
+        self.triggered.take()
+    }
+
+    /// Lines logged since the last call.
+    pub fn take_log(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.log)
+    }
+}
+
+/// `Runner::run` prints what watchpoints log and stops when one triggers;
+/// debuggers stop with `StopReason::Watchpoint`.
+impl Hook for Watchpoints {
+    fn instruction(&mut self, chip: &Chip8, before: &Registers, opcode: u16) {
+        if !self.is_empty() {
+            self.check(chip, *before, opcode);
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        self.is_triggered()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Run the program with the watchpoints for `steps` instructions.
+    fn run(watchpoints: &mut Watchpoints, steps: usize) -> Chip8 {
+        let mut chip = Chip8::new();
+        // 200: ADD V3, 1
+        // 202: JP 200
+        chip.load_binary(vec![0x73, 0x01, 0x12, 0x00]);
+        for _ in 0..steps {
+            let (before, opcode) = (chip.registers(), chip.opcode());
+            chip.step();
+            watchpoints.check(&chip, before, opcode);
+        }
+        chip
+    }
+
+    #[test]
+    fn breaks_and_hit_counts() {
+        let mut watchpoints = Watchpoints::new();
+        let every = watchpoints.insert(Watchpoint::new("v3 changes", &Symbols::new()).unwrap());
+        let third = Watchpoint::new("v3 changes", &Symbols::new()).unwrap();
+        let third = watchpoints.insert(third.hit_condition("3").unwrap());
+
+        run(&mut watchpoints, 1);
+        assert_eq!(watchpoints.take_triggered().map(|hit| hit.id), Some(every));
+        assert!(watchpoints.remove(every));
+        assert!(!watchpoints.remove(every));
+
+        run(&mut watchpoints, 3);
+        assert_eq!(
+            watchpoints.take_triggered(),
+            Some(Hit {
+                id: third,
+                pc: 0x200,
+                opcode: 0x7301
+            })
+        );
+        assert_eq!(watchpoints.get(third).unwrap().hits, 3);
+        assert!(!watchpoints.is_triggered());
+    }
+
+    #[test]
+    fn logs() {
+        let mut watchpoints = Watchpoints::new();
+        let every_other = Watchpoint::log("pc == 0x202", None, &Symbols::new()).unwrap();
+        watchpoints.insert(every_other.hit_condition("% 2").unwrap());
+        let message =
+            Watchpoint::log("v3 >= 2", Some("v3 is {v3}, i is {i}!"), &Symbols::new()).unwrap();
+        watchpoints.insert(message.hit_condition("% 3").unwrap());
+
+        run(&mut watchpoints, 8);
+        assert_eq!(
+            watchpoints.take_log(),
+            [
+                "00000003 0200 pc == 0x202 (hit 2)",
+                "v3 is 0x3, i is 0x0!",
+                "00000007 0200 pc == 0x202 (hit 4)",
+                "v3 is 0x4, i is 0x0!",
+            ]
+        );
+        assert!(watchpoints.take_log().is_empty());
+        assert!(!watchpoints.is_triggered());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Watchpoint::new("v3 ==", &Symbols::new()).is_err());
+        assert!(Watchpoint::log("v3", Some("{v3"), &Symbols::new()).is_err());
+        assert!(Watchpoint::log("v3", Some("{vz}"), &Symbols::new()).is_err());
+        let watchpoint = || Watchpoint::new("v3", &Symbols::new()).unwrap();
+        assert!(watchpoint().hit_condition("% 0").is_err());
+        assert!(watchpoint().hit_condition("lots").is_err());
+        assert!(watchpoint().hit_condition("== 2").is_ok());
+        assert!(watchpoint().hit_condition("> 2").is_ok());
+    }
+
+    #[test]
+    fn evaluate() {
+        let chip = run(&mut Watchpoints::new(), 3);
+        let symbols = Symbols::parse("202 again", Path::new("")).unwrap();
+        assert_eq!(Watchpoint::evaluate("v3 + 1", &chip, &symbols), Ok(3));
+        assert_eq!(Watchpoint::evaluate("v3 changes", &chip, &symbols), Ok(0));
+        assert_eq!(Watchpoint::evaluate("pc == again", &chip, &symbols), Ok(1));
+    }
+}
@@ -1,4 +1,33 @@
-pub fn disassemble_opcode(pc: usize, opcode: u16) -> String {
+use crate::symbols::Symbols;
+
+pub fn disassemble_opcode(pc: usize, opcode: u16, symbols: &Symbols) -> String {
+    format!(
+        "{:04x} {:02x} {:02x} {}",
+        pc,
+        opcode >> 8,
+        opcode & 0xFF,
+        mnemonic_with_symbols(opcode, symbols)
+    )
+}
+
+/// `mnemonic` with the address of a jump, call, `SYS` or `LD I` replaced by
+/// its label, like `CALL       draw_paddle`.
+pub fn mnemonic_with_symbols(opcode: u16, symbols: &Symbols) -> String {
+    let text = mnemonic(opcode);
+    let addr = opcode & 0x0FFF;
+    let has_address = match opcode >> 12 {
+        0x0 => !matches!(opcode & 0xFF, 0xE0 | 0xEE),
+        0x1 | 0x2 | 0xA | 0xB => true,
+        _ => false,
+    };
+    match symbols.label(addr) {
+        Some(label) if has_address => text.replace(&format!("${:03x}", addr), label),
+        _ => text,
+    }
+}
+
+/// Just the instruction, like `DRW        V1, V2, #$f`.
+pub fn mnemonic(opcode: u16) -> String {
     let first_nibble = (opcode >> 12 & 0x000F) as u8;
 
     let assembly: String = match first_nibble {
@@ -9,6 +38,18 @@ pub fn disassemble_opcode(pc: usize, opcode: u16) -> String {
             0xEE => {
                 format!("{: <10}", "RET")
             }
+            // SUPER-CHIP, then XO-CHIP
+            0xC0..=0xCF if opcode < 0x0100 => {
+                format!("{: <10} #${:01x}", "SCD", opcode & 0x000F)
+            }
+            0xFB if opcode < 0x0100 => format!("{: <10}", "SCR"),
+            0xFC if opcode < 0x0100 => format!("{: <10}", "SCL"),
+            0xFD if opcode < 0x0100 => format!("{: <10}", "EXIT"),
+            0xFE if opcode < 0x0100 => format!("{: <10}", "LOW"),
+            0xFF if opcode < 0x0100 => format!("{: <10}", "HIGH"),
+            0xD0..=0xDF if opcode < 0x0100 => {
+                format!("{: <10} #${:01x}", "SCU", opcode & 0x000F)
+            }
             _ => {
                 let addr = opcode & 0x0FFF;
                 format!("{: <10} ${:03x}", "SYS", addr)
@@ -35,7 +76,12 @@ pub fn disassemble_opcode(pc: usize, opcode: u16) -> String {
         0x05 => {
             let regx = opcode >> 8 & 0x000F;
             let regy = opcode >> 4 & 0x000F;
-            format!("{: <10} V{:01x}, V{:01x}", "SE", regx, regy)
+            match opcode & 0x000F {
+                // XO-CHIP saves and loads a range of registers
+                0x2 => format!("{: <10} [I], V{:01x}-V{:01x}", "LD", regx, regy),
+                0x3 => format!("{: <10} V{:01x}-V{:01x}, [I]", "LD", regx, regy),
+                _ => format!("{: <10} V{:01x}, V{:01x}", "SE", regx, regy),
+            }
         }
         0x06 => {
             let reg = opcode >> 8 & 0x000F;
@@ -127,6 +173,13 @@ pub fn disassemble_opcode(pc: usize, opcode: u16) -> String {
             format!("{: <10} V{:01x}", "SKP", reg)
         }
         0x0f => match (opcode & 0x00FF) as u8 {
+            // XO-CHIP's 4 byte instruction, with the address in the next 2
+            0x00 if opcode == 0xF000 => format!("{: <10} I, long", "LD"),
+            0x01 => {
+                let plane = opcode >> 8 & 0x000F;
+                format!("{: <10} #${:01x}", "PLANE", plane)
+            }
+            0x02 if opcode == 0xF002 => format!("{: <10}", "AUDIO"),
             0x07 => {
                 let reg = opcode >> 8 & 0x000F;
                 format!("{: <10} V{:01x}, DT", "LD", reg)
@@ -163,16 +216,57 @@ pub fn disassemble_opcode(pc: usize, opcode: u16) -> String {
                 let reg = opcode >> 8 & 0x000F;
                 format!("{: <10} V{:01x}, [I]", "LD", reg)
             }
+            // SUPER-CHIP
+            0x30 => {
+                let reg = opcode >> 8 & 0x000F;
+                format!("{: <10} HF, V{:01x}", "LD", reg)
+            }
+            0x75 => {
+                let reg = opcode >> 8 & 0x000F;
+                format!("{: <10} R, V{:01x}", "LD", reg)
+            }
+            0x85 => {
+                let reg = opcode >> 8 & 0x000F;
+                format!("{: <10} V{:01x}, R", "LD", reg)
+            }
+            // XO-CHIP
+            0x3a => {
+                let reg = opcode >> 8 & 0x000F;
+                format!("{: <10} V{:01x}", "PITCH", reg)
+            }
             _ => "UNKNOWN".to_string(),
         },
         _ => "UNKNOWN".to_string(),
     };
 
-    format!(
-        "{:04x} {:02x} {:02x} {}",
-        pc,
-        opcode >> 8,
-        opcode & 0xFF,
-        assembly
-    )
+    assembly.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn labels() {
+        let symbols = Symbols::parse("22a main_loop\n2f0 draw_paddle", Path::new("")).unwrap();
+        assert_eq!(
+            disassemble_opcode(0x230, 0x122a, &symbols),
+            "0230 12 2a JP         main_loop"
+        );
+        assert_eq!(
+            mnemonic_with_symbols(0x22f0, &symbols),
+            "CALL       draw_paddle"
+        );
+        assert_eq!(
+            mnemonic_with_symbols(0xB22A, &symbols),
+            "JP         V0, main_loop"
+        );
+        // Only addresses are named, not bytes that happen to match
+        assert_eq!(
+            mnemonic_with_symbols(0x622a, &symbols),
+            "LD         V2, #$2a"
+        );
+        assert_eq!(mnemonic_with_symbols(0x12f2, &symbols), "JP         $2f2");
+    }
 }
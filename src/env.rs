@@ -0,0 +1,418 @@
+//! Environments for training reinforcement learning agents on CHIP-8 games.
+//!
+//! What counts as reward and when an episode ends depend on the game, so
+//! they're given per ROM in an INI file keyed by SHA-1, like the
+//! compatibility database, using the expressions from `--break`:
+//!
+//! ```text
+//! [b232ef880bd6060fb45fa6effed7edf0ae95670e]
+//! # Reward is how much this goes up in a step
+//! reward = [0x2f5] - [0x2f6]
+//! # The episode ends once this is true
+//! done = [0x2f7] == 0
+//! # Keys the agent can hold, by action number
+//! actions = none, 1, 4
+//! # Frames each step runs for
+//! frames = 4
+//! # Steps before the episode is cut off
+//! max_steps = 10000
+//! # Instructions per frame
+//! speed = 15
+//! ```
+//!
+//! Only `reward` and `done` are needed. By default the actions are no key
+//! followed by keys 0-F, each step runs one frame, episodes only end when
+//! `done` is true, and the speed comes from the compatibility database.
+
+use crate::chip8::{Chip8, Quirks, HEIGHT, WIDTH};
+use crate::compat;
+use crate::debug::{Context, Expr};
+use crate::runner::DEFAULT_SPEED;
+use crate::settings::{self, Section};
+use sha1_smol::Sha1;
+use std::collections::BTreeMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+
+/// The display, one byte per pixel, 1 for on and 0 for off, row by row.
+pub type Observation = Vec<u8>;
+
+/// Size of an `Observation`.
+pub const OBSERVATION_SIZE: usize = WIDTH * HEIGHT;
+
+/// How to score a game, from a section of a spec file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RewardSpec {
+    /// Its increase over a step is the step's reward.
+    pub reward: Expr,
+    /// The episode ends when this is true after a step.
+    pub done: Expr,
+    /// The key each action holds down, if any.
+    pub actions: Vec<Option<usize>>,
+    /// Frames each step runs for.
+    pub frames: usize,
+    pub max_steps: Option<usize>,
+    /// Instructions per frame, if not from the compatibility database.
+    pub speed: Option<usize>,
+}
+
+impl RewardSpec {
+    pub fn from_section(section: &Section) -> Result<Self, String> {
+        let expr = |key: &str| {
+            let text = section
+                .get(key)
+                .ok_or_else(|| format!("Missing {} expression", key))?;
+            Expr::parse(text).map_err(|e| format!("Invalid {}: {}", key, e))
+        };
+        let actions = match section.get("actions") {
+            Some(text) => text
+                .split(',')
+                .map(|key| parse_action(key.trim()))
+                .collect::<Result<_, _>>()?,
+            None => std::iter::once(None).chain((0..16).map(Some)).collect(),
+        };
+        Ok(RewardSpec {
+            reward: expr("reward")?,
+            done: expr("done")?,
+            actions,
+            frames: parse_number(section, "frames")?.unwrap_or(1),
+            max_steps: parse_number(section, "max_steps")?,
+            speed: parse_number(section, "speed")?,
+        })
+    }
+
+    /// Load a spec file, keyed by ROM SHA-1.
+    pub fn load(path: &Path) -> Result<BTreeMap<String, Self>, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        settings::parse(&text)
+            .iter()
+            .map(|(hash, section)| {
+                let spec = RewardSpec::from_section(section)
+                    .map_err(|e| format!("{}: [{}]: {}", path.display(), hash, e))?;
+                Ok((hash.clone(), spec))
+            })
+            .collect()
+    }
+}
+
+fn parse_action(text: &str) -> Result<Option<usize>, String> {
+    if text == "none" {
+        return Ok(None);
+    }
+    match usize::from_str_radix(text, 16) {
+        Ok(key) if key < 16 => Ok(Some(key)),
+        _ => Err(format!(
+            "Invalid action {}, expected a key 0-F or none",
+            text
+        )),
+    }
+}
+
+fn parse_number<T: FromStr>(section: &Section, key: &str) -> Result<Option<T>, String> {
+    section
+        .get(key)
+        .map(|text| {
+            text.parse()
+                .map_err(|_| format!("Invalid {} {}", key, text))
+        })
+        .transpose()
+}
+
+/// A game an agent can play one step at a time.
+pub struct Chip8Env {
+    rom: Vec<u8>,
+    spec: RewardSpec,
+    speed: usize,
+    quirks: Quirks,
+    chip: Chip8,
+    /// Seed for the next episode's random numbers.
+    seed: u64,
+    steps: usize,
+    /// The value of the reward expression after the last step.
+    score: i64,
+}
+
+impl Chip8Env {
+    /// Make an environment for `rom`, with its quirks from the compatibility
+    /// database. Episodes are seeded `seed`, `seed + 1` and so on.
+    pub fn new(rom: Vec<u8>, spec: RewardSpec, seed: u64) -> Self {
+        let info = compat::lookup(&Sha1::from(&rom).digest().to_string());
+        let speed = spec
+            .speed
+            .or_else(|| info.as_ref().and_then(|info| info.speed))
+            .unwrap_or(DEFAULT_SPEED);
+        let quirks = info.map(|info| info.quirks).unwrap_or_default();
+        let mut env = Chip8Env {
+            rom,
+            spec,
+            speed,
+            quirks,
+            chip: Chip8::with_seed(seed),
+            seed,
+            steps: 0,
+            score: 0,
+        };
+        env.reset();
+        env
+    }
+
+    /// Number of actions `step` takes.
+    pub fn actions(&self) -> usize {
+        self.spec.actions.len()
+    }
+
+    pub fn chip(&self) -> &Chip8 {
+        &self.chip
+    }
+
+    /// Start a new episode, returning the first observation.
+    pub fn reset(&mut self) -> Observation {
+        let mut chip = Chip8::with_seed(self.seed);
+        self.seed = self.seed.wrapping_add(1);
+        chip.quirks = self.quirks;
+        chip.load_binary(self.rom.clone());
+        self.chip = chip;
+        self.steps = 0;
+        self.score = self.spec.reward.value(&Context::current(&self.chip));
+        self.observation()
+    }
+
+    /// Hold down the action's key for a step, returning the observation
+    /// after it, the reward and whether the episode is over. An instruction
+    /// the emulator doesn't have also ends the episode. An action that isn't
+    /// below `actions()` is an error.
+    pub fn step(&mut self, action: usize) -> Result<(Observation, f64, bool), String> {
+        let mut keypad = [false; 16];
+        if let Some(key) = self.action(action)? {
+            keypad[key] = true;
+        }
+        let (chip, frames, speed) = (&mut self.chip, self.spec.frames, self.speed);
+        let crashed = panic::catch_unwind(AssertUnwindSafe(|| {
+            for _ in 0..frames {
+                chip.keypad = keypad;
+                for _ in 0..speed {
+                    chip.step();
+                }
+                chip.decrement_timers();
+            }
+        }))
+        .is_err();
+        self.steps += 1;
+
+        let context = Context::current(&self.chip);
+        let score = self.spec.reward.value(&context);
+        let reward = score.wrapping_sub(self.score) as f64;
+        self.score = score;
+        let done = crashed
+            || self.spec.done.is_true(&context)
+            || self.spec.max_steps.is_some_and(|max| self.steps >= max);
+        Ok((self.observation(), reward, done))
+    }
+
+    /// The key `action` holds, if it's a valid action.
+    fn action(&self, action: usize) -> Result<Option<usize>, String> {
+        self.spec.actions.get(action).copied().ok_or_else(|| {
+            format!(
+                "Invalid action {}, expected 0-{}",
+                action,
+                self.actions() - 1
+            )
+        })
+    }
+
+    pub fn observation(&self) -> Observation {
+        self.chip
+            .display
+            .iter()
+            .map(|&pixel| (pixel != 0) as u8)
+            .collect()
+    }
+}
+
+/// Many independent environments stepped together on several threads.
+pub struct BatchEnv {
+    envs: Vec<Chip8Env>,
+    threads: usize,
+}
+
+impl BatchEnv {
+    /// `count` environments for `rom`, seeded differently, stepped on as
+    /// many threads as there are CPUs.
+    pub fn new(rom: &[u8], spec: &RewardSpec, count: usize, seed: u64) -> Self {
+        let envs = (0..count as u64)
+            .map(|n| {
+                // Far enough apart that episodes don't share seeds
+                let seed = seed.wrapping_add(n << 32);
+                Chip8Env::new(rom.to_vec(), spec.clone(), seed)
+            })
+            .collect();
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        BatchEnv { envs, threads }
+    }
+
+    /// Step on at most `threads` threads.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    pub fn envs(&self) -> &[Chip8Env] {
+        &self.envs
+    }
+
+    pub fn reset(&mut self) -> Vec<Observation> {
+        self.envs.iter_mut().map(Chip8Env::reset).collect()
+    }
+
+    /// Step each environment with its action. Environments whose episode
+    /// ended are reset, so the next step starts a new one, but the
+    /// observation returned is still the last of the old episode. Actions
+    /// are checked before any environment steps.
+    pub fn step(&mut self, actions: &[usize]) -> Result<Vec<(Observation, f64, bool)>, String> {
+        if actions.len() != self.envs.len() {
+            return Err(format!(
+                "Got {} actions for {} environments",
+                actions.len(),
+                self.envs.len()
+            ));
+        }
+        for (env, &action) in self.envs.iter().zip(actions) {
+            env.action(action)?;
+        }
+        let chunk = self.envs.len().div_ceil(self.threads).max(1);
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .envs
+                .chunks_mut(chunk)
+                .zip(actions.chunks(chunk))
+                .map(|(envs, actions)| {
+                    scope.spawn(move || {
+                        envs.iter_mut()
+                            .zip(actions)
+                            .map(|(env, &action)| {
+                                let result = env.step(action)?;
+                                if result.2 {
+                                    env.reset();
+                                }
+                                Ok(result)
+                            })
+                            .collect::<Result<Vec<_>, String>>()
+                    })
+                })
+                .collect();
+            let mut results = Vec::new();
+            for handle in handles {
+                results.extend(handle.join().unwrap()?);
+            }
+            Ok(results)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts up at 0x300 twice a frame while key 5 is held.
+    const ROM: [u8; 16] = [
+        0xA3, 0x00, // 200: LD I, 300
+        0x62, 0x05, // 202: LD V2, 5
+        0xE2, 0x9E, // 204: SKP V2
+        0x12, 0x04, // 206: JP 204
+        0xF0, 0x65, // 208: LD V0, [I]
+        0x70, 0x01, // 20a: ADD V0, 1
+        0xF0, 0x55, // 20c: LD [I], V0
+        0x12, 0x04, // 20e: JP 204
+    ];
+
+    fn spec() -> RewardSpec {
+        let sections =
+            settings::parse("[rom]\nreward = [0x300]\ndone = [0x300] >= 6\nactions = none, 5\n");
+        RewardSpec::from_section(&sections["rom"]).unwrap()
+    }
+
+    #[test]
+    fn episodes() {
+        let mut env = Chip8Env::new(ROM.to_vec(), spec(), 1);
+        assert_eq!(env.actions(), 2);
+        assert_eq!(env.reset().len(), OBSERVATION_SIZE);
+        assert_eq!(env.step(0).unwrap().1, 0.0);
+        assert_eq!(env.step(1).unwrap().1, 2.0);
+        assert_eq!(env.step(1), Ok((vec![0; OBSERVATION_SIZE], 2.0, false)));
+        assert!(env.step(1).unwrap().2);
+        assert_eq!(
+            env.step(2),
+            Err("Invalid action 2, expected 0-1".to_string())
+        );
+        env.reset();
+        assert_eq!(env.chip().memory()[0x300], 0);
+    }
+
+    #[test]
+    fn specs() {
+        let spec = spec();
+        assert_eq!(spec.actions, vec![None, Some(5)]);
+        assert_eq!((spec.frames, spec.max_steps, spec.speed), (1, None, None));
+        let sections =
+            settings::parse("[a]\nreward = v0\n[b]\nreward = v0\ndone = 1\nactions = 10\n");
+        assert!(RewardSpec::from_section(&sections["a"]).is_err());
+        assert!(RewardSpec::from_section(&sections["b"]).is_err());
+
+        let mut section = sections["b"].clone();
+        section.remove("actions");
+        section.insert("done".to_string(), "0".to_string());
+        section.insert("max_steps".to_string(), "2".to_string());
+        let spec = RewardSpec::from_section(&section).unwrap();
+        assert_eq!(spec.actions.len(), 17);
+        let mut env = Chip8Env::new(ROM.to_vec(), spec, 1);
+        assert!(!env.step(0).unwrap().2);
+        assert!(env.step(0).unwrap().2);
+    }
+
+    #[test]
+    fn doc_example() {
+        let source = include_str!("env.rs");
+        let start = source.find("//! ```text\n").unwrap() + 12;
+        let end = start + source[start..].find("//! ```\n").unwrap();
+        let example: String = source[start..end]
+            .lines()
+            .map(|line| line.trim_start_matches("//!").trim_start().to_string() + "\n")
+            .collect();
+        let specs = settings::parse(&example);
+        let spec = RewardSpec::from_section(&specs["b232ef880bd6060fb45fa6effed7edf0ae95670e"]);
+        let spec = spec.unwrap();
+        assert_eq!(spec.actions, vec![None, Some(1), Some(4)]);
+        assert_eq!(
+            (spec.frames, spec.max_steps, spec.speed),
+            (4, Some(10000), Some(15))
+        );
+    }
+
+    #[test]
+    fn batches() {
+        let mut batch = BatchEnv::new(&ROM, &spec(), 5, 1);
+        batch.set_threads(2);
+        assert_eq!(batch.reset().len(), 5);
+        // The first step also runs the setup, so it only counts once
+        let results = batch.step(&[1, 0, 1, 0, 1]).unwrap();
+        let rewards: Vec<f64> = results.iter().map(|result| result.1).collect();
+        assert_eq!(rewards, vec![1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        // Finished episodes start again
+        for _ in 0..2 {
+            batch.step(&[1; 5]).unwrap();
+        }
+        let results = batch.step(&[1; 5]).unwrap();
+        assert_eq!((results[0].1, results[0].2), (2.0, true));
+        let results = batch.step(&[1; 5]).unwrap();
+        assert_eq!((results[0].1, results[0].2), (1.0, false));
+        assert_eq!(batch.envs()[0].chip().memory()[0x300], 1);
+
+        // A bad action is an error, and no environment steps
+        assert!(batch.step(&[1, 1, 1, 1, 7]).is_err());
+        assert!(batch.step(&[1]).is_err());
+        assert_eq!(batch.envs()[0].chip().memory()[0x300], 1);
+    }
+}
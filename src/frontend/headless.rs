@@ -0,0 +1,28 @@
+use super::Frontend;
+
+/// Runs without any input or output, optionally stopping after a number of frames.
+pub struct Headless {
+    frames: Option<usize>,
+    presented: usize,
+}
+
+impl Headless {
+    pub fn new(frames: Option<usize>) -> Self {
+        Headless {
+            frames,
+            presented: 0,
+        }
+    }
+}
+
+impl Frontend for Headless {
+    fn poll_input(&mut self, _keypad: &mut [bool; 16]) {}
+
+    fn present(&mut self, _display: &[u32]) {
+        self.presented += 1;
+    }
+
+    fn should_quit(&self) -> bool {
+        self.frames.is_some_and(|frames| self.presented >= frames)
+    }
+}
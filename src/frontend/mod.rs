@@ -0,0 +1,43 @@
+mod headless;
+mod palette;
+mod terminal;
+mod window;
+
+pub use headless::Headless;
+pub use palette::Palette;
+pub(crate) use terminal::{enter_raw_mode, leave_raw_mode, render_cells, HeldKeys};
+pub use terminal::{Glyphs, Terminal};
+pub use window::{DisplaySettings, Window};
+
+/// Emulator controls, as opposed to CHIP-8 keypad input.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    /// Start or stop recording video.
+    ToggleRecording,
+}
+
+/// Everything the emulator needs from the outside world: input, video and sound.
+///
+/// `Runner` drives a `Chip8` through any implementation of this, so the same
+/// loop serves the window, the terminal, headless runs and tests.
+pub trait Frontend {
+    /// Update the keypad with the keys currently held down.
+    fn poll_input(&mut self, keypad: &mut [bool; 16]);
+
+    /// Show a frame. Called once per frame whether or not the display changed.
+    fn present(&mut self, display: &[u32]);
+
+    /// Start sounding the buzzer.
+    fn play_tone(&mut self) {}
+
+    /// Silence the buzzer.
+    fn stop_tone(&mut self) {}
+
+    /// Hotkeys pressed since the last call.
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        Vec::new()
+    }
+
+    /// Whether the user asked to quit.
+    fn should_quit(&self) -> bool;
+}
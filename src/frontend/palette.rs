@@ -0,0 +1,118 @@
+use std::fmt;
+
+/// Colours used to draw the display, as 0RGB.
+///
+/// Index 0 is the background and 1 the foreground. The display has a single
+/// plane, so there are no more colours to pick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub colors: [u32; 2],
+}
+
+/// Built-in palettes, by name.
+const NAMED: [(&str, [u32; 2]); 8] = [
+    ("classic", [0x000000, 0xFFFFFF]),
+    ("green", [0x0A1A0A, 0x33FF66]),
+    ("amber", [0x1A0F00, 0xFFB000]),
+    ("lcd", [0x9BBC0F, 0x0F380F]),
+    ("high-contrast", [0x000000, 0xFFFF00]),
+    // The background and first plane of Octo's and other XO-CHIP sets
+    ("octo", [0x996600, 0xFFCC00]),
+    ("cga", [0x000000, 0xFF55FF]),
+    ("gameboy", [0xE0F8D0, 0x081820]),
+];
+
+impl Palette {
+    /// Get a built-in palette by name.
+    pub fn named(name: &str) -> Option<Self> {
+        NAMED
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, colors)| Palette { colors: *colors })
+    }
+
+    /// Names of the built-in palettes.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        NAMED.iter().map(|(name, _)| *name)
+    }
+
+    /// Parse a palette name or a background and foreground hex colour,
+    /// e.g. `amber` or `#000000,#33ff66`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(palette) = Palette::named(spec) {
+            return Ok(palette);
+        }
+
+        let parts: Vec<&str> = spec.split(',').map(str::trim).collect();
+        if parts.len() != 2 {
+            return Err(format!(
+                "Unknown palette '{}'. Use one of {} or 2 hex colours",
+                spec,
+                Palette::names().collect::<Vec<_>>().join(", ")
+            ));
+        }
+
+        let mut palette = Palette::default();
+        for (color, part) in palette.colors.iter_mut().zip(parts) {
+            let hex = part.trim_start_matches('#');
+            *color = match u32::from_str_radix(hex, 16) {
+                Ok(value) if hex.len() == 6 => value,
+                _ => return Err(format!("Invalid colour '{}', expected #RRGGBB", part)),
+            };
+        }
+        Ok(palette)
+    }
+
+    /// Colour of a display pixel.
+    pub fn color(&self, pixel: u32) -> u32 {
+        if pixel != 0 {
+            self.colors[1]
+        } else {
+            self.colors[0]
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::named("classic").unwrap()
+    }
+}
+
+/// Formats as a hex colour list that `Palette::parse` reads back.
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let colors: Vec<String> = self.colors.iter().map(|c| format!("#{:06x}", c)).collect();
+        write!(f, "{}", colors.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_named() {
+        assert_eq!(
+            Palette::parse("Amber"),
+            Ok(Palette::named("amber").unwrap())
+        );
+        assert!(Palette::parse("purple").is_err());
+    }
+
+    #[test]
+    fn parse_hex() {
+        let palette = Palette::parse("#102030, 405060").unwrap();
+        assert_eq!(palette.colors, [0x102030, 0x405060]);
+        assert!(Palette::parse("#102030").is_err());
+        assert!(Palette::parse("#102030,#405060,#708090").is_err());
+        assert!(Palette::parse("#102030,#4050").is_err());
+        assert!(Palette::parse("#102030,#40506g").is_err());
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let palette = Palette::named("octo").unwrap();
+        assert_eq!(Palette::parse(&palette.to_string()), Ok(palette));
+    }
+}
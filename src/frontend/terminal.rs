@@ -0,0 +1,355 @@
+use super::{Frontend, Hotkey, Palette};
+use crate::chip8::{HEIGHT, WIDTH};
+use crossterm::{
+    cursor,
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+        PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    queue, style, terminal,
+};
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+
+/// How long a key counts as held after a press when the terminal can't report key releases.
+/// Long enough to bridge the gap before the terminal's key repeat kicks in.
+const HOLD_TIME: Duration = Duration::from_millis(250);
+
+/// How pixels are packed into terminal cells.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Glyphs {
+    /// Unicode half-blocks, 1x2 pixels per cell.
+    HalfBlock,
+    /// Unicode Braille patterns, 2x4 pixels per cell, so the 64x32 display
+    /// fits in a 32x8 terminal at the cost of gaps between the dots.
+    Braille,
+}
+
+impl Glyphs {
+    fn cell_size(self) -> (usize, usize) {
+        match self {
+            Glyphs::HalfBlock => (1, 2),
+            Glyphs::Braille => (2, 4),
+        }
+    }
+
+    /// Build the character for the cell whose top left pixel is at (x, y).
+    fn cell(self, display: &[u32], width: usize, height: usize, x: usize, y: usize) -> char {
+        let lit = |dx: usize, dy: usize| {
+            x + dx < width && y + dy < height && display[(y + dy) * width + x + dx] != 0
+        };
+        match self {
+            Glyphs::HalfBlock => match (lit(0, 0), lit(0, 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            },
+            Glyphs::Braille => {
+                // Dot numbering of the Braille block, column-major with the bottom row last
+                const DOTS: [(usize, usize, u32); 8] = [
+                    (0, 0, 0x01),
+                    (0, 1, 0x02),
+                    (0, 2, 0x04),
+                    (1, 0, 0x08),
+                    (1, 1, 0x10),
+                    (1, 2, 0x20),
+                    (0, 3, 0x40),
+                    (1, 3, 0x80),
+                ];
+                let bits = DOTS
+                    .iter()
+                    .filter(|(dx, dy, _)| lit(*dx, *dy))
+                    .fold(0, |acc, (_, _, bit)| acc | bit);
+                std::char::from_u32(0x2800 + bits).unwrap()
+            }
+        }
+    }
+}
+
+/// Convert a display buffer into rows of terminal cells.
+pub(crate) fn render_cells(
+    glyphs: Glyphs,
+    display: &[u32],
+    width: usize,
+    height: usize,
+) -> Vec<char> {
+    let (cell_w, cell_h) = glyphs.cell_size();
+    let mut cells = Vec::new();
+    for y in (0..height).step_by(cell_h) {
+        for x in (0..width).step_by(cell_w) {
+            cells.push(glyphs.cell(display, width, height, x, y));
+        }
+    }
+    cells
+}
+
+/// Map a key to its keypad index. Uses the same layout as the window:
+///
+/// 1 2 3 4    1 2 3 C
+/// Q W E R    4 5 6 D
+/// A S D F    7 8 9 E
+/// Z X C V    A 0 B F
+fn keypad_index(c: char) -> Option<usize> {
+    match c.to_ascii_lowercase() {
+        '1' => Some(0x1),
+        '2' => Some(0x2),
+        '3' => Some(0x3),
+        '4' => Some(0xC),
+        'q' => Some(0x4),
+        'w' => Some(0x5),
+        'e' => Some(0x6),
+        'r' => Some(0xD),
+        'a' => Some(0x7),
+        's' => Some(0x8),
+        'd' => Some(0x9),
+        'f' => Some(0xE),
+        'z' => Some(0xA),
+        'x' => Some(0x0),
+        'c' => Some(0xB),
+        'v' => Some(0xF),
+        _ => None,
+    }
+}
+
+/// Keypad state from terminal key events.
+pub(crate) struct HeldKeys {
+    /// Whether the terminal reports key releases (kitty keyboard protocol).
+    pub(crate) releases: bool,
+    /// When each key was last pressed, or `None` if it has been released.
+    pressed: [Option<Instant>; 16],
+}
+
+impl HeldKeys {
+    pub(crate) fn new(releases: bool) -> Self {
+        HeldKeys {
+            releases,
+            pressed: [None; 16],
+        }
+    }
+
+    /// Track a key event. Returns false if it isn't a keypad key.
+    pub(crate) fn handle(&mut self, key: &KeyEvent) -> bool {
+        let index = match key.code {
+            KeyCode::Char(c) => keypad_index(c),
+            _ => None,
+        };
+        if let Some(i) = index {
+            self.pressed[i] = match key.kind {
+                KeyEventKind::Release => None,
+                _ => Some(Instant::now()),
+            };
+        }
+        index.is_some()
+    }
+
+    pub(crate) fn update(&self, keypad: &mut [bool; 16]) {
+        let now = Instant::now();
+        for (key, pressed) in keypad.iter_mut().zip(self.pressed.iter()) {
+            *key = match pressed {
+                Some(_) if self.releases => true,
+                Some(at) => now.duration_since(*at) < HOLD_TIME,
+                None => false,
+            };
+        }
+    }
+}
+
+/// Put the terminal in raw mode on the alternate screen, asking for key
+/// release events if it supports them. Returns whether it does.
+pub(crate) fn enter_raw_mode(out: &mut Stdout) -> io::Result<bool> {
+    terminal::enable_raw_mode()?;
+    let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+    queue!(
+        out,
+        terminal::EnterAlternateScreen,
+        terminal::Clear(terminal::ClearType::All),
+        cursor::Hide
+    )?;
+    if releases {
+        queue!(
+            out,
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+    }
+    out.flush()?;
+    Ok(releases)
+}
+
+/// Undo `enter_raw_mode`.
+pub(crate) fn leave_raw_mode(out: &mut Stdout, releases: bool) {
+    if releases {
+        let _ = queue!(out, PopKeyboardEnhancementFlags);
+    }
+    let _ = queue!(
+        out,
+        style::ResetColor,
+        cursor::Show,
+        terminal::LeaveAlternateScreen
+    );
+    let _ = out.flush();
+    let _ = terminal::disable_raw_mode();
+}
+
+/// A raw-mode terminal that draws the display with Unicode block characters.
+///
+/// The terminal is restored when this is dropped.
+pub struct Terminal {
+    out: Stdout,
+    glyphs: Glyphs,
+    /// Cells currently on screen, used to only redraw what changed.
+    cells: Vec<char>,
+    keys: HeldKeys,
+    hotkeys: Vec<Hotkey>,
+    quit: bool,
+}
+
+impl Terminal {
+    pub fn new(glyphs: Glyphs, palette: Palette) -> io::Result<Self> {
+        let mut out = io::stdout();
+        let releases = enter_raw_mode(&mut out)?;
+        let rgb = |color: u32| style::Color::Rgb {
+            r: (color >> 16) as u8,
+            g: (color >> 8) as u8,
+            b: color as u8,
+        };
+        queue!(
+            out,
+            style::SetColors(style::Colors::new(
+                rgb(palette.colors[1]),
+                rgb(palette.colors[0])
+            )),
+            terminal::Clear(terminal::ClearType::All)
+        )?;
+        out.flush()?;
+
+        Ok(Terminal {
+            out,
+            glyphs,
+            cells: Vec::new(),
+            keys: HeldKeys::new(releases),
+            hotkeys: Vec::new(),
+            quit: false,
+        })
+    }
+
+    /// Drain pending terminal events and update the keypad.
+    fn poll_keys(&mut self, keypad: &mut [bool; 16]) -> io::Result<()> {
+        while event::poll(Duration::ZERO)? {
+            match event::read()? {
+                Event::Key(key) => self.handle_key(key),
+                // Contents are lost on resize, so force a full redraw
+                Event::Resize(..) => {
+                    queue!(self.out, terminal::Clear(terminal::ClearType::All))?;
+                    self.cells.clear();
+                }
+                _ => (),
+            }
+        }
+        self.keys.update(keypad);
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => self.quit = true,
+            KeyCode::F(9) if key.kind == KeyEventKind::Press => {
+                self.hotkeys.push(Hotkey::ToggleRecording)
+            }
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            _ => {
+                self.keys.handle(&key);
+            }
+        }
+    }
+
+    /// Draw the display, writing only the cells that changed since the last draw.
+    fn draw(&mut self, display: &[u32], width: usize, height: usize) -> io::Result<()> {
+        let (cell_w, _) = self.glyphs.cell_size();
+        let cols = width.div_ceil(cell_w);
+        let cells = render_cells(self.glyphs, display, width, height);
+        if self.cells.len() != cells.len() {
+            self.cells = vec!['\0'; cells.len()];
+        }
+
+        for (i, (old, new)) in self.cells.iter_mut().zip(cells.iter()).enumerate() {
+            if old != new {
+                queue!(
+                    self.out,
+                    cursor::MoveTo((i % cols) as u16, (i / cols) as u16),
+                    style::Print(new)
+                )?;
+                *old = *new;
+            }
+        }
+        self.out.flush()
+    }
+}
+
+impl Frontend for Terminal {
+    fn poll_input(&mut self, keypad: &mut [bool; 16]) {
+        self.poll_keys(keypad)
+            .expect("Error reading terminal input");
+    }
+
+    fn present(&mut self, display: &[u32]) {
+        self.draw(display, WIDTH, HEIGHT)
+            .expect("Error drawing to terminal");
+    }
+
+    /// Terminals have no tone generator, so ring the bell instead.
+    fn play_tone(&mut self) {
+        let _ = queue!(self.out, style::Print('\x07'));
+    }
+
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        std::mem::take(&mut self.hotkeys)
+    }
+
+    /// Whether Escape or Ctrl-C was pressed.
+    fn should_quit(&self) -> bool {
+        self.quit
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        leave_raw_mode(&mut self.out, self.keys.releases);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_block_cells() {
+        let display = [u32::MAX, u32::MAX, 0, 0, u32::MAX, 0, u32::MAX, 0];
+        let cells = render_cells(Glyphs::HalfBlock, &display, 4, 2);
+        assert_eq!(cells, vec!['█', '▀', '▄', ' ']);
+    }
+
+    #[test]
+    fn braille_cells() {
+        let mut display = [0u32; 2 * 4];
+        assert_eq!(
+            render_cells(Glyphs::Braille, &display, 2, 4),
+            vec!['\u{2800}']
+        );
+        display[0] = u32::MAX; // dot 1
+        display[7] = u32::MAX; // dot 8
+        assert_eq!(
+            render_cells(Glyphs::Braille, &display, 2, 4),
+            vec!['\u{2881}']
+        );
+    }
+
+    #[test]
+    fn keypad_layout() {
+        assert_eq!(keypad_index('1'), Some(0x1));
+        assert_eq!(keypad_index('4'), Some(0xC));
+        assert_eq!(keypad_index('X'), Some(0x0));
+        assert_eq!(keypad_index('v'), Some(0xF));
+        assert_eq!(keypad_index('p'), None);
+    }
+}
@@ -0,0 +1,242 @@
+use super::{Frontend, Hotkey, Palette};
+use crate::chip8::{HEIGHT, WIDTH};
+use crate::settings::Section;
+use minifb::{Key, KeyRepeat, Scale, ScaleMode, WindowOptions};
+
+/// How the display is scaled to fill a resizable or fullscreen window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaling {
+    /// Largest whole multiple of the display size, centred.
+    Integer,
+    /// As large as possible while keeping the aspect ratio.
+    Aspect,
+    /// Fill the whole window.
+    Stretch,
+}
+
+impl Scaling {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "integer" => Ok(Scaling::Integer),
+            "aspect" => Ok(Scaling::Aspect),
+            "stretch" => Ok(Scaling::Stretch),
+            _ => Err(format!(
+                "Unknown scaling '{}', expected integer, aspect or stretch",
+                name
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Scaling::Integer => "integer",
+            Scaling::Aspect => "aspect",
+            Scaling::Stretch => "stretch",
+        }
+    }
+}
+
+/// Window appearance, remembered per ROM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DisplaySettings {
+    pub palette: Palette,
+    /// Initial window size as a multiple of the display size.
+    pub scale: usize,
+    pub scaling: Scaling,
+    pub resizable: bool,
+    /// minifb can't do real fullscreen, so this is a borderless window sized
+    /// to fit the screen.
+    pub fullscreen: bool,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings {
+            palette: Palette::default(),
+            scale: 8,
+            scaling: Scaling::Stretch,
+            resizable: false,
+            fullscreen: false,
+        }
+    }
+}
+
+impl DisplaySettings {
+    /// Load from a saved settings section, ignoring anything invalid.
+    pub fn from_section(section: &Section) -> Self {
+        let mut settings = DisplaySettings::default();
+        for (key, value) in section {
+            let _ = settings.set(key, value);
+        }
+        settings
+    }
+
+    pub fn to_section(self) -> Section {
+        let mut section = Section::new();
+        section.insert("palette".to_string(), self.palette.to_string());
+        section.insert("scale".to_string(), self.scale.to_string());
+        section.insert("scaling".to_string(), self.scaling.name().to_string());
+        section.insert("resizable".to_string(), self.resizable.to_string());
+        section.insert("fullscreen".to_string(), self.fullscreen.to_string());
+        section
+    }
+
+    /// Change a setting by name. Unknown names are ignored so that other
+    /// settings can share the same section.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let parse_bool = |value: &str| {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value '{}' for {}", value, key))
+        };
+        match key {
+            "palette" => self.palette = Palette::parse(value)?,
+            "scale" => {
+                self.scale = match value.parse() {
+                    Ok(scale) if window_scale(scale).is_some() => scale,
+                    _ => {
+                        return Err(format!(
+                            "Invalid scale '{}', expected 1, 2, 4, 8, 16 or 32",
+                            value
+                        ))
+                    }
+                }
+            }
+            "scaling" => self.scaling = Scaling::parse(value)?,
+            "resizable" => self.resizable = parse_bool(value)?,
+            "fullscreen" => self.fullscreen = parse_bool(value)?,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+fn window_scale(scale: usize) -> Option<Scale> {
+    match scale {
+        1 => Some(Scale::X1),
+        2 => Some(Scale::X2),
+        4 => Some(Scale::X4),
+        8 => Some(Scale::X8),
+        16 => Some(Scale::X16),
+        32 => Some(Scale::X32),
+        _ => None,
+    }
+}
+
+/// A desktop window.
+pub struct Window {
+    window: minifb::Window,
+    palette: Palette,
+    /// Whether frames are scaled up here rather than by minifb. minifb has
+    /// no integer scaling mode for resizable windows.
+    upscale: bool,
+    buffer: Vec<u32>,
+}
+
+impl Window {
+    pub fn new(settings: &DisplaySettings) -> Self {
+        let upscale =
+            settings.resizable && !settings.fullscreen && settings.scaling == Scaling::Integer;
+        let scale_mode = match settings.scaling {
+            Scaling::Integer if upscale => ScaleMode::Center,
+            Scaling::Aspect => ScaleMode::AspectRatioStretch,
+            _ => ScaleMode::Stretch,
+        };
+        let scale = if settings.fullscreen {
+            Scale::FitScreen
+        } else if upscale {
+            Scale::X1
+        } else {
+            window_scale(settings.scale).unwrap_or(Scale::X8)
+        };
+        let (width, height) = if upscale {
+            (WIDTH * settings.scale, HEIGHT * settings.scale)
+        } else {
+            (WIDTH, HEIGHT)
+        };
+
+        let opts = WindowOptions {
+            borderless: settings.fullscreen,
+            title: !settings.fullscreen,
+            resize: settings.resizable,
+            scale,
+            scale_mode,
+            topmost: settings.fullscreen,
+            transparency: false,
+            none: false,
+        };
+        let window = minifb::Window::new("Chip 8 Emulator", width, height, opts)
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        Window {
+            window,
+            palette: settings.palette,
+            upscale,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl Frontend for Window {
+    fn poll_input(&mut self, keypad: &mut [bool; 16]) {
+        // Keypad layout:
+        // 1 2 3 C
+        // 4 5 6 D
+        // 7 8 9 E
+        // A 0 B F
+        keypad.iter_mut().for_each(|key| *key = false);
+        self.window.get_keys().iter().for_each(|key| match key {
+            Key::Key1 => keypad[0x1] = true,
+            Key::Key2 => keypad[0x2] = true,
+            Key::Key3 => keypad[0x3] = true,
+            Key::Key4 => keypad[0xc] = true,
+            Key::Q => keypad[0x4] = true,
+            Key::W => keypad[0x5] = true,
+            Key::E => keypad[0x6] = true,
+            Key::R => keypad[0xD] = true,
+            Key::A => keypad[0x7] = true,
+            Key::S => keypad[0x8] = true,
+            Key::D => keypad[0x9] = true,
+            Key::F => keypad[0xE] = true,
+            Key::Z => keypad[0xA] = true,
+            Key::X => keypad[0x0] = true,
+            Key::C => keypad[0xB] = true,
+            Key::V => keypad[0xF] = true,
+            _ => (),
+        });
+    }
+
+    fn present(&mut self, display: &[u32]) {
+        let factor = if self.upscale {
+            let (width, height) = self.window.get_size();
+            (width / WIDTH).min(height / HEIGHT).max(1)
+        } else {
+            1
+        };
+
+        let (width, height) = (WIDTH * factor, HEIGHT * factor);
+        let palette = self.palette;
+        self.buffer.clear();
+        for y in 0..height {
+            let row = &display[(y / factor) * WIDTH..][..WIDTH];
+            self.buffer
+                .extend((0..width).map(|x| palette.color(row[x / factor])));
+        }
+
+        self.window
+            .update_with_buffer(&self.buffer, width, height)
+            .unwrap();
+    }
+
+    fn take_hotkeys(&mut self) -> Vec<Hotkey> {
+        let mut hotkeys = Vec::new();
+        if self.window.is_key_pressed(Key::F9, KeyRepeat::No) {
+            hotkeys.push(Hotkey::ToggleRecording);
+        }
+        hotkeys
+    }
+
+    fn should_quit(&self) -> bool {
+        !self.window.is_open() || self.window.is_key_down(Key::Escape)
+    }
+}
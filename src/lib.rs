@@ -0,0 +1,20 @@
+pub mod analyzer;
+pub mod batch;
+pub mod cheats;
+pub mod chip8;
+pub mod compat;
+pub mod coverage;
+pub mod debug;
+pub mod disassembler;
+pub mod env;
+pub mod frontend;
+pub mod movie;
+pub mod patch;
+pub mod profiler;
+pub mod recorder;
+pub mod runner;
+pub mod script;
+pub mod settings;
+pub mod suite;
+pub mod symbols;
+pub mod trace;
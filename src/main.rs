@@ -1,86 +1,508 @@
-use chip8::{Chip8, HEIGHT, WIDTH};
-use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
+use chip_8_emulator::cheats::{Cheats, Freeze, Poke};
+use chip_8_emulator::chip8::{
+    Chip8, Platform, Quirks, DEFAULT_STACK_LIMIT, MAX_ROM_SIZE, STACK_SIZE,
+};
+use chip_8_emulator::compat;
+use chip_8_emulator::coverage;
+use chip_8_emulator::debug::tui::{self, Tui};
+use chip_8_emulator::debug::{self, Watchpoint, Watchpoints};
+use chip_8_emulator::frontend::{DisplaySettings, Frontend, Glyphs, Headless, Terminal, Window};
+use chip_8_emulator::movie::{Movie, MovieMode, Playback};
+use chip_8_emulator::patch::Patch;
+use chip_8_emulator::profiler::Profiler;
+use chip_8_emulator::recorder::{RecorderConfig, Recording, VideoRecorder};
+use chip_8_emulator::runner::{Hooks, HostConsole, Runner, DEFAULT_SPEED};
+use chip_8_emulator::script::Script;
+use chip_8_emulator::settings::Settings;
+use chip_8_emulator::symbols::Symbols;
+use chip_8_emulator::trace::{TraceConfig, Tracer};
+use sha1_smol::Sha1;
+use std::cell::RefCell;
 use std::env;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
 
-use std::time::{Duration, Instant};
+const USAGE: &str = "Usage: chip_8_emulator [options] <rom>
 
-mod chip8;
-mod disassembler;
+Loading:
+  --patch FILE            Apply an IPS or BPS patch to the ROM, like a fix
+                          made with chip8-patch. Repeatable, applied in order
+  --poke ADDR=VALUE       Set a byte of memory like `1ff=1` after loading,
+                          for test ROMs that read options. Repeatable
+
+Compatibility, looked up for known ROMs:
+  --platform PLATFORM     chip8, schip or xo-chip, setting the quirks
+  --quirks LIST           Turn quirks on or off, like `wrap,no-shift`: vf-reset,
+                          memory, shift, jump, wrap or none
+  --speed N               Instructions per frame
+
+Frontends:
+  --terminal              Draw in the terminal with half-blocks
+  --braille               Draw in the terminal with Braille characters
+  --headless              Run without a display as fast as possible
+  --frames N              Stop after N frames
+
+Recording:
+  --record FILE           Record video to a .gif or .y4m file from the start.
+                          F9 starts and stops recording while running
+  --record-movie FILE     Record keypad input for replaying the run exactly
+  --play-movie FILE       Replay recorded input, reporting any desync.
+                          Neither works with --poke, --cheat, --freeze or
+                          --script
+
+Debugging:
+  --gdb PORT              Wait for GDB to connect on localhost:PORT
+  --tui                   Debug in a full-screen terminal UI
+  --symbols FILE          Load labels (`22a main_loop` lines) to show in
+                          disassembly, traces, profiles and the debuggers
+  --break EXPR            Stop when an expression like `pc == 0x2a4 && v3 > 10`
+                          or `write [0x300..0x310]` becomes true, with labels
+                          from --symbols standing for addresses. Repeatable
+  --log EXPR              Print a line each time an expression is true
+  --trace FILE            Log every instruction and the registers after it
+  --trace-range START-END Only trace instructions in this hex address range
+  --trace-max N           Stop tracing after N lines
+  --coverage FILE         Write the disassembly with how often each
+                          instruction ran and each address was read or written
+  --heatmap FILE          Draw memory accesses as a 64x64 heatmap GIF
+  --profile FILE          Write time spent in each subroutine, the call graph,
+                          the busiest addresses and how often frames draw
+  --profile-folded FILE   Write call stacks for flamegraph tools
+  --strict                Warn about undefined behaviour like executing below
+                          0x200 or reading memory that was never written
+  --stack-limit N         Warn in strict mode when calls nest deeper than N
+                          (12 by default, at most 16)
+  --semihosting           Let test ROMs print and assert with `0nnn` host
+                          calls, exiting with an error if an assertion fails
+
+Cheats:
+  --cheat NAME            Use a cheat saved for this ROM from the TUI.
+                          Repeatable
+  --freeze TARGET=VALUE   Keep an address or register like `2f5=3` or `v3=0`
+                          at a value every frame. Repeatable
+
+Scripting:
+  --script FILE           Run a Rhai script that presses keys, runs frames and
+                          reads and writes memory. Not with --gdb or --tui
+
+Display, remembered for each ROM:
+  --palette PALETTE       classic, green, amber, lcd, high-contrast, octo, cga,
+                          gameboy, or 2 hex colours like #000000,#33ff66
+  --scale N               Window size multiplier: 1, 2, 4, 8, 16 or 32
+  --scaling MODE          integer, aspect or stretch
+  --resizable             Allow resizing the window
+  --fixed-size            Don't allow resizing the window
+  --fullscreen            Borderless window that fills the screen
+  --windowed              Normal window";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let file_name = &args[1];
+    let mut file_name = None;
+    let mut patches = Vec::new();
+    let mut platform = None;
+    let mut quirk_changes = Vec::new();
+    let mut speed_arg = None;
+    let mut glyphs = None;
+    let mut headless = false;
+    let mut frames = None;
+    let mut record = None;
+    let mut record_movie = None;
+    let mut play_movie = None;
+    let mut gdb = None;
+    let mut debug_tui = false;
+    let mut trace_path = None;
+    let mut trace_config = TraceConfig::default();
+    let mut coverage = None;
+    let mut strict = false;
+    let mut semihosting = false;
+    let mut stack_limit = DEFAULT_STACK_LIMIT;
+    let mut heatmap = None;
+    let mut profile = None;
+    let mut profile_folded = None;
+    // Conditions and whether they log, parsed once the symbols are loaded
+    let mut conditions = Vec::new();
+    let mut symbols = Symbols::new();
+    let mut cheat_names = Vec::new();
+    let mut freezes = Vec::new();
+    let mut pokes = Vec::new();
+    let mut script_path = None;
+    let mut display_args = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| panic!("{} takes a value", name))
+        };
+        match arg.as_str() {
+            "--patch" => {
+                let path = PathBuf::from(value("--patch"));
+                let patch = Patch::load(&path)
+                    .unwrap_or_else(|e| panic!("Couldn't load {}: {}", path.display(), e));
+                patches.push(patch);
+            }
+            "--platform" => {
+                let name = value("--platform");
+                platform = Some(Platform::parse(&name).unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--quirks" => {
+                let changes = value("--quirks");
+                Quirks::parse(&changes).unwrap_or_else(|e| panic!("{}", e));
+                quirk_changes.push(changes);
+            }
+            "--speed" => {
+                let n = value("--speed").parse();
+                speed_arg = Some(n.expect("--speed takes a number of instructions"));
+            }
+            "--terminal" => glyphs = Some(Glyphs::HalfBlock),
+            "--braille" => glyphs = Some(Glyphs::Braille),
+            "--headless" => headless = true,
+            "--frames" => {
+                let n = value("--frames").parse();
+                frames = Some(n.expect("--frames takes a number of frames"));
+            }
+            "--record" => record = Some(PathBuf::from(value("--record"))),
+            "--record-movie" => record_movie = Some(PathBuf::from(value("--record-movie"))),
+            "--play-movie" => play_movie = Some(PathBuf::from(value("--play-movie"))),
+            "--gdb" => {
+                let port = value("--gdb").parse();
+                gdb = Some(port.expect("--gdb takes a port number"));
+            }
+            "--tui" => debug_tui = true,
+            "--symbols" => {
+                let path = PathBuf::from(value("--symbols"));
+                symbols = Symbols::load(&path)
+                    .unwrap_or_else(|e| panic!("Couldn't load {}: {}", path.display(), e));
+            }
+            "--break" => conditions.push((value("--break"), false)),
+            "--log" => conditions.push((value("--log"), true)),
+            "--trace" => trace_path = Some(PathBuf::from(value("--trace"))),
+            "--trace-range" => {
+                let range = TraceConfig::parse_range(&value("--trace-range"));
+                trace_config.range = Some(range.unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--trace-max" => {
+                let n = value("--trace-max").parse();
+                trace_config.max_lines = Some(n.expect("--trace-max takes a number of lines"));
+            }
+            "--coverage" => coverage = Some(PathBuf::from(value("--coverage"))),
+            "--heatmap" => heatmap = Some(PathBuf::from(value("--heatmap"))),
+            "--profile" => profile = Some(PathBuf::from(value("--profile"))),
+            "--profile-folded" => profile_folded = Some(PathBuf::from(value("--profile-folded"))),
+            "--strict" => strict = true,
+            "--semihosting" => semihosting = true,
+            "--stack-limit" => {
+                let n = value("--stack-limit").parse();
+                stack_limit = n.expect("--stack-limit takes a number of calls");
+                if stack_limit > STACK_SIZE {
+                    panic!("--stack-limit can be at most {}", STACK_SIZE);
+                }
+            }
+            "--script" => script_path = Some(PathBuf::from(value("--script"))),
+            "--cheat" => cheat_names.push(value("--cheat")),
+            "--poke" => {
+                let poke = Poke::parse(&value("--poke"));
+                pokes.push(poke.unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--freeze" => {
+                let freeze = Freeze::parse(&value("--freeze"));
+                freezes.push(freeze.unwrap_or_else(|e| panic!("{}", e)));
+            }
+            "--palette" => display_args.push(("palette", value("--palette"))),
+            "--scale" => display_args.push(("scale", value("--scale"))),
+            "--scaling" => display_args.push(("scaling", value("--scaling"))),
+            "--resizable" => display_args.push(("resizable", "true".to_string())),
+            "--fixed-size" => display_args.push(("resizable", "false".to_string())),
+            "--fullscreen" => display_args.push(("fullscreen", "true".to_string())),
+            "--windowed" => display_args.push(("fullscreen", "false".to_string())),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => file_name = Some(arg),
+        }
+    }
+    let file_name = file_name.unwrap_or_else(|| {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    });
 
-    let binary = fs::read(file_name).expect("Error reading file");
+    let mut binary = fs::read(file_name).expect("Error reading file");
+    // Settings and movies go with the patched ROM
+    for patch in &patches {
+        binary = patch.apply(&binary).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if binary.len() > MAX_ROM_SIZE {
+        panic!(
+            "The ROM is {} bytes, but at most {} fit in memory",
+            binary.len(),
+            MAX_ROM_SIZE
+        );
+    }
+    let rom_hash = Sha1::from(&binary).digest().to_string();
 
-    // use disassembler::*;
+    // use chip_8_emulator::disassembler::*;
     // let start = 0x200;
     // for (pc, chunk) in binary.chunks(2).enumerate() {
     //     let opcode = ((chunk[0] as u16) << 8) | chunk[1] as u16;
-    //     println!("{}", disassemble_opcode(start + pc * 2, opcode));
+    //     println!("{}", disassemble_opcode(start + pc * 2, opcode, &symbols));
     // }
     // return;
 
+    // The command line overrides what's known about the ROM
+    let info = compat::lookup(&rom_hash);
+    if let Some(info) = &info {
+        eprintln!("{}", info.summary());
+    }
+    let mut quirks = match (platform, &info) {
+        (Some(platform), _) => platform.quirks(),
+        (None, Some(info)) => info.quirks,
+        (None, None) => Quirks::default(),
+    };
+    for changes in &quirk_changes {
+        quirks.change(changes).unwrap();
+    }
+    let mut speed = speed_arg
+        .or_else(|| info.as_ref().and_then(|info| info.speed))
+        .unwrap_or(DEFAULT_SPEED);
+
+    // Movies replay with the same seed, speed and quirks they were recorded
+    // with
     let mut chip = Chip8::new();
+    let movie = if let Some(path) = play_movie {
+        let movie = Movie::load(&path).unwrap_or_else(|e| panic!("{}", e));
+        if movie.rom_hash != rom_hash {
+            panic!("{} was recorded with a different ROM", path.display());
+        }
+        chip = Chip8::with_seed(movie.seed);
+        speed = movie.speed;
+        quirks = movie.quirks;
+        // Headless playback stops at the end of the movie
+        frames = frames.or(Some(movie.frames.len()));
+        Some(MovieMode::Playback(Playback::new(movie)))
+    } else {
+        record_movie.map(|path| {
+            let mut movie = Movie::new(rom_hash.clone(), chip.seed(), speed);
+            movie.quirks = quirks;
+            MovieMode::Recording { movie, path }
+        })
+    };
+    if let Some(movie) = &movie {
+        let unrecorded = [
+            ("--poke", !pokes.is_empty()),
+            ("--cheat", !cheat_names.is_empty()),
+            ("--freeze", !freezes.is_empty()),
+            ("--script", script_path.is_some()),
+        ];
+        let used: Vec<&str> = unrecorded
+            .iter()
+            .filter(|(_, used)| *used)
+            .map(|(flag, _)| *flag)
+            .collect();
+        movie.refuse(&used).unwrap_or_else(|e| panic!("{}", e));
+    }
+    chip.quirks = quirks;
+    let mut cheats = Cheats::new(&rom_hash);
+    for name in &cheat_names {
+        cheats.load(name).unwrap_or_else(|e| panic!("{}", e));
+    }
+    for freeze in freezes {
+        cheats.freeze(freeze);
+    }
+
+    let rom_len = binary.len();
+    if strict {
+        chip.enable_strict(stack_limit);
+    }
     chip.load_binary(binary);
+    for poke in &pokes {
+        poke.apply(&mut chip);
+    }
+    if semihosting {
+        chip.enable_semihosting();
+    }
+    if coverage.is_some() || heatmap.is_some() {
+        chip.enable_coverage();
+    }
+
+    // Display options given on the command line are saved for next time
+    let mut settings = Settings::load();
+    let mut section = settings.rom(&rom_hash).cloned().unwrap_or_default();
+    let mut display = DisplaySettings::from_section(&section);
+    for (key, value) in &display_args {
+        display.set(key, value).unwrap_or_else(|e| panic!("{}", e));
+    }
+    if !display_args.is_empty() {
+        section.extend(display.to_section());
+        settings.set_rom(&rom_hash, section);
+        if let Err(e) = settings.save() {
+            eprintln!("Couldn't save settings: {}", e);
+        }
+    }
+
+    // Movie playback sets the keypad before cheats and watchpoints see it
+    let mut hooks = Hooks::default();
+    if let Some(movie) = movie {
+        hooks.add(movie);
+    }
+    hooks.add(cheats);
 
-    let opts = WindowOptions {
-        borderless: false,
-        title: true,
-        resize: false,
-        scale: Scale::X8,
-        scale_mode: ScaleMode::Stretch,
-        topmost: false,
-        transparency: false,
-        none: false,
+    let record_config = RecorderConfig {
+        palette: display.palette,
+        scale: display.scale,
     };
-    let mut window =
-        Window::new("Chip 8 Emulator", WIDTH, HEIGHT, opts).unwrap_or_else(|e| panic!("{}", e));
-
-    // TODO: Calibrate
-    window.limit_update_rate(Some(std::time::Duration::from_micros(1500)));
-
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        // Keypad layout:
-        // 1 2 3 C
-        // 4 5 6 D
-        // 7 8 9 E
-        // A 0 B F
-        chip.keypad.iter_mut().for_each(|key| *key = false);
-        window.get_keys().iter().for_each(|key| match key {
-            Key::Key1 => chip.keypad[0x1] = true,
-            Key::Key2 => chip.keypad[0x2] = true,
-            Key::Key3 => chip.keypad[0x3] = true,
-            Key::Key4 => chip.keypad[0xc] = true,
-            Key::Q => chip.keypad[0x4] = true,
-            Key::W => chip.keypad[0x5] = true,
-            Key::E => chip.keypad[0x6] = true,
-            Key::R => chip.keypad[0xD] = true,
-            Key::A => chip.keypad[0x7] = true,
-            Key::S => chip.keypad[0x8] = true,
-            Key::D => chip.keypad[0x9] = true,
-            Key::F => chip.keypad[0xE] = true,
-            Key::Z => chip.keypad[0xA] = true,
-            Key::X => chip.keypad[0x0] = true,
-            Key::C => chip.keypad[0xB] = true,
-            Key::V => chip.keypad[0xF] = true,
-            _ => (),
+    let recording = record
+        .map(|path| Recording::start(&record_config, path).unwrap_or_else(|e| panic!("{}", e)));
+    hooks.add(VideoRecorder::new(record_config, recording));
+    if script_path.is_some() && (gdb.is_some() || debug_tui) {
+        panic!("--script can't be used with --gdb or --tui");
+    }
+    let script = script_path.map(|path| {
+        Script::load(&path).unwrap_or_else(|e| panic!("Couldn't load {}: {}", path.display(), e))
+    });
+    if let Some(path) = trace_path {
+        let trace = Tracer::create(&path, trace_config)
+            .unwrap_or_else(|e| panic!("Couldn't create {}: {}", path.display(), e));
+        hooks.add(trace.with_symbols(symbols.clone()));
+    }
+    if profile.is_some() || profile_folded.is_some() {
+        hooks.add(Profiler::new());
+    }
+    if semihosting {
+        hooks.add(HostConsole::default());
+    }
+
+    let mut watchpoints = Watchpoints::new();
+    for (condition, log) in &conditions {
+        let watchpoint = if *log {
+            Watchpoint::log(condition, None, &symbols)
+        } else {
+            Watchpoint::new(condition, &symbols)
+        };
+        watchpoints.insert(watchpoint.unwrap_or_else(|e| panic!("{}", e)));
+    }
+    hooks.add(watchpoints);
+
+    let session = Session {
+        speed,
+        hooks,
+        gdb,
+        rom_len,
+        coverage,
+        heatmap,
+        profile,
+        profile_folded,
+        symbols,
+        script,
+    };
+    if debug_tui {
+        let tui = Tui::new().expect("Error setting up terminal");
+        session.run_with(Runner::new(chip, tui), |mut runner| {
+            tui::run(&mut runner).expect("Error drawing to terminal");
+            runner
         });
+    } else if headless {
+        let mut runner = Runner::new(chip, Headless::new(frames));
+        runner.throttle = false;
+        session.run(runner);
+    } else if let Some(glyphs) = glyphs {
+        let terminal = Terminal::new(glyphs, display.palette).expect("Error setting up terminal");
+        session.run(Runner::new(chip, terminal));
+    } else {
+        session.run(Runner::new(chip, Window::new(&display)));
+    }
+}
 
-        for _ in 0..10 {
-            chip.step();
+/// Runner settings that don't depend on the frontend.
+struct Session {
+    speed: usize,
+    hooks: Hooks,
+    gdb: Option<u16>,
+    rom_len: usize,
+    coverage: Option<PathBuf>,
+    heatmap: Option<PathBuf>,
+    profile: Option<PathBuf>,
+    profile_folded: Option<PathBuf>,
+    symbols: Symbols,
+    script: Option<Script>,
+}
 
-            if chip.draw_flag == true {
-                window
-                    .update_with_buffer(&chip.display, WIDTH, HEIGHT)
-                    .unwrap();
-            } else {
-                window.update();
+impl Session {
+    fn run<F: Frontend + 'static>(mut self, runner: Runner<F>) {
+        if let Some(script) = self.script.take() {
+            let mut result = Ok(());
+            self.run_with(runner, |runner| {
+                // Shared with the functions the script calls
+                let runner = Rc::new(RefCell::new(runner));
+                result = script.run(&runner);
+                Rc::try_unwrap(runner)
+                    .unwrap_or_else(|_| panic!("Script still running"))
+                    .into_inner()
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                process::exit(1);
             }
+            return;
         }
-        chip.decrement_timers();
+        let gdb = self.gdb;
+        self.run_with(runner, |mut runner| {
+            match gdb {
+                Some(port) => debug::gdb::serve(&mut runner, port).expect("GDB connection failed"),
+                None => runner.run(),
+            }
+            runner
+        });
     }
 
-    println!("Escape was pressed!");
+    /// Set up `runner`, run it with `run` and save what was collected.
+    fn run_with<F: Frontend>(
+        self,
+        mut runner: Runner<F>,
+        run: impl FnOnce(Runner<F>) -> Runner<F>,
+    ) {
+        runner.speed = self.speed;
+        runner.hooks = self.hooks;
+        runner.symbols = self.symbols;
+        let runner = run(runner);
+
+        if let Some(stats) = runner.chip.coverage() {
+            if let Some(path) = &self.coverage {
+                let memory = runner.chip.memory();
+                coverage::save_listing(path, memory, stats, self.rom_len, &runner.symbols)
+                    .unwrap_or_else(|e| eprintln!("Couldn't save coverage: {}", e));
+            }
+            if let Some(path) = &self.heatmap {
+                coverage::save_heatmap(path, stats)
+                    .unwrap_or_else(|e| eprintln!("Couldn't save heatmap: {}", e));
+            }
+        }
+
+        if let Some(profiler) = runner.hooks.get::<Profiler>() {
+            if let Some(path) = &self.profile {
+                save(path, |out| profiler.write_report(out, &runner.symbols))
+                    .unwrap_or_else(|e| eprintln!("Couldn't save profile: {}", e));
+            }
+            if let Some(path) = &self.profile_folded {
+                save(path, |out| profiler.write_folded(out, &runner.symbols))
+                    .unwrap_or_else(|e| eprintln!("Couldn't save profile: {}", e));
+            }
+        }
+
+        let failures = runner
+            .hooks
+            .get::<HostConsole>()
+            .map_or(0, |console| console.failures);
+        if failures > 0 {
+            eprintln!("Host assertions failed: {}", failures);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn save(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write(&mut out)?;
+    out.flush()
 }
@@ -0,0 +1,306 @@
+use crate::chip8::{Chip8, Quirks};
+use crate::runner::Hook;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const HEADER: &str = "chip8-movie 1";
+
+/// Keypad input for every frame of a run, enough to replay it exactly.
+///
+/// Saved as text: a header, then `key value` lines for the ROM's SHA-1, the
+/// RNG seed, instructions per frame and quirks, then one line per frame with the
+/// keypad as a 16-bit hex mask (bit n for key n) and the state hash after the
+/// frame ran:
+///
+/// ```text
+/// chip8-movie 1
+/// rom 0123456789abcdef0123456789abcdef01234567
+/// seed 42
+/// speed 10
+/// quirks vf-reset,memory
+/// 0000 5c1f0e2d3a4b6978
+/// 0010 8e3d51a7f6c204b9
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_hash: String,
+    pub seed: u64,
+    pub speed: usize,
+    /// Movies from before quirks could be chosen have the default quirks.
+    pub quirks: Quirks,
+    pub frames: Vec<MovieFrame>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieFrame {
+    pub keypad: u16,
+    /// `Chip8::state_hash` after the frame.
+    pub hash: u64,
+}
+
+pub fn keypad_to_bits(keypad: &[bool; 16]) -> u16 {
+    keypad
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &key)| bits | (key as u16) << i)
+}
+
+pub fn keypad_from_bits(bits: u16) -> [bool; 16] {
+    let mut keypad = [false; 16];
+    for (i, key) in keypad.iter_mut().enumerate() {
+        *key = bits & (1 << i) != 0;
+    }
+    keypad
+}
+
+impl Movie {
+    pub fn new(rom_hash: String, seed: u64, speed: usize) -> Self {
+        Movie {
+            rom_hash,
+            seed,
+            speed,
+            quirks: Quirks::default(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Movie::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, HEADER)) => (),
+            _ => return Err("Not a movie file".to_string()),
+        }
+
+        let mut movie = Movie::new(String::new(), 0, 0);
+        for (n, line) in lines {
+            let error = || format!("Invalid movie line {}: {}", n + 1, line);
+            let (key, value) = line.split_once(' ').ok_or_else(error)?;
+            match key {
+                "rom" => movie.rom_hash = value.to_string(),
+                "seed" => movie.seed = value.parse().map_err(|_| error())?,
+                "speed" => movie.speed = value.parse().map_err(|_| error())?,
+                "quirks" => movie.quirks = Quirks::parse(value).map_err(|_| error())?,
+                _ => movie.frames.push(MovieFrame {
+                    keypad: u16::from_str_radix(key, 16).map_err(|_| error())?,
+                    hash: u64::from_str_radix(value, 16).map_err(|_| error())?,
+                }),
+            }
+        }
+        Ok(movie)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "rom {}", self.rom_hash)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "speed {}", self.speed)?;
+        writeln!(f, "quirks {}", self.quirks)?;
+        for frame in &self.frames {
+            writeln!(f, "{:04x} {:016x}", frame.keypad, frame.hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a movie is being recorded or played back.
+pub enum MovieMode {
+    Recording { movie: Movie, path: PathBuf },
+    Playback(Playback),
+}
+
+impl MovieMode {
+    /// Record a frame, or check it against the recording.
+    pub fn frame(&mut self, keypad: &[bool; 16], hash: u64) {
+        match self {
+            MovieMode::Recording { movie, .. } => movie.frames.push(MovieFrame {
+                keypad: keypad_to_bits(keypad),
+                hash,
+            }),
+            MovieMode::Playback(playback) => playback.check(hash),
+        }
+    }
+
+    /// Refuse `options` that change the machine, like `--poke`. Movies only
+    /// hold keypad input, so a run using them wouldn't replay the same.
+    pub fn refuse(&self, options: &[&str]) -> Result<(), String> {
+        if options.is_empty() {
+            return Ok(());
+        }
+        let flag = match self {
+            MovieMode::Recording { .. } => "--record-movie",
+            MovieMode::Playback(_) => "--play-movie",
+        };
+        Err(format!(
+            "{} can't be used with {}",
+            flag,
+            options.join(", ")
+        ))
+    }
+
+    /// Save a recording.
+    pub fn finish(&self) -> io::Result<()> {
+        if let MovieMode::Recording { movie, path } = self {
+            movie.save(path)?;
+            eprintln!("Saved {} frames to {}", movie.frames.len(), path.display());
+        }
+        Ok(())
+    }
+}
+
+/// Replays a movie's input and checks the emulator stays in sync with it.
+pub struct Playback {
+    movie: Movie,
+    frame: usize,
+    /// First frame whose state didn't match the recording.
+    pub desync: Option<usize>,
+}
+
+impl Playback {
+    pub fn new(movie: Movie) -> Self {
+        Playback {
+            movie,
+            frame: 0,
+            desync: None,
+        }
+    }
+
+    /// Keypad for the next frame, or `None` once the movie has ended.
+    pub fn input(&self) -> Option<[bool; 16]> {
+        let frame = self.movie.frames.get(self.frame)?;
+        Some(keypad_from_bits(frame.keypad))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    fn check(&mut self, hash: u64) {
+        if let Some(frame) = self.movie.frames.get(self.frame) {
+            if frame.hash != hash && self.desync.is_none() {
+                eprintln!(
+                    "Movie desynced at frame {}: expected state {:016x}, got {:016x}",
+                    self.frame, frame.hash, hash
+                );
+                self.desync = Some(self.frame);
+            }
+            self.frame += 1;
+            if self.finished() {
+                eprintln!("Movie finished after {} frames", self.frame);
+            }
+        }
+    }
+}
+
+/// Playback sets the keypad at the start of each frame, after anything
+/// else holding keys.
+impl Hook for MovieMode {
+    fn begin_frame(&mut self, chip: &mut Chip8) {
+        if let MovieMode::Playback(playback) = self {
+            if let Some(keypad) = playback.input() {
+                chip.keypad = keypad;
+            }
+        }
+    }
+
+    fn end_frame(&mut self, chip: &mut Chip8) {
+        self.frame(&chip.keypad, chip.state_hash());
+    }
+
+    fn finish(&mut self, _chip: &mut Chip8) {
+        if let Err(e) = MovieMode::finish(self) {
+            eprintln!("Couldn't save movie: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keypad_bits() {
+        let mut keypad = [false; 16];
+        keypad[0] = true;
+        keypad[0xF] = true;
+        assert_eq!(keypad_to_bits(&keypad), 0x8001);
+        assert_eq!(keypad_from_bits(0x8001), keypad);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let mut movie = Movie::new("abc123".to_string(), 42, 10);
+        movie.quirks = Quirks::NONE;
+        movie.frames.push(MovieFrame {
+            keypad: 0x0010,
+            hash: 0xdeadbeef,
+        });
+        movie.frames.push(MovieFrame {
+            keypad: 0,
+            hash: u64::MAX,
+        });
+        let text = movie.to_string();
+        assert!(text.starts_with(
+            "chip8-movie 1\nrom abc123\nseed 42\nspeed 10\nquirks none\n0010 00000000deadbeef\n"
+        ));
+        assert_eq!(Movie::parse(&text), Ok(movie));
+
+        let old = Movie::parse("chip8-movie 1\nrom abc123\nseed 42\nspeed 10\n").unwrap();
+        assert_eq!(old.quirks, Quirks::default());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Movie::parse("not a movie").is_err());
+        assert!(Movie::parse("chip8-movie 1\nseed x").is_err());
+        assert!(Movie::parse("chip8-movie 1\nzzzz 0").is_err());
+    }
+
+    #[test]
+    fn playback_reports_first_desync() {
+        let mut movie = Movie::new(String::new(), 0, 10);
+        for hash in 0..3 {
+            movie.frames.push(MovieFrame { keypad: 1, hash });
+        }
+        let mut playback = Playback::new(movie);
+        assert!(playback.input().unwrap()[0]);
+        playback.check(0);
+        playback.check(5);
+        playback.check(6);
+        assert_eq!(playback.desync, Some(1));
+        assert!(playback.finished());
+        assert_eq!(playback.input(), None);
+    }
+
+    #[test]
+    fn refuses_unrecorded_options() {
+        let movie = Movie::new(String::new(), 0, 10);
+        let recording = MovieMode::Recording {
+            movie: movie.clone(),
+            path: PathBuf::new(),
+        };
+        let playback = MovieMode::Playback(Playback::new(movie));
+        assert_eq!(recording.refuse(&[]), Ok(()));
+        assert_eq!(playback.refuse(&[]), Ok(()));
+        assert_eq!(
+            recording.refuse(&["--poke", "--script"]),
+            Err("--record-movie can't be used with --poke, --script".to_string())
+        );
+        assert_eq!(
+            playback.refuse(&["--freeze"]),
+            Err("--play-movie can't be used with --freeze".to_string())
+        );
+    }
+}
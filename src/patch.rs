@@ -0,0 +1,445 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+const BPS_HEADER: &[u8] = b"BPS1";
+/// Largest record an IPS patch can hold.
+const IPS_MAX_RECORD: usize = 0xFFFF;
+/// Unchanged bytes that a difference can span before it's split in two. A new
+/// IPS record costs five bytes, a new BPS action one or two.
+const MERGE_GAP: usize = 4;
+
+/// Patch file formats.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The classic format: records of bytes to write at 24-bit offsets.
+    Ips,
+    /// beat's format, which copies from the original and the output, and
+    /// carries CRC32s of the original ROM, the patched ROM and itself.
+    Bps,
+}
+
+impl Format {
+    /// The format for a file name ending in `.ips` or `.bps`.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "ips" => Some(Format::Ips),
+            "bps" => Some(Format::Bps),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Ips => "IPS",
+            Format::Bps => "BPS",
+        })
+    }
+}
+
+/// An IPS or BPS patch, applied to a ROM before it's loaded. Community fixes
+/// for old games are usually distributed this way.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Patch {
+    pub format: Format,
+    data: Vec<u8>,
+}
+
+impl Patch {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Patch::parse(fs::read(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Recognise a patch by its header.
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        let format = if data.starts_with(IPS_HEADER) {
+            Format::Ips
+        } else if data.starts_with(BPS_HEADER) {
+            Format::Bps
+        } else {
+            return Err("Not an IPS or BPS patch".to_string());
+        };
+        Ok(Patch { format, data })
+    }
+
+    /// A patch that turns `original` into `modified`.
+    pub fn create(format: Format, original: &[u8], modified: &[u8]) -> Self {
+        let data = match format {
+            Format::Ips => create_ips(original, modified),
+            Format::Bps => create_bps(original, modified),
+        };
+        Patch { format, data }
+    }
+
+    /// The patch file's contents.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Apply the patch to `rom`. BPS patches check that `rom` is the ROM
+    /// they were made for, and that the result is what they should produce.
+    pub fn apply(&self, rom: &[u8]) -> Result<Vec<u8>, String> {
+        match self.format {
+            Format::Ips => apply_ips(&self.data, rom),
+            Format::Bps => apply_bps(&self.data, rom),
+        }
+    }
+}
+
+/// Reads a patch, failing on truncation instead of panicking.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).ok_or("Patch is truncated")?;
+        let bytes = self.data.get(self.pos..end).ok_or("Patch is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// A big-endian number of `len` bytes, as IPS uses.
+    fn big_endian(&mut self, len: usize) -> Result<usize, String> {
+        let bytes = self.bytes(len)?;
+        Ok(bytes.iter().fold(0, |n, &b| n << 8 | b as usize))
+    }
+
+    /// BPS's variable-length number.
+    fn number(&mut self) -> Result<usize, String> {
+        let (mut n, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.byte()?;
+            n = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|add| n.checked_add(add))
+                .ok_or("Patch has a number too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(n);
+            }
+            shift = shift.checked_shl(7).ok_or("Patch has a number too large")?;
+            n = n.checked_add(shift).ok_or("Patch has a number too large")?;
+        }
+    }
+}
+
+fn apply_ips(data: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = Reader::new(data, IPS_HEADER.len());
+    let mut output = rom.to_vec();
+    loop {
+        if reader.bytes(3)? == IPS_FOOTER {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.big_endian(3)?;
+        let (len, bytes) = match reader.big_endian(2)? {
+            // Run-length encoded: a count and the byte to repeat
+            0 => {
+                let len = reader.big_endian(2)?;
+                (len, vec![reader.byte()?; len])
+            }
+            len => (len, reader.bytes(len)?.to_vec()),
+        };
+        if output.len() < offset + len {
+            output.resize(offset + len, 0);
+        }
+        output[offset..offset + len].copy_from_slice(&bytes);
+    }
+    // An extension some tools use to shrink the ROM
+    if let Ok(len) = reader.big_endian(3) {
+        output.truncate(len);
+    }
+    Ok(output)
+}
+
+fn apply_bps(data: &[u8], rom: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < BPS_HEADER.len() + 12 {
+        return Err("Patch is truncated".to_string());
+    }
+    let footer = data.len() - 12;
+    let checksum = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+    if crc32(&data[..footer + 8]) != checksum(footer + 8) {
+        return Err("Patch is corrupt: its checksum doesn't match".to_string());
+    }
+    if crc32(rom) != checksum(footer) {
+        return Err("Patch is for a different ROM: the ROM's checksum doesn't match".to_string());
+    }
+
+    let mut reader = Reader::new(&data[..footer], BPS_HEADER.len());
+    let source_len = reader.number()?;
+    let target_len = reader.number()?;
+    let metadata_len = reader.number()?;
+    reader.bytes(metadata_len)?;
+    if source_len != rom.len() {
+        return Err(format!(
+            "Patch is for a {} byte ROM, not {} bytes",
+            source_len,
+            rom.len()
+        ));
+    }
+
+    let mut output = Vec::new();
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    let out_of_range = || "Patch copies from outside the ROM".to_string();
+    while reader.pos < footer {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        if output.len() + len > target_len {
+            return Err("Patch writes past the end of the ROM".to_string());
+        }
+        match action & 3 {
+            // Copy from the same place in the original
+            0 => {
+                let start = output.len();
+                output.extend_from_slice(rom.get(start..start + len).ok_or_else(out_of_range)?);
+            }
+            // New bytes
+            1 => output.extend_from_slice(reader.bytes(len)?),
+            // Copy from elsewhere in the original
+            2 => {
+                source_offset =
+                    relative(source_offset, reader.number()?).ok_or_else(out_of_range)?;
+                let end = source_offset.checked_add(len).ok_or_else(out_of_range)?;
+                let bytes = rom.get(source_offset..end).ok_or_else(out_of_range)?;
+                output.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            // Copy from earlier in the output, which can overlap what's
+            // being written to repeat a pattern
+            _ => {
+                target_offset =
+                    relative(target_offset, reader.number()?).ok_or_else(out_of_range)?;
+                if target_offset >= output.len() {
+                    return Err(out_of_range());
+                }
+                for _ in 0..len {
+                    output.push(output[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if output.len() != target_len {
+        return Err("Patch is truncated".to_string());
+    }
+    if crc32(&output) != checksum(footer + 4) {
+        return Err("Patched ROM's checksum doesn't match".to_string());
+    }
+    Ok(output)
+}
+
+/// Move `offset` by a BPS relative offset, whose lowest bit is the sign.
+fn relative(offset: usize, encoded: usize) -> Option<usize> {
+    let distance = encoded >> 1;
+    if encoded & 1 == 0 {
+        offset.checked_add(distance)
+    } else {
+        offset.checked_sub(distance)
+    }
+}
+
+/// The ranges of `modified` that differ from `original`, with bytes past the
+/// end of `original` counting as different.
+fn differences(original: &[u8], modified: &[u8]) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (i, &byte) in modified.iter().enumerate() {
+        if original.get(i) == Some(&byte) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some((_, end)) if i - *end <= MERGE_GAP => *end = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+    ranges
+}
+
+fn create_ips(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut data = IPS_HEADER.to_vec();
+    for (start, end) in differences(original, modified) {
+        for offset in (start..end).step_by(IPS_MAX_RECORD) {
+            let len = IPS_MAX_RECORD.min(end - offset);
+            data.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+            data.extend_from_slice(&(len as u16).to_be_bytes());
+            data.extend_from_slice(&modified[offset..offset + len]);
+        }
+    }
+    data.extend_from_slice(IPS_FOOTER);
+    if modified.len() < original.len() {
+        data.extend_from_slice(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+    data
+}
+
+fn create_bps(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let mut data = BPS_HEADER.to_vec();
+    write_number(&mut data, original.len());
+    write_number(&mut data, modified.len());
+    write_number(&mut data, 0);
+    let mut pos = 0;
+    let mut ranges = differences(original, modified);
+    // Anything after the last difference is unchanged
+    ranges.push((modified.len(), modified.len()));
+    for (start, end) in ranges {
+        if start > pos {
+            write_number(&mut data, (start - pos - 1) << 2);
+        }
+        if end > start {
+            write_number(&mut data, (end - start - 1) << 2 | 1);
+            data.extend_from_slice(&modified[start..end]);
+        }
+        pos = end;
+    }
+    data.extend_from_slice(&crc32(original).to_le_bytes());
+    data.extend_from_slice(&crc32(modified).to_le_bytes());
+    let checksum = crc32(&data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+fn write_number(data: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let low = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            data.push(low | 0x80);
+            return;
+        }
+        data.push(low);
+        n -= 1;
+    }
+}
+
+/// The CRC-32 used by zip and BPS.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roms() -> (Vec<u8>, Vec<u8>) {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut modified = original.clone();
+        modified[0x10] = 0xAA;
+        modified[0x12] = 0xBB;
+        modified[0x80..0x90].copy_from_slice(&[0x55; 16]);
+        (original, modified)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (original, modified) = roms();
+        let mut shorter = modified.clone();
+        shorter.truncate(200);
+        let mut longer = modified.clone();
+        longer.extend_from_slice(&[1, 2, 3]);
+        for format in [Format::Ips, Format::Bps] {
+            for target in [&modified, &shorter, &longer, &original] {
+                let patch = Patch::create(format, &original, target);
+                let patch = Patch::parse(patch.data().to_vec()).unwrap();
+                assert_eq!(patch.format, format);
+                assert_eq!(&patch.apply(&original).unwrap(), target);
+            }
+        }
+    }
+
+    #[test]
+    fn ips() {
+        let rom = [0u8; 8];
+        // 0x0002: 2 bytes, then 3 copies of 0x7f at 0x0006, then a truncation
+        let patch = Patch::parse(
+            b"PATCH\x00\x00\x02\x00\x02\x12\x34\x00\x00\x06\x00\x00\x00\x03\x7fEOF\x00\x00\x07"
+                .to_vec(),
+        )
+        .unwrap();
+        assert_eq!(patch.apply(&rom).unwrap(), [0, 0, 0x12, 0x34, 0, 0, 0x7F]);
+        let truncated = Patch::parse(b"PATCH\x00\x00\x02\x00\x02\x12".to_vec()).unwrap();
+        assert!(truncated.apply(&rom).is_err());
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let (original, modified) = roms();
+        let patch = Patch::create(Format::Bps, &original, &modified);
+
+        let mut other = original.clone();
+        other[0] = 1;
+        let error = patch.apply(&other).unwrap_err();
+        assert!(error.contains("different ROM"), "{}", error);
+
+        let mut data = patch.data().to_vec();
+        data[6] ^= 1;
+        let error = Patch::parse(data).unwrap().apply(&original).unwrap_err();
+        assert!(error.contains("corrupt"), "{}", error);
+    }
+
+    #[test]
+    fn bps_copies() {
+        let rom = b"abcdef";
+        // Copy 4 bytes from source offset 2, then 6 from output offset 2,
+        // overlapping what's being written
+        let mut data = b"BPS1".to_vec();
+        for n in [6, 10, 0, 3 << 2 | 2, 2 << 1, 5 << 2 | 3, 2 << 1] {
+            write_number(&mut data, n);
+        }
+        data.extend_from_slice(&crc32(rom).to_le_bytes());
+        data.extend_from_slice(&crc32(b"cdefefefef").to_le_bytes());
+        let checksum = crc32(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        let patch = Patch::parse(data).unwrap();
+        assert_eq!(patch.apply(rom).unwrap(), b"cdefefefef");
+    }
+
+    #[test]
+    fn bps_huge_metadata() {
+        let rom = b"abcdef";
+        let mut data = b"BPS1".to_vec();
+        for n in [6, 6, usize::MAX] {
+            write_number(&mut data, n);
+        }
+        data.extend_from_slice(&crc32(rom).to_le_bytes());
+        data.extend_from_slice(&crc32(rom).to_le_bytes());
+        let checksum = crc32(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        let patch = Patch::parse(data).unwrap();
+        assert_eq!(patch.apply(rom).unwrap_err(), "Patch is truncated");
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(Format::from_path(Path::new("fix.IPS")), Some(Format::Ips));
+        assert_eq!(Format::from_path(Path::new("fix.bps")), Some(Format::Bps));
+        assert_eq!(Format::from_path(Path::new("fix")), None);
+        assert!(Patch::parse(b"NOTAPATCH".to_vec()).is_err());
+    }
+}
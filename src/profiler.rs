@@ -0,0 +1,361 @@
+use crate::chip8::{Chip8, Registers, MEMORY_SIZE};
+use crate::disassembler::mnemonic_with_symbols;
+use crate::runner::Hook;
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Entry point of the main program, which the call stack starts in.
+const MAIN: u16 = 0x200;
+
+/// Busiest addresses shown in the report.
+const TOP_ADDRESSES: usize = 10;
+
+/// Attributes executed instructions to subroutines by following CALL and RET.
+///
+/// Each instruction counts towards the subroutine it's in (exclusive) and
+/// every subroutine on the call stack (inclusive). CALL counts towards the
+/// caller and RET towards the subroutine returning.
+pub struct Profiler {
+    /// Entry addresses of the subroutines being executed, outermost first.
+    stack: Vec<u16>,
+    instructions: u64,
+    calls: HashMap<u16, u64>,
+    /// Calls from one subroutine to another.
+    edges: HashMap<(u16, u16), u64>,
+    /// Instructions executed with each call stack seen, so counting one
+    /// only takes an index until CALL or RET changes the stack.
+    stacks: Vec<(Vec<u16>, u64)>,
+    stack_ids: HashMap<Vec<u16>, usize>,
+    /// Index of `stack` in `stacks`.
+    current: usize,
+    addresses: Vec<u64>,
+    /// Last instruction executed at each address.
+    opcodes: Vec<u16>,
+    frames: u64,
+    /// DRW instructions in the current frame.
+    draws: u64,
+    draw_frames: u64,
+    total_draws: u64,
+    /// Frames since the last one that drew anything.
+    gap: u64,
+    longest_gap: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            stack: vec![MAIN],
+            instructions: 0,
+            calls: HashMap::new(),
+            edges: HashMap::new(),
+            stacks: vec![(vec![MAIN], 0)],
+            stack_ids: vec![(vec![MAIN], 0)].into_iter().collect(),
+            current: 0,
+            addresses: vec![0; MEMORY_SIZE],
+            opcodes: vec![0; MEMORY_SIZE],
+            frames: 0,
+            draws: 0,
+            draw_frames: 0,
+            total_draws: 0,
+            gap: 0,
+            longest_gap: 0,
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    /// Count the instruction at `pc`, which `chip` has just executed.
+    pub fn record(&mut self, chip: &Chip8, pc: u16, opcode: u16) {
+        self.instructions += 1;
+        // A skip at the end of memory leaves the PC past it until the next
+        // instruction wraps it round
+        let addr = pc as usize % MEMORY_SIZE;
+        self.addresses[addr] += 1;
+        self.opcodes[addr] = opcode;
+        self.stacks[self.current].1 += 1;
+
+        let mut changed = true;
+        if opcode & 0xF000 == 0x2000 {
+            let current = *self.stack.last().unwrap();
+            let target = opcode & 0x0FFF;
+            *self.calls.entry(target).or_insert(0) += 1;
+            *self.edges.entry((current, target)).or_insert(0) += 1;
+            self.stack.push(target);
+        } else if opcode == 0x00EE && self.stack.len() > 1 {
+            self.stack.pop();
+        } else {
+            if opcode & 0xF000 == 0xD000 {
+                self.draws += 1;
+            }
+            changed = false;
+        }
+
+        // Follow the real stack if something else changed it, like a debugger
+        let depth = chip.registers().sp as usize + 1;
+        if self.stack.len() != depth {
+            self.stack.truncate(depth);
+            while self.stack.len() < depth {
+                self.stack.push(chip.pc());
+            }
+            changed = true;
+        }
+        if changed {
+            self.current = self.intern();
+        }
+    }
+
+    /// Index of the current call stack in `stacks`, adding it if it's new.
+    fn intern(&mut self) -> usize {
+        if let Some(&id) = self.stack_ids.get(&self.stack) {
+            return id;
+        }
+        self.stacks.push((self.stack.clone(), 0));
+        self.stack_ids
+            .insert(self.stack.clone(), self.stacks.len() - 1);
+        self.stacks.len() - 1
+    }
+
+    /// Instructions executed in each subroutine itself.
+    fn exclusive(&self) -> HashMap<u16, u64> {
+        let mut exclusive = HashMap::new();
+        for (stack, count) in self.stacks.iter().filter(|(_, count)| *count > 0) {
+            *exclusive.entry(*stack.last().unwrap()).or_insert(0) += count;
+        }
+        exclusive
+    }
+
+    /// Instructions executed in each subroutine and everything it called.
+    fn inclusive(&self) -> HashMap<u16, u64> {
+        let mut inclusive = HashMap::new();
+        for (stack, count) in self.stacks.iter().filter(|(_, count)| *count > 0) {
+            for (i, entry) in stack.iter().enumerate() {
+                // Recursive subroutines only count once
+                if !stack[..i].contains(entry) {
+                    *inclusive.entry(*entry).or_insert(0) += count;
+                }
+            }
+        }
+        inclusive
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        if self.draws > 0 {
+            self.draw_frames += 1;
+            self.total_draws += self.draws;
+            self.draws = 0;
+            self.gap = 0;
+        } else {
+            self.gap += 1;
+            self.longest_gap = self.longest_gap.max(self.gap);
+        }
+    }
+
+    /// Write a summary of where the time went, naming subroutines that have
+    /// labels in `symbols`.
+    pub fn write_report(&self, out: &mut impl Write, symbols: &Symbols) -> io::Result<()> {
+        let percent = |n: u64| 100.0 * n as f64 / self.instructions.max(1) as f64;
+        writeln!(
+            out,
+            "{} instructions over {} frames",
+            self.instructions, self.frames
+        )?;
+
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:<12} {:>8} {:>12} {:>7} {:>12} {:>7}",
+            "Subroutine", "calls", "inclusive", "%", "exclusive", "%"
+        )?;
+        let (inclusive, exclusive) = (self.inclusive(), self.exclusive());
+        let mut subroutines: Vec<u16> = inclusive.keys().copied().collect();
+        subroutines.sort_by_key(|entry| (std::cmp::Reverse(inclusive[entry]), *entry));
+        for entry in subroutines {
+            let inclusive = inclusive[&entry];
+            let exclusive = exclusive.get(&entry).copied().unwrap_or(0);
+            writeln!(
+                out,
+                "{:<12} {:>8} {:>12} {:>6.1}% {:>12} {:>6.1}%",
+                name(entry, symbols),
+                self.calls.get(&entry).copied().unwrap_or(0),
+                inclusive,
+                percent(inclusive),
+                exclusive,
+                percent(exclusive)
+            )?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Call graph")?;
+        let mut edges: Vec<_> = self.edges.iter().collect();
+        edges.sort_by_key(|&(&edge, &count)| (std::cmp::Reverse(count), edge));
+        for ((caller, callee), count) in edges {
+            let call = format!("{} -> {}", name(*caller, symbols), name(*callee, symbols));
+            writeln!(out, "{:<28} {:>8}", call, count)?;
+        }
+
+        writeln!(out)?;
+        writeln!(out, "Busiest addresses")?;
+        for (addr, count) in self.busiest(TOP_ADDRESSES) {
+            writeln!(
+                out,
+                "{:#05x} {:>12} {:>6.1}%  {}",
+                addr,
+                count,
+                percent(count),
+                mnemonic_with_symbols(self.opcodes[addr as usize], symbols)
+            )?;
+        }
+
+        writeln!(out)?;
+        let draw_percent = 100.0 * self.draw_frames as f64 / self.frames.max(1) as f64;
+        let draws_per_frame = self.total_draws as f64 / self.draw_frames.max(1) as f64;
+        writeln!(
+            out,
+            "{} of {} frames drew ({:.1}%), {:.1} draws per drawing frame, \
+             longest gap {} frames",
+            self.draw_frames, self.frames, draw_percent, draws_per_frame, self.longest_gap
+        )
+    }
+
+    /// Addresses that ran the most instructions, busiest first.
+    pub fn busiest(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut addresses: Vec<(u16, u64)> = (0..MEMORY_SIZE)
+            .filter(|&addr| self.addresses[addr] > 0)
+            .map(|addr| (addr as u16, self.addresses[addr]))
+            .collect();
+        addresses.sort_by_key(|&(addr, count)| (std::cmp::Reverse(count), addr));
+        addresses.truncate(count);
+        addresses
+    }
+
+    /// Write call stacks in the folded format read by flamegraph tools, one
+    /// `main;0x2a4;0x2c0 123` line per stack with its instruction count.
+    pub fn write_folded(&self, out: &mut impl Write, symbols: &Symbols) -> io::Result<()> {
+        let mut stacks: Vec<_> = self.stacks.iter().filter(|(_, count)| *count > 0).collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            let names: Vec<String> = stack.iter().map(|&entry| name(entry, symbols)).collect();
+            writeln!(out, "{} {}", names.join(";"), count)?;
+        }
+        Ok(())
+    }
+}
+
+fn name(entry: u16, symbols: &Symbols) -> String {
+    if let Some(label) = symbols.label(entry) {
+        label.to_string()
+    } else if entry == MAIN {
+        "main".to_string()
+    } else {
+        format!("{:#05x}", entry)
+    }
+}
+
+impl Hook for Profiler {
+    fn instruction(&mut self, chip: &Chip8, before: &Registers, opcode: u16) {
+        self.record(chip, before.pc, opcode);
+    }
+
+    fn end_frame(&mut self, _chip: &mut Chip8) {
+        Profiler::end_frame(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> Profiler {
+        let mut chip = Chip8::new();
+        // 200: CALL 206
+        // 202: DRW V0, V0, 1
+        // 204: JP 200
+        // 206: LD V1, 1
+        // 208: RET
+        chip.load_binary(vec![
+            0x22, 0x06, 0xD0, 0x01, 0x12, 0x00, 0x61, 0x01, 0x00, 0xEE,
+        ]);
+        let mut profiler = Profiler::new();
+        // Two loops of 5 instructions, in frames of 3
+        for n in 0..10 {
+            let (pc, opcode) = (chip.pc(), chip.opcode());
+            chip.step();
+            profiler.record(&chip, pc, opcode);
+            if n % 3 == 2 {
+                profiler.end_frame();
+            }
+        }
+        profiler
+    }
+
+    #[test]
+    fn attribution() {
+        let profiler = profile();
+        assert_eq!(profiler.instructions, 10);
+        assert_eq!(profiler.calls[&0x206], 2);
+        assert_eq!(profiler.inclusive()[&MAIN], 10);
+        assert_eq!(profiler.exclusive()[&MAIN], 6);
+        assert_eq!(profiler.inclusive()[&0x206], 4);
+        assert_eq!(profiler.exclusive()[&0x206], 4);
+        assert_eq!(profiler.edges[&(MAIN, 0x206)], 2);
+        assert_eq!(profiler.busiest(1), vec![(0x200, 2)]);
+        assert_eq!(profiler.stack, vec![MAIN]);
+    }
+
+    #[test]
+    fn skip_past_end_of_memory() {
+        let mut chip = Chip8::new();
+        chip.load_binary(vec![0x1F, 0xFE]);
+        // FFE: SE V0, 0
+        // 002: JP 200
+        chip.memory_mut()[0xFFE] = 0x30;
+        chip.memory_mut()[0x002..0x004].copy_from_slice(&[0x12, 0x00]);
+        let mut profiler = Profiler::new();
+        for _ in 0..3 {
+            let (pc, opcode) = (chip.registers().pc, chip.opcode());
+            chip.step();
+            profiler.record(&chip, pc, opcode);
+        }
+        assert_eq!(
+            profiler.busiest(3),
+            vec![(0x002, 1), (0x200, 1), (0xFFE, 1)]
+        );
+    }
+
+    #[test]
+    fn draw_statistics() {
+        let profiler = profile();
+        // Frames are cycles 0-2, 3-5 and 6-8, with draws at cycles 3 and 8
+        assert_eq!(profiler.frames, 3);
+        assert_eq!(profiler.draw_frames, 2);
+        assert_eq!(profiler.longest_gap, 1);
+    }
+
+    #[test]
+    fn folded_stacks() {
+        let mut out = Vec::new();
+        profile().write_folded(&mut out, &Symbols::new()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "main 6\nmain;0x206 4\n");
+
+        let symbols = Symbols::parse("200 start\n206 draw", std::path::Path::new("")).unwrap();
+        let mut out = Vec::new();
+        profile().write_folded(&mut out, &symbols).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "start 6\nstart;draw 4\n");
+    }
+
+    #[test]
+    fn report() {
+        let mut out = Vec::new();
+        profile().write_report(&mut out, &Symbols::new()).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("10 instructions over 3 frames\n"));
+        assert!(text.contains("\nmain -> 0x206"));
+        assert!(text.contains("2 of 3 frames drew (66.7%)"));
+    }
+}
@@ -0,0 +1,409 @@
+use crate::chip8::{Chip8, HEIGHT, WIDTH};
+use crate::frontend::{Hotkey, Palette};
+use crate::runner::Hook;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Frames per second of the emulated display.
+const FPS: u64 = 60;
+
+/// Shortest GIF frame delay in centiseconds. Most viewers play anything
+/// shorter than this at a much slower rate.
+const MIN_GIF_DELAY: u64 = 2;
+
+/// Video container to record to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Animated GIF, with repeated frames merged.
+    Gif,
+    /// Uncompressed YUV4MPEG2, one picture per frame.
+    Y4m,
+}
+
+impl Format {
+    /// Pick a format from a file extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "gif" => Some(Format::Gif),
+            "y4m" => Some(Format::Y4m),
+            _ => None,
+        }
+    }
+}
+
+/// Appearance of recorded video.
+#[derive(Clone, Copy, Debug)]
+pub struct RecorderConfig {
+    pub palette: Palette,
+    /// Each display pixel becomes a `scale` by `scale` square.
+    pub scale: usize,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            palette: Palette::default(),
+            scale: 4,
+        }
+    }
+}
+
+impl RecorderConfig {
+    /// Start recording to a file, choosing the format from its extension.
+    pub fn create(&self, path: &Path) -> io::Result<Recorder> {
+        let format = Format::from_path(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "recordings must end in .gif or .y4m",
+            )
+        })?;
+        let out = BufWriter::new(File::create(path)?);
+        Recorder::new(out, format, *self)
+    }
+}
+
+/// A recording to a file, started from the command line or a hotkey.
+pub struct Recording {
+    recorder: Recorder,
+    path: PathBuf,
+}
+
+impl Recording {
+    pub fn start(config: &RecorderConfig, path: PathBuf) -> io::Result<Self> {
+        let recorder = config.create(&path)?;
+        eprintln!("Recording to {}", path.display());
+        Ok(Recording { recorder, path })
+    }
+
+    pub fn capture(&mut self, display: &[u32]) -> io::Result<()> {
+        self.recorder.capture(display)
+    }
+
+    pub fn stop(self) -> io::Result<()> {
+        let frames = self.recorder.frames();
+        self.recorder.finish()?;
+        eprintln!("Saved {} frames to {}", frames, self.path.display());
+        Ok(())
+    }
+}
+
+/// Records every frame while a recording is going, which the hotkey starts
+/// and stops.
+#[derive(Default)]
+pub struct VideoRecorder {
+    /// Used for recordings started with the hotkey.
+    pub config: RecorderConfig,
+    pub recording: Option<Recording>,
+}
+
+impl VideoRecorder {
+    pub fn new(config: RecorderConfig, recording: Option<Recording>) -> Self {
+        VideoRecorder { config, recording }
+    }
+
+    /// Start recording to a new timestamped GIF, or stop the current recording.
+    fn toggle(&mut self) {
+        if self.recording.is_some() {
+            self.stop();
+            return;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        let path = PathBuf::from(format!("chip8-{}.gif", time));
+        match Recording::start(&self.config, path) {
+            Ok(recording) => self.recording = Some(recording),
+            Err(e) => eprintln!("Couldn't start recording: {}", e),
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            if let Err(e) = recording.stop() {
+                eprintln!("Couldn't save recording: {}", e);
+            }
+        }
+    }
+}
+
+impl Hook for VideoRecorder {
+    fn end_frame(&mut self, chip: &mut Chip8) {
+        if let Some(recording) = &mut self.recording {
+            if let Err(e) = recording.capture(&chip.display) {
+                eprintln!("Recording failed: {}", e);
+                self.recording = None;
+            }
+        }
+    }
+
+    fn hotkey(&mut self, hotkey: Hotkey) {
+        match hotkey {
+            Hotkey::ToggleRecording => self.toggle(),
+        }
+    }
+
+    fn finish(&mut self, _chip: &mut Chip8) {
+        self.stop();
+    }
+}
+
+enum Encoder<W: Write> {
+    Gif {
+        encoder: gif::Encoder<W>,
+        /// Frame waiting to be written, with the frame number it first appeared on.
+        pending: Option<(Vec<u8>, u64)>,
+    },
+    Y4m(W),
+}
+
+/// Records the display once per frame.
+pub struct Recorder<W: Write = BufWriter<File>> {
+    encoder: Encoder<W>,
+    config: RecorderConfig,
+    frames: u64,
+}
+
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    match e {
+        gif::EncodingError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Time from the start of the recording to a frame, in centiseconds.
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + FPS / 2) / FPS
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut out: W, format: Format, config: RecorderConfig) -> io::Result<Self> {
+        let (width, height) = (WIDTH * config.scale, HEIGHT * config.scale);
+        let encoder = match format {
+            Format::Gif => {
+                let palette: Vec<u8> = config
+                    .palette
+                    .colors
+                    .iter()
+                    .flat_map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8])
+                    .collect();
+                let mut encoder = gif::Encoder::new(out, width as u16, height as u16, &palette)
+                    .map_err(gif_error)?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(gif_error)?;
+                Encoder::Gif {
+                    encoder,
+                    pending: None,
+                }
+            }
+            Format::Y4m => {
+                writeln!(
+                    out,
+                    "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444",
+                    width, height, FPS
+                )?;
+                Encoder::Y4m(out)
+            }
+        };
+
+        Ok(Recorder {
+            encoder,
+            config,
+            frames: 0,
+        })
+    }
+
+    /// Add a frame of video.
+    pub fn capture(&mut self, display: &[u32]) -> io::Result<()> {
+        // Palette indices at display resolution
+        let pixels: Vec<u8> = display.iter().map(|&p| (p != 0) as u8).collect();
+        let frame = self.frames;
+        self.frames += 1;
+
+        match &mut self.encoder {
+            Encoder::Gif { encoder, pending } => match pending.take() {
+                // Unchanged frames just lengthen the previous one
+                Some((last, start)) if last == pixels => *pending = Some((last, start)),
+                // Too soon to show another frame, so this one replaces it
+                Some((_, start)) if centiseconds(frame) - centiseconds(start) < MIN_GIF_DELAY => {
+                    *pending = Some((pixels, start))
+                }
+                Some((last, start)) => {
+                    let delay = centiseconds(frame) - centiseconds(start);
+                    write_gif_frame(encoder, &last, delay, self.config.scale)?;
+                    *pending = Some((pixels, frame));
+                }
+                None => *pending = Some((pixels, frame)),
+            },
+            Encoder::Y4m(out) => {
+                write_y4m_frame(out, &pixels, &self.config)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of frames captured so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Write out anything buffered and return the writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.encoder {
+            Encoder::Gif {
+                mut encoder,
+                pending,
+            } => {
+                if let Some((last, start)) = pending {
+                    let delay = centiseconds(self.frames) - centiseconds(start);
+                    write_gif_frame(&mut encoder, &last, delay, self.config.scale)?;
+                }
+                let mut out = encoder.into_inner()?;
+                out.flush()?;
+                Ok(out)
+            }
+            Encoder::Y4m(mut out) => {
+                out.flush()?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Scale up a frame of palette indices.
+fn scale_pixels(pixels: &[u8], scale: usize) -> Vec<u8> {
+    let mut scaled = Vec::with_capacity(pixels.len() * scale * scale);
+    for row in pixels.chunks(WIDTH) {
+        for _ in 0..scale {
+            for &p in row {
+                scaled.extend(std::iter::repeat_n(p, scale));
+            }
+        }
+    }
+    scaled
+}
+
+fn write_gif_frame<W: Write>(
+    encoder: &mut gif::Encoder<W>,
+    pixels: &[u8],
+    delay: u64,
+    scale: usize,
+) -> io::Result<()> {
+    let frame = gif::Frame {
+        width: (WIDTH * scale) as u16,
+        height: (HEIGHT * scale) as u16,
+        delay: delay.min(u16::MAX as u64) as u16,
+        buffer: scale_pixels(pixels, scale).into(),
+        ..Default::default()
+    };
+    encoder.write_frame(&frame).map_err(gif_error)
+}
+
+/// Convert 0RGB to BT.601 studio range Y'CbCr.
+fn ycbcr(color: u32) -> [u8; 3] {
+    let (r, g, b) = (
+        ((color >> 16) & 0xFF) as f32,
+        ((color >> 8) & 0xFF) as f32,
+        (color & 0xFF) as f32,
+    );
+    let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+    let cb = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+    let cr = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+    [y.round() as u8, cb.round() as u8, cr.round() as u8]
+}
+
+fn write_y4m_frame<W: Write>(
+    out: &mut W,
+    pixels: &[u8],
+    config: &RecorderConfig,
+) -> io::Result<()> {
+    let colors: Vec<[u8; 3]> = config.palette.colors.iter().map(|&c| ycbcr(c)).collect();
+    let scaled = scale_pixels(pixels, config.scale);
+
+    // Planar: all of Y', then Cb, then Cr
+    out.write_all(b"FRAME\n")?;
+    (0..3).try_for_each(|plane| {
+        let bytes: Vec<u8> = scaled.iter().map(|&p| colors[p as usize][plane]).collect();
+        out.write_all(&bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RecorderConfig {
+        RecorderConfig {
+            palette: Palette::default(),
+            scale: 1,
+        }
+    }
+
+    /// Count image descriptors and total delay by walking the GIF blocks.
+    fn gif_frames(data: &[u8]) -> (usize, u64) {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(data).unwrap();
+        let (mut frames, mut delay) = (0, 0);
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames += 1;
+            delay += frame.delay as u64;
+        }
+        (frames, delay)
+    }
+
+    #[test]
+    fn gif_merges_repeated_frames() {
+        let mut recorder = Recorder::new(Vec::new(), Format::Gif, config()).unwrap();
+        let mut display = [0u32; WIDTH * HEIGHT];
+        for _ in 0..30 {
+            recorder.capture(&display).unwrap();
+        }
+        display[0] = u32::MAX;
+        for _ in 0..30 {
+            recorder.capture(&display).unwrap();
+        }
+        let data = recorder.finish().unwrap();
+        assert_eq!(gif_frames(&data), (2, 100));
+    }
+
+    #[test]
+    fn gif_skips_frames_shorter_than_min_delay() {
+        let mut recorder = Recorder::new(Vec::new(), Format::Gif, config()).unwrap();
+        let mut display = [0u32; WIDTH * HEIGHT];
+        for i in 0..60 {
+            display[0] = if i % 2 == 0 { u32::MAX } else { 0 };
+            recorder.capture(&display).unwrap();
+        }
+        let data = recorder.finish().unwrap();
+        let (frames, delay) = gif_frames(&data);
+        assert!(frames <= 50);
+        assert_eq!(delay, 100);
+    }
+
+    #[test]
+    fn y4m_writes_every_frame() {
+        let mut recorder = Recorder::new(Vec::new(), Format::Y4m, config()).unwrap();
+        let display = [0u32; WIDTH * HEIGHT];
+        for _ in 0..3 {
+            recorder.capture(&display).unwrap();
+        }
+        let data = recorder.finish().unwrap();
+        let header = b"YUV4MPEG2 W64 H32 F60:1 Ip A1:1 C444\n";
+        assert!(data.starts_with(header));
+        assert_eq!(data.len(), header.len() + 3 * (6 + 3 * WIDTH * HEIGHT));
+        // Black is (16, 128, 128) in studio range
+        assert_eq!(&data[header.len() + 6..][..1], &[16]);
+    }
+
+    #[test]
+    fn format_from_extension() {
+        assert_eq!(Format::from_path(Path::new("a.GIF")), Some(Format::Gif));
+        assert_eq!(Format::from_path(Path::new("a.y4m")), Some(Format::Y4m));
+        assert_eq!(Format::from_path(Path::new("a.mp4")), None);
+    }
+}
@@ -0,0 +1,490 @@
+use crate::chip8::{Chip8, HostOutput, Registers};
+use crate::debug::Watchpoints;
+use crate::frontend::{Frontend, Hotkey};
+use crate::symbols::Symbols;
+use crate::trace::TraceLine;
+use std::any::Any;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Instructions executed per frame by default.
+pub const DEFAULT_SPEED: usize = 10;
+
+/// Something that follows a `Runner` as it runs, like a trace, a movie or
+/// cheats. Each method does nothing unless the hook needs it.
+pub trait Hook: Any {
+    /// Before the first instruction of a frame, once input has been read.
+    /// Hooks can change the chip, as movies set the keypad.
+    fn begin_frame(&mut self, _chip: &mut Chip8) {}
+
+    /// After an instruction ran, with the registers from before it.
+    fn instruction(&mut self, _chip: &Chip8, _before: &Registers, _opcode: u16) {}
+
+    /// After the timers ticked and the display was presented.
+    fn end_frame(&mut self, _chip: &mut Chip8) {}
+
+    fn hotkey(&mut self, _hotkey: Hotkey) {}
+
+    /// Whether execution should stop before the end of the frame, as it does
+    /// when a watchpoint triggers.
+    fn stopped(&self) -> bool {
+        false
+    }
+
+    /// The run is over, so save anything collected.
+    fn finish(&mut self, _chip: &mut Chip8) {}
+}
+
+/// The hooks added to a `Runner`, called in the order they were added. There
+/// is at most one of each type, found by type.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Box<dyn Hook>>,
+}
+
+impl Hooks {
+    /// Add `hook`, replacing any hook of the same type.
+    pub fn add<H: Hook>(&mut self, hook: H) {
+        match self.get_mut::<H>() {
+            Some(old) => *old = hook,
+            None => self.hooks.push(Box::new(hook)),
+        }
+    }
+
+    pub fn get<H: Hook>(&self) -> Option<&H> {
+        self.hooks
+            .iter()
+            .find_map(|hook| (hook.as_ref() as &dyn Any).downcast_ref())
+    }
+
+    pub fn get_mut<H: Hook>(&mut self) -> Option<&mut H> {
+        self.hooks
+            .iter_mut()
+            .find_map(|hook| (hook.as_mut() as &mut dyn Any).downcast_mut())
+    }
+
+    /// The hook of type `H`, adding a default one if there isn't one yet.
+    pub fn get_or_default<H: Hook + Default>(&mut self) -> &mut H {
+        if self.get::<H>().is_none() {
+            self.hooks.push(Box::new(H::default()));
+        }
+        self.get_mut().unwrap()
+    }
+
+    pub fn remove<H: Hook>(&mut self) -> Option<H> {
+        let index = self
+            .hooks
+            .iter()
+            .position(|hook| (hook.as_ref() as &dyn Any).is::<H>())?;
+        let hook: Box<dyn Any> = self.hooks.remove(index);
+        hook.downcast().ok().map(|hook| *hook)
+    }
+
+    fn stopped(&self) -> bool {
+        self.hooks.iter().any(|hook| hook.stopped())
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Box<dyn Hook>> {
+        self.hooks.iter_mut()
+    }
+}
+
+/// Prints what host calls print and counts the assertions that failed, for
+/// `--semihosting`.
+#[derive(Debug, Default)]
+pub struct HostConsole {
+    pub failures: usize,
+}
+
+impl HostConsole {
+    fn print(&mut self, chip: &mut Chip8) {
+        for output in chip.take_host_output() {
+            match output {
+                HostOutput::Text(text) => println!("{}", text),
+                HostOutput::Failure(message) => {
+                    eprintln!("{}", message);
+                    self.failures += 1;
+                }
+            }
+        }
+    }
+}
+
+impl Hook for HostConsole {
+    fn end_frame(&mut self, chip: &mut Chip8) {
+        self.print(chip);
+    }
+
+    fn finish(&mut self, chip: &mut Chip8) {
+        self.print(chip);
+    }
+}
+
+/// Drives a `Chip8` through a `Frontend` at 60 frames per second.
+///
+/// Each frame polls input, executes `speed` instructions, ticks the timers,
+/// updates the buzzer and presents the display. Everything else, like
+/// traces, movies and watchpoints, is added as a `Hook`.
+pub struct Runner<F: Frontend> {
+    pub chip: Chip8,
+    pub frontend: F,
+    /// Instructions executed per frame.
+    pub speed: usize,
+    /// Sleep between frames to run in real time. Headless runs turn this off.
+    pub throttle: bool,
+    pub hooks: Hooks,
+    /// Labels for warnings and debuggers.
+    pub symbols: Symbols,
+    tone: bool,
+    /// Instructions executed so far in the current frame.
+    cycle: usize,
+    frame_start: Instant,
+}
+
+impl<F: Frontend> Runner<F> {
+    pub fn new(chip: Chip8, frontend: F) -> Self {
+        Runner {
+            chip,
+            frontend,
+            speed: DEFAULT_SPEED,
+            throttle: true,
+            hooks: Hooks::default(),
+            symbols: Symbols::new(),
+            tone: false,
+            cycle: 0,
+            frame_start: Instant::now(),
+        }
+    }
+
+    /// Run frames until the frontend asks to quit or a watchpoint stops
+    /// execution.
+    pub fn run(&mut self) {
+        while !self.frontend.should_quit() && self.frame() {
+            self.wait_for_frame();
+        }
+        self.finish();
+    }
+
+    /// Run a frame and print what strict mode and watchpoints logged.
+    /// Returns false if a watchpoint stopped execution.
+    pub fn frame(&mut self) -> bool {
+        self.run_frame();
+        self.print_warnings();
+        let watchpoints = match self.hooks.get_mut::<Watchpoints>() {
+            Some(watchpoints) => watchpoints,
+            None => return true,
+        };
+        for line in watchpoints.take_log() {
+            eprintln!("{}", line);
+        }
+        if let Some(hit) = watchpoints.take_triggered() {
+            let watchpoint = watchpoints.get(hit.id).unwrap();
+            eprintln!(
+                "Stopped by watchpoint {} (hit {}): {}",
+                hit.id, watchpoint.hits, watchpoint.text
+            );
+            let registers = self.chip.registers();
+            let line = TraceLine::new(self.chip.cycles(), hit.pc, hit.opcode, &registers);
+            eprintln!("{}", line.format(&self.symbols));
+            return false;
+        }
+        true
+    }
+
+    /// Run until the end of the current frame, or until a hook like a
+    /// watchpoint stops execution.
+    pub fn run_frame(&mut self) {
+        self.step();
+        while self.cycle != 0 && !self.hooks.stopped() {
+            self.step();
+        }
+    }
+
+    /// Execute one instruction. Input is read before the first instruction
+    /// of a frame, and the timers and display are updated after the last.
+    pub fn step(&mut self) {
+        if self.cycle == 0 {
+            self.begin_frame();
+        }
+        if self.cycle < self.speed {
+            let opcode = self.chip.opcode();
+            let before = self.chip.registers();
+            self.chip.step();
+            for hook in self.hooks.iter_mut() {
+                hook.instruction(&self.chip, &before, opcode);
+            }
+        }
+        self.cycle += 1;
+        if self.cycle >= self.speed {
+            self.end_frame();
+            self.cycle = 0;
+        }
+    }
+
+    /// Undo the last instruction if the chip keeps a history, going back a
+    /// frame if it was the last of one. Returns false if there's no history.
+    pub fn step_back(&mut self) -> bool {
+        if !self.chip.step_back() {
+            return false;
+        }
+        // Frames stay lined up, so timers tick at the same points when
+        // running forwards again
+        self.cycle = match self.cycle {
+            0 => self.speed.max(1) - 1,
+            cycle => cycle - 1,
+        };
+        true
+    }
+
+    /// Whether the next `step` starts a new frame.
+    pub fn at_frame_start(&self) -> bool {
+        self.cycle == 0
+    }
+
+    /// Sleep until it's time for the next frame, if throttled.
+    pub fn wait_for_frame(&mut self) {
+        let frame_time = Duration::from_secs(1) / 60;
+        if self.throttle {
+            if let Some(remaining) = frame_time.checked_sub(self.frame_start.elapsed()) {
+                thread::sleep(remaining);
+            }
+        }
+        self.frame_start = Instant::now();
+    }
+
+    /// Keep the frontend responsive while the emulator is paused.
+    pub fn idle(&mut self) {
+        let mut keypad = self.chip.keypad;
+        self.frontend.poll_input(&mut keypad);
+        self.frontend.present(&self.chip.display);
+    }
+
+    /// Silence the buzzer and let the hooks save what they collected.
+    pub fn finish(&mut self) {
+        if self.tone {
+            self.frontend.stop_tone();
+            self.tone = false;
+        }
+        self.print_warnings();
+        for hook in self.hooks.iter_mut() {
+            hook.finish(&mut self.chip);
+        }
+    }
+
+    /// Print what strict mode found.
+    fn print_warnings(&mut self) {
+        for warning in self.chip.take_warnings() {
+            eprintln!("{}", warning.format(&self.symbols));
+        }
+    }
+
+    fn begin_frame(&mut self) {
+        for hotkey in self.frontend.take_hotkeys() {
+            for hook in self.hooks.iter_mut() {
+                hook.hotkey(hotkey);
+            }
+        }
+        self.frontend.poll_input(&mut self.chip.keypad);
+        for hook in self.hooks.iter_mut() {
+            hook.begin_frame(&mut self.chip);
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.chip.decrement_timers();
+
+        let tone = self.chip.sound_active();
+        if tone != self.tone {
+            if tone {
+                self.frontend.play_tone();
+            } else {
+                self.frontend.stop_tone();
+            }
+            self.tone = tone;
+        }
+
+        self.frontend.present(&self.chip.display);
+        for hook in self.hooks.iter_mut() {
+            hook.end_frame(&mut self.chip);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::{Cheats, Freeze};
+
+    /// Feeds a fixed keypad state and records what it was asked to do.
+    #[derive(Default)]
+    struct TestFrontend {
+        keypad: [bool; 16],
+        frames: Vec<Vec<u32>>,
+        tones: Vec<bool>,
+        quit_after: usize,
+    }
+
+    impl Frontend for TestFrontend {
+        fn poll_input(&mut self, keypad: &mut [bool; 16]) {
+            *keypad = self.keypad;
+        }
+
+        fn present(&mut self, display: &[u32]) {
+            self.frames.push(display.to_vec());
+        }
+
+        fn play_tone(&mut self) {
+            self.tones.push(true);
+        }
+
+        fn stop_tone(&mut self) {
+            self.tones.push(false);
+        }
+
+        fn should_quit(&self) -> bool {
+            self.frames.len() >= self.quit_after
+        }
+    }
+
+    #[test]
+    fn runs_until_quit() {
+        let mut chip = Chip8::new();
+        // 200: JP 200
+        chip.load_binary(vec![0x12, 0x00]);
+        let frontend = TestFrontend {
+            quit_after: 3,
+            ..Default::default()
+        };
+        let mut runner = Runner::new(chip, frontend);
+        runner.throttle = false;
+        runner.run();
+        assert_eq!(runner.frontend.frames.len(), 3);
+    }
+
+    #[test]
+    fn tone_follows_sound_timer() {
+        let mut chip = Chip8::new();
+        // 200: LD V0, 2
+        // 202: LD ST, V0
+        // 204: JP 204
+        chip.load_binary(vec![0x60, 0x02, 0xF0, 0x18, 0x12, 0x04]);
+        let frontend = TestFrontend {
+            quit_after: 4,
+            ..Default::default()
+        };
+        let mut runner = Runner::new(chip, frontend);
+        runner.throttle = false;
+        runner.run();
+        assert_eq!(runner.frontend.tones, vec![true, false]);
+    }
+
+    #[test]
+    fn watchpoint_stops_run() {
+        let mut chip = Chip8::new();
+        // 200: ADD V3, 1
+        // 202: JP 200
+        chip.load_binary(vec![0x73, 0x01, 0x12, 0x00]);
+        let frontend = TestFrontend {
+            quit_after: 100,
+            ..Default::default()
+        };
+        let mut runner = Runner::new(chip, frontend);
+        runner.throttle = false;
+        let watchpoint = crate::debug::Watchpoint::new("v3 == 25", &Symbols::new()).unwrap();
+        runner
+            .hooks
+            .get_or_default::<Watchpoints>()
+            .insert(watchpoint);
+        runner.run();
+        assert_eq!(runner.chip.registers().v[3], 25);
+        assert_eq!(runner.chip.pc(), 0x202);
+        assert_eq!(runner.frontend.frames.len(), 4);
+    }
+
+    #[test]
+    fn cheats_apply_every_frame() {
+        let mut chip = Chip8::new();
+        // 200: ADD V3, 1
+        // 202: JP 200
+        chip.load_binary(vec![0x73, 0x01, 0x12, 0x00]);
+        let mut runner = Runner::new(chip, TestFrontend::default());
+        runner.speed = 4;
+        let mut cheats = Cheats::default();
+        cheats.freeze(Freeze::parse("v3=10").unwrap());
+        runner.hooks.add(cheats);
+        runner.run_frame();
+        assert_eq!(runner.chip.registers().v[3], 12);
+        runner.run_frame();
+        assert_eq!(runner.chip.registers().v[3], 12);
+    }
+
+    /// Counts instructions and frames, stopping after `stop_after`
+    /// instructions.
+    #[derive(Default)]
+    struct Counter {
+        instructions: usize,
+        frames: usize,
+        stop_after: usize,
+    }
+
+    impl Hook for Counter {
+        fn instruction(&mut self, _chip: &Chip8, _before: &Registers, _opcode: u16) {
+            self.instructions += 1;
+        }
+
+        fn end_frame(&mut self, _chip: &mut Chip8) {
+            self.frames += 1;
+        }
+
+        fn stopped(&self) -> bool {
+            self.instructions == self.stop_after
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let mut chip = Chip8::new();
+        // 200: JP 200
+        chip.load_binary(vec![0x12, 0x00]);
+        let mut runner = Runner::new(chip, TestFrontend::default());
+        runner.speed = 4;
+        runner.hooks.get_or_default::<Counter>().stop_after = 6;
+        runner.run_frame();
+        runner.run_frame();
+        let counter = runner.hooks.get::<Counter>().unwrap();
+        assert_eq!((counter.instructions, counter.frames), (6, 1));
+
+        // Adding another replaces it
+        runner.hooks.add(Counter::default());
+        assert_eq!(runner.hooks.get::<Counter>().unwrap().instructions, 0);
+        assert!(runner.hooks.remove::<Counter>().is_some());
+        assert!(runner.hooks.get::<Counter>().is_none());
+    }
+
+    #[test]
+    fn step_back_rewinds_frames() {
+        let mut chip = Chip8::new();
+        // 200: ADD V3, 1
+        // 202: JP 200
+        chip.load_binary(vec![0x73, 0x01, 0x12, 0x00]);
+        chip.enable_history(100);
+        let mut registers = chip.registers();
+        registers.delay = 10;
+        chip.set_registers(&registers);
+        let mut runner = Runner::new(chip, TestFrontend::default());
+        runner.speed = 3;
+        for _ in 0..4 {
+            runner.step();
+        }
+        let after = runner.chip.registers();
+        assert_eq!(after.delay, 9);
+
+        // Back across the end of the first frame, so the timer ticks again
+        assert!(runner.step_back() && runner.step_back());
+        assert_eq!(runner.chip.registers().delay, 10);
+        assert!(!runner.at_frame_start());
+        runner.step();
+        runner.step();
+        assert_eq!(runner.chip.registers(), after);
+    }
+}
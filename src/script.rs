@@ -0,0 +1,283 @@
+//! Scripts that drive the emulator, for bots, menus and regression tests,
+//! written in [Rhai](https://rhai.rs). The script runs when the ROM starts
+//! and can use:
+//!
+//! | Function              | Does                                            |
+//! |-----------------------|-------------------------------------------------|
+//! | `frames(n)`           | Run `n` frames                                  |
+//! | `step()`              | Run one instruction                             |
+//! | `press(k)`            | Hold keypad key `k` down until `release(k)`     |
+//! | `release(k)`          | Let go of key `k`                               |
+//! | `peek(addr)`          | Read a byte of memory                           |
+//! | `poke(addr, value)`   | Write a byte of memory                          |
+//! | `v(x)`, `set_v(x, n)` | Read or write Vx                                |
+//! | `i()`, `pc()`         | Read I or the PC                                |
+//! | `cycles()`            | Instructions run so far                         |
+//! | `pixel(x, y)`         | Whether a pixel is on                           |
+//! | `screen()`            | The display as lines of `#` and `.`             |
+//! | `on_frame(f)`         | Call `f` after every frame from now on          |
+//! | `quit()`              | Stop the emulator                               |
+//!
+//! When the script ends the emulator keeps running, still calling the
+//! `on_frame` callbacks, until the frontend quits.
+
+use crate::chip8::{Chip8, HEIGHT, WIDTH};
+use crate::frontend::Frontend;
+use crate::runner::{Hook, Runner};
+use rhai::{Engine, EvalAltResult, FnPtr, NativeCallContext, AST, INT};
+use std::cell::{Cell, RefCell};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+type Result<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+/// A compiled script.
+pub struct Script {
+    ast: AST,
+}
+
+/// What the functions given to the script share.
+struct State<F: Frontend> {
+    runner: Rc<RefCell<Runner<F>>>,
+    callbacks: RefCell<Vec<FnPtr>>,
+    /// Set by `quit()`, or when the frontend quits or a watchpoint stops
+    /// execution.
+    stopped: Cell<bool>,
+}
+
+/// Error thrown to unwind the script when it's stopped.
+const STOPPED: &str = "stopped";
+
+/// Keys held down by the script, on top of the frontend's input.
+#[derive(Default)]
+struct HeldKeys([bool; 16]);
+
+impl Hook for HeldKeys {
+    fn begin_frame(&mut self, chip: &mut Chip8) {
+        for (key, &held) in chip.keypad.iter_mut().zip(&self.0) {
+            *key |= held;
+        }
+    }
+}
+
+impl Script {
+    pub fn load(path: &Path) -> std::result::Result<Self, String> {
+        let source = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Script::compile(&source)
+    }
+
+    pub fn compile(source: &str) -> std::result::Result<Self, String> {
+        let ast = Engine::new().compile(source).map_err(|e| e.to_string())?;
+        Ok(Script { ast })
+    }
+
+    /// Run the script, then keep running the emulator until the frontend
+    /// quits, and finish as `Runner::run` does.
+    pub fn run<F: Frontend + 'static>(
+        &self,
+        runner: &Rc<RefCell<Runner<F>>>,
+    ) -> std::result::Result<(), String> {
+        let state = Rc::new(State {
+            runner: runner.clone(),
+            callbacks: RefCell::new(Vec::new()),
+            stopped: Cell::new(false),
+        });
+        let engine = engine(&state);
+        let mut result = engine.run_ast(&self.ast);
+        while result.is_ok() && !state.stopped.get() {
+            result = state.frame(None).and_then(|_| {
+                let callbacks = state.callbacks.borrow().clone();
+                for callback in callbacks {
+                    callback.call::<()>(&engine, &self.ast, ())?;
+                }
+                Ok(())
+            });
+        }
+        runner.borrow_mut().finish();
+        match result {
+            Err(_) if state.stopped.get() => Ok(()),
+            result => result.map_err(|e| format!("Script failed: {}", e)),
+        }
+    }
+}
+
+impl<F: Frontend> State<F> {
+    /// Run a frame, then the callbacks if `context` is given.
+    fn frame(&self, context: Option<&NativeCallContext>) -> Result<()> {
+        if self.stopped.get() {
+            return Err(STOPPED.into());
+        }
+        {
+            let mut runner = self.runner.borrow_mut();
+            if runner.frontend.should_quit() || !runner.frame() {
+                self.stopped.set(true);
+                return Err(STOPPED.into());
+            }
+            runner.wait_for_frame();
+        }
+        if let Some(context) = context {
+            let callbacks = self.callbacks.borrow().clone();
+            for callback in callbacks {
+                callback.call_within_context::<()>(context, ())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn engine<F: Frontend + 'static>(state: &Rc<State<F>>) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = state.clone();
+    engine.register_fn(
+        "frames",
+        move |context: NativeCallContext, n: INT| -> Result<()> {
+            for _ in 0..n {
+                s.frame(Some(&context))?;
+            }
+            Ok(())
+        },
+    );
+    let s = state.clone();
+    engine.register_fn("step", move || s.runner.borrow_mut().step());
+    let s = state.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| {
+        s.callbacks.borrow_mut().push(callback)
+    });
+    let s = state.clone();
+    engine.register_fn("quit", move || -> Result<()> {
+        s.stopped.set(true);
+        Err(STOPPED.into())
+    });
+
+    for (name, down) in [("press", true), ("release", false)] {
+        let s = state.clone();
+        engine.register_fn(name, move |key: INT| -> Result<()> {
+            let key = index(key, 16, "key")?;
+            let mut runner = s.runner.borrow_mut();
+            runner.hooks.get_or_default::<HeldKeys>().0[key] = down;
+            runner.chip.keypad[key] = down;
+            Ok(())
+        });
+    }
+
+    let s = state.clone();
+    engine.register_fn("peek", move |addr: INT| -> Result<INT> {
+        let runner = s.runner.borrow();
+        let memory = runner.chip.memory();
+        Ok(memory[index(addr, memory.len(), "address")?] as INT)
+    });
+    let s = state.clone();
+    engine.register_fn("poke", move |addr: INT, value: INT| -> Result<()> {
+        let mut runner = s.runner.borrow_mut();
+        let addr = index(addr, runner.chip.memory().len(), "address")?;
+        runner.chip.poke(addr as u16, byte(value)?);
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("v", move |x: INT| -> Result<INT> {
+        let registers = s.runner.borrow().chip.registers();
+        Ok(registers.v[index(x, 16, "register")?] as INT)
+    });
+    let s = state.clone();
+    engine.register_fn("set_v", move |x: INT, value: INT| -> Result<()> {
+        let mut runner = s.runner.borrow_mut();
+        let mut registers = runner.chip.registers();
+        registers.v[index(x, 16, "register")?] = byte(value)?;
+        runner.chip.set_registers(&registers);
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("i", move || s.runner.borrow().chip.registers().i as INT);
+    let s = state.clone();
+    engine.register_fn("pc", move || s.runner.borrow().chip.pc() as INT);
+    let s = state.clone();
+    engine.register_fn("cycles", move || s.runner.borrow().chip.cycles() as INT);
+    let s = state.clone();
+    engine.register_fn("pixel", move |x: INT, y: INT| -> Result<bool> {
+        let (x, y) = (index(x, WIDTH, "x")?, index(y, HEIGHT, "y")?);
+        Ok(s.runner.borrow().chip.display[y * WIDTH + x] != 0)
+    });
+    let s = state.clone();
+    engine.register_fn("screen", move || s.runner.borrow().chip.display_text());
+
+    engine
+}
+
+/// Check `n` is below `len`.
+fn index(n: INT, len: usize, what: &str) -> Result<usize> {
+    usize::try_from(n)
+        .ok()
+        .filter(|&n| n < len)
+        .ok_or_else(|| format!("Invalid {} {}", what, n).into())
+}
+
+fn byte(value: INT) -> Result<u8> {
+    u8::try_from(value).map_err(|_| format!("Invalid byte {}", value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::Headless;
+
+    fn runner(frames: Option<usize>) -> Rc<RefCell<Runner<Headless>>> {
+        let mut chip = Chip8::with_seed(1);
+        // 200: LD V1, K
+        // 202: LD F, V1
+        // 204: DRW V0, V0, 5
+        // 206: JP 206
+        chip.load_binary(vec![0xF1, 0x0A, 0xF1, 0x29, 0xD0, 0x05, 0x12, 0x06]);
+        let mut runner = Runner::new(chip, Headless::new(frames));
+        runner.throttle = false;
+        Rc::new(RefCell::new(runner))
+    }
+
+    #[test]
+    fn bindings() {
+        let runner = runner(Some(100));
+        let script = Script::compile(
+            r#"
+            let seen = [];
+            on_frame(|| seen.push(pc()));
+            frames(2);
+            if pc() != 0x200 { throw "not waiting for a key"; }
+            press(0xA);
+            frames(1);
+            release(0xA);
+            if v(1) != 10 { throw `v1 is ${v(1)}`; }
+            if !pixel(0, 0) || pixel(4, 0) { throw screen(); }
+            poke(0x300, 0xFF);
+            set_v(3, peek(0x300));
+            if seen != [0x200, 0x200, 0x206] { throw seen; }
+            quit();
+            throw "quit() returned";
+            "#,
+        )
+        .unwrap();
+        script.run(&runner).unwrap();
+        let runner = runner.borrow();
+        assert_eq!(runner.chip.registers().v[3], 0xFF);
+        assert!(!runner.chip.keypad[0xA]);
+    }
+
+    #[test]
+    fn keeps_running_callbacks() {
+        let runner = runner(Some(10));
+        let script = Script::compile("on_frame(|| poke(0x300, peek(0x300) + 1));").unwrap();
+        script.run(&runner).unwrap();
+        assert_eq!(runner.borrow().chip.memory()[0x300], 10);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Script::compile("frames(").is_err());
+        let script = Script::compile("poke(0x300, 256);").unwrap();
+        let error = script.run(&runner(None)).unwrap_err();
+        assert!(error.contains("Invalid byte 256"), "{}", error);
+        let script = Script::compile("press(16);").unwrap();
+        assert!(script.run(&runner(None)).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub type Section = BTreeMap<String, String>;
+
+/// Settings remembered per ROM, keyed by the ROM's SHA-1.
+///
+/// Stored as an INI-style file with one `[sha1]` section per ROM and
+/// `key = value` lines, in `$XDG_CONFIG_HOME/chip8/roms.ini` or
+/// `~/.config/chip8/roms.ini`.
+pub struct Settings {
+    path: Option<PathBuf>,
+    sections: BTreeMap<String, Section>,
+}
+
+impl Settings {
+    /// Load the settings file. A missing or unreadable file gives empty settings.
+    pub fn load() -> Self {
+        Settings::load_file(config_path("roms.ini"))
+    }
+
+    /// Load another file in the same format, or nothing if `path` is `None`.
+    pub fn load_file(path: Option<PathBuf>) -> Self {
+        let sections = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| parse(&text))
+            .unwrap_or_default();
+
+        Settings { path, sections }
+    }
+
+    pub fn rom(&self, rom_hash: &str) -> Option<&Section> {
+        self.sections.get(rom_hash)
+    }
+
+    pub fn set_rom(&mut self, rom_hash: &str, section: Section) {
+        self.sections.insert(rom_hash.to_string(), section);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, format(&self.sections))
+    }
+}
+
+/// Path of a file in the config directory, `$XDG_CONFIG_HOME/chip8` or
+/// `~/.config/chip8`.
+pub fn config_path(file_name: &str) -> Option<PathBuf> {
+    let config = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("chip8").join(file_name))
+}
+
+/// Parse INI-style text. Lines outside a section, comments (`#` or `;`) and
+/// lines without `=` are ignored.
+pub fn parse(text: &str) -> BTreeMap<String, Section> {
+    let mut sections = BTreeMap::new();
+    let mut current = None;
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let name = line[1..line.len() - 1].trim().to_string();
+            sections.entry(name.clone()).or_insert_with(Section::new);
+            current = Some(name);
+        } else if let (Some(name), Some((key, value))) = (&current, line.split_once('=')) {
+            sections
+                .get_mut(name)
+                .unwrap()
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+
+    sections
+}
+
+fn format(sections: &BTreeMap<String, Section>) -> String {
+    let mut text = String::new();
+    for (name, section) in sections {
+        text += &format!("[{}]\n", name);
+        for (key, value) in section {
+            text += &format!("{} = {}\n", key, value);
+        }
+        text += "\n";
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sections() {
+        let sections = parse(
+            "stray = 1\n\
+             # comment\n\
+             [abc]\n\
+             palette = amber\n\
+             scale=4\n\
+             \n\
+             [def]\n\
+             ; comment\n\
+             not a setting\n",
+        );
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections["abc"]["palette"], "amber");
+        assert_eq!(sections["abc"]["scale"], "4");
+        assert!(sections["def"].is_empty());
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let mut sections = BTreeMap::new();
+        let mut section = Section::new();
+        section.insert("resizable".to_string(), "true".to_string());
+        section.insert("palette".to_string(), "#000000,#ffffff".to_string());
+        sections.insert("0123abcd".to_string(), section);
+        assert_eq!(parse(&format(&sections)), sections);
+    }
+}
@@ -0,0 +1,348 @@
+//! Runs Timendus' CHIP-8 test suite (https://github.com/Timendus/chip8-test-suite)
+//! without a keyboard. The ROMs read 0x1FF to choose a test or platform, so
+//! each case pokes it before running, and keypad tests are given the keys
+//! they ask for.
+//!
+//! The tests mark each result on screen with a tick or a cross, so a case
+//! passes when the screen it ends on has ticks and no crosses. The quirks
+//! test is left out: on CHIP-8 it checks that drawing waits for the vertical
+//! blank, which the emulator doesn't do, and the SUPER-CHIP and XO-CHIP
+//! platforms use instructions it doesn't have.
+//!
+//! The ROMs aren't distributed with the emulator, so the test is ignored by
+//! default. Run it with `cargo test -- --ignored` after putting them in
+//! `programs/chip8-test-suite`, or in `$CHIP8_TEST_SUITE`.
+
+use crate::cheats::Poke;
+use crate::chip8::{Chip8, Platform, HEIGHT, WIDTH};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// Where the byte choosing the test or platform goes.
+pub const OPTION_ADDR: u16 = 0x1FF;
+
+/// Instructions per frame, enough for every test to finish in time.
+const SPEED: usize = 1000;
+
+/// The mark for a test that passed.
+const TICK: &[&str] = &["....#", "...#.", "#.#..", ".#..."];
+
+/// The mark for a test that failed.
+const CROSS: &[&str] = &["#...#", ".#.#.", "..#..", ".#.#.", "#...#"];
+
+/// Frames a key is held or released for in keypad tests.
+const KEY_FRAMES: usize = 10;
+
+/// How a case is driven and checked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// Runs to the end by itself, marking each test.
+    Results,
+    /// Highlights the keys held down (EX9E) or not held down (EXA1), so
+    /// holding each key in turn should change the screen, and letting go
+    /// should change it back.
+    ShowsKeys,
+    /// Waits for a key to be pressed and released (FX0A), then marks the
+    /// result.
+    WaitsForKey,
+}
+
+/// A suite ROM run with one set of options.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Case {
+    pub name: &'static str,
+    /// File name in the suite.
+    pub rom: &'static str,
+    /// Value for `OPTION_ADDR`, if the ROM reads it.
+    pub option: Option<u8>,
+    pub kind: Kind,
+    /// Most frames to run for before the results are all drawn.
+    pub frames: usize,
+}
+
+/// The tests that can run on the emulator.
+pub const CASES: &[Case] = &[
+    Case {
+        name: "corax",
+        rom: "3-corax+.ch8",
+        option: None,
+        kind: Kind::Results,
+        frames: 60,
+    },
+    Case {
+        name: "flags",
+        rom: "4-flags.ch8",
+        option: None,
+        kind: Kind::Results,
+        frames: 120,
+    },
+    Case {
+        name: "keypad-ex9e",
+        rom: "6-keypad.ch8",
+        option: Some(1),
+        kind: Kind::ShowsKeys,
+        frames: 60,
+    },
+    Case {
+        name: "keypad-exa1",
+        rom: "6-keypad.ch8",
+        option: Some(2),
+        kind: Kind::ShowsKeys,
+        frames: 60,
+    },
+    Case {
+        name: "keypad-fx0a",
+        rom: "6-keypad.ch8",
+        option: Some(3),
+        kind: Kind::WaitsForKey,
+        frames: 60,
+    },
+];
+
+impl Case {
+    /// Load the case's ROM from `suite`.
+    pub fn load(&self, suite: &Path) -> Result<Vec<u8>, String> {
+        let path = suite.join(self.rom);
+        fs::read(&path).map_err(|e| format!("Couldn't load {}: {}", path.display(), e))
+    }
+
+    /// Set up a chip for the case with its options.
+    pub fn start(&self, rom: Vec<u8>) -> Chip8 {
+        let mut chip = Chip8::with_seed(0);
+        chip.quirks = Platform::Chip8.quirks();
+        chip.load_binary(rom);
+        if let Some(value) = self.option {
+            let poke = Poke {
+                addr: OPTION_ADDR,
+                value,
+            };
+            poke.apply(&mut chip);
+        }
+        chip
+    }
+
+    /// Run the case, returning why it failed if it did.
+    pub fn check(&self, rom: Vec<u8>) -> Result<(), String> {
+        let mut chip = self.start(rom);
+        // Instructions the emulator doesn't have stop it with a panic
+        panic::catch_unwind(AssertUnwindSafe(|| self.drive(&mut chip)))
+            .unwrap_or_else(|_| Err("stopped with an error".to_string()))
+            .map_err(|e| format!("{}: {}\n{}", self.name, e, chip.display_text()))
+    }
+
+    fn drive(&self, chip: &mut Chip8) -> Result<(), String> {
+        match self.kind {
+            Kind::Results => {
+                if !run_until_halted(chip, self.frames) {
+                    return Err(format!("didn't finish in {} frames", self.frames));
+                }
+                check_marks(&chip.display)
+            }
+            Kind::ShowsKeys => {
+                run(chip, self.frames, None);
+                let idle = chip.display;
+                for key in 0..16 {
+                    run(chip, KEY_FRAMES, Some(key));
+                    if chip.display == idle {
+                        return Err(format!("holding key {:X} didn't change the screen", key));
+                    }
+                    run(chip, KEY_FRAMES, None);
+                    if chip.display != idle {
+                        return Err(format!(
+                            "letting go of key {:X} left the screen changed",
+                            key
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            Kind::WaitsForKey => {
+                run(chip, self.frames, None);
+                run(chip, KEY_FRAMES, Some(5));
+                run(chip, self.frames, None);
+                check_marks(&chip.display)
+            }
+        }
+    }
+}
+
+/// Run `frames` frames with `key` held down, if any.
+fn run(chip: &mut Chip8, frames: usize, key: Option<usize>) {
+    for _ in 0..frames {
+        chip.keypad = [false; 16];
+        if let Some(key) = key {
+            chip.keypad[key] = true;
+        }
+        for _ in 0..SPEED {
+            chip.step();
+        }
+        chip.decrement_timers();
+    }
+}
+
+/// Run until the ROM jumps to itself, as the tests do when they're done.
+/// Returns false if it's still going after `frames` frames.
+fn run_until_halted(chip: &mut Chip8, frames: usize) -> bool {
+    let halted = |chip: &Chip8| chip.opcode() == 0x1000 | chip.pc();
+    for _ in 0..frames {
+        for _ in 0..SPEED {
+            if halted(chip) {
+                return true;
+            }
+            chip.step();
+        }
+        chip.decrement_timers();
+    }
+    halted(chip)
+}
+
+/// Check the screen has ticks and no crosses.
+fn check_marks(display: &[u32]) -> Result<(), String> {
+    match (count(display, TICK), count(display, CROSS)) {
+        (_, crosses) if crosses > 0 => Err(format!("{} tests failed", crosses)),
+        (0, _) => Err("no results on screen".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// Times `glyph` appears on the display with nothing touching it.
+fn count(display: &[u32], glyph: &[&str]) -> usize {
+    let (width, height) = (glyph[0].len(), glyph.len());
+    let mut count = 0;
+    for top in 0..=HEIGHT - height {
+        for left in 0..=WIDTH - width {
+            if matches(display, glyph, left, top) {
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Whether `glyph` is at `left`, `top`, with a blank border round it.
+fn matches(display: &[u32], glyph: &[&str], left: usize, top: usize) -> bool {
+    let (width, height) = (glyph[0].len(), glyph.len());
+    let rows = top.saturating_sub(1)..(top + height + 1).min(HEIGHT);
+    for y in rows {
+        for x in left.saturating_sub(1)..(left + width + 1).min(WIDTH) {
+            let inside = (top..top + height).contains(&y) && (left..left + width).contains(&x);
+            let want = inside && glyph[y - top].as_bytes()[x - left] == b'#';
+            if (display[y * WIDTH + x] != 0) != want {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    #[test]
+    #[ignore = "needs the suite's ROMs in programs/chip8-test-suite or $CHIP8_TEST_SUITE"]
+    fn timendus_suite() {
+        let suite = env::var_os("CHIP8_TEST_SUITE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                Path::new(env!("CARGO_MANIFEST_DIR")).join("programs/chip8-test-suite")
+            });
+        let failures: Vec<String> = CASES
+            .iter()
+            .filter_map(|case| case.load(&suite).and_then(|rom| case.check(rom)).err())
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+    }
+
+    #[test]
+    fn option_is_poked() {
+        // 200: LD I, 1ff
+        // 202: LD V0, [I]
+        // 204: JP 204
+        let rom = vec![0xA1, 0xFF, 0xF0, 0x65, 0x12, 0x04];
+        let mut chip = CASES[2].start(rom);
+        run(&mut chip, 1, None);
+        assert_eq!(chip.registers().v[0], 1);
+        assert_eq!(chip.quirks, Platform::Chip8.quirks());
+    }
+
+    /// Draws the 4 or 5 row sprite at 20a and stops.
+    fn marks_rom(rows: u8, sprite: &[u8]) -> Vec<u8> {
+        // 200: LD I, 20a
+        // 202: LD V0, 8
+        // 204: DRW V0, V0, rows
+        // 206: JP 206
+        let mut rom = vec![0xA2, 0x0A, 0x60, 0x08, 0xD0, rows, 0x12, 0x06, 0, 0];
+        rom.extend_from_slice(sprite);
+        rom
+    }
+
+    #[test]
+    fn results() {
+        let case = CASES[0];
+        let tick = marks_rom(4, &[0x08, 0x10, 0xA0, 0x40]);
+        assert_eq!(case.check(tick), Ok(()));
+
+        let cross = marks_rom(5, &[0x88, 0x50, 0x20, 0x50, 0x88]);
+        let error = case.check(cross).unwrap_err();
+        assert!(error.starts_with("corax: 1 tests failed"), "{}", error);
+
+        // A tick that's part of something bigger doesn't count
+        let blob = marks_rom(4, &[0x0C, 0x10, 0xA0, 0x40]);
+        let error = case.check(blob).unwrap_err();
+        assert!(error.starts_with("corax: no results"), "{}", error);
+
+        // 200: JP 202
+        // 202: JP 200
+        let error = case.check(vec![0x12, 0x02, 0x12, 0x00]).unwrap_err();
+        assert!(error.starts_with("corax: didn't finish"), "{}", error);
+
+        // An unknown instruction fails rather than stopping the other cases
+        let error = case.check(vec![0xFF, 0xFF]).unwrap_err();
+        assert!(
+            error.starts_with("corax: stopped with an error"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn keypad() {
+        // Draws the digit of the key held, if any
+        let rom = vec![
+            0x65, 0x10, // 200: LD V5, 10
+            0x60, 0x00, // 202: LD V0, 0
+            0xE0, 0xA1, // 204: SKNP V0
+            0x12, 0x0E, // 206: JP 20e
+            0x70, 0x01, // 208: ADD V0, 1
+            0x30, 0x10, // 20a: SE V0, 10
+            0x12, 0x04, // 20c: JP 204
+            0x90, 0x50, // 20e: SNE V0, V5
+            0x12, 0x02, // 210: JP 202
+            0x00, 0xE0, // 212: CLS
+            0x85, 0x00, // 214: LD V5, V0
+            0x40, 0x10, // 216: SNE V0, 10
+            0x12, 0x02, // 218: JP 202
+            0xF0, 0x29, // 21a: LD F, V0
+            0xD1, 0x15, // 21c: DRW V1, V1, 5
+            0x12, 0x02, // 21e: JP 202
+        ];
+        assert_eq!(CASES[2].check(rom), Ok(()));
+
+        // 200: JP 200
+        let error = CASES[2].check(vec![0x12, 0x00]).unwrap_err();
+        assert!(error.contains("holding key 0"), "{}", error);
+
+        // 200: LD F, V0
+        // 202: SKNP V0
+        // 204: DRW V1, V1, 5
+        // 206: JP 202
+        let error = CASES[2]
+            .check(vec![0xF0, 0x29, 0xE0, 0xA1, 0xD1, 0x15, 0x12, 0x02])
+            .unwrap_err();
+        assert!(error.contains("letting go of key 0"), "{}", error);
+    }
+}
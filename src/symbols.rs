@@ -0,0 +1,158 @@
+use crate::debug::SourceMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Names for addresses, from an assembler or written by hand, so listings and
+/// debuggers can show `CALL draw_paddle` instead of `CALL $2f0`.
+///
+/// Loaded from a text file with one `address label` entry per line, with the
+/// address in hex. Entries can also be `address file:line` source lines as in
+/// a `SourceMap`, so one file can carry both. Blank lines and lines starting
+/// with `#` are ignored:
+///
+/// ```text
+/// # pong.sym
+/// 200 main
+/// 22a main_loop
+/// 2f0 draw_paddle
+/// 22a pong.8o:31
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    /// The first label given for each address.
+    labels: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+    /// Source lines, if the file had any.
+    pub source_map: SourceMap,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Symbols::parse(&text, dir).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Parse a symbol file, resolving source file names relative to `dir`.
+    pub fn parse(text: &str, dir: &Path) -> Result<Self, String> {
+        let mut symbols = Symbols::new();
+        let mut source_lines = String::new();
+        for (n, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid symbol file line {}: {}", n + 1, line);
+            let (addr, name) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let name = name.trim();
+            let addr = u16::from_str_radix(addr.trim_start_matches("0x"), 16);
+            let addr = addr.map_err(|_| error())?;
+            if is_source_line(name) {
+                source_lines += line;
+                source_lines.push('\n');
+                continue;
+            }
+            if name.contains(char::is_whitespace) {
+                return Err(error());
+            }
+            if symbols.addresses.insert(name.to_string(), addr).is_some() {
+                return Err(format!("Label {} is defined twice", name));
+            }
+            symbols
+                .labels
+                .entry(addr)
+                .or_insert_with(|| name.to_string());
+        }
+        symbols.source_map = SourceMap::parse(&source_lines, dir)?;
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// The label at exactly `addr`.
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// The address of `label`.
+    pub fn address(&self, label: &str) -> Option<u16> {
+        self.addresses.get(label).copied()
+    }
+
+    /// The nearest label at or before `addr`, with the offset from it if
+    /// there is one, like `draw_paddle+0x6`.
+    pub fn locate(&self, addr: u16) -> Option<String> {
+        let (&start, label) = self.labels.range(..=addr).next_back()?;
+        Some(match addr - start {
+            0 => label.clone(),
+            offset => format!("{}+{:#x}", label, offset),
+        })
+    }
+
+    /// `addr` in hex, followed by where it is if there are labels, like
+    /// `0x22a (main_loop)`.
+    pub fn describe(&self, addr: u16) -> String {
+        match self.locate(addr) {
+            Some(location) => format!("{:#05x} ({})", addr, location),
+            None => format!("{:#05x}", addr),
+        }
+    }
+}
+
+/// Whether a symbol file entry is a `file:line` source line.
+fn is_source_line(entry: &str) -> bool {
+    match entry.rsplit_once(':') {
+        Some((_, line)) => !line.is_empty() && line.bytes().all(|b| b.is_ascii_digit()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups() {
+        let symbols = Symbols::parse(
+            "# pong\n\
+             200 main\n\
+             0x22a main_loop\n\
+             22a also_main_loop\n\
+             \n\
+             2f0 draw_paddle\n\
+             22a pong.8o:31\n",
+            Path::new("/src"),
+        )
+        .unwrap();
+
+        assert_eq!(symbols.label(0x22a), Some("main_loop"));
+        assert_eq!(symbols.label(0x22c), None);
+        assert_eq!(symbols.address("also_main_loop"), Some(0x22a));
+        assert_eq!(symbols.address("nothing"), None);
+        assert_eq!(symbols.locate(0x2f6).as_deref(), Some("draw_paddle+0x6"));
+        assert_eq!(symbols.locate(0x1fe), None);
+        assert_eq!(symbols.describe(0x200), "0x200 (main)");
+        assert_eq!(Symbols::new().describe(0x200), "0x200");
+
+        let line = symbols.source_map.location(0x22a).unwrap();
+        assert_eq!(
+            (line.file.as_path(), line.line),
+            (Path::new("/src/pong.8o"), 31)
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Symbols::parse("200", Path::new("")).is_err());
+        assert!(Symbols::parse("zz main", Path::new("")).is_err());
+        assert!(Symbols::parse("200 main loop", Path::new("")).is_err());
+        assert!(Symbols::parse("200 main\n202 main", Path::new("")).is_err());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::chip8::{Chip8, Registers};
+use crate::disassembler::mnemonic_with_symbols;
+use crate::runner::Hook;
+use crate::symbols::Symbols;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Which instructions to trace.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceConfig {
+    /// Only trace instructions at addresses in this inclusive range.
+    pub range: Option<(u16, u16)>,
+    /// Stop tracing after this many lines.
+    pub max_lines: Option<usize>,
+}
+
+impl TraceConfig {
+    /// Parse an address range like `200-2ff`.
+    pub fn parse_range(text: &str) -> Result<(u16, u16), String> {
+        let error = || format!("Invalid address range {}, expected e.g. 200-2ff", text);
+        let (start, end) = text.split_once('-').ok_or_else(error)?;
+        let parse = |addr: &str| u16::from_str_radix(addr.trim_start_matches("0x"), 16);
+        let start = parse(start).map_err(|_| error())?;
+        let end = parse(end).map_err(|_| error())?;
+        if start > end {
+            return Err(error());
+        }
+        Ok((start, end))
+    }
+}
+
+/// Writes a line for every instruction executed, for comparing runs.
+///
+/// Each line has the cycle number, the instruction's address, opcode and
+/// mnemonic (with addresses named by any symbols), then the registers after
+/// it ran:
+///
+/// ```text
+/// 00000001 0200 6a02 LD         Va, #$02    v=00000000000000000000020000000000 i=0000 sp=0 dt=00 st=00
+/// ```
+pub struct Tracer<W: Write = BufWriter<File>> {
+    writer: W,
+    config: TraceConfig,
+    lines: usize,
+    /// Labels for the addresses in mnemonics.
+    symbols: Symbols,
+    /// Set when writing failed, to stop tracing.
+    failed: bool,
+}
+
+impl Tracer {
+    pub fn create(path: &Path, config: TraceConfig) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(BufWriter::new(file), config))
+    }
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(writer: W, config: TraceConfig) -> Self {
+        Tracer {
+            writer,
+            config,
+            lines: 0,
+            symbols: Symbols::new(),
+            failed: false,
+        }
+    }
+
+    /// Name addresses in mnemonics with `symbols`.
+    pub fn with_symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    /// Trace the instruction at `pc`, which `chip` has just executed.
+    pub fn record(&mut self, chip: &Chip8, pc: u16, opcode: u16) -> io::Result<()> {
+        if let Some((start, end)) = self.config.range {
+            if pc < start || pc > end {
+                return Ok(());
+            }
+        }
+        if let Some(max_lines) = self.config.max_lines {
+            if self.lines >= max_lines {
+                return Ok(());
+            }
+            if self.lines + 1 == max_lines {
+                eprintln!("Trace stopped after {} lines", max_lines);
+            }
+        }
+
+        let line = TraceLine::new(chip.cycles(), pc, opcode, &chip.registers());
+        writeln!(self.writer, "{}", line.format(&self.symbols))?;
+        self.lines += 1;
+        Ok(())
+    }
+
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// One line of a trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceLine {
+    pub cycle: u64,
+    pub pc: u16,
+    pub opcode: u16,
+    /// Registers after the instruction ran.
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub delay: u8,
+    pub sound: u8,
+}
+
+impl TraceLine {
+    pub fn new(cycle: u64, pc: u16, opcode: u16, registers: &Registers) -> Self {
+        TraceLine {
+            cycle,
+            pc,
+            opcode,
+            v: registers.v,
+            i: registers.i,
+            sp: registers.sp,
+            delay: registers.delay,
+            sound: registers.sound,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let error = || format!("Invalid trace line: {}", text);
+        let mut fields = text.split_whitespace();
+        let mut next = |radix| {
+            let field = fields.next().ok_or_else(error)?;
+            u64::from_str_radix(field, radix).map_err(|_| error())
+        };
+        let cycle = next(10)?;
+        let pc = next(16)? as u16;
+        let opcode = next(16)? as u16;
+
+        // Skip the mnemonic, which has spaces in it
+        let registers = text.find(" v=").map(|i| &text[i..]).ok_or_else(error)?;
+        let mut line = TraceLine::new(cycle, pc, opcode, &Registers::default());
+        for field in registers.split_whitespace() {
+            let (name, value) = field.split_once('=').ok_or_else(error)?;
+            let number = || u16::from_str_radix(value, 16).map_err(|_| error());
+            match name {
+                "v" => {
+                    if value.len() != 32 {
+                        return Err(error());
+                    }
+                    for (n, v) in line.v.iter_mut().enumerate() {
+                        let hex = value.get(2 * n..2 * n + 2).ok_or_else(error)?;
+                        *v = u8::from_str_radix(hex, 16).map_err(|_| error())?;
+                    }
+                }
+                "i" => line.i = number()?,
+                "sp" => line.sp = number()? as u8,
+                "dt" => line.delay = number()? as u8,
+                "st" => line.sound = number()? as u8,
+                _ => return Err(error()),
+            }
+        }
+        Ok(line)
+    }
+
+    /// Registers with different values in `other`, as (name, ours, theirs).
+    pub fn register_differences(&self, other: &TraceLine) -> Vec<(String, u16, u16)> {
+        let mut differences = Vec::new();
+        for (n, (&a, &b)) in self.v.iter().zip(&other.v).enumerate() {
+            if a != b {
+                differences.push((format!("V{:X}", n), a as u16, b as u16));
+            }
+        }
+        let others = [
+            ("I", self.i, other.i),
+            ("SP", self.sp as u16, other.sp as u16),
+            ("DT", self.delay as u16, other.delay as u16),
+            ("ST", self.sound as u16, other.sound as u16),
+        ];
+        for (name, a, b) in others.iter() {
+            if a != b {
+                differences.push((name.to_string(), *a, *b));
+            }
+        }
+        differences
+    }
+}
+
+impl TraceLine {
+    /// The line with addresses in the mnemonic named by `symbols`.
+    pub fn format(&self, symbols: &Symbols) -> String {
+        let v: String = self.v.iter().map(|v| format!("{:02x}", v)).collect();
+        format!(
+            "{:08} {:04x} {:04x} {:<22} v={} i={:04x} sp={:x} dt={:02x} st={:02x}",
+            self.cycle,
+            self.pc,
+            self.opcode,
+            mnemonic_with_symbols(self.opcode, symbols),
+            v,
+            self.i,
+            self.sp,
+            self.delay,
+            self.sound
+        )
+    }
+}
+
+impl fmt::Display for TraceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.format(&Symbols::new()))
+    }
+}
+
+pub fn parse_trace(text: &str) -> Result<Vec<TraceLine>, String> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(TraceLine::parse)
+        .collect()
+}
+
+/// Where two traces first disagree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    /// Index of the first differing line in each trace. May be the length of
+    /// the trace if it ended early.
+    pub a: usize,
+    pub b: usize,
+    pub cycle: u64,
+    pub difference: Difference,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    /// Only the first trace has a line for this cycle.
+    OnlyInA,
+    /// Only the second trace has a line for this cycle.
+    OnlyInB,
+    /// A different instruction ran.
+    Instruction,
+    /// The same instruction left different values, as (name, a, b).
+    Registers(Vec<(String, u16, u16)>),
+}
+
+/// Line the traces up by cycle and find the first difference.
+pub fn first_divergence(a: &[TraceLine], b: &[TraceLine]) -> Option<Divergence> {
+    let (mut i, mut j) = (0, 0);
+    loop {
+        let (cycle, difference) = match (a.get(i), b.get(j)) {
+            (None, None) => return None,
+            (Some(x), None) => (x.cycle, Difference::OnlyInA),
+            (None, Some(y)) => (y.cycle, Difference::OnlyInB),
+            (Some(x), Some(y)) if x.cycle < y.cycle => (x.cycle, Difference::OnlyInA),
+            (Some(x), Some(y)) if x.cycle > y.cycle => (y.cycle, Difference::OnlyInB),
+            (Some(x), Some(y)) if (x.pc, x.opcode) != (y.pc, y.opcode) => {
+                (x.cycle, Difference::Instruction)
+            }
+            (Some(x), Some(y)) => {
+                let differences = x.register_differences(y);
+                if differences.is_empty() {
+                    i += 1;
+                    j += 1;
+                    continue;
+                }
+                (x.cycle, Difference::Registers(differences))
+            }
+        };
+        return Some(Divergence {
+            a: i,
+            b: j,
+            cycle,
+            difference,
+        });
+    }
+}
+
+impl<W: Write + 'static> Hook for Tracer<W> {
+    fn instruction(&mut self, chip: &Chip8, before: &Registers, opcode: u16) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.record(chip, before.pc, opcode) {
+            eprintln!("Tracing failed: {}", e);
+            self.failed = true;
+        }
+    }
+
+    fn finish(&mut self, _chip: &mut Chip8) {
+        if let Err(e) = self.writer.flush() {
+            eprintln!("Couldn't save trace: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(config: TraceConfig) -> String {
+        trace_with_symbols(config, &Symbols::new())
+    }
+
+    fn trace_with_symbols(config: TraceConfig, symbols: &Symbols) -> String {
+        let mut chip = Chip8::new();
+        // 200: LD VA, 2
+        // 202: LD I, 2A4
+        // 204: JP 200
+        chip.load_binary(vec![0x6A, 0x02, 0xA2, 0xA4, 0x12, 0x00]);
+        let mut tracer = Tracer::new(Vec::new(), config).with_symbols(symbols.clone());
+        for _ in 0..6 {
+            let (pc, opcode) = (chip.pc(), chip.opcode());
+            chip.step();
+            tracer.record(&chip, pc, opcode).unwrap();
+        }
+        String::from_utf8(tracer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn line_format() {
+        let text = trace(TraceConfig::default());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            "00000001 0200 6a02 LD         Va, #$02    \
+             v=00000000000000000000020000000000 i=0000 sp=0 dt=00 st=00"
+        );
+        assert_eq!(
+            lines[4],
+            "00000005 0202 a2a4 LD         I, $2a4     \
+             v=00000000000000000000020000000000 i=02a4 sp=0 dt=00 st=00"
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let symbols = Symbols::parse("2a4 sprite", Path::new("")).unwrap();
+        let text = trace_with_symbols(TraceConfig::default(), &symbols);
+        let line = text.lines().nth(1).unwrap();
+        assert!(line.starts_with("00000002 0202 a2a4 LD         I, sprite   v="));
+        assert_eq!(TraceLine::parse(line).unwrap().i, 0x2a4);
+    }
+
+    #[test]
+    fn filters() {
+        let text = trace(TraceConfig {
+            range: Some((0x202, 0x204)),
+            max_lines: Some(3),
+        });
+        let pcs: Vec<&str> = text.lines().map(|line| &line[9..13]).collect();
+        assert_eq!(pcs, ["0202", "0204", "0202"]);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let text = trace(TraceConfig::default());
+        let lines = parse_trace(&text).unwrap();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[4].i, 0x2a4);
+        assert_eq!(lines[4].v[0xA], 2);
+        let formatted: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+        assert_eq!(formatted, text);
+
+        assert!(TraceLine::parse("00000001 0200 6a02 LD Va, #$02").is_err());
+        assert!(TraceLine::parse("00000001 0200 6a02 LD Va, #$02 v=00 i=0000").is_err());
+    }
+
+    #[test]
+    fn divergence() {
+        let a = parse_trace(&trace(TraceConfig::default())).unwrap();
+        assert_eq!(first_divergence(&a, &a), None);
+
+        let mut b = a.clone();
+        b[3].v[0xF] = 1;
+        b[3].i = 0x300;
+        let divergence = first_divergence(&a, &b).unwrap();
+        assert_eq!((divergence.a, divergence.b, divergence.cycle), (3, 3, 4));
+        assert_eq!(
+            divergence.difference,
+            Difference::Registers(vec![
+                ("VF".to_string(), 0, 1),
+                ("I".to_string(), 0x2a4, 0x300)
+            ])
+        );
+
+        b[2].opcode = 0x1202;
+        assert_eq!(
+            first_divergence(&a, &b).unwrap().difference,
+            Difference::Instruction
+        );
+
+        // A cycle missing from one trace, or one trace ending early
+        let mut b = a.clone();
+        b.remove(1);
+        let divergence = first_divergence(&a, &b).unwrap();
+        assert_eq!(
+            (divergence.a, divergence.difference),
+            (1, Difference::OnlyInA)
+        );
+        let divergence = first_divergence(&a[..2], &a).unwrap();
+        assert_eq!((divergence.b, divergence.cycle), (2, 3));
+        assert_eq!(divergence.difference, Difference::OnlyInB);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(TraceConfig::parse_range("200-2ff"), Ok((0x200, 0x2ff)));
+        assert_eq!(TraceConfig::parse_range("0x200-0x204"), Ok((0x200, 0x204)));
+        assert!(TraceConfig::parse_range("300-200").is_err());
+        assert!(TraceConfig::parse_range("200").is_err());
+    }
+}